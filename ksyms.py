@@ -0,0 +1,67 @@
+import os
+import subprocess
+import sys
+from struct import pack
+
+# Embeds the kernel symbol table used by the panic backtrace.
+#
+# The linker script reserves a `.ksyms` section in the image, this fills it with the text symbols
+# of the ELF so addresses can be turned into names at runtime. Run it after every build:
+#   python3 ksyms.py <kernel elf> <kernel image>
+#
+# The table layout is documented in src/backtrace.rs.
+
+NM = os.environ.get('NM', 'aarch64-none-elf-nm')
+# Address the firmware loads kernel8.img to, see link.ld
+LOAD_ADDR = 0x80000
+
+def readSymbols(elf):
+    output = subprocess.check_output([NM, '-n', '-C', '--defined-only', elf]).decode('utf-8')
+
+    symbols = []
+    markers = {}
+    for line in output.splitlines():
+        parts = line.split(' ', 2)
+        if len(parts) != 3:
+            continue
+
+        addr, kind, name = int(parts[0], 16), parts[1], parts[2]
+        if name in ('__ksyms_start', '__ksyms_end'):
+            markers[name] = addr
+        elif kind in ('t', 'T'):
+            symbols.append((addr, name))
+
+    return symbols, markers['__ksyms_start'], markers['__ksyms_end']
+
+def buildTable(symbols):
+    entries = b''
+    names = b''
+    for addr, name in symbols:
+        encoded = name.encode('utf-8')
+        entries += pack('<QII', addr, len(names), len(encoded))
+        names += encoded
+
+    return b'KSYM' + pack('<I', len(symbols)) + entries + names
+
+def main():
+    if len(sys.argv) != 3:
+        print("Usage: {} <kernel elf> <kernel image>".format(sys.argv[0]))
+        sys.exit(1)
+
+    elf, image = sys.argv[1], sys.argv[2]
+
+    symbols, start, end = readSymbols(elf)
+    table = buildTable(symbols)
+
+    if len(table) > end - start:
+        print("Symbol table is {} bytes, only {} reserved in link.ld".format(len(table), end - start))
+        sys.exit(1)
+
+    with open(image, 'r+b') as f:
+        f.seek(start - LOAD_ADDR)
+        f.write(table)
+
+    print("Wrote {} symbols ({} bytes) to {}".format(len(symbols), len(table), image))
+
+if __name__ == '__main__':
+    main()
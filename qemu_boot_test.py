@@ -0,0 +1,115 @@
+import os
+import select
+import subprocess
+import sys
+import time
+
+# Boots the kernel under `qemu-system-aarch64 -M raspi3b` and drives the mini UART console.
+#
+# Build the kernel with `--features bsp_qemu_raspi3` first, then run
+#   python3 qemu_boot_test.py <kernel image>
+# or have `cargo test -- --ignored kernel_boots` run it, see src/qemu_boot_test.rs.
+#
+# The mini UART is QEMU's second serial port, the PL011 is routed to nothing.
+
+QEMU = os.environ.get('QEMU', 'qemu-system-aarch64')
+# The kernel waits for a newline before booting on, give the firmware stub time to hand over
+BOOT_DELAY = 1
+# The newline is sent again this often until the kernel answers, the mini UART drops what arrives
+# before `bsp::init` has set it up
+RESEND_INTERVAL = 1
+
+# Each step optionally sends some input and then waits up to its timeout for the expected output.
+# The timeouts cover the worst case of what the kernel does before printing the pattern:
+# - bsp::init, fs::init and usb::init run before the console is read. Without an SD card or USB
+#   device every EMMC and DWC wait runs into its 100ms timeout.
+# - Before "Echoing input now." the partition table read times out on the missing card and each
+#   of the three I2C buses is scanned. On QEMU versions without a BSC model the first probe on
+#   each bus waits out TRANSFER_TIMEOUT_US and the rest of that bus is skipped.
+# - Input is read from the idle loop, between polling USB, cpufreq, the thermal sensor and the
+#   serviced devices. Those only do work when they are due, so the echo comes back well within
+#   a second.
+STEPS = [
+    (b'\r', b'Booting on <Raspberry Pi 3 (QEMU)>', 30),
+    (None, b'Drivers loaded:', 10),
+    (None, b'BCM2XXX MiniUart', 10),
+    (None, b'USB devices:', 30),
+    (None, b'Echoing input now.', 30),
+    (b'ping\r', b'ping', 10),
+]
+
+def startQemu(kernel):
+    args = [
+        QEMU,
+        '-M', 'raspi3b',
+        '-kernel', kernel,
+        '-display', 'none',
+        '-serial', 'null',
+        '-serial', 'stdio',
+    ]
+    print("Starting {}".format(' '.join(args)))
+    return subprocess.Popen(args, stdin=subprocess.PIPE, stdout=subprocess.PIPE, stderr=subprocess.STDOUT)
+
+def send(qemu, data):
+    qemu.stdin.write(data)
+    qemu.stdin.flush()
+
+def expect(qemu, output, pattern, timeout, resend=None):
+    deadline = time.time() + timeout
+    while pattern not in output:
+        remaining = deadline - time.time()
+        if remaining <= 0:
+            return False, output
+
+        wait = min(remaining, RESEND_INTERVAL) if resend is not None else remaining
+        ready, _, _ = select.select([qemu.stdout], [], [], wait)
+        if not ready:
+            if resend is not None:
+                send(qemu, resend)
+            continue
+
+        data = os.read(qemu.stdout.fileno(), 4096)
+        if not data:
+            return False, output
+        output += data
+
+    # Consume everything up to and including the match so the next step starts fresh
+    return True, output[output.index(pattern) + len(pattern):]
+
+def main():
+    if len(sys.argv) != 2:
+        print("usage: {} <kernel image>".format(sys.argv[0]))
+        sys.exit(2)
+
+    qemu = startQemu(sys.argv[1])
+    output = b''
+    failed = False
+
+    try:
+        time.sleep(BOOT_DELAY)
+
+        for i, (data, pattern, timeout) in enumerate(STEPS):
+            if data is not None:
+                send(qemu, data)
+
+            # Only the first newline can get lost, later input is echoed back
+            resend = data if i == 0 else None
+            found, output = expect(qemu, output, pattern, timeout, resend)
+            if not found:
+                print("FAIL: expected {!r}, got {!r}".format(pattern, output))
+                failed = True
+                break
+
+            print("ok: {!r}".format(pattern))
+    finally:
+        qemu.kill()
+        qemu.wait()
+
+    if failed:
+        sys.exit(1)
+
+    print("All boot checks passed")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,6 +1,12 @@
 // Here we export the required architecture for the board
 
-#[cfg(feature = "bsp_rpi3")]
+#[cfg(all(not(test), any(feature = "bsp_rpi3", feature = "bsp_rpi4")))]
 pub mod aarch64;
-#[cfg(feature = "bsp_rpi3")]
+#[cfg(all(not(test), any(feature = "bsp_rpi3", feature = "bsp_rpi4")))]
 pub use aarch64::*;
+
+// Unit tests run on the build machine, against fake hardware
+#[cfg(test)]
+pub mod host;
+#[cfg(test)]
+pub use host::*;
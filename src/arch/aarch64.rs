@@ -39,6 +39,48 @@ pub unsafe extern "C" fn _start() -> ! {
 
 pub use asm::nop;
 
+/// ID of the core we are running on
+pub fn core_id() -> u64 {
+    MPIDR_EL1.get() & bsp::CORE_MASK
+}
+
+/// Exception level we are currently executing at
+pub fn exception_level() -> u32 {
+    CurrentEL.read(CurrentEL::EL) as u32
+}
+
+/// Snapshot of the registers worth looking at when things go wrong
+pub struct Registers {
+    pub sp: u64,
+    pub fp: u64,
+    pub lr: u64,
+    pub daif: u64,
+    pub mpidr: u64,
+}
+
+/// Capture the registers of the calling function.
+///
+/// Inlined so that FP/LR still belong to the caller and not to this function.
+#[inline(always)]
+pub fn registers() -> Registers {
+    let fp: u64;
+    let lr: u64;
+
+    // LLVM style `asm!`, the one cortex-a 2.7 is written against, see rust-toolchain
+    unsafe {
+        asm!("mov $0, x29" : "=r"(fp) : : : "volatile");
+        asm!("mov $0, x30" : "=r"(lr) : : : "volatile");
+    }
+
+    Registers {
+        sp: SP.get(),
+        fp,
+        lr,
+        daif: DAIF.get() as u64,
+        mpidr: MPIDR_EL1.get(),
+    }
+}
+
 /// Wait N microseconds
 pub fn wait_usec(n: usize) {
     let frq = CNTFRQ_EL0.get() as usize;
@@ -0,0 +1,71 @@
+//! Host stand-in for the architecture code, used when the kernel is built as a `cargo test` binary.
+//!
+//! Drivers busy-wait on their hardware through `nop()`. Here `nop()` instead runs whatever poll
+//! hook the current test installed, which is how a fake register file gets to change state
+//! underneath a driver (a FIFO filling up, a status bit clearing, ...).
+
+#[path = "aarch64/sync.rs"]
+pub mod sync;
+
+use std::cell::RefCell;
+
+thread_local! {
+    static POLL_HOOK: RefCell<Option<Box<dyn FnMut()>>> = RefCell::new(None);
+}
+
+/// Install a closure that runs every time a driver polls its hardware, replacing any previous one
+pub fn set_poll_hook(hook: impl FnMut() + 'static) {
+    POLL_HOOK.with(|h| *h.borrow_mut() = Some(Box::new(hook)));
+}
+
+/// Remove the poll hook of the current thread
+pub fn clear_poll_hook() {
+    POLL_HOOK.with(|h| *h.borrow_mut() = None);
+}
+
+pub fn nop() {
+    POLL_HOOK.with(|h| {
+        if let Some(hook) = h.borrow_mut().as_mut() {
+            hook();
+        }
+    });
+}
+
+/// Tests always run on the boot core
+pub fn core_id() -> u64 {
+    0
+}
+
+/// The host has no exception levels, pretend to be a kernel
+pub fn exception_level() -> u32 {
+    1
+}
+
+pub struct Registers {
+    pub sp: u64,
+    pub fp: u64,
+    pub lr: u64,
+    pub daif: u64,
+    pub mpidr: u64,
+}
+
+/// There is nothing meaningful to capture on the host
+pub fn registers() -> Registers {
+    Registers {
+        sp: 0,
+        fp: 0,
+        lr: 0,
+        daif: 0,
+        mpidr: 0,
+    }
+}
+
+/// Time does not pass on the host
+pub fn wait_usec(_n: usize) {}
+
+/// Loop forever (Trap state)
+///
+/// A driver giving up on the hardware is a test failure, not something to spin on.
+pub fn wait_forever() -> ! {
+    panic!("wait_forever() called")
+}
@@ -0,0 +1,197 @@
+//! Frame pointer based stack walking and symbolisation.
+//!
+//! AArch64 frame records are two words, the caller's frame pointer followed by the return
+//! address, and x29 always points at the current one. This only works when the kernel is built
+//! with frame pointers (see `.cargo/config`).
+//!
+//! Names come from a symbol table embedded in the `.ksyms` section of the kernel image. The
+//! linker only reserves the space, `ksyms.py` fills it in after the build:
+//!
+//! ```
+//! python3 ksyms.py kernel kernel8.img
+//! ```
+//!
+//! The table layout (all little endian) is
+//!
+//! ```
+//! "KSYM" | count: u32 | count * { addr: u64, name_offset: u32, name_len: u32 } | names
+//! ```
+//!
+//! with entries sorted by address and `name_offset` relative to the start of `names`.
+
+use crate::{bsp, println};
+
+const KSYMS_MAGIC: &[u8; 4] = b"KSYM";
+const KSYMS_HEADER_SIZE: usize = 8;
+const KSYMS_ENTRY_SIZE: usize = 16;
+
+/// Deepest backtrace printed, protects against loops in a corrupted stack
+const MAX_FRAMES: usize = 32;
+
+fn read_u32(table: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&table[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&table[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(not(test))]
+fn symbol_table() -> &'static [u8] {
+    extern "C" {
+        static __ksyms_start: u8;
+        static __ksyms_end: u8;
+    }
+
+    unsafe {
+        let start = &__ksyms_start as *const u8;
+        let end = &__ksyms_end as *const u8;
+
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+#[cfg(test)]
+fn symbol_table() -> &'static [u8] {
+    &[]
+}
+
+/// Find the symbol containing `addr` in `table`, returns its name and the offset into it
+fn lookup(table: &[u8], addr: u64) -> Option<(&str, u64)> {
+    if table.len() < KSYMS_HEADER_SIZE || &table[0..4] != KSYMS_MAGIC {
+        return None;
+    }
+
+    let count = read_u32(table, 4) as usize;
+    let names = KSYMS_HEADER_SIZE + count * KSYMS_ENTRY_SIZE;
+    if names > table.len() {
+        return None;
+    }
+
+    let entry_addr = |i: usize| read_u64(table, KSYMS_HEADER_SIZE + i * KSYMS_ENTRY_SIZE);
+
+    // Binary search for the last symbol starting at or below addr
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if entry_addr(mid) <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    if lo == 0 {
+        return None;
+    }
+
+    let entry = KSYMS_HEADER_SIZE + (lo - 1) * KSYMS_ENTRY_SIZE;
+    let name_start = names + read_u32(table, entry + 8) as usize;
+    let name_end = name_start + read_u32(table, entry + 12) as usize;
+
+    let name = table.get(name_start..name_end)?;
+    let name = core::str::from_utf8(name).ok()?;
+
+    Some((name, addr - read_u64(table, entry)))
+}
+
+/// Resolve a code address against the embedded symbol table
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    lookup(symbol_table(), addr)
+}
+
+fn print_frame(index: usize, pc: u64) {
+    match symbolize(pc) {
+        Some((name, offset)) => println!("    {:>2}: {:#018x} {}+{:#x}", index, pc, name, offset),
+        None => println!("    {:>2}: {:#018x} <unknown>", index, pc),
+    }
+}
+
+/// Walk the frame records starting at `fp` and print every return address on the way
+pub fn print_backtrace(fp: u64, sp: u64) {
+    let stack_top = bsp::BOOT_CORE_STACK_START;
+    let mut fp = fp;
+
+    println!("Backtrace:");
+
+    for i in 0..MAX_FRAMES {
+        // Frame records live on our stack and are 16 byte aligned, anything else means we ran
+        // off the end or the stack is corrupt
+        if fp == 0 || fp % 16 != 0 || fp < sp || fp >= stack_top {
+            break;
+        }
+
+        let record = fp as *const u64;
+        let (next_fp, lr) = unsafe { (record.read_volatile(), record.add(1).read_volatile()) };
+
+        if lr == 0 {
+            break;
+        }
+
+        // The return address is the instruction after the call, report the call itself
+        print_frame(i, lr - 4);
+
+        // The stack grows down, callers' frames are always above ours
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn build_table(symbols: &[(u64, &str)]) -> Vec<u8> {
+        let mut table = Vec::new();
+        let mut names = Vec::new();
+
+        table.extend_from_slice(KSYMS_MAGIC);
+        table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+
+        for (addr, name) in symbols {
+            table.extend_from_slice(&addr.to_le_bytes());
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            names.extend_from_slice(name.as_bytes());
+        }
+
+        table.extend_from_slice(&names);
+        table
+    }
+
+    #[test]
+    fn lookup_finds_enclosing_symbol() {
+        let table = build_table(&[
+            (0x80000, "_start"),
+            (0x80100, "kernel::kernel_entry"),
+            (0x80400, "kernel::bsp::init"),
+        ]);
+
+        assert_eq!(lookup(&table, 0x80000), Some(("_start", 0)));
+        assert_eq!(lookup(&table, 0x80104), Some(("kernel::kernel_entry", 4)));
+        assert_eq!(lookup(&table, 0x80500), Some(("kernel::bsp::init", 0x100)));
+    }
+
+    #[test]
+    fn lookup_below_first_symbol_fails() {
+        let table = build_table(&[(0x80000, "_start")]);
+
+        assert_eq!(lookup(&table, 0x7FFFC), None);
+    }
+
+    #[test]
+    fn lookup_without_table_fails() {
+        // This is what an image that never went through ksyms.py looks like
+        let table = [0u8; 64];
+
+        assert_eq!(lookup(&table, 0x80000), None);
+        assert_eq!(lookup(&[], 0x80000), None);
+    }
+}
@@ -0,0 +1,16 @@
+//! Kernel side of block storage, built on `interface::block::BlockDevice`.
+//!
+//! A `BufferCache` sits in front of a device and keeps recently used blocks in memory, writing
+//! them back lazily. A `PartitionTable` reads the MBR or GPT of a device and hands out each
+//! partition as a `BlockDevice` of its own, so file systems never see absolute block numbers.
+//!
+//! There is no heap, both have a fixed capacity.
+
+mod cache;
+mod partition;
+
+#[cfg(test)]
+pub mod mock;
+
+pub use cache::BufferCache;
+pub use partition::{Error, Kind, Partition, PartitionInfo, PartitionTable};
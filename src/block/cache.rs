@@ -0,0 +1,245 @@
+use crate::{arch::sync::NullLock, interface};
+use interface::block::{self, BlockDevice, BLOCK_SIZE};
+
+/// Blocks kept in memory per cache
+const CACHE_SLOTS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    lba: u64,
+    valid: bool,
+    /// Changed in memory but not yet written to the device
+    dirty: bool,
+    /// Access counter value of the last use, the smallest one gets evicted
+    last_used: u64,
+    data: [u8; BLOCK_SIZE],
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    lba: 0,
+    valid: false,
+    dirty: false,
+    last_used: 0,
+    data: [0; BLOCK_SIZE],
+};
+
+struct CacheInner {
+    slots: [Slot; CACHE_SLOTS],
+    clock: u64,
+}
+
+impl CacheInner {
+    const fn new() -> CacheInner {
+        CacheInner {
+            slots: [EMPTY_SLOT; CACHE_SLOTS],
+            clock: 0,
+        }
+    }
+
+    fn write_back(&mut self, device: &dyn BlockDevice, index: usize) -> block::Result<()> {
+        let slot = &mut self.slots[index];
+
+        if slot.valid && slot.dirty {
+            device.write_blocks(slot.lba, &slot.data)?;
+            slot.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// Find the slot holding `lba`, evicting the least recently used one if it isn't cached.
+    /// `load` reads the block from the device, callers about to overwrite all of it skip that.
+    fn slot(&mut self, device: &dyn BlockDevice, lba: u64, load: bool) -> block::Result<usize> {
+        self.clock += 1;
+
+        if let Some(index) = self.slots.iter().position(|s| s.valid && s.lba == lba) {
+            self.slots[index].last_used = self.clock;
+            return Ok(index);
+        }
+
+        let victim = match self.slots.iter().position(|s| !s.valid) {
+            Some(index) => index,
+            None => (0..CACHE_SLOTS)
+                .min_by_key(|&i| self.slots[i].last_used)
+                .unwrap_or(0),
+        };
+
+        self.write_back(device, victim)?;
+
+        let slot = &mut self.slots[victim];
+        slot.valid = false;
+
+        if load {
+            device.read_blocks(lba, &mut slot.data)?;
+        }
+
+        slot.lba = lba;
+        slot.valid = true;
+        slot.dirty = false;
+        slot.last_used = self.clock;
+
+        Ok(victim)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// Write-back cache in front of a block device. Writes only reach the device when the block is
+/// evicted or on `flush`.
+pub struct BufferCache<'a> {
+    device: &'a dyn BlockDevice,
+    inner: NullLock<CacheInner>,
+}
+
+impl<'a> BufferCache<'a> {
+    pub fn new(device: &'a dyn BlockDevice) -> BufferCache<'a> {
+        BufferCache {
+            device,
+            inner: NullLock::new(CacheInner::new()),
+        }
+    }
+
+    fn check(&self, lba: u64, len: usize) -> block::Result<()> {
+        if len % BLOCK_SIZE != 0 {
+            return Err(block::Error::BadBuffer);
+        }
+
+        if lba + (len / BLOCK_SIZE) as u64 > self.device.block_count() {
+            return Err(block::Error::OutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> BlockDevice for BufferCache<'a> {
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+        self.check(lba, buf.len())?;
+
+        let device = self.device;
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for (block, chunk) in (lba..).zip(buf.chunks_mut(BLOCK_SIZE)) {
+                let index = inner.slot(device, block, true)?;
+                chunk.copy_from_slice(&inner.slots[index].data);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> block::Result<()> {
+        self.check(lba, buf.len())?;
+
+        let device = self.device;
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for (block, chunk) in (lba..).zip(buf.chunks(BLOCK_SIZE)) {
+                let index = inner.slot(device, block, false)?;
+                inner.slots[index].data.copy_from_slice(chunk);
+                inner.slots[index].dirty = true;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> block::Result<()> {
+        let device = self.device;
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for index in 0..CACHE_SLOTS {
+                inner.write_back(device, index)?;
+            }
+
+            Ok(())
+        })?;
+
+        device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::DiskImage;
+
+    fn filled(byte: u8) -> [u8; BLOCK_SIZE] {
+        [byte; BLOCK_SIZE]
+    }
+
+    #[test]
+    fn reads_are_served_from_memory() {
+        let image = DiskImage::new(64);
+        image.write_at(3 * BLOCK_SIZE, &filled(0xAB));
+        let cache = BufferCache::new(&image);
+
+        let mut buf = [0; BLOCK_SIZE];
+        cache.read_blocks(3, &mut buf).unwrap();
+        cache.read_blocks(3, &mut buf).unwrap();
+
+        assert_eq!(buf[..], filled(0xAB)[..]);
+        assert_eq!(image.reads(), 1);
+    }
+
+    #[test]
+    fn writes_wait_for_flush() {
+        let image = DiskImage::new(64);
+        let cache = BufferCache::new(&image);
+
+        cache.write_blocks(5, &filled(0x5A)).unwrap();
+        assert_eq!(image.writes(), 0);
+        assert_eq!(image.bytes()[5 * BLOCK_SIZE], 0);
+
+        let mut buf = [0; BLOCK_SIZE];
+        cache.read_blocks(5, &mut buf).unwrap();
+        assert_eq!(buf[..], filled(0x5A)[..]);
+        assert_eq!(image.reads(), 0);
+
+        cache.flush().unwrap();
+        assert_eq!(image.bytes()[5 * BLOCK_SIZE], 0x5A);
+        assert_eq!(image.writes(), 1);
+        assert_eq!(image.flushes(), 1);
+
+        // Nothing is dirty anymore
+        cache.flush().unwrap();
+        assert_eq!(image.writes(), 1);
+    }
+
+    #[test]
+    fn eviction_writes_back_the_oldest_block() {
+        let image = DiskImage::new(64);
+        let cache = BufferCache::new(&image);
+
+        for lba in 0..=CACHE_SLOTS as u64 {
+            cache.write_blocks(lba, &filled(lba as u8 + 1)).unwrap();
+        }
+
+        assert_eq!(image.writes(), 1);
+        assert_eq!(image.bytes()[0], 1);
+        assert_eq!(image.bytes()[BLOCK_SIZE], 0);
+    }
+
+    #[test]
+    fn requests_are_checked() {
+        let image = DiskImage::new(8);
+        let cache = BufferCache::new(&image);
+        let mut buf = [0; 2 * BLOCK_SIZE];
+
+        assert_eq!(
+            cache.read_blocks(7, &mut buf),
+            Err(block::Error::OutOfRange)
+        );
+        assert_eq!(
+            cache.write_blocks(0, &buf[..10]),
+            Err(block::Error::BadBuffer)
+        );
+    }
+}
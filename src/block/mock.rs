@@ -0,0 +1,100 @@
+//! Disk images in host memory for testing the block layer.
+
+use crate::interface::block::{self, BlockDevice, BLOCK_SIZE};
+use std::cell::{Cell, Ref, RefCell};
+
+pub struct DiskImage {
+    data: RefCell<Vec<u8>>,
+    reads: Cell<usize>,
+    writes: Cell<usize>,
+    flushes: Cell<usize>,
+}
+
+impl DiskImage {
+    /// A zeroed image of `blocks` blocks
+    pub fn new(blocks: usize) -> DiskImage {
+        DiskImage {
+            data: RefCell::new(vec![0; blocks * BLOCK_SIZE]),
+            reads: Cell::new(0),
+            writes: Cell::new(0),
+            flushes: Cell::new(0),
+        }
+    }
+
+    /// Load an image file, padded with zeroes to a whole number of blocks
+    pub fn from_file(path: &std::path::Path) -> std::io::Result<DiskImage> {
+        let mut data = std::fs::read(path)?;
+        let blocks = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        data.resize(blocks * BLOCK_SIZE, 0);
+
+        let image = DiskImage::new(0);
+        image.data.replace(data);
+
+        Ok(image)
+    }
+
+    pub fn bytes(&self) -> Ref<Vec<u8>> {
+        self.data.borrow()
+    }
+
+    /// Patch the image behind the back of whoever is using it
+    pub fn write_at(&self, offset: usize, bytes: &[u8]) {
+        self.data.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Number of `read_blocks` calls that reached the image
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.get()
+    }
+
+    pub fn flushes(&self) -> usize {
+        self.flushes.get()
+    }
+
+    fn range(&self, lba: u64, len: usize) -> block::Result<std::ops::Range<usize>> {
+        if len % BLOCK_SIZE != 0 {
+            return Err(block::Error::BadBuffer);
+        }
+
+        let start = lba as usize * BLOCK_SIZE;
+        if start + len > self.data.borrow().len() {
+            return Err(block::Error::OutOfRange);
+        }
+
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for DiskImage {
+    fn block_count(&self) -> u64 {
+        (self.data.borrow().len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+        let range = self.range(lba, buf.len())?;
+
+        self.reads.set(self.reads.get() + 1);
+        buf.copy_from_slice(&self.data.borrow()[range]);
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> block::Result<()> {
+        let range = self.range(lba, buf.len())?;
+
+        self.writes.set(self.writes.get() + 1);
+        self.data.borrow_mut()[range].copy_from_slice(buf);
+
+        Ok(())
+    }
+
+    fn flush(&self) -> block::Result<()> {
+        self.flushes.set(self.flushes.get() + 1);
+
+        Ok(())
+    }
+}
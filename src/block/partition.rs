@@ -0,0 +1,565 @@
+use crate::interface::block::{self, BlockDevice, BLOCK_SIZE};
+use core::fmt;
+
+/// Partitions past this many are ignored
+pub const MAX_PARTITIONS: usize = 16;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_BOOTABLE: u8 = 0x80;
+/// The single partition of a GPT disk's protective MBR
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Upper bound on the EBR chain, stops us from walking a loop forever
+const MAX_EBRS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Legacy BIOS bootable attribute
+const GPT_ATTR_BOOTABLE: u64 = 1 << 2;
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Reading the device failed
+    Io(block::Error),
+    /// Block 0 carries no boot signature
+    NoTable,
+    /// The disk has a protective MBR but neither GPT header passed its checks
+    BadGpt,
+}
+
+type Result<T> = ::core::result::Result<T, Error>;
+
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// MBR partition type byte
+    Mbr(u8),
+    /// GPT partition type GUID, in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Mbr(kind) => write!(f, "type {:#04x}", kind),
+            // The first three GUID fields are stored little endian
+            Kind::Gpt(g) => write!(
+                f,
+                "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-\
+                 {:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                g[3],
+                g[2],
+                g[1],
+                g[0],
+                g[5],
+                g[4],
+                g[7],
+                g[6],
+                g[8],
+                g[9],
+                g[10],
+                g[11],
+                g[12],
+                g[13],
+                g[14],
+                g[15]
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartitionInfo {
+    pub kind: Kind,
+    pub first_lba: u64,
+    pub block_count: u64,
+    pub bootable: bool,
+}
+
+fn read_u32(block: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&block[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(block: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&block[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Running CRC-32 (IEEE 802.3, reflected) as used by GPT. Start from `!0` and invert the result.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = crc;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// The four primary entries of an MBR or EBR: (type, bootable, first block, block count)
+fn mbr_entries(block: &[u8]) -> [(u8, bool, u64, u64); 4] {
+    let mut entries = [(0, false, 0, 0); 4];
+
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let offset = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+
+        *entry = (
+            block[offset + 4],
+            block[offset] == MBR_BOOTABLE,
+            read_u32(block, offset + 8) as u64,
+            read_u32(block, offset + 12) as u64,
+        );
+    }
+
+    entries
+}
+
+fn has_signature(block: &[u8]) -> bool {
+    block[510..512] == MBR_SIGNATURE
+}
+
+pub struct PartitionTable {
+    entries: [Option<PartitionInfo>; MAX_PARTITIONS],
+    count: usize,
+}
+
+impl PartitionTable {
+    const fn empty() -> PartitionTable {
+        PartitionTable {
+            entries: [None; MAX_PARTITIONS],
+            count: 0,
+        }
+    }
+
+    /// Read the partition table of `device`. A protective MBR means GPT, where the backup
+    /// header at the end of the disk is used if the primary one is damaged.
+    pub fn read(device: &dyn BlockDevice) -> Result<PartitionTable> {
+        let mut block = [0; BLOCK_SIZE];
+        device.read_blocks(0, &mut block)?;
+
+        if !has_signature(&block) {
+            return Err(Error::NoTable);
+        }
+
+        let entries = mbr_entries(&block);
+        if entries.iter().any(|e| e.0 == MBR_TYPE_PROTECTIVE) {
+            return PartitionTable::read_gpt(device);
+        }
+
+        PartitionTable::read_mbr(device, &entries)
+    }
+
+    fn push(&mut self, info: PartitionInfo) {
+        if self.count < MAX_PARTITIONS {
+            self.entries[self.count] = Some(info);
+            self.count += 1;
+        }
+    }
+
+    fn read_mbr(device: &dyn BlockDevice, entries: &[(u8, bool, u64, u64); 4]) -> Result<Self> {
+        let mut table = PartitionTable::empty();
+        let mut extended = None;
+
+        for &(kind, bootable, first_lba, block_count) in entries.iter() {
+            if kind == 0 || block_count == 0 {
+                continue;
+            }
+
+            if MBR_TYPES_EXTENDED.contains(&kind) {
+                extended = Some(first_lba);
+                continue;
+            }
+
+            table.push(PartitionInfo {
+                kind: Kind::Mbr(kind),
+                first_lba,
+                block_count,
+                bootable,
+            });
+        }
+
+        // Logical partitions follow the primaries, chained through one EBR each. Entry 0 of an
+        // EBR is relative to the EBR itself, the link in entry 1 to the start of the extended
+        // partition.
+        if let Some(base) = extended {
+            let mut block = [0; BLOCK_SIZE];
+            let mut ebr = base;
+
+            for _ in 0..MAX_EBRS {
+                device.read_blocks(ebr, &mut block)?;
+                if !has_signature(&block) {
+                    break;
+                }
+
+                let entries = mbr_entries(&block);
+                let (kind, bootable, first_lba, block_count) = entries[0];
+                if kind != 0 && block_count != 0 {
+                    table.push(PartitionInfo {
+                        kind: Kind::Mbr(kind),
+                        first_lba: ebr + first_lba,
+                        block_count,
+                        bootable,
+                    });
+                }
+
+                let (next_kind, _, next_lba, _) = entries[1];
+                if next_kind == 0 || next_lba == 0 {
+                    break;
+                }
+                ebr = base + next_lba;
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn read_gpt(device: &dyn BlockDevice) -> Result<Self> {
+        let backup = device.block_count().saturating_sub(1);
+
+        for &header_lba in [1, backup].iter() {
+            if let Some(table) = PartitionTable::read_gpt_copy(device, header_lba)? {
+                return Ok(table);
+            }
+        }
+
+        Err(Error::BadGpt)
+    }
+
+    /// Parse the GPT header at `header_lba` and its entry array, None if either fails its CRC
+    fn read_gpt_copy(device: &dyn BlockDevice, header_lba: u64) -> Result<Option<Self>> {
+        let mut block = [0; BLOCK_SIZE];
+        device.read_blocks(header_lba, &mut block)?;
+
+        if &block[0..8] != GPT_SIGNATURE {
+            return Ok(None);
+        }
+
+        let header_size = read_u32(&block, 12) as usize;
+        if header_size < GPT_MIN_HEADER_SIZE || header_size > BLOCK_SIZE {
+            return Ok(None);
+        }
+
+        // The header CRC is computed with its own field zeroed
+        let header_crc = read_u32(&block, 16);
+        block[16..20].copy_from_slice(&[0; 4]);
+        if !crc32_update(!0, &block[..header_size]) != header_crc
+            || read_u64(&block, 24) != header_lba
+        {
+            return Ok(None);
+        }
+
+        let entries_lba = read_u64(&block, 72);
+        let entry_count = read_u32(&block, 80) as usize;
+        let entry_size = read_u32(&block, 84) as usize;
+        let entries_crc = read_u32(&block, 88);
+
+        // Entries are 128 << n bytes, so they never straddle a block
+        if entry_size < GPT_MIN_ENTRY_SIZE || BLOCK_SIZE % entry_size != 0 {
+            return Ok(None);
+        }
+
+        let per_block = BLOCK_SIZE / entry_size;
+        let blocks = (entry_count + per_block - 1) / per_block;
+        let mut table = PartitionTable::empty();
+        let mut crc = !0;
+
+        for i in 0..blocks {
+            device.read_blocks(entries_lba + i as u64, &mut block)?;
+
+            let remaining = (entry_count - i * per_block).min(per_block);
+            for entry in block.chunks(entry_size).take(remaining) {
+                crc = crc32_update(crc, entry);
+
+                let mut type_guid = [0; 16];
+                type_guid.copy_from_slice(&entry[0..16]);
+                let first_lba = read_u64(entry, 32);
+                let last_lba = read_u64(entry, 40);
+
+                if type_guid == [0; 16] || last_lba < first_lba {
+                    continue;
+                }
+
+                table.push(PartitionInfo {
+                    kind: Kind::Gpt(type_guid),
+                    first_lba,
+                    block_count: last_lba - first_lba + 1,
+                    bootable: read_u64(entry, 48) & GPT_ATTR_BOOTABLE != 0,
+                });
+            }
+        }
+
+        if !crc != entries_crc {
+            return Ok(None);
+        }
+
+        Ok(Some(table))
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&PartitionInfo> {
+        self.entries.get(index).and_then(|e| e.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PartitionInfo> {
+        self.entries[..self.count].iter().filter_map(|e| e.as_ref())
+    }
+
+    /// Partition `index` of `device` as a block device of its own
+    pub fn partition<'a>(
+        &self,
+        device: &'a dyn BlockDevice,
+        index: usize,
+    ) -> Option<Partition<'a>> {
+        self.get(index).map(|&info| Partition { device, info })
+    }
+}
+
+/// A window onto part of a block device. Block numbers are relative to the partition start and
+/// nothing outside of it can be reached.
+pub struct Partition<'a> {
+    device: &'a dyn BlockDevice,
+    info: PartitionInfo,
+}
+
+impl<'a> Partition<'a> {
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    fn translate(&self, lba: u64, len: usize) -> block::Result<u64> {
+        if len % BLOCK_SIZE != 0 {
+            return Err(block::Error::BadBuffer);
+        }
+
+        if lba + (len / BLOCK_SIZE) as u64 > self.info.block_count {
+            return Err(block::Error::OutOfRange);
+        }
+
+        Ok(self.info.first_lba + lba)
+    }
+}
+
+impl<'a> BlockDevice for Partition<'a> {
+    fn block_count(&self) -> u64 {
+        self.info.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+        let lba = self.translate(lba, buf.len())?;
+        self.device.read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> block::Result<()> {
+        let lba = self.translate(lba, buf.len())?;
+        self.device.write_blocks(lba, buf)
+    }
+
+    fn flush(&self) -> block::Result<()> {
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::DiskImage;
+
+    /// EFI system partition
+    const ESP_GUID: [u8; 16] = [
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ];
+    const LINUX_GUID: [u8; 16] = [
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ];
+
+    const IMAGE_BLOCKS: usize = 128;
+    const ENTRY_COUNT: usize = 128;
+    const ENTRY_BLOCKS: usize = ENTRY_COUNT * GPT_MIN_ENTRY_SIZE / BLOCK_SIZE;
+
+    fn mbr_entry(image: &DiskImage, block: u64, slot: usize, kind: u8, first: u32, count: u32) {
+        let offset = block as usize * BLOCK_SIZE + MBR_TABLE_OFFSET + slot * MBR_ENTRY_SIZE;
+
+        image.write_at(offset + 4, &[kind]);
+        image.write_at(offset + 8, &first.to_le_bytes());
+        image.write_at(offset + 12, &count.to_le_bytes());
+        image.write_at(block as usize * BLOCK_SIZE + 510, &MBR_SIGNATURE);
+    }
+
+    fn gpt_header(my_lba: u64, alternate: u64, entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+        let mut header = vec![0; GPT_MIN_HEADER_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_MIN_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + ENTRY_BLOCKS as u64).to_le_bytes());
+        header[48..56].copy_from_slice(&((IMAGE_BLOCKS - ENTRY_BLOCKS - 2) as u64).to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_MIN_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        let crc = !crc32_update(!0, &header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        header
+    }
+
+    /// GPT disk with an ESP on blocks 34-63 and a Linux partition on 64-94
+    fn gpt_image() -> DiskImage {
+        let image = DiskImage::new(IMAGE_BLOCKS);
+        mbr_entry(
+            &image,
+            0,
+            0,
+            MBR_TYPE_PROTECTIVE,
+            1,
+            IMAGE_BLOCKS as u32 - 1,
+        );
+
+        let mut entries = vec![0; ENTRY_COUNT * GPT_MIN_ENTRY_SIZE];
+        for (i, &(guid, first, last)) in [(ESP_GUID, 34u64, 63u64), (LINUX_GUID, 64, 94)]
+            .iter()
+            .enumerate()
+        {
+            let entry = &mut entries[i * GPT_MIN_ENTRY_SIZE..];
+            entry[0..16].copy_from_slice(&guid);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        entries[48] = GPT_ATTR_BOOTABLE as u8;
+        let entries_crc = !crc32_update(!0, &entries);
+
+        let last = IMAGE_BLOCKS as u64 - 1;
+        let backup_entries = last - ENTRY_BLOCKS as u64;
+        image.write_at(BLOCK_SIZE, &gpt_header(1, last, 2, entries_crc));
+        image.write_at(2 * BLOCK_SIZE, &entries);
+        image.write_at(backup_entries as usize * BLOCK_SIZE, &entries);
+        image.write_at(
+            last as usize * BLOCK_SIZE,
+            &gpt_header(last, 1, backup_entries, entries_crc),
+        );
+
+        image
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let image = DiskImage::new(IMAGE_BLOCKS);
+        mbr_entry(&image, 0, 0, 0x0C, 8, 24);
+        mbr_entry(&image, 0, 1, 0x0F, 40, 80);
+        image.write_at(MBR_TABLE_OFFSET, &[MBR_BOOTABLE]);
+        // Two logical partitions, each preceded by its EBR
+        mbr_entry(&image, 40, 0, 0x83, 2, 20);
+        mbr_entry(&image, 40, 1, 0x05, 30, 40);
+        mbr_entry(&image, 70, 0, 0x82, 2, 30);
+
+        let table = PartitionTable::read(&image).unwrap();
+        let found: Vec<_> = table
+            .iter()
+            .map(|p| (p.kind, p.first_lba, p.block_count, p.bootable))
+            .collect();
+
+        assert_eq!(
+            found,
+            [
+                (Kind::Mbr(0x0C), 8, 24, true),
+                (Kind::Mbr(0x83), 42, 20, false),
+                (Kind::Mbr(0x82), 72, 30, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn gpt_from_image_file() {
+        let path = std::env::temp_dir().join(format!("gpt-{}.img", std::process::id()));
+        std::fs::write(&path, &*gpt_image().bytes()).unwrap();
+        let image = DiskImage::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let table = PartitionTable::read(&image).unwrap();
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.get(0),
+            Some(&PartitionInfo {
+                kind: Kind::Gpt(ESP_GUID),
+                first_lba: 34,
+                block_count: 30,
+                bootable: true,
+            })
+        );
+        assert_eq!(table.get(1).map(|p| p.block_count), Some(31));
+        assert_eq!(
+            format!("{}", table.get(0).unwrap().kind),
+            "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"
+        );
+    }
+
+    #[test]
+    fn gpt_falls_back_to_backup_header() {
+        let image = gpt_image();
+        // Corrupt the primary entry array, its CRC no longer matches
+        image.write_at(2 * BLOCK_SIZE + 32, &[0xFF]);
+
+        let table = PartitionTable::read(&image).unwrap();
+        assert_eq!(table.get(0).map(|p| p.first_lba), Some(34));
+
+        image.write_at((IMAGE_BLOCKS - 1) * BLOCK_SIZE, b"garbage!");
+        assert_eq!(PartitionTable::read(&image).err(), Some(Error::BadGpt));
+    }
+
+    #[test]
+    fn partitions_are_confined() {
+        let image = gpt_image();
+        image.write_at(64 * BLOCK_SIZE, &[0x42; BLOCK_SIZE]);
+
+        let table = PartitionTable::read(&image).unwrap();
+        let linux = table.partition(&image, 1).unwrap();
+        let mut buf = [0; BLOCK_SIZE];
+
+        linux.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 0x42);
+        assert_eq!(
+            linux.read_blocks(31, &mut buf),
+            Err(block::Error::OutOfRange)
+        );
+        assert_eq!(
+            PartitionTable::read(&DiskImage::new(4)).err(),
+            Some(Error::NoTable)
+        );
+    }
+}
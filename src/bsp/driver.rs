@@ -1,9 +1,15 @@
-#[cfg(feature = "bsp_rpi3")]
+#[cfg(feature = "bsp_rpi4")]
+mod arm;
+#[cfg(any(feature = "bsp_rpi3", feature = "bsp_rpi4"))]
 mod bcm;
 #[cfg(feature = "bsp_rpi3")]
 mod dwc_usb_2_0_hs_otg;
+#[cfg(test)]
+mod mock;
 
-#[cfg(feature = "bsp_rpi3")]
+#[cfg(feature = "bsp_rpi4")]
+pub use arm::*;
+#[cfg(any(feature = "bsp_rpi3", feature = "bsp_rpi4"))]
 pub use bcm::*;
 #[cfg(feature = "bsp_rpi3")]
 pub use dwc_usb_2_0_hs_otg::USB;
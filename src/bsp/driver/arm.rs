@@ -0,0 +1,5 @@
+// ARM IP drivers
+
+mod gic400;
+
+pub use gic400::GIC;
@@ -0,0 +1,306 @@
+use crate::{arch::sync::NullLock, interface};
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    /// SYNOPSIS Distributor Control Register, globally enables forwarding of interrupts to the
+    /// CPU interfaces.
+    GICD_CTLR [
+        ENABLE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// SYNOPSIS Interrupt Controller Type Register, provides information about the configuration
+    /// of the GIC.
+    GICD_TYPER [
+        /// Indicates the maximum number of interrupts that the GIC supports.
+        /// The maximum number of interrupts is 32 * (ITLinesNumber + 1).
+        IT_LINES_NUMBER OFFSET(0) NUMBITS(5) []
+    ],
+
+    /// SYNOPSIS CPU Interface Control Register, enables the signalling of interrupts to the
+    /// connected processor.
+    GICC_CTLR [
+        ENABLE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// SYNOPSIS Interrupt Priority Mask Register, only interrupts with a higher priority (lower
+    /// value) than this field are signalled to the processor.
+    GICC_PMR [
+        PRIORITY OFFSET(0) NUMBITS(8) []
+    ],
+
+    /// SYNOPSIS Interrupt Acknowledge Register, reading it acknowledges the highest priority
+    /// pending interrupt.
+    GICC_IAR [
+        INTERRUPT_ID OFFSET(0) NUMBITS(10) []
+    ],
+
+    /// SYNOPSIS End of Interrupt Register, writing the acknowledged ID signals completion.
+    GICC_EOIR [
+        EOI_INT_ID OFFSET(0) NUMBITS(10) []
+    ]
+}
+
+/// Interrupt ID returned by the IAR when there is no pending interrupt
+const SPURIOUS_INTERRUPT: u32 = 1023;
+
+/// Number of Software Generated + Private Peripheral interrupts that are banked per core
+const PRIVATE_INTERRUPTS: usize = 32;
+
+/// Default priority handed to every interrupt during init, lower is more urgent
+const DEFAULT_PRIORITY: u32 = 0xA0;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct DistributorRegisterBlock {
+    CTLR: ReadWrite<u32, GICD_CTLR::Register>,  // 0x000
+    TYPER: ReadOnly<u32, GICD_TYPER::Register>, // 0x004
+    IIDR: ReadOnly<u32>,                        // 0x008
+    __reserved_0: [u32; 29],                    // 0x00C
+    IGROUPR: [ReadWrite<u32>; 32],              // 0x080
+    ISENABLER: [ReadWrite<u32>; 32],            // 0x100
+    ICENABLER: [ReadWrite<u32>; 32],            // 0x180
+    ISPENDR: [ReadWrite<u32>; 32],              // 0x200
+    ICPENDR: [ReadWrite<u32>; 32],              // 0x280
+    ISACTIVER: [ReadWrite<u32>; 32],            // 0x300
+    ICACTIVER: [ReadWrite<u32>; 32],            // 0x380
+    IPRIORITYR: [ReadWrite<u32>; 255],          // 0x400
+    __reserved_1: u32,                          // 0x7FC
+    ITARGETSR: [ReadWrite<u32>; 255],           // 0x800
+    __reserved_2: u32,                          // 0xBFC
+    ICFGR: [ReadWrite<u32>; 64],                // 0xC00
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct CpuRegisterBlock {
+    CTLR: ReadWrite<u32, GICC_CTLR::Register>, // 0x00
+    PMR: ReadWrite<u32, GICC_PMR::Register>,   // 0x04
+    BPR: ReadWrite<u32>,                       // 0x08
+    IAR: ReadOnly<u32, GICC_IAR::Register>,    // 0x0C
+    EOIR: WriteOnly<u32, GICC_EOIR::Register>, // 0x10
+    RPR: ReadOnly<u32>,                        // 0x14
+    HPPIR: ReadOnly<u32>,                      // 0x18
+}
+
+struct GICDInner {
+    base_addr: usize,
+}
+
+impl ops::Deref for GICDInner {
+    type Target = DistributorRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GICDInner {
+    const fn new(base_addr: usize) -> GICDInner {
+        GICDInner { base_addr }
+    }
+
+    fn ptr(&self) -> *const DistributorRegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn num_irqs(&self) -> usize {
+        ((self.TYPER.read(GICD_TYPER::IT_LINES_NUMBER) as usize) + 1) * 32
+    }
+
+    fn init(&self) {
+        self.CTLR.write(GICD_CTLR::ENABLE::CLEAR);
+
+        // ITLinesNumber 31 claims 1024 lines, the last four IDs are special and have no registers
+        let num_irqs = self.num_irqs();
+        let priority_regs = (num_irqs / 4).min(self.IPRIORITYR.len());
+        let target_regs = (num_irqs / 4).min(self.ITARGETSR.len());
+
+        // Shared peripheral interrupts are disabled, de-asserted, level sensitive and routed to
+        // the boot core. The banked private interrupts are left to each core.
+        for i in (PRIVATE_INTERRUPTS / 32)..(num_irqs / 32) {
+            self.ICENABLER[i].set(0xFFFF_FFFF);
+            self.ICPENDR[i].set(0xFFFF_FFFF);
+        }
+
+        for i in (PRIVATE_INTERRUPTS / 16)..(num_irqs / 16) {
+            self.ICFGR[i].set(0);
+        }
+
+        for i in 0..priority_regs {
+            self.IPRIORITYR[i].set(
+                DEFAULT_PRIORITY
+                    | (DEFAULT_PRIORITY << 8)
+                    | (DEFAULT_PRIORITY << 16)
+                    | (DEFAULT_PRIORITY << 24),
+            );
+        }
+
+        for i in (PRIVATE_INTERRUPTS / 4)..target_regs {
+            self.ITARGETSR[i].set(0x0101_0101);
+        }
+
+        self.CTLR.write(GICD_CTLR::ENABLE::SET);
+    }
+
+    fn enable_irq(&self, irq: usize) {
+        self.ISENABLER[irq / 32].set(1 << (irq % 32));
+    }
+
+    fn disable_irq(&self, irq: usize) {
+        self.ICENABLER[irq / 32].set(1 << (irq % 32));
+    }
+
+    fn set_priority(&self, irq: usize, priority: u8) {
+        let shift = (irq % 4) * 8;
+        let value = self.IPRIORITYR[irq / 4].get() & !(0xFF << shift);
+
+        self.IPRIORITYR[irq / 4].set(value | ((priority as u32) << shift));
+    }
+
+    fn set_target(&self, irq: usize, core: usize) {
+        let shift = (irq % 4) * 8;
+        let value = self.ITARGETSR[irq / 4].get() & !(0xFF << shift);
+
+        self.ITARGETSR[irq / 4].set(value | ((1 << core) << shift));
+    }
+}
+
+struct GICCInner {
+    base_addr: usize,
+}
+
+impl ops::Deref for GICCInner {
+    type Target = CpuRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GICCInner {
+    const fn new(base_addr: usize) -> GICCInner {
+        GICCInner { base_addr }
+    }
+
+    fn ptr(&self) -> *const CpuRegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&self) {
+        // Let every priority through, the distributor does the filtering for us
+        self.PMR.write(GICC_PMR::PRIORITY.val(0xFF));
+        self.CTLR.write(GICC_CTLR::ENABLE::SET);
+    }
+
+    fn acknowledge(&self) -> Option<u32> {
+        let irq = self.IAR.read(GICC_IAR::INTERRUPT_ID);
+
+        if irq == SPURIOUS_INTERRUPT {
+            None
+        } else {
+            Some(irq)
+        }
+    }
+
+    fn end_of_interrupt(&self, irq: u32) {
+        self.EOIR.write(GICC_EOIR::EOI_INT_ID.val(irq));
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct GIC {
+    gicd: NullLock<GICDInner>,
+    gicc: NullLock<GICCInner>,
+}
+
+impl GIC {
+    pub const unsafe fn new(gicd_base_addr: usize, gicc_base_addr: usize) -> GIC {
+        GIC {
+            gicd: NullLock::new(GICDInner::new(gicd_base_addr)),
+            gicc: NullLock::new(GICCInner::new(gicc_base_addr)),
+        }
+    }
+
+    /// Unmask an interrupt at the distributor
+    pub fn enable_irq(&self, irq: usize) {
+        let mut r = &self.gicd;
+        r.lock(|inner| inner.enable_irq(irq))
+    }
+
+    /// Mask an interrupt at the distributor
+    pub fn disable_irq(&self, irq: usize) {
+        let mut r = &self.gicd;
+        r.lock(|inner| inner.disable_irq(irq))
+    }
+
+    pub fn set_priority(&self, irq: usize, priority: u8) {
+        let mut r = &self.gicd;
+        r.lock(|inner| inner.set_priority(irq, priority))
+    }
+
+    /// Route a shared peripheral interrupt to a single core
+    pub fn set_target(&self, irq: usize, core: usize) {
+        let mut r = &self.gicd;
+        r.lock(|inner| inner.set_target(irq, core))
+    }
+
+    /// Acknowledge the highest priority pending interrupt, returns None if nothing was pending
+    pub fn acknowledge(&self) -> Option<u32> {
+        let mut r = &self.gicc;
+        r.lock(|inner| inner.acknowledge())
+    }
+
+    /// Signal that the interrupt returned by `acknowledge` has been handled
+    pub fn end_of_interrupt(&self, irq: u32) {
+        let mut r = &self.gicc;
+        r.lock(|inner| inner.end_of_interrupt(irq))
+    }
+}
+
+impl interface::driver::DeviceDriver for GIC {
+    fn compatible(&self) -> &str {
+        "ARM GIC-400"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.gicd;
+        r.lock(|inner| inner.init());
+
+        let mut r = &self.gicc;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const TYPER: usize = 0x004;
+    const IPRIORITYR: usize = 0x400;
+    const ITARGETSR: usize = 0x800;
+
+    #[test]
+    fn init_stops_at_the_last_register_with_every_line_implemented() {
+        let fake = FakeRegisterFile::new(0xD00);
+        fake.write(TYPER, 31);
+
+        let gicd = GICDInner::new(fake.base_addr());
+        gicd.init();
+
+        assert_eq!(fake.read(IPRIORITYR + 254 * 4), 0xA0A0_A0A0);
+        assert_eq!(fake.read(ITARGETSR + 254 * 4), 0x0101_0101);
+        // The reserved words behind the arrays are left alone
+        assert_eq!(fake.read(IPRIORITYR + 255 * 4), 0);
+        assert_eq!(fake.read(ITARGETSR + 255 * 4), 0);
+    }
+}
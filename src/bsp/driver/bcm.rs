@@ -1,20 +1,71 @@
 // BCM SoC drivers
 
+#[cfg(feature = "bsp_rpi4")]
+mod bcm2711_gpio;
+#[cfg(feature = "bsp_rpi4")]
+mod bcm2711_rng200;
+mod bcm2835_bsc_slave;
+mod bcm2835_cprman;
+mod bcm2835_dma;
+mod bcm2835_i2c;
+mod bcm2835_pcm;
+mod bcm2835_pwm;
+mod bcm2835_pwm_audio;
+#[cfg(feature = "bsp_rpi3")]
 mod bcm2835_rand;
+mod bcm2835_spi;
 mod bcm2835_systimer;
+mod bcm2835_watchdog;
+#[cfg(feature = "bsp_rpi3")]
 mod bcm2837_gpio;
+mod bcm2xxx_arm_clock;
 mod bcm2xxx_aux;
+mod bcm2xxx_aux_spi;
+mod bcm2xxx_emmc;
+mod bcm2xxx_gpio;
 mod bcm2xxx_mailbox;
 mod bcm2xxx_mini_uart;
+mod bcm2xxx_power;
+mod bcm2xxx_thermal;
 mod bcm2xxx_uart;
 
+#[cfg(feature = "bsp_rpi4")]
+pub use bcm2711_gpio::GPIO;
+#[cfg(feature = "bsp_rpi4")]
+pub use bcm2711_rng200::Rng;
+pub use bcm2835_bsc_slave::BscSlave;
+pub use bcm2835_cprman::{Clock, ClockConfig, ClockError, ClockManager, Mash, Source};
+pub use bcm2835_dma::{
+    Channel as DmaChannel, Completed, ControlBlock, DmaController, DmaError, Dreq, Transfer,
+};
+pub use bcm2835_i2c::{I2c, Port as I2cPort};
+pub use bcm2835_pcm::{Config as PcmConfig, Pcm, PcmError, Role as PcmRole};
+pub use bcm2835_pwm::{
+    Channel as PwmChannel, ChannelConfig as PwmChannelConfig, Mode as PwmMode, Pwm, PwmError,
+};
+pub use bcm2835_pwm_audio::PwmAudio;
+#[cfg(feature = "bsp_rpi3")]
 pub use bcm2835_rand::Rng;
+pub use bcm2835_spi::{Spi, SpiDmaTransfer};
 pub use bcm2835_systimer::SysTimer;
+pub use bcm2835_watchdog::Watchdog;
+#[cfg(feature = "bsp_rpi3")]
 pub use bcm2837_gpio::GPIO;
+pub use bcm2xxx_arm_clock::ArmClock;
 pub use bcm2xxx_aux::AuxRegisters;
+pub use bcm2xxx_aux_spi::{AuxSpi, Port as AuxSpiPort};
+pub use bcm2xxx_emmc::Emmc;
+pub use bcm2xxx_gpio::{Function, Pull};
+pub use bcm2xxx_mailbox::Clocks;
 pub use bcm2xxx_mailbox::Mail;
 pub use bcm2xxx_mailbox::Mbox;
+#[cfg(feature = "bsp_rpi3")]
+pub use bcm2xxx_mailbox::Power;
 pub use bcm2xxx_mini_uart::MiniUart;
+pub use bcm2xxx_power::{DomainStatus, PowerError, PowerHandle, PowerManager};
+pub use bcm2xxx_thermal::{
+    Sensor as ThermalSensor, Thermal, ThermalError, ThermalEvent, TripKind, TripPoint,
+};
 pub use bcm2xxx_uart::Uart;
 
 // Here we get all the pub structs/enums from bcm2xxx_mailbox::bcm2837_mail so that we can type check
@@ -0,0 +1,362 @@
+use super::bcm2xxx_gpio::{apply_assignments, format_levels, with_function, Function, Pull};
+use crate::{arch, arch::sync::NullLock, interface};
+use core::ops;
+use register::{mmio::ReadWrite, register_bitfields};
+
+/// Number of GPIO pins on the chip
+const PINS: usize = 58;
+
+register_bitfields! {
+    u32,
+
+    GPFSEL1 [
+        // Pin 15
+        FSEL15 OFFSET(15) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            RXD0 = 0b100,
+            // ALT5
+            RXD1 = 0b010
+        ],
+
+        // Pin 14
+        FSEL14 OFFSET(12) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            TXD0 = 0b100,
+            // ALT5
+            TXD1 = 0b010
+        ]
+    ],
+
+    /// SYNOPSIS The BCM2711 replaces GPPUD/GPPUDCLK with a direct two bit pull state per pin,
+    /// no clocking sequence is needed anymore.
+    GPIO_PUP_PDN_CNTRL_REG0 [
+        // Pin 15
+        GPIO_PUP_PDN_CNTRL15 OFFSET(30) NUMBITS(2) [
+            NoResistor = 0b00,
+            PullUp = 0b01,
+            PullDown = 0b10
+        ],
+
+        // Pin 14
+        GPIO_PUP_PDN_CNTRL14 OFFSET(28) NUMBITS(2) [
+            NoResistor = 0b00,
+            PullUp = 0b01,
+            PullDown = 0b10
+        ]
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    pub GPFSEL0: ReadWrite<u32>,                    // 0x00
+    pub GPFSEL1: ReadWrite<u32, GPFSEL1::Register>, // 0x04
+    pub GPFSEL2: ReadWrite<u32>,                    // 0x08
+    pub GPFSEL3: ReadWrite<u32>,                    // 0x0C
+    pub GPFSEL4: ReadWrite<u32>,                    // 0x10
+    pub GPFSEL5: ReadWrite<u32>,                    // 0x14
+    __reserved_0: u32,                              // 0x18
+    GPSET0: ReadWrite<u32>,                         // 0x1C
+    GPSET1: ReadWrite<u32>,                         // 0x20
+    __reserved_1: u32,                              //
+    GPCLR0: ReadWrite<u32>,                         // 0x28
+    GPCLR1: ReadWrite<u32>,                         // 0x2C
+    __reserved_2: u32,                              //
+    GPLEV0: ReadWrite<u32>,                         // 0x34
+    GPLEV1: ReadWrite<u32>,                         // 0x38
+    __reserved_3: u32,                              //
+    GPEDS0: ReadWrite<u32>,                         // 0x40
+    GPEDS1: ReadWrite<u32>,                         // 0x44
+    __reserved_4: [u32; 7],                         //
+    GPHEN0: ReadWrite<u32>,                         // 0x64
+    GPHEN1: ReadWrite<u32>,                         // 0x68
+    __reserved_5: [u32; 30],                        //
+    pub GPIO_PUP_PDN_CNTRL_REG0: ReadWrite<u32, GPIO_PUP_PDN_CNTRL_REG0::Register>, // 0xE4
+    pub GPIO_PUP_PDN_CNTRL_REG1: ReadWrite<u32>,    // 0xE8
+    pub GPIO_PUP_PDN_CNTRL_REG2: ReadWrite<u32>,    // 0xEC
+    pub GPIO_PUP_PDN_CNTRL_REG3: ReadWrite<u32>,    // 0xF0
+}
+
+struct GPIOInner {
+    base_addr: usize,
+    /// Bit n set once pin n has been handed to a peripheral
+    claimed: u64,
+}
+
+impl ops::Deref for GPIOInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GPIOInner {
+    const fn new(base_addr: usize) -> GPIOInner {
+        GPIOInner {
+            base_addr,
+            claimed: 0,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn set_function(&self, pin: usize, function: Function) {
+        let update = |value| with_function(value, pin, function);
+
+        match pin / 10 {
+            0 => self.GPFSEL0.set(update(self.GPFSEL0.get())),
+            1 => self.GPFSEL1.set(update(self.GPFSEL1.get())),
+            2 => self.GPFSEL2.set(update(self.GPFSEL2.get())),
+            3 => self.GPFSEL3.set(update(self.GPFSEL3.get())),
+            4 => self.GPFSEL4.set(update(self.GPFSEL4.get())),
+            _ => self.GPFSEL5.set(update(self.GPFSEL5.get())),
+        }
+    }
+
+    fn level(&self, pin: usize) -> bool {
+        if pin < 32 {
+            self.GPLEV0.get() & (1 << pin) != 0
+        } else {
+            self.GPLEV1.get() & (1 << (pin - 32)) != 0
+        }
+    }
+
+    /// Drive an output `pin` high or low
+    fn set_level(&self, pin: usize, high: bool) {
+        match (pin < 32, high) {
+            (true, true) => self.GPSET0.set(1 << pin),
+            (true, false) => self.GPCLR0.set(1 << pin),
+            (false, true) => self.GPSET1.set(1 << (pin - 32)),
+            (false, false) => self.GPCLR1.set(1 << (pin - 32)),
+        }
+    }
+
+    /// Hand pins 7-11 (CE1, CE0, MISO, MOSI, SCLK) to SPI0 on ALT0
+    fn map_spi0(&mut self) {
+        for pin in 7..=11 {
+            self.claim(pin, Function::Alt0, Pull::None);
+        }
+    }
+
+    /// Hand pins 16-21 (CE2, CE1, CE0, MISO, MOSI, SCLK) to the AUX SPI1 on ALT4
+    fn map_spi1(&mut self) {
+        for pin in 16..=21 {
+            self.claim(pin, Function::Alt4, Pull::None);
+        }
+    }
+
+    /// Hand pins 0-1 (SDA, SCL) to BSC0 on ALT0. These go to the HAT ID EEPROM.
+    fn map_i2c0(&mut self) {
+        for pin in 0..=1 {
+            self.claim(pin, Function::Alt0, Pull::Up);
+        }
+    }
+
+    /// Hand pins 2-3 (SDA, SCL) to BSC1 on ALT0. The board has its own pull-ups on these.
+    fn map_i2c1(&mut self) {
+        for pin in 2..=3 {
+            self.claim(pin, Function::Alt0, Pull::None);
+        }
+    }
+
+    /// Hand pins 10-11 (SDA, SCL) to the BSC slave on ALT3. These are shared with SPI0.
+    fn map_bsc_slave(&mut self) {
+        for pin in 10..=11 {
+            self.claim(pin, Function::Alt3, Pull::None);
+        }
+    }
+
+    /// Hand pins 40 and 41 (left, right) to PWM1 channels 1 and 2 on ALT0, for the headphone jack
+    fn map_pwm_audio(&mut self) {
+        for &pin in [40, 41].iter() {
+            self.claim(pin, Function::Alt0, Pull::None);
+        }
+    }
+
+    /// Hand pins 18-21 (CLK, FS, DIN, DOUT) to PCM/I2S on ALT0
+    fn map_pcm(&mut self) {
+        for pin in 18..=21 {
+            self.claim(pin, Function::Alt0, Pull::None);
+        }
+    }
+
+    /// Hand `pin` to a peripheral, `/dev/gpio` leaves it alone from then on
+    fn claim(&mut self, pin: usize, function: Function, pull: Pull) {
+        self.set_function(pin, function);
+        self.set_pull(pin, pull);
+        self.claimed |= 1 << pin;
+    }
+
+    fn set_pull(&self, pin: usize, pull: Pull) {
+        let control = match pull {
+            Pull::None => 0b00,
+            Pull::Up => 0b01,
+            Pull::Down => 0b10,
+        };
+        let shift = (pin % 16) * 2;
+        let update = |value: u32| (value & !(0b11 << shift)) | (control << shift);
+
+        match pin / 16 {
+            0 => self
+                .GPIO_PUP_PDN_CNTRL_REG0
+                .set(update(self.GPIO_PUP_PDN_CNTRL_REG0.get())),
+            1 => self
+                .GPIO_PUP_PDN_CNTRL_REG1
+                .set(update(self.GPIO_PUP_PDN_CNTRL_REG1.get())),
+            2 => self
+                .GPIO_PUP_PDN_CNTRL_REG2
+                .set(update(self.GPIO_PUP_PDN_CNTRL_REG2.get())),
+            _ => self
+                .GPIO_PUP_PDN_CNTRL_REG3
+                .set(update(self.GPIO_PUP_PDN_CNTRL_REG3.get())),
+        }
+    }
+
+    fn map_mini_uart(&mut self) {
+        self.claimed |= 1 << 14 | 1 << 15;
+
+        // Map to pins.
+        self.GPFSEL1
+            .modify(GPFSEL1::FSEL14::TXD1 + GPFSEL1::FSEL15::RXD1);
+
+        // Disable the pull resistors on pins 14 and 15.
+        self.GPIO_PUP_PDN_CNTRL_REG0.modify(
+            GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL14::NoResistor
+                + GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL15::NoResistor,
+        );
+    }
+
+    fn map_uart0(&mut self) {
+        self.claimed |= 1 << 14 | 1 << 15;
+
+        self.GPFSEL1
+            .modify(GPFSEL1::FSEL14::TXD0 + GPFSEL1::FSEL15::RXD0);
+
+        // The PL011 idles high, keep RX pulled up so a floating line does not read as a break.
+        self.GPIO_PUP_PDN_CNTRL_REG0.modify(
+            GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL14::NoResistor
+                + GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL15::PullUp,
+        );
+
+        for _ in 0..150 {
+            arch::nop();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct GPIO {
+    inner: NullLock<GPIOInner>,
+}
+
+impl GPIO {
+    pub const unsafe fn new(base_addr: usize) -> GPIO {
+        GPIO {
+            inner: NullLock::new(GPIOInner::new(base_addr)),
+        }
+    }
+
+    pub fn map_mini_uart(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_mini_uart());
+    }
+
+    pub fn map_uart0(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_uart0());
+    }
+
+    /// Select what drives `pin`
+    pub fn set_function(&self, pin: usize, function: Function) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_function(pin, function));
+    }
+
+    pub fn set_pull(&self, pin: usize, pull: Pull) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_pull(pin, pull));
+    }
+
+    /// Current input level of `pin`
+    pub fn level(&self, pin: usize) -> bool {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.level(pin))
+    }
+
+    pub fn set_level(&self, pin: usize, high: bool) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_level(pin, high));
+    }
+
+    pub fn map_spi0(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_spi0());
+    }
+
+    pub fn map_spi1(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_spi1());
+    }
+
+    pub fn map_i2c0(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_i2c0());
+    }
+
+    pub fn map_i2c1(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_i2c1());
+    }
+
+    pub fn map_bsc_slave(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_bsc_slave());
+    }
+
+    pub fn map_pwm_audio(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_pwm_audio());
+    }
+
+    pub fn map_pcm(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.map_pcm());
+    }
+}
+
+impl interface::driver::DeviceDriver for GPIO {
+    fn compatible(&self) -> &str {
+        "BCM2711 GPIO"
+    }
+
+    // Use default init()
+}
+
+impl interface::driver::CharDevice for GPIO {
+    /// A snapshot of every pin level, one '0' or '1' per pin
+    fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| format_levels(PINS, |pin| inner.level(pin), offset, buf))
+    }
+
+    /// Takes `<pin>=<0|1>` lines, each named pin is made an output and driven to that level.
+    /// Stops short at a pin that belongs to a peripheral.
+    fn write(&self, buf: &[u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            apply_assignments(buf, PINS, inner.claimed, |pin, high| {
+                inner.set_function(pin, Function::Output);
+                inner.set_level(pin, high);
+            })
+        })
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{arch, arch::sync::NullLock, interface};
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    /// SYNOPSIS The BCM2711 replaces the BCM2835 RNG with the iProc RNG200 block.
+    RNG_CTRL [
+        /// Random bit generator enable
+        RBGEN OFFSET(0) NUMBITS(13) [
+            Enabled = 0x1,
+            Disabled = 0x0
+        ]
+    ],
+
+    RNG_FIFO_COUNT [
+        /// Number of 32 bit words that are ready to be read from the FIFO
+        COUNT OFFSET(0) NUMBITS(8) [],
+        THRESHOLD OFFSET(8) NUMBITS(8) []
+    ]
+}
+
+const RNG_WARMUP_COUNT: u32 = 0x40_000;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CTRL: ReadWrite<u32, RNG_CTRL::Register>,             // 0x00
+    SOFT_RESET: ReadWrite<u32>,                           // 0x04
+    RBG_SOFT_RESET: ReadWrite<u32>,                       // 0x08
+    TOTAL_BIT_COUNT: ReadOnly<u32>,                       // 0x0C
+    TOTAL_BIT_COUNT_THRESHOLD: ReadWrite<u32>,            // 0x10
+    __reserved_0: u32,                                    // 0x14
+    INT_STATUS: ReadWrite<u32>,                           // 0x18
+    INT_ENABLE: ReadWrite<u32>,                           // 0x1C
+    FIFO_DATA: ReadOnly<u32>,                             // 0x20
+    FIFO_COUNT: ReadWrite<u32, RNG_FIFO_COUNT::Register>, // 0x24
+}
+
+struct RngInner {
+    base_addr: usize,
+}
+
+impl ops::Deref for RngInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl RngInner {
+    const fn new(base_addr: usize) -> RngInner {
+        RngInner { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&self) {
+        // Interrupts are not used, everything is polled
+        self.INT_ENABLE.set(0);
+
+        self.TOTAL_BIT_COUNT_THRESHOLD.set(RNG_WARMUP_COUNT);
+        self.FIFO_COUNT.modify(RNG_FIFO_COUNT::THRESHOLD.val(2));
+        self.CTRL.modify(RNG_CTRL::RBGEN::Enabled);
+    }
+
+    fn rand(&self, min: usize, max: usize) -> usize {
+        loop {
+            if self.FIFO_COUNT.read(RNG_FIFO_COUNT::COUNT) >= 2 {
+                break;
+            }
+
+            arch::nop();
+        }
+
+        let l = self.FIFO_DATA.get();
+        let r = self.FIFO_DATA.get();
+
+        let rand = ((l as usize) << 32) | (r as usize);
+
+        rand % (max - min) + min
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Rng {
+    inner: NullLock<RngInner>,
+}
+
+impl Rng {
+    pub const fn new(base_addr: usize) -> Rng {
+        Rng {
+            inner: NullLock::new(RngInner::new(base_addr)),
+        }
+    }
+
+    pub fn rand(&self, min: usize, max: usize) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.rand(min, max))
+    }
+}
+
+impl interface::driver::DeviceDriver for Rng {
+    fn compatible(&self) -> &str {
+        "BCM2711 RNG200 Hardware Random Number Generator"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+impl interface::driver::CharDevice for Rng {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for byte in buf.iter_mut() {
+                *byte = inner.rand(0, 256) as u8;
+            }
+        });
+
+        buf.len()
+    }
+
+    /// The generator has no seed to feed, writes are dropped
+    fn write(&self, _buf: &[u8]) -> usize {
+        0
+    }
+}
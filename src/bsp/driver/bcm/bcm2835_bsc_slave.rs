@@ -0,0 +1,369 @@
+use crate::{arch::sync::NullLock, interface};
+use core::ops;
+use interface::i2c::{self, I2cTarget};
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    RSR [
+        /// TX FIFO ran dry while the master was reading
+        UE OFFSET(1) NUMBITS(1) [],
+        /// RX FIFO overflowed
+        OE OFFSET(0) NUMBITS(1) []
+    ],
+
+    SLV [
+        ADDR OFFSET(0) NUMBITS(7) []
+    ],
+
+    CR [
+        INV_TXF OFFSET(13) NUMBITS(1) [],
+        HOSTCTRLEN OFFSET(12) NUMBITS(1) [],
+        TESTFIFO OFFSET(11) NUMBITS(1) [],
+        INV_RXF OFFSET(10) NUMBITS(1) [],
+        RXE OFFSET(9) NUMBITS(1) [],
+        TXE OFFSET(8) NUMBITS(1) [],
+        /// Abandon the current transfer and flush both FIFOs
+        BRK OFFSET(7) NUMBITS(1) [],
+        ENCTRL OFFSET(6) NUMBITS(1) [],
+        ENSTAT OFFSET(5) NUMBITS(1) [],
+        CPOL OFFSET(4) NUMBITS(1) [],
+        CPHA OFFSET(3) NUMBITS(1) [],
+        I2C OFFSET(2) NUMBITS(1) [],
+        SPI OFFSET(1) NUMBITS(1) [],
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+
+    FR [
+        RXFLEVEL OFFSET(11) NUMBITS(5) [],
+        TXFLEVEL OFFSET(6) NUMBITS(5) [],
+        /// A master is writing to us
+        RXBUSY OFFSET(5) NUMBITS(1) [],
+        TXFE OFFSET(4) NUMBITS(1) [],
+        RXFF OFFSET(3) NUMBITS(1) [],
+        TXFF OFFSET(2) NUMBITS(1) [],
+        RXFE OFFSET(1) NUMBITS(1) [],
+        /// A master is reading from us
+        TXBUSY OFFSET(0) NUMBITS(1) []
+    ],
+
+    IFLS [
+        /// Interrupt at 1/8, 1/4, 1/2, 3/4 or 7/8 full
+        RXIFLSEL OFFSET(3) NUMBITS(3) [],
+        /// Interrupt at 1/8, 1/4, 1/2, 3/4 or 7/8 empty
+        TXIFLSEL OFFSET(0) NUMBITS(3) []
+    ],
+
+    /// Layout shared by IMSC, RIS, MIS and ICR
+    INT [
+        OEIM OFFSET(3) NUMBITS(1) [],
+        BEIM OFFSET(2) NUMBITS(1) [],
+        TXIM OFFSET(1) NUMBITS(1) [],
+        RXIM OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    DR: ReadWrite<u32>,                   // 0x00
+    RSR: ReadWrite<u32, RSR::Register>,   // 0x04
+    SLV: ReadWrite<u32, SLV::Register>,   // 0x08
+    CR: ReadWrite<u32, CR::Register>,     // 0x0C
+    FR: ReadOnly<u32, FR::Register>,      // 0x10
+    IFLS: ReadWrite<u32, IFLS::Register>, // 0x14
+    IMSC: ReadWrite<u32, INT::Register>,  // 0x18
+    RIS: ReadOnly<u32, INT::Register>,    // 0x1C
+    MIS: ReadOnly<u32, INT::Register>,    // 0x20
+    ICR: WriteOnly<u32, INT::Register>,   // 0x24
+}
+
+/// Registers read ahead into the TX FIFO. Only one, so at most one register is read that the
+/// master never asks for, and a new register pointer leaves at most one stale byte queued.
+const READ_AHEAD: u32 = 1;
+
+struct BscSlaveInner {
+    base_addr: usize,
+    target: Option<&'static dyn I2cTarget>,
+    /// Register the next access goes to
+    pointer: u8,
+    /// The next byte written by the master selects a register
+    expect_pointer: bool,
+    /// Bytes we put in the TX FIFO for registers `pointer` onwards
+    queued: u32,
+}
+
+impl ops::Deref for BscSlaveInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl BscSlaveInner {
+    const fn new(base_addr: usize) -> BscSlaveInner {
+        BscSlaveInner {
+            base_addr,
+            target: None,
+            pointer: 0,
+            expect_pointer: true,
+            queued: 0,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn stop(&mut self) {
+        self.IMSC.set(0);
+        self.CR.set(0);
+        self.flush();
+        self.target = None;
+    }
+
+    fn listen(&mut self, address: u8, target: &'static dyn I2cTarget) -> i2c::Result<()> {
+        if address > 0x7F {
+            return Err(i2c::Error::InvalidAddress);
+        }
+
+        self.stop();
+
+        self.target = Some(target);
+        self.pointer = 0;
+        self.expect_pointer = true;
+
+        self.SLV.write(SLV::ADDR.val(address as u32));
+        self.RSR.set(0);
+        self.CR
+            .write(CR::EN::SET + CR::I2C::SET + CR::RXE::SET + CR::TXE::SET);
+
+        self.refill(target);
+
+        Ok(())
+    }
+
+    /// Drop both FIFOs, the only way to take back bytes queued for the master
+    fn flush(&mut self) {
+        self.CR.modify(CR::BRK::SET);
+        self.CR.modify(CR::BRK::CLEAR);
+        self.queued = 0;
+    }
+
+    /// Queue the register following the queued ones once the master took them
+    fn refill(&mut self, target: &dyn I2cTarget) {
+        let level = self.FR.read(FR::TXFLEVEL);
+
+        for _ in level..READ_AHEAD {
+            let register = self.pointer.wrapping_add(self.queued as u8);
+            self.DR.set(target.read_register(register) as u32);
+            self.queued += 1;
+        }
+    }
+
+    fn service(&mut self) {
+        let target = match self.target {
+            Some(target) => target,
+            None => return,
+        };
+
+        self.RSR.set(0);
+
+        // Whatever left the TX FIFO since last time has been read by the master
+        let level = self.FR.read(FR::TXFLEVEL);
+        let sent = self.queued.saturating_sub(level);
+        self.pointer = self.pointer.wrapping_add(sent as u8);
+        self.queued = level;
+
+        let mut received = false;
+        for _ in 0..self.FR.read(FR::RXFLEVEL) {
+            let byte = self.DR.get() as u8;
+
+            if self.expect_pointer {
+                self.pointer = byte;
+                self.expect_pointer = false;
+            } else {
+                target.write_register(self.pointer, byte);
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+
+            received = true;
+        }
+
+        if !self.FR.is_set(FR::RXBUSY) {
+            self.expect_pointer = true;
+        }
+
+        // What is queued was read from the old position, or before the write changed it
+        if received && self.queued > 0 {
+            self.flush();
+        }
+
+        self.refill(target);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// The BSC/SPI slave, run as an I2C target. An external master sees a register map served by
+/// the `I2cTarget` passed to `listen`.
+///
+/// The kernel doesn't take interrupts, the FIFOs are moved along by `service()` from the idle
+/// loop. A master that writes the register pointer and reads back after a repeated start is
+/// not supported: the byte it reads first was queued for the old pointer, unless `service()`
+/// happened to run in between. Use a stop between the write and the read.
+pub struct BscSlave {
+    inner: NullLock<BscSlaveInner>,
+}
+
+impl BscSlave {
+    pub const unsafe fn new(base_addr: usize) -> BscSlave {
+        BscSlave {
+            inner: NullLock::new(BscSlaveInner::new(base_addr)),
+        }
+    }
+
+    /// Answer to `address` on the bus from now on, serving accesses from `target`
+    pub fn listen(&self, address: u8, target: &'static dyn I2cTarget) -> i2c::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.listen(address, target))
+    }
+
+    /// Stop answering, the master will see its address NACKed
+    pub fn stop(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.stop());
+    }
+
+    /// Move bytes between the FIFOs and the target, called from the idle loop through
+    /// `bsp::service_devices`. Reads stall until it runs, only one byte is queued ahead.
+    pub fn service(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.service());
+    }
+}
+
+impl interface::driver::DeviceDriver for BscSlave {
+    fn compatible(&self) -> &str {
+        "BCM2835 BSC Slave"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        // Nothing to answer with until someone calls listen()
+        self.stop();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+    use std::sync::Mutex;
+
+    const DR: usize = 0x00;
+    const SLV_REG: usize = 0x08;
+    const CR_REG: usize = 0x0C;
+    const FR_REG: usize = 0x10;
+
+    const EN: u32 = 1 << 0;
+    const I2C: u32 = 1 << 2;
+    const BRK: u32 = 1 << 7;
+    const RXBUSY: u32 = 1 << 5;
+
+    fn rx_level(n: u32) -> u32 {
+        n << 11
+    }
+
+    fn tx_level(n: u32) -> u32 {
+        n << 6
+    }
+
+    /// Reads return twice the register number, writes are recorded
+    struct Registers {
+        written: Mutex<Vec<(u8, u8)>>,
+    }
+
+    impl I2cTarget for Registers {
+        fn read_register(&self, register: u8) -> u8 {
+            register.wrapping_mul(2)
+        }
+
+        fn write_register(&self, register: u8, value: u8) {
+            self.written.lock().unwrap().push((register, value));
+        }
+    }
+
+    fn target() -> &'static Registers {
+        Box::leak(Box::new(Registers {
+            written: Mutex::new(Vec::new()),
+        }))
+    }
+
+    #[test]
+    fn listen_sets_the_address_and_fills_the_tx_fifo() {
+        let fake = FakeRegisterFile::new(0x40);
+        let mut slave = BscSlaveInner::new(fake.base_addr());
+
+        assert_eq!(
+            slave.listen(0x80, target()),
+            Err(i2c::Error::InvalidAddress)
+        );
+        slave.listen(0x42, target()).unwrap();
+
+        assert_eq!(fake.read(SLV_REG), 0x42);
+        assert_eq!(fake.read(CR_REG) & (EN | I2C), EN | I2C);
+        assert_eq!(slave.queued, READ_AHEAD);
+        // Only register 0 was read
+        assert_eq!(fake.read(DR), 0);
+    }
+
+    #[test]
+    fn first_byte_selects_the_register_and_the_rest_are_written() {
+        let fake = FakeRegisterFile::new(0x40);
+        let mut slave = BscSlaveInner::new(fake.base_addr());
+        let target = target();
+        slave.listen(0x42, target).unwrap();
+
+        // Pretend the master read nothing, so the queued byte is still there
+        fake.write(FR_REG, rx_level(1) | tx_level(1) | RXBUSY);
+        fake.write(DR, 0x10);
+        slave.service();
+        assert_eq!(slave.pointer, 0x10);
+
+        fake.write(FR_REG, rx_level(1) | tx_level(1));
+        fake.write(DR, 0xAA);
+        slave.service();
+
+        assert_eq!(*target.written.lock().unwrap(), [(0x10, 0xAA)]);
+        assert_eq!(slave.pointer, 0x11);
+        assert!(slave.expect_pointer);
+    }
+
+    #[test]
+    fn reads_advance_the_pointer_and_stale_bytes_are_flushed() {
+        let fake = FakeRegisterFile::new(0x40);
+        let mut slave = BscSlaveInner::new(fake.base_addr());
+        slave.listen(0x42, target()).unwrap();
+
+        // The master clocked out the queued byte, the next register takes its place
+        fake.write(FR_REG, tx_level(0));
+        slave.service();
+        assert_eq!(slave.pointer, 1);
+        assert_eq!(slave.queued, 1);
+        assert_eq!(fake.read(DR), 2);
+
+        // A new register pointer makes the queued byte wrong, BRK drops it
+        fake.write(FR_REG, rx_level(1) | tx_level(1));
+        fake.write(DR, 0x20);
+        slave.service();
+        assert_eq!(slave.pointer, 0x20);
+        assert_eq!(fake.read(CR_REG) & BRK, 0);
+    }
+}
@@ -0,0 +1,505 @@
+use crate::{arch, arch::sync::NullLock, interface};
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+// The clock manager ignores any write that does not carry the password in the top byte
+register_bitfields! {
+    u32,
+
+    CTL [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+        /// MASH noise shaping, 0 means plain integer division
+        MASH OFFSET(9) NUMBITS(2) [],
+        /// Invert the generator output, for testing
+        FLIP OFFSET(8) NUMBITS(1) [],
+        /// Set while the generator is running, wait for it to clear before changing anything
+        BUSY OFFSET(7) NUMBITS(1) [],
+        /// Stop the generator immediately, may glitch the output
+        KILL OFFSET(5) NUMBITS(1) [],
+        ENAB OFFSET(4) NUMBITS(1) [],
+        SRC OFFSET(0) NUMBITS(4) []
+    ],
+
+    DIV [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+        DIVI OFFSET(12) NUMBITS(12) [],
+        DIVF OFFSET(0) NUMBITS(12) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved0: [u32; 28],                // 0x00
+    GP0CTL: ReadWrite<u32, CTL::Register>, // 0x70
+    GP0DIV: ReadWrite<u32, DIV::Register>, // 0x74
+    GP1CTL: ReadWrite<u32, CTL::Register>, // 0x78
+    GP1DIV: ReadWrite<u32, DIV::Register>, // 0x7C
+    GP2CTL: ReadWrite<u32, CTL::Register>, // 0x80
+    GP2DIV: ReadWrite<u32, DIV::Register>, // 0x84
+    __reserved1: [u32; 4],                 // 0x88
+    PCMCTL: ReadWrite<u32, CTL::Register>, // 0x98
+    PCMDIV: ReadWrite<u32, DIV::Register>, // 0x9C
+    PWMCTL: ReadWrite<u32, CTL::Register>, // 0xA0
+    PWMDIV: ReadWrite<u32, DIV::Register>, // 0xA4
+}
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum ClockError {
+    /// The divisor is out of range for the selected MASH stage
+    InvalidDivisor,
+    /// The generator did not stop when asked to
+    Busy,
+    /// Reading the register back did not return what was written, most likely a bad password
+    WriteRejected,
+    /// No source can produce the requested frequency
+    Unreachable,
+}
+type Result<T> = ::core::result::Result<T, ClockError>;
+
+/// Clock generators this driver can program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    GP0,
+    GP1,
+    GP2,
+    PCM,
+    PWM,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Source {
+    Ground = 0,
+    Oscillator = 1,
+    TestDebug0 = 2,
+    TestDebug1 = 3,
+    PLLA = 4,
+    PLLC = 5,
+    PLLD = 6,
+    HDMIAux = 7,
+}
+
+impl Source {
+    fn from_u32(value: u32) -> Source {
+        match value {
+            1 => Source::Oscillator,
+            2 => Source::TestDebug0,
+            3 => Source::TestDebug1,
+            4 => Source::PLLA,
+            5 => Source::PLLC,
+            6 => Source::PLLD,
+            7 => Source::HDMIAux,
+            _ => Source::Ground,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Mash {
+    /// Integer division only, the fractional part is ignored
+    Integer = 0,
+    Stage1 = 1,
+    Stage2 = 2,
+    Stage3 = 3,
+}
+
+impl Mash {
+    fn from_u32(value: u32) -> Mash {
+        match value {
+            1 => Mash::Stage1,
+            2 => Mash::Stage2,
+            3 => Mash::Stage3,
+            _ => Mash::Integer,
+        }
+    }
+
+    /// Smallest integer divisor that the filter can work with
+    fn min_divi(self) -> u32 {
+        match self {
+            Mash::Integer => 1,
+            Mash::Stage1 => 2,
+            Mash::Stage2 => 3,
+            Mash::Stage3 => 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockConfig {
+    pub source: Source,
+    /// Integer part of the divisor
+    pub divi: u32,
+    /// Fractional part of the divisor, in 1/4096
+    pub divf: u32,
+    pub mash: Mash,
+}
+
+const CM_PASSWORD: u32 = 0x5A00_0000;
+
+const MAX_DIVI: u32 = 0xFFF;
+const MAX_DIVF: u32 = 0xFFF;
+
+/// Polls before giving up on the generator going idle
+const BUSY_TIMEOUT: usize = 10_000;
+
+/// Sources `set_frequency` picks from. The other PLLs get retuned by the firmware (PLLC follows
+/// the core clock) so their rate can't be relied on.
+const AUTO_SOURCES: [Source; 2] = [Source::Oscillator, Source::PLLD];
+
+/// Frequency generated from `rate` with the given divisor. MASH dithers between neighbouring
+/// divisors to produce the fractional part on average.
+fn divided_rate(rate: u32, divi: u32, divf: u32, mash: Mash) -> u32 {
+    match mash {
+        Mash::Integer => rate / divi,
+        _ => ((rate as u64 * 4096) / (divi as u64 * 4096 + divf as u64)) as u32,
+    }
+}
+
+struct ClockManagerInner {
+    base_addr: usize,
+    oscillator_hz: u32,
+    plld_hz: u32,
+}
+
+impl ops::Deref for ClockManagerInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl ClockManagerInner {
+    const fn new(base_addr: usize, oscillator_hz: u32, plld_hz: u32) -> ClockManagerInner {
+        ClockManagerInner {
+            base_addr,
+            oscillator_hz,
+            plld_hz,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn registers(
+        &self,
+        clock: Clock,
+    ) -> (
+        &ReadWrite<u32, CTL::Register>,
+        &ReadWrite<u32, DIV::Register>,
+    ) {
+        match clock {
+            Clock::GP0 => (&self.GP0CTL, &self.GP0DIV),
+            Clock::GP1 => (&self.GP1CTL, &self.GP1DIV),
+            Clock::GP2 => (&self.GP2CTL, &self.GP2DIV),
+            Clock::PCM => (&self.PCMCTL, &self.PCMDIV),
+            Clock::PWM => (&self.PWMCTL, &self.PWMDIV),
+        }
+    }
+
+    /// Rate of a clock source, `None` for the ones we don't track
+    fn source_rate(&self, source: Source) -> Option<u32> {
+        match source {
+            Source::Ground => Some(0),
+            Source::Oscillator => Some(self.oscillator_hz),
+            Source::PLLD => Some(self.plld_hz),
+            _ => None,
+        }
+    }
+
+    /// Write a control register with the password added and check that the write stuck. The
+    /// password and BUSY can't be read back so they are left out of the comparison.
+    fn write_ctl(&self, clock: Clock, value: u32) -> Result<()> {
+        let (ctl, _) = self.registers(clock);
+        let mask = 0x00FF_FFFF & !(CTL::BUSY.mask << CTL::BUSY.shift);
+
+        ctl.set(CM_PASSWORD | (value & 0x00FF_FFFF));
+
+        if ctl.get() & mask != value & mask {
+            return Err(ClockError::WriteRejected);
+        }
+
+        Ok(())
+    }
+
+    fn write_div(&self, clock: Clock, value: u32) -> Result<()> {
+        let (_, div) = self.registers(clock);
+
+        div.set(CM_PASSWORD | (value & 0x00FF_FFFF));
+
+        if div.get() & 0x00FF_FFFF != value & 0x00FF_FFFF {
+            return Err(ClockError::WriteRejected);
+        }
+
+        Ok(())
+    }
+
+    fn disable(&self, clock: Clock) -> Result<()> {
+        let (ctl, _) = self.registers(clock);
+        let enab = CTL::ENAB.mask << CTL::ENAB.shift;
+
+        self.write_ctl(clock, ctl.get() & !enab)?;
+
+        for _ in 0..BUSY_TIMEOUT {
+            if !ctl.is_set(CTL::BUSY) {
+                return Ok(());
+            }
+
+            arch::nop();
+        }
+
+        Err(ClockError::Busy)
+    }
+
+    fn configure(&self, clock: Clock, config: &ClockConfig) -> Result<()> {
+        if config.divi < config.mash.min_divi() || config.divi > MAX_DIVI || config.divf > MAX_DIVF
+        {
+            return Err(ClockError::InvalidDivisor);
+        }
+
+        // Changing source or divisor while the generator runs can lock it up
+        self.disable(clock)?;
+
+        let div = (config.divi << DIV::DIVI.shift) | config.divf;
+        let ctl = ((config.mash as u32) << CTL::MASH.shift) | config.source as u32;
+        let enab = CTL::ENAB.mask << CTL::ENAB.shift;
+
+        // Source and divisor have to be settled before the generator is enabled
+        self.write_div(clock, div)?;
+        self.write_ctl(clock, ctl)?;
+        self.write_ctl(clock, ctl | enab)
+    }
+
+    fn config(&self, clock: Clock) -> ClockConfig {
+        let (ctl, div) = self.registers(clock);
+
+        ClockConfig {
+            source: Source::from_u32(ctl.read(CTL::SRC)),
+            divi: div.read(DIV::DIVI),
+            divf: div.read(DIV::DIVF),
+            mash: Mash::from_u32(ctl.read(CTL::MASH)),
+        }
+    }
+
+    fn frequency(&self, clock: Clock) -> Option<u32> {
+        let (ctl, _) = self.registers(clock);
+        let config = self.config(clock);
+
+        if !ctl.is_set(CTL::ENAB) {
+            return Some(0);
+        }
+
+        if config.divi == 0 {
+            return None;
+        }
+
+        let rate = self.source_rate(config.source)?;
+
+        Some(divided_rate(rate, config.divi, config.divf, config.mash))
+    }
+
+    /// Best divisor of the known sources for `hz`, returns the config and the achieved rate
+    fn best_config(&self, hz: u32, mash: Mash) -> Result<(ClockConfig, u32)> {
+        let mut best: Option<(ClockConfig, u32)> = None;
+
+        if hz == 0 {
+            return Err(ClockError::Unreachable);
+        }
+
+        for &source in AUTO_SOURCES.iter() {
+            let rate = match self.source_rate(source) {
+                Some(rate) if rate >= hz => rate as u64,
+                _ => continue,
+            };
+
+            // Divisor in 1/4096 steps, rounded to nearest
+            let scaled = (rate * 4096 + hz as u64 / 2) / hz as u64;
+            let (divi, divf) = match mash {
+                Mash::Integer => (((rate + hz as u64 / 2) / hz as u64) as u32, 0),
+                _ => ((scaled / 4096) as u32, (scaled % 4096) as u32),
+            };
+
+            if divi < mash.min_divi() || divi > MAX_DIVI {
+                continue;
+            }
+
+            let achieved = divided_rate(rate as u32, divi, divf, mash);
+            let error = (achieved as i64 - hz as i64).abs();
+
+            if best.map_or(true, |(_, b)| error < (b as i64 - hz as i64).abs()) {
+                let config = ClockConfig {
+                    source,
+                    divi,
+                    divf,
+                    mash,
+                };
+                best = Some((config, achieved));
+            }
+        }
+
+        best.ok_or(ClockError::Unreachable)
+    }
+
+    fn set_frequency(&self, clock: Clock, hz: u32, mash: Mash) -> Result<u32> {
+        let (config, achieved) = self.best_config(hz, mash)?;
+
+        self.configure(clock, &config)?;
+
+        Ok(achieved)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct ClockManager {
+    inner: NullLock<ClockManagerInner>,
+}
+
+impl ClockManager {
+    /// `oscillator_hz` and `plld_hz` are the board's crystal and PLLD peripheral rates, they are
+    /// used to pick divisors and report frequencies
+    pub const unsafe fn new(base_addr: usize, oscillator_hz: u32, plld_hz: u32) -> ClockManager {
+        ClockManager {
+            inner: NullLock::new(ClockManagerInner::new(base_addr, oscillator_hz, plld_hz)),
+        }
+    }
+
+    /// Program a generator with an explicit source and divisor, then start it
+    pub fn configure(&self, clock: Clock, config: &ClockConfig) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(clock, config))
+    }
+
+    /// Run a generator as close to `hz` as the oscillator or PLLD allow, returns the frequency
+    /// actually produced
+    pub fn set_frequency(&self, clock: Clock, hz: u32, mash: Mash) -> Result<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_frequency(clock, hz, mash))
+    }
+
+    pub fn disable(&self, clock: Clock) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.disable(clock))
+    }
+
+    pub fn config(&self, clock: Clock) -> ClockConfig {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.config(clock))
+    }
+
+    /// Current output frequency, `None` if it runs from a source we don't know the rate of
+    pub fn frequency(&self, clock: Clock) -> Option<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.frequency(clock))
+    }
+}
+
+impl interface::driver::DeviceDriver for ClockManager {
+    fn compatible(&self) -> &str {
+        "BCM2835 CPRMAN Clock Manager"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const GP0CTL: usize = 0x70;
+    const GP0DIV: usize = 0x74;
+    const PWMCTL: usize = 0xA0;
+    const PWMDIV: usize = 0xA4;
+
+    const OSCILLATOR_HZ: u32 = 19_200_000;
+    const PLLD_HZ: u32 = 500_000_000;
+
+    fn clock_manager(fake: &FakeRegisterFile) -> ClockManagerInner {
+        ClockManagerInner::new(fake.base_addr(), OSCILLATOR_HZ, PLLD_HZ)
+    }
+
+    #[test]
+    fn configure_writes_with_password() {
+        let fake = FakeRegisterFile::new(0xA8);
+        let cprman = clock_manager(&fake);
+
+        let config = ClockConfig {
+            source: Source::PLLD,
+            divi: 5,
+            divf: 1024,
+            mash: Mash::Stage1,
+        };
+        cprman.configure(Clock::PWM, &config).unwrap();
+
+        assert_eq!(fake.read(PWMDIV), 0x5A00_0000 | (5 << 12) | 1024);
+        assert_eq!(fake.read(PWMCTL), 0x5A00_0000 | (1 << 9) | (1 << 4) | 6);
+        assert_eq!(cprman.config(Clock::PWM), config);
+        assert_eq!(cprman.frequency(Clock::PWM), Some(500_000_000 * 4 / 21));
+    }
+
+    #[test]
+    fn configure_rejects_divisor_too_small_for_mash() {
+        let fake = FakeRegisterFile::new(0xA8);
+        let cprman = clock_manager(&fake);
+
+        let config = ClockConfig {
+            source: Source::Oscillator,
+            divi: 4,
+            divf: 0,
+            mash: Mash::Stage3,
+        };
+
+        assert_eq!(
+            cprman.configure(Clock::GP0, &config),
+            Err(ClockError::InvalidDivisor)
+        );
+        assert_eq!(fake.read(GP0DIV), 0);
+    }
+
+    #[test]
+    fn disable_times_out_when_generator_stays_busy() {
+        let fake = FakeRegisterFile::new(0xA8);
+        let cprman = clock_manager(&fake);
+
+        fake.write(GP0CTL, (1 << 7) | (1 << 4) | 1);
+
+        assert_eq!(cprman.disable(Clock::GP0), Err(ClockError::Busy));
+        assert_eq!(fake.read(GP0CTL) & (1 << 4), 0);
+    }
+
+    #[test]
+    fn set_frequency_picks_best_source() {
+        let fake = FakeRegisterFile::new(0xA8);
+        let cprman = clock_manager(&fake);
+
+        // 9.6 MHz divides the oscillator exactly
+        assert_eq!(
+            cprman.set_frequency(Clock::GP0, 9_600_000, Mash::Integer),
+            Ok(9_600_000)
+        );
+        assert_eq!(cprman.config(Clock::GP0).source, Source::Oscillator);
+
+        // 100 MHz is out of the oscillator's reach
+        assert_eq!(
+            cprman.set_frequency(Clock::PCM, 100_000_000, Mash::Integer),
+            Ok(100_000_000)
+        );
+        assert_eq!(cprman.config(Clock::PCM).source, Source::PLLD);
+        assert_eq!(cprman.config(Clock::PCM).divi, 5);
+
+        assert_eq!(
+            cprman.set_frequency(Clock::PWM, 1_000_000_000, Mash::Integer),
+            Err(ClockError::Unreachable)
+        );
+    }
+}
@@ -0,0 +1,676 @@
+use super::Mail;
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::ops;
+use core::sync::atomic::{compiler_fence, Ordering};
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    /// Channel Control and Status
+    CS [
+        RESET OFFSET(31) NUMBITS(1) [],
+        ABORT OFFSET(30) NUMBITS(1) [],
+        DISDEBUG OFFSET(29) NUMBITS(1) [],
+        WAIT_FOR_OUTSTANDING_WRITES OFFSET(28) NUMBITS(1) [],
+        PANIC_PRIORITY OFFSET(20) NUMBITS(4) [],
+        PRIORITY OFFSET(16) NUMBITS(4) [],
+        ERROR OFFSET(8) NUMBITS(1) [],
+        WAITING_FOR_OUTSTANDING_WRITES OFFSET(6) NUMBITS(1) [],
+        DREQ_STOPS_DMA OFFSET(5) NUMBITS(1) [],
+        PAUSED OFFSET(4) NUMBITS(1) [],
+        DREQ OFFSET(3) NUMBITS(1) [],
+        /// Set when a control block with INTEN completes, write 1 to clear
+        INT OFFSET(2) NUMBITS(1) [],
+        /// Set when the chain is done, write 1 to clear
+        END OFFSET(1) NUMBITS(1) [],
+        ACTIVE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Channel Debug
+    DEBUG [
+        /// Reduced performance channel, transfers are limited to 64KB
+        LITE OFFSET(28) NUMBITS(1) [],
+        READ_ERROR OFFSET(2) NUMBITS(1) [],
+        FIFO_ERROR OFFSET(1) NUMBITS(1) [],
+        READ_LAST_NOT_SET_ERROR OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct ChannelRegisterBlock {
+    CS: ReadWrite<u32, CS::Register>,       // 0x00
+    CONBLK_AD: ReadWrite<u32>,              // 0x04
+    TI: ReadOnly<u32>,                      // 0x08
+    SOURCE_AD: ReadOnly<u32>,               // 0x0C
+    DEST_AD: ReadOnly<u32>,                 // 0x10
+    TXFR_LEN: ReadOnly<u32>,                // 0x14
+    STRIDE: ReadOnly<u32>,                  // 0x18
+    NEXTCONBK: ReadWrite<u32>,              // 0x1C
+    DEBUG: ReadWrite<u32, DEBUG::Register>, // 0x20
+    __reserved0: [u32; 55],                 // 0x24
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CHANNELS: [ChannelRegisterBlock; CHANNEL_COUNT], // 0x000
+    __reserved0: [u32; 56],                          // 0xF00
+    INT_STATUS: ReadWrite<u32>,                      // 0xFE0
+    __reserved1: [u32; 3],                           // 0xFE4
+    ENABLE: ReadWrite<u32>,                          // 0xFF0
+}
+
+/// Channels in the main DMA block, channel 15 lives elsewhere and is left alone
+const CHANNEL_COUNT: usize = 15;
+const CHANNEL_MASK: u32 = (1 << CHANNEL_COUNT) - 1;
+
+// Transfer Information bits, for building control blocks by hand
+pub const TI_WAIT_RESP: u32 = 1 << 3;
+pub const TI_DEST_INC: u32 = 1 << 4;
+pub const TI_DEST_DREQ: u32 = 1 << 6;
+pub const TI_SRC_INC: u32 = 1 << 8;
+pub const TI_SRC_DREQ: u32 = 1 << 10;
+pub const TI_PERMAP_SHIFT: u32 = 16;
+
+/// Longest transfer a single control block can describe, kept 32 byte aligned so chained
+/// blocks start on a burst boundary
+const MAX_LEN: usize = 0x3FFF_FFE0;
+const LITE_MAX_LEN: usize = 0xFFE0;
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum DmaError {
+    /// Not enough control blocks to describe the whole transfer
+    ChainTooShort,
+    /// Source and destination buffers differ in length
+    LengthMismatch,
+    /// The engine flagged an error, holds the DEBUG register
+    Bus(u32),
+}
+type Result<T> = ::core::result::Result<T, DmaError>;
+
+/// Peripherals that can pace a transfer through their DREQ line
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Dreq {
+    /// Always asserted, the transfer runs at full speed
+    None = 0,
+    PcmTx = 2,
+    PcmRx = 3,
+    Pwm = 5,
+    SpiTx = 6,
+    SpiRx = 7,
+    BscSpiSlaveTx = 8,
+    BscSpiSlaveRx = 9,
+    Emmc = 11,
+    UartTx = 12,
+    SdHost = 13,
+    UartRx = 14,
+}
+
+/// A hardware control block. The engine follows `nextconbk` from one block to the next, so
+/// a chain is just blocks linked in memory.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, align(32))]
+pub struct ControlBlock {
+    ti: u32,
+    source_ad: u32,
+    dest_ad: u32,
+    txfr_len: u32,
+    stride: u32,
+    nextconbk: u32,
+    __reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    pub const fn new() -> ControlBlock {
+        ControlBlock {
+            ti: 0,
+            source_ad: 0,
+            dest_ad: 0,
+            txfr_len: 0,
+            stride: 0,
+            nextconbk: 0,
+            __reserved: [0; 2],
+        }
+    }
+
+    /// Describe a single transfer of `len` bytes between two bus addresses. `ti` is the raw
+    /// Transfer Information word.
+    pub fn configure(&mut self, ti: u32, source: u32, dest: u32, len: u32) {
+        self.ti = ti;
+        self.source_ad = source;
+        self.dest_ad = dest;
+        self.txfr_len = len;
+        self.stride = 0;
+        self.nextconbk = 0;
+    }
+
+    /// Continue with `next` once this block is done, `None` ends the chain
+    pub fn link(&mut self, next: Option<&ControlBlock>) {
+        self.nextconbk = next.map_or(0, |next| bsp::bus_address(next as *const _ as usize));
+    }
+}
+
+/// One side of a transfer
+#[derive(Clone, Copy)]
+struct Endpoint {
+    bus_addr: u32,
+    /// Memory is walked through, a peripheral FIFO is hit at the same address every time
+    increment: bool,
+}
+
+struct DmaControllerInner {
+    base_addr: usize,
+    /// Channels the firmware lets us use
+    permitted: u32,
+    allocated: u32,
+}
+
+impl ops::Deref for DmaControllerInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl DmaControllerInner {
+    const fn new(base_addr: usize) -> DmaControllerInner {
+        DmaControllerInner {
+            base_addr,
+            permitted: 0,
+            allocated: 0,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, permitted: u32) {
+        self.permitted = permitted & CHANNEL_MASK;
+        self.ENABLE.set(self.ENABLE.get() | self.permitted);
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        let free = self.permitted & !self.allocated;
+
+        if free == 0 {
+            return None;
+        }
+
+        let index = free.trailing_zeros() as usize;
+        self.allocated |= 1 << index;
+
+        Some(index)
+    }
+
+    fn release(&mut self, index: usize) {
+        self.allocated &= !(1 << index);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct DmaController {
+    inner: NullLock<DmaControllerInner>,
+}
+
+/// An allocated DMA channel, given back to the controller when dropped
+pub struct Channel {
+    index: usize,
+    base_addr: usize,
+    controller: &'static DmaController,
+}
+
+impl ops::Deref for Channel {
+    type Target = ChannelRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.base_addr as *const ChannelRegisterBlock) }
+    }
+}
+
+impl Channel {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Lite channels are slower and limited to 64KB per control block
+    pub fn is_lite(&self) -> bool {
+        self.DEBUG.is_set(DEBUG::LITE)
+    }
+
+    fn max_len(&self) -> usize {
+        if self.is_lite() {
+            LITE_MAX_LEN
+        } else {
+            MAX_LEN
+        }
+    }
+
+    /// Stop whatever the channel is doing and return it to a clean state
+    pub fn reset(&self) {
+        self.CS.write(CS::RESET::SET);
+
+        // Clear the sticky error flags
+        self.DEBUG.write(
+            DEBUG::READ_ERROR::SET + DEBUG::FIFO_ERROR::SET + DEBUG::READ_LAST_NOT_SET_ERROR::SET,
+        );
+    }
+
+    /// Start executing the chain at `first`.
+    ///
+    /// # Safety
+    ///
+    /// The control blocks and every buffer they point to must stay valid, and must not be
+    /// touched by the CPU, until `is_busy()` returns false.
+    pub unsafe fn start_chain(&self, first: &ControlBlock) {
+        self.reset();
+
+        // The control blocks must be in memory before the engine goes looking for them
+        compiler_fence(Ordering::Release);
+
+        self.CONBLK_AD
+            .set(bsp::bus_address(first as *const _ as usize));
+        self.CS.write(
+            CS::ACTIVE::SET
+                + CS::END::SET
+                + CS::INT::SET
+                + CS::WAIT_FOR_OUTSTANDING_WRITES::SET
+                + CS::PRIORITY.val(8)
+                + CS::PANIC_PRIORITY.val(15),
+        );
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.CS.is_set(CS::ACTIVE)
+    }
+
+    /// Spin until the channel goes idle
+    pub fn wait(&self) -> Result<()> {
+        while self.is_busy() && !self.CS.is_set(CS::ERROR) {
+            arch::nop();
+        }
+
+        compiler_fence(Ordering::Acquire);
+
+        if self.CS.is_set(CS::ERROR) {
+            let debug = self.DEBUG.get();
+            self.reset();
+
+            return Err(DmaError::Bus(debug));
+        }
+
+        Ok(())
+    }
+
+    /// Fill `blocks` with a chain moving `len` bytes, split to fit the channel's length limit.
+    /// Returns the number of blocks used.
+    fn build_chain(
+        &self,
+        blocks: &mut [ControlBlock],
+        ti: u32,
+        source: Endpoint,
+        dest: Endpoint,
+        len: usize,
+    ) -> Result<usize> {
+        let max_len = self.max_len();
+        let count = ((len + max_len - 1) / max_len).max(1);
+
+        if count > blocks.len() {
+            return Err(DmaError::ChainTooShort);
+        }
+
+        for (i, block) in blocks.iter_mut().take(count).enumerate() {
+            let offset = i * max_len;
+            let chunk = (len - offset).min(max_len);
+
+            let src = source.bus_addr + if source.increment { offset as u32 } else { 0 };
+            let dst = dest.bus_addr + if dest.increment { offset as u32 } else { 0 };
+
+            block.configure(ti, src, dst, chunk as u32);
+        }
+
+        for i in 0..count - 1 {
+            let (current, next) = blocks.split_at_mut(i + 1);
+            current[i].link(Some(&next[0]));
+        }
+
+        Ok(count)
+    }
+
+    fn start_transfer<B>(
+        self,
+        blocks: &'static mut [ControlBlock],
+        ti: u32,
+        source: Endpoint,
+        dest: Endpoint,
+        len: usize,
+        buffers: B,
+    ) -> Result<Transfer<B>> {
+        self.build_chain(blocks, ti, source, dest, len)?;
+
+        // The chain and the buffers are 'static and owned by the transfer from here on
+        unsafe { self.start_chain(&blocks[0]) };
+
+        Ok(Transfer {
+            channel: self,
+            blocks,
+            buffers,
+        })
+    }
+
+    /// Copy `source` into `dest`. The buffers are held by the returned `Transfer` until it
+    /// completes.
+    pub fn mem_to_mem(
+        self,
+        blocks: &'static mut [ControlBlock],
+        source: &'static [u8],
+        dest: &'static mut [u8],
+    ) -> Result<Transfer<(&'static [u8], &'static mut [u8])>> {
+        if source.len() != dest.len() {
+            return Err(DmaError::LengthMismatch);
+        }
+
+        let src = Endpoint {
+            bus_addr: bsp::bus_address(source.as_ptr() as usize),
+            increment: true,
+        };
+        let dst = Endpoint {
+            bus_addr: bsp::bus_address(dest.as_mut_ptr() as usize),
+            increment: true,
+        };
+        let ti = TI_SRC_INC | TI_DEST_INC | TI_WAIT_RESP;
+        let len = source.len();
+
+        self.start_transfer(blocks, ti, src, dst, len, (source, dest))
+    }
+
+    /// Feed `source` into the peripheral register at `register` (an ARM physical address), one
+    /// word at a time as the peripheral asks for it. `source` goes out byte for byte in memory
+    /// order, whatever its element type.
+    pub fn mem_to_peripheral<T>(
+        self,
+        blocks: &'static mut [ControlBlock],
+        source: &'static [T],
+        register: usize,
+        dreq: Dreq,
+    ) -> Result<Transfer<&'static [T]>> {
+        let src = Endpoint {
+            bus_addr: bsp::bus_address(source.as_ptr() as usize),
+            increment: true,
+        };
+        let dst = Endpoint {
+            bus_addr: bsp::bus_address(register),
+            increment: false,
+        };
+        let ti = TI_SRC_INC | TI_DEST_DREQ | TI_WAIT_RESP | (dreq as u32) << TI_PERMAP_SHIFT;
+        let len = core::mem::size_of_val(source);
+
+        self.start_transfer(blocks, ti, src, dst, len, source)
+    }
+
+    /// Drain the peripheral register at `register` into `dest`, paced by the peripheral
+    pub fn peripheral_to_mem(
+        self,
+        blocks: &'static mut [ControlBlock],
+        register: usize,
+        dreq: Dreq,
+        dest: &'static mut [u8],
+    ) -> Result<Transfer<&'static mut [u8]>> {
+        let src = Endpoint {
+            bus_addr: bsp::bus_address(register),
+            increment: false,
+        };
+        let dst = Endpoint {
+            bus_addr: bsp::bus_address(dest.as_mut_ptr() as usize),
+            increment: true,
+        };
+        let ti = TI_DEST_INC | TI_SRC_DREQ | TI_WAIT_RESP | (dreq as u32) << TI_PERMAP_SHIFT;
+        let len = dest.len();
+
+        self.start_transfer(blocks, ti, src, dst, len, dest)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // Never hand back a channel that could still be writing somewhere
+        self.reset();
+        self.controller.release(self.index);
+    }
+}
+
+/// A running transfer. It owns the channel, the control blocks and the buffers so none of
+/// them can be touched or reused until the engine is done. Dropping it aborts the transfer.
+///
+/// Completion is polled with `is_done` or `wait`. The kernel doesn't take interrupts, so the
+/// control blocks never set INTEN.
+pub struct Transfer<B> {
+    channel: Channel,
+    blocks: &'static mut [ControlBlock],
+    buffers: B,
+}
+
+/// What a finished transfer hands back
+pub struct Completed<B> {
+    pub status: Result<()>,
+    pub channel: Channel,
+    pub blocks: &'static mut [ControlBlock],
+    pub buffers: B,
+}
+
+impl<B> Transfer<B> {
+    pub fn is_done(&self) -> bool {
+        !self.channel.is_busy()
+    }
+
+    /// Block until the transfer is over and take the resources back
+    pub fn wait(self) -> Completed<B> {
+        let status = self.channel.wait();
+
+        Completed {
+            status,
+            channel: self.channel,
+            blocks: self.blocks,
+            buffers: self.buffers,
+        }
+    }
+}
+
+impl DmaController {
+    pub const unsafe fn new(base_addr: usize) -> DmaController {
+        DmaController {
+            inner: NullLock::new(DmaControllerInner::new(base_addr)),
+        }
+    }
+
+    /// Claim a free channel out of the ones the firmware left for us
+    pub fn allocate(&'static self) -> Option<Channel> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.allocate().map(|index| Channel {
+                index,
+                base_addr: inner.base_addr + index * core::mem::size_of::<ChannelRegisterBlock>(),
+                controller: self,
+            })
+        })
+    }
+
+    fn release(&self, index: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.release(index));
+    }
+
+    /// Bitmask of the channels the firmware lets us use
+    pub fn permitted_channels(&self) -> u32 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.permitted)
+    }
+}
+
+impl interface::driver::DeviceDriver for DmaController {
+    fn compatible(&self) -> &str {
+        "BCM2835 DMA Controller"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        // The firmware uses some channels itself, only touch the ones it hands out
+        let mut mail = Mail::new();
+        let permitted = match mail.get_dma_channels() {
+            Ok(mask) => mask,
+            Err(_) => return Err(()),
+        };
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(permitted));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CHANNEL_STRIDE: usize = 0x100;
+    const CS: usize = 0x00;
+    const CONBLK_AD: usize = 0x04;
+    const DEBUG: usize = 0x20;
+    const ENABLE: usize = 0xFF0;
+
+    fn controller(fake: &FakeRegisterFile, permitted: u32) -> &'static DmaController {
+        let dma = Box::leak(Box::new(unsafe { DmaController::new(fake.base_addr()) }));
+
+        let mut r = &dma.inner;
+        r.lock(|inner| inner.init(permitted));
+
+        dma
+    }
+
+    fn blocks(count: usize) -> &'static mut [ControlBlock] {
+        Box::leak(vec![ControlBlock::new(); count].into_boxed_slice())
+    }
+
+    #[test]
+    fn allocates_only_permitted_channels() {
+        let fake = FakeRegisterFile::new(0x1000);
+        let dma = controller(&fake, 0x0001_0014);
+
+        assert_eq!(fake.read(ENABLE), 0x14);
+
+        let a = dma.allocate().unwrap();
+        let b = dma.allocate().unwrap();
+        assert_eq!((a.index(), b.index()), (2, 4));
+        assert!(dma.allocate().is_none());
+
+        drop(a);
+        assert_eq!(dma.allocate().map(|c| c.index()), Some(2));
+    }
+
+    #[test]
+    fn mem_to_mem_starts_single_block() {
+        let fake = FakeRegisterFile::new(0x1000);
+        let dma = controller(&fake, 1 << 5);
+
+        let source: &'static [u8] = Box::leak(vec![0xAA; 256].into_boxed_slice());
+        let dest: &'static mut [u8] = Box::leak(vec![0; 256].into_boxed_slice());
+        let blocks = blocks(2);
+        let first = &blocks[0] as *const ControlBlock;
+
+        let channel = dma.allocate().unwrap();
+        let transfer = channel.mem_to_mem(blocks, source, dest).unwrap();
+
+        let base = 5 * CHANNEL_STRIDE;
+        assert_eq!(
+            fake.read(base + CONBLK_AD),
+            bsp::bus_address(first as usize)
+        );
+        assert_eq!(fake.read(base + CS) & 1, 1);
+        assert!(!transfer.is_done());
+
+        let cb = unsafe { &*first };
+        assert_eq!(cb.txfr_len, 256);
+        assert_eq!(cb.nextconbk, 0);
+        assert_eq!(cb.ti, TI_SRC_INC | TI_DEST_INC | TI_WAIT_RESP);
+
+        // The engine finishes on the third poll
+        fake.on_poll(move |regs, polls| {
+            if polls == 3 {
+                regs.clear_bits(base + CS, 1);
+            }
+        });
+
+        let completed = transfer.wait();
+        assert_eq!(completed.status, Ok(()));
+        assert_eq!(completed.buffers.1.len(), 256);
+    }
+
+    #[test]
+    fn lite_channel_splits_into_chain() {
+        let fake = FakeRegisterFile::new(0x1000);
+        let dma = controller(&fake, 1 << 8);
+
+        fake.write(8 * CHANNEL_STRIDE + DEBUG, 1 << 28);
+
+        let source: &'static [u8] = Box::leak(vec![0; 0x18000].into_boxed_slice());
+        let blocks = blocks(2);
+        let cbs = blocks.as_ptr();
+
+        let channel = dma.allocate().unwrap();
+        assert!(channel.is_lite());
+
+        let _transfer = channel
+            .mem_to_peripheral(blocks, source, 0x3F20_100C, Dreq::SpiTx)
+            .unwrap();
+
+        let (first, second) = unsafe { (&*cbs, &*cbs.add(1)) };
+        assert_eq!(first.txfr_len as usize, LITE_MAX_LEN);
+        assert_eq!(second.txfr_len as usize, 0x18000 - LITE_MAX_LEN);
+        assert_eq!(
+            first.nextconbk,
+            bsp::bus_address(second as *const _ as usize)
+        );
+        assert_eq!(first.dest_ad, second.dest_ad);
+        assert_eq!((second.ti >> TI_PERMAP_SHIFT) & 0x1F, Dreq::SpiTx as u32);
+    }
+
+    #[test]
+    fn peripheral_source_length_is_in_bytes() {
+        let fake = FakeRegisterFile::new(0x1000);
+        let dma = controller(&fake, 1 << 5);
+
+        let words: &'static [u32] = Box::leak(vec![0; 100].into_boxed_slice());
+        let blocks = blocks(1);
+        let first = &blocks[0] as *const ControlBlock;
+
+        let channel = dma.allocate().unwrap();
+        let _transfer = channel
+            .mem_to_peripheral(blocks, words, 0x3F20_C018, Dreq::Pwm)
+            .unwrap();
+
+        assert_eq!(unsafe { (*first).txfr_len }, 400);
+    }
+
+    #[test]
+    fn chain_too_short_is_rejected() {
+        let fake = FakeRegisterFile::new(0x1000);
+        let dma = controller(&fake, 1 << 8);
+
+        fake.write(8 * CHANNEL_STRIDE + DEBUG, 1 << 28);
+
+        let source: &'static [u8] = Box::leak(vec![0; 0x18000].into_boxed_slice());
+        let channel = dma.allocate().unwrap();
+
+        let result = channel.mem_to_peripheral(blocks(1), source, 0x3F20_100C, Dreq::SpiTx);
+        assert_eq!(result.err(), Some(DmaError::ChainTooShort));
+    }
+}
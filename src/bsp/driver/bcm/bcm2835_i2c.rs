@@ -0,0 +1,511 @@
+use super::{bcm2835_spi::clock_divider, Power, PowerHandle};
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::ops;
+use interface::i2c::{self, Address, Config};
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    C [
+        I2CEN OFFSET(15) NUMBITS(1) [],
+        /// Interrupt while the RX FIFO needs reading
+        INTR OFFSET(10) NUMBITS(1) [],
+        /// Interrupt while the TX FIFO needs writing
+        INTT OFFSET(9) NUMBITS(1) [],
+        /// Interrupt when DONE is set
+        INTD OFFSET(8) NUMBITS(1) [],
+        /// Start a new transfer, self clearing
+        ST OFFSET(7) NUMBITS(1) [],
+        /// Flush the FIFO, either bit works
+        CLEAR OFFSET(4) NUMBITS(2) [
+            Clear = 0b01
+        ],
+        READ OFFSET(0) NUMBITS(1) []
+    ],
+
+    S [
+        /// Target held SCL low for too long, write 1 to clear
+        CLKT OFFSET(9) NUMBITS(1) [],
+        /// Address or data not acknowledged, write 1 to clear
+        ERR OFFSET(8) NUMBITS(1) [],
+        RXF OFFSET(7) NUMBITS(1) [],
+        TXE OFFSET(6) NUMBITS(1) [],
+        /// RX FIFO holds data
+        RXD OFFSET(5) NUMBITS(1) [],
+        /// TX FIFO can accept data
+        TXD OFFSET(4) NUMBITS(1) [],
+        RXR OFFSET(3) NUMBITS(1) [],
+        TXW OFFSET(2) NUMBITS(1) [],
+        /// Transfer complete, write 1 to clear
+        DONE OFFSET(1) NUMBITS(1) [],
+        /// Transfer active
+        TA OFFSET(0) NUMBITS(1) []
+    ],
+
+    DLEN [
+        DLEN OFFSET(0) NUMBITS(16) []
+    ],
+
+    A [
+        ADDR OFFSET(0) NUMBITS(7) []
+    ],
+
+    DIV [
+        /// Core clock divider, even values only, 0 means 32768
+        CDIV OFFSET(0) NUMBITS(16) []
+    ],
+
+    DEL [
+        /// Core clocks between the falling edge of SCL and SDA changing
+        FEDL OFFSET(16) NUMBITS(16) [],
+        /// Core clocks between the rising edge of SCL and SDA being sampled
+        REDL OFFSET(0) NUMBITS(16) []
+    ],
+
+    CLKT [
+        /// SCL cycles a target may stretch the clock for, 0 disables the check
+        TOUT OFFSET(0) NUMBITS(16) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    C: ReadWrite<u32, C::Register>,       // 0x00
+    S: ReadWrite<u32, S::Register>,       // 0x04
+    DLEN: ReadWrite<u32, DLEN::Register>, // 0x08
+    A: ReadWrite<u32, A::Register>,       // 0x0C
+    FIFO: ReadWrite<u32>,                 // 0x10
+    DIV: ReadWrite<u32, DIV::Register>,   // 0x14
+    DEL: ReadWrite<u32, DEL::Register>,   // 0x18
+    CLKT: ReadWrite<u32, CLKT::Register>, // 0x1C
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Port {
+    Bsc0,
+    Bsc1,
+    Bsc2,
+}
+
+/// Give up on a transfer after this long without the FIFO moving. The stretch timeout normally
+/// fires first, this is for a controller that has stopped altogether.
+const TRANSFER_TIMEOUT_US: usize = 100_000;
+
+/// Status bits that are cleared by writing 1 to them
+const STATUS_W1C: u32 = 1 << 9 | 1 << 8 | 1 << 1;
+
+/// The write half of a write-then-read has to fit in the FIFO, so that the read can be queued
+/// before the write finishes
+const FIFO_DEPTH: usize = 16;
+
+/// DLEN is 16 bits wide
+const MAX_LEN: usize = 0xFFFF;
+
+/// 10-bit addresses go out as 0b11110xx followed by the low byte
+const TEN_BIT_PREFIX: u8 = 0x78;
+
+/// The largest divider, written as a CDIV of 0
+const MAX_DIVIDER: u32 = 32768;
+
+/// The value for the A register and, for 10-bit addresses, the byte that has to be sent first
+fn split_address(address: Address) -> i2c::Result<(u8, Option<u8>)> {
+    match address {
+        Address::SevenBit(a) if a <= 0x7F => Ok((a, None)),
+        Address::TenBit(a) if a <= 0x3FF => Ok((TEN_BIT_PREFIX | (a >> 8) as u8, Some(a as u8))),
+        _ => Err(i2c::Error::InvalidAddress),
+    }
+}
+
+struct I2cInner {
+    base_addr: usize,
+    /// Set in init, SCL is divided down from it
+    core_clock: u32,
+    config: Config,
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
+}
+
+impl ops::Deref for I2cInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl I2cInner {
+    const fn new(base_addr: usize) -> I2cInner {
+        I2cInner {
+            base_addr,
+            core_clock: 0,
+            config: Config::new(100_000),
+            power: None,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, core_clock: u32) {
+        self.core_clock = core_clock;
+        self.reset();
+
+        let config = self.config;
+        let _ = self.configure(&config);
+    }
+
+    fn configure(&mut self, config: &Config) -> i2c::Result<u32> {
+        if config.frequency == 0 {
+            return Err(i2c::Error::InvalidConfig);
+        }
+
+        let (div, rate) = clock_divider(self.core_clock, config.frequency, MAX_DIVIDER);
+        self.DIV.write(DIV::CDIV.val(div));
+
+        // Keep SDA changes well clear of the SCL edges, same margins Linux uses
+        let div = if div == 0 { 32768 } else { div };
+        self.DEL
+            .write(DEL::FEDL.val((div / 16).max(1)) + DEL::REDL.val((div / 4).max(1)));
+
+        let cycles = config.clock_stretch_timeout_us as u64 * rate as u64 / 1_000_000;
+        self.CLKT
+            .write(CLKT::TOUT.val(cycles.max(1).min(0xFFFF) as u32));
+
+        self.config = *config;
+
+        Ok(rate)
+    }
+
+    /// Flush the FIFO and clear any status left over from the last transfer
+    fn reset(&self) {
+        self.C.write(C::I2CEN::SET + C::CLEAR::Clear);
+
+        // Only write back what is set, the other bits of S are read only anyway
+        self.S.set(self.S.get() & STATUS_W1C);
+    }
+
+    fn start(&self, len: usize, read: bool) {
+        self.DLEN.write(DLEN::DLEN.val(len as u32));
+
+        if read {
+            self.C.write(C::I2CEN::SET + C::ST::SET + C::READ::SET);
+        } else {
+            self.C.write(C::I2CEN::SET + C::ST::SET);
+        }
+    }
+
+    /// Turn the error bits into an error
+    fn check(&self) -> i2c::Result<()> {
+        if self.S.is_set(S::ERR) {
+            Err(i2c::Error::Nack)
+        } else if self.S.is_set(S::CLKT) {
+            Err(i2c::Error::ClockStretchTimeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for the transfer to get going, or to already be done with
+    fn wait_for_start(&self) -> i2c::Result<()> {
+        for _ in 0..TRANSFER_TIMEOUT_US {
+            self.check()?;
+
+            if self.S.is_set(S::TA) || self.S.is_set(S::DONE) {
+                return Ok(());
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        Err(i2c::Error::Timeout)
+    }
+
+    /// Keep the FIFO moving until the transfer is done. `tx` supplies the byte to send at each
+    /// position of a `tx_len` byte write, `read` is filled for a read.
+    fn pump(&self, tx_len: usize, tx: impl Fn(usize) -> u8, read: &mut [u8]) -> i2c::Result<()> {
+        let (mut tx_pos, mut rx_pos) = (0, 0);
+        let mut idle = 0;
+
+        loop {
+            let progress = (tx_pos, rx_pos);
+
+            while tx_pos < tx_len && self.S.is_set(S::TXD) {
+                self.FIFO.set(tx(tx_pos) as u32);
+                tx_pos += 1;
+            }
+
+            while rx_pos < read.len() && self.S.is_set(S::RXD) {
+                read[rx_pos] = self.FIFO.get() as u8;
+                rx_pos += 1;
+            }
+
+            self.check()?;
+
+            if self.S.is_set(S::DONE) && tx_pos == tx_len && rx_pos == read.len() {
+                return Ok(());
+            }
+
+            if progress == (tx_pos, rx_pos) {
+                idle += 1;
+                if idle > TRANSFER_TIMEOUT_US {
+                    return Err(i2c::Error::Timeout);
+                }
+
+                arch::nop();
+                arch::wait_usec(1);
+            } else {
+                idle = 0;
+            }
+        }
+    }
+
+    fn write_read(&self, address: Address, write: &[u8], read: &mut [u8]) -> i2c::Result<()> {
+        let (slave, prefix) = split_address(address)?;
+        let tx_len = write.len() + prefix.is_some() as usize;
+
+        if tx_len > MAX_LEN || read.len() > MAX_LEN {
+            return Err(i2c::Error::TooLong);
+        }
+
+        if !read.is_empty() && tx_len > FIFO_DEPTH {
+            return Err(i2c::Error::TooLong);
+        }
+
+        let tx = |i: usize| match prefix {
+            Some(low) if i == 0 => low,
+            Some(_) => write[i - 1],
+            None => write[i],
+        };
+
+        self.reset();
+        self.A.write(A::ADDR.val(slave as u32));
+
+        let result = if read.is_empty() {
+            self.start(tx_len, false);
+            self.pump(tx_len, tx, read)
+        } else {
+            // A 10-bit read always needs the low address byte written first
+            let write_phase = if tx_len > 0 {
+                self.DLEN.write(DLEN::DLEN.val(tx_len as u32));
+                (0..tx_len).for_each(|i| self.FIFO.set(tx(i) as u32));
+                self.C.write(C::I2CEN::SET + C::ST::SET);
+
+                // Queueing the read while the write is still active makes the controller
+                // follow it with a repeated START instead of a STOP
+                self.wait_for_start()
+            } else {
+                Ok(())
+            };
+
+            write_phase.and_then(|_| {
+                self.start(read.len(), true);
+                self.pump(0, tx, read)
+            })
+        };
+
+        self.reset();
+
+        result
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct I2c {
+    inner: NullLock<I2cInner>,
+    port: Port,
+}
+
+impl I2c {
+    pub const unsafe fn new(base_addr: usize, port: Port) -> I2c {
+        I2c {
+            inner: NullLock::new(I2cInner::new(base_addr)),
+            port,
+        }
+    }
+
+    fn power_domain(&self) -> Power {
+        match self.port {
+            Port::Bsc0 => Power::I2C0,
+            Port::Bsc1 => Power::I2C1,
+            Port::Bsc2 => Power::I2C2,
+        }
+    }
+}
+
+impl interface::driver::DeviceDriver for I2c {
+    fn compatible(&self) -> &str {
+        match self.port {
+            Port::Bsc0 => "BCM2835 BSC0",
+            Port::Bsc1 => "BCM2835 BSC1",
+            Port::Bsc2 => "BCM2835 BSC2",
+        }
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let power = bsp::power().acquire(self.power_domain()).ok();
+
+        let core_clock = bsp::core_clock_rate();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.power = power;
+            inner.init(core_clock)
+        });
+
+        Ok(())
+    }
+
+    fn shutdown(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl i2c::I2cBus for I2c {
+    fn configure(&self, config: &Config) -> i2c::Result<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(config))
+    }
+
+    fn write_read(&self, address: Address, write: &[u8], read: &mut [u8]) -> i2c::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_read(address, write, read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const C_REG: usize = 0x00;
+    const S_REG: usize = 0x04;
+    const DLEN_REG: usize = 0x08;
+    const A_REG: usize = 0x0C;
+    const FIFO: usize = 0x10;
+    const DIV_REG: usize = 0x14;
+    const DEL_REG: usize = 0x18;
+    const CLKT_REG: usize = 0x1C;
+
+    const ST: u32 = 1 << 7;
+    const READ: u32 = 1 << 0;
+    const TA: u32 = 1 << 0;
+    const DONE: u32 = 1 << 1;
+    const TXD: u32 = 1 << 4;
+    const RXD: u32 = 1 << 5;
+    const ERR: u32 = 1 << 8;
+
+    fn i2c(fake: &FakeRegisterFile) -> I2cInner {
+        let mut i2c = I2cInner::new(fake.base_addr());
+        i2c.init(250_000_000);
+        i2c
+    }
+
+    #[test]
+    fn configure_sets_divider_delays_and_stretch_timeout() {
+        let fake = FakeRegisterFile::new(0x20);
+        let mut i2c = i2c(&fake);
+
+        assert_eq!(i2c.configure(&Config::new(100_000)), Ok(100_000));
+        assert_eq!(fake.read(DIV_REG), 2500);
+        assert_eq!(fake.read(DEL_REG), 156 << 16 | 625);
+        assert_eq!(fake.read(CLKT_REG), 3500);
+
+        assert_eq!(
+            i2c.configure(&Config::new(0)),
+            Err(i2c::Error::InvalidConfig)
+        );
+    }
+
+    #[test]
+    fn addresses_are_checked_and_split() {
+        assert_eq!(split_address(Address::SevenBit(0x50)), Ok((0x50, None)));
+        assert_eq!(
+            split_address(Address::TenBit(0x2A5)),
+            Ok((0x7A, Some(0xA5)))
+        );
+        assert_eq!(
+            split_address(Address::SevenBit(0x80)),
+            Err(i2c::Error::InvalidAddress)
+        );
+        assert_eq!(
+            split_address(Address::TenBit(0x400)),
+            Err(i2c::Error::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn write_fills_the_fifo_until_done() {
+        let fake = FakeRegisterFile::new(0x20);
+        let i2c = i2c(&fake);
+
+        fake.on_poll(|regs, _| regs.set_bits(S_REG, TXD | DONE));
+
+        assert_eq!(
+            i2c.write_read(Address::TenBit(0x123), &[0xAB, 0xCD], &mut []),
+            Ok(())
+        );
+        assert_eq!(fake.read(A_REG), 0x79);
+        assert_eq!(fake.read(DLEN_REG), 3);
+        assert_eq!(fake.read(FIFO), 0xCD);
+        assert_eq!(fake.read(C_REG) & ST, 0);
+    }
+
+    #[test]
+    fn nack_is_reported() {
+        let fake = FakeRegisterFile::new(0x20);
+        let i2c = i2c(&fake);
+
+        fake.on_poll(|regs, _| regs.set_bits(S_REG, ERR | DONE));
+
+        let mut buf = [0; 1];
+        assert_eq!(
+            i2c.write_read(Address::SevenBit(0x50), &[], &mut buf),
+            Err(i2c::Error::Nack)
+        );
+        assert_eq!(fake.read(C_REG) & ST, 0);
+    }
+
+    #[test]
+    fn write_then_read_queues_the_read_once_the_write_is_active() {
+        let fake = FakeRegisterFile::new(0x20);
+        let i2c = i2c(&fake);
+
+        fake.on_poll(|regs, polls| match polls {
+            1 => {
+                // The register address went out, now the read has been programmed
+                assert_eq!(regs.read(FIFO), 0x10);
+                regs.set_bits(S_REG, TA);
+            }
+            2 => {
+                assert_eq!(regs.read(C_REG) & (ST | READ), ST | READ);
+                regs.write(FIFO, 0x42);
+                regs.set_bits(S_REG, RXD | DONE);
+            }
+            _ => (),
+        });
+
+        let mut buf = [0; 1];
+        assert_eq!(
+            i2c.write_read(Address::SevenBit(0x68), &[0x10], &mut buf),
+            Ok(())
+        );
+        assert_eq!(buf, [0x42]);
+        assert_eq!(fake.read(DLEN_REG), 1);
+
+        let long = [0; FIFO_DEPTH + 1];
+        let result = i2c.write_read(Address::SevenBit(0x68), &long, &mut buf);
+        assert_eq!(result, Err(i2c::Error::TooLong));
+    }
+}
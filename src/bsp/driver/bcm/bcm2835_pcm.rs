@@ -0,0 +1,645 @@
+use super::{Clock, ClockManager, ControlBlock, DmaChannel, DmaError, Dreq, Mash, Transfer};
+use crate::{arch, arch::sync::NullLock, interface};
+use core::ops;
+use interface::audio::{self, Format};
+use register::{mmio::*, register_bitfields, FieldValue};
+
+register_bitfields! {
+    u32,
+
+    CS_A [
+        /// RAM out of standby, needed before the FIFOs can be used
+        STBY OFFSET(25) NUMBITS(1) [],
+        /// Reads back what was written once the PCM clock domain has caught up
+        SYNC OFFSET(24) NUMBITS(1) [],
+        RXSEX OFFSET(23) NUMBITS(1) [],
+        RXF OFFSET(22) NUMBITS(1) [],
+        TXE OFFSET(21) NUMBITS(1) [],
+        /// RX FIFO holds data
+        RXD OFFSET(20) NUMBITS(1) [],
+        /// TX FIFO can accept data
+        TXD OFFSET(19) NUMBITS(1) [],
+        RXR OFFSET(18) NUMBITS(1) [],
+        TXW OFFSET(17) NUMBITS(1) [],
+        /// RX FIFO overflowed, write 1 to clear
+        RXERR OFFSET(16) NUMBITS(1) [],
+        /// TX FIFO underflowed, write 1 to clear
+        TXERR OFFSET(15) NUMBITS(1) [],
+        RXSYNC OFFSET(14) NUMBITS(1) [],
+        TXSYNC OFFSET(13) NUMBITS(1) [],
+        DMAEN OFFSET(9) NUMBITS(1) [],
+        RXTHR OFFSET(7) NUMBITS(2) [],
+        TXTHR OFFSET(5) NUMBITS(2) [],
+        RXCLR OFFSET(4) NUMBITS(1) [],
+        TXCLR OFFSET(3) NUMBITS(1) [],
+        TXON OFFSET(2) NUMBITS(1) [],
+        RXON OFFSET(1) NUMBITS(1) [],
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+
+    MODE_A [
+        CLK_DIS OFFSET(28) NUMBITS(1) [],
+        PDMN OFFSET(27) NUMBITS(1) [],
+        PDME OFFSET(26) NUMBITS(1) [],
+        FRXP OFFSET(25) NUMBITS(1) [],
+        FTXP OFFSET(24) NUMBITS(1) [],
+        /// Bit clock is an input
+        CLKM OFFSET(23) NUMBITS(1) [],
+        CLKI OFFSET(22) NUMBITS(1) [],
+        /// Frame sync is an input
+        FSM OFFSET(21) NUMBITS(1) [],
+        FSI OFFSET(20) NUMBITS(1) [],
+        /// Frame length in bit clocks, minus one
+        FLEN OFFSET(10) NUMBITS(10) [],
+        /// Bit clocks frame sync stays asserted for
+        FSLEN OFFSET(0) NUMBITS(10) []
+    ],
+
+    /// Layout shared by RXC_A and TXC_A. Channel widths are 8 + WID + 16 * WEX bits.
+    XC [
+        CH1WEX OFFSET(31) NUMBITS(1) [],
+        CH1EN OFFSET(30) NUMBITS(1) [],
+        CH1POS OFFSET(20) NUMBITS(10) [],
+        CH1WID OFFSET(16) NUMBITS(4) [],
+        CH2WEX OFFSET(15) NUMBITS(1) [],
+        CH2EN OFFSET(14) NUMBITS(1) [],
+        CH2POS OFFSET(4) NUMBITS(10) [],
+        CH2WID OFFSET(0) NUMBITS(4) []
+    ],
+
+    DREQ_A [
+        TX_PANIC OFFSET(24) NUMBITS(7) [],
+        RX_PANIC OFFSET(16) NUMBITS(7) [],
+        TX OFFSET(8) NUMBITS(7) [],
+        RX OFFSET(0) NUMBITS(7) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CS_A: ReadWrite<u32, CS_A::Register>,     // 0x00
+    FIFO_A: ReadWrite<u32>,                   // 0x04
+    MODE_A: ReadWrite<u32, MODE_A::Register>, // 0x08
+    RXC_A: ReadWrite<u32, XC::Register>,      // 0x0C
+    TXC_A: ReadWrite<u32, XC::Register>,      // 0x10
+    DREQ_A: ReadWrite<u32, DREQ_A::Register>, // 0x14
+    INTEN_A: ReadWrite<u32>,                  // 0x18
+    INTSTC_A: ReadWrite<u32>,                 // 0x1C
+    GRAY: ReadWrite<u32>,                     // 0x20
+}
+
+const FIFO_OFFSET: usize = 0x04;
+
+/// Give up on the FIFO after this long without it moving
+const FIFO_TIMEOUT_US: usize = 10_000;
+
+/// Give up on the PCM clock domain after this many polls. With no bit clock coming in, as in
+/// slave mode before the master starts, it never answers.
+const SYNC_TIMEOUT: usize = 1_000;
+
+/// FLEN is 10 bits wide
+const MAX_FRAME_LENGTH: u32 = 1024;
+
+/// Which side drives the bit clock and frame sync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Master,
+    Slave,
+}
+
+/// Layout of an I2S frame: two equal slots, left then right, with the data starting one bit
+/// clock after frame sync changes. A sample as wide as its slot ends on the first bit of the
+/// next one, as the I2S spec has it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub role: Role,
+    /// Bit clocks per frame, both slots together
+    pub frame_length: u32,
+    /// Bits of each sample, sent MSB first at the start of its slot
+    pub channel_width: u32,
+}
+
+impl Config {
+    pub const fn new(role: Role, frame_length: u32, channel_width: u32) -> Config {
+        Config {
+            role,
+            frame_length,
+            channel_width,
+        }
+    }
+
+    fn slot_length(&self) -> u32 {
+        self.frame_length / 2
+    }
+
+    fn is_valid(&self) -> bool {
+        (8..=32).contains(&self.channel_width)
+            && self.frame_length <= MAX_FRAME_LENGTH
+            && self.slot_length() >= self.channel_width
+    }
+
+    fn mode(&self) -> FieldValue<u32, MODE_A::Register> {
+        // I2S has frame sync (LRCLK) low for the left channel
+        let mut mode = MODE_A::FLEN.val(self.frame_length - 1)
+            + MODE_A::FSLEN.val(self.slot_length())
+            + MODE_A::FSI::SET;
+
+        if self.role == Role::Slave {
+            mode = mode + MODE_A::CLKM::SET + MODE_A::FSM::SET;
+        }
+
+        mode
+    }
+
+    /// RXC_A/TXC_A value, channel 2 only enabled for stereo
+    fn channels(&self, stereo: bool) -> FieldValue<u32, XC::Register> {
+        let wid = (self.channel_width - 8) & 0xF;
+        let wex = (self.channel_width - 8) >> 4;
+
+        let mut xc = XC::CH1EN::SET
+            + XC::CH1POS.val(1)
+            + XC::CH1WID.val(wid)
+            + XC::CH1WEX.val(wex)
+            + XC::CH2POS.val(self.slot_length() + 1)
+            + XC::CH2WID.val(wid)
+            + XC::CH2WEX.val(wex);
+
+        if stereo {
+            xc = xc + XC::CH2EN::SET;
+        }
+
+        xc
+    }
+}
+
+/// A sample as the FIFO wants it, right justified in `width` bits
+fn to_word(sample: i16, width: u32) -> u32 {
+    let value = if width >= 16 {
+        (sample as i32) << (width - 16)
+    } else {
+        (sample as i32) >> (16 - width)
+    };
+
+    value as u32 & (u32::max_value() >> (32 - width))
+}
+
+/// The inverse of `to_word`, dropping bits below the top 16
+fn from_word(word: u32, width: u32) -> i16 {
+    // Sign extend from the width of the channel
+    let value = ((word << (32 - width)) as i32) >> (32 - width);
+
+    if width >= 16 {
+        (value >> (width - 16)) as i16
+    } else {
+        (value << (16 - width)) as i16
+    }
+}
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum PcmError {
+    /// Frame or channel width the hardware can't do
+    InvalidConfig,
+    /// The FIFO stopped moving
+    Timeout,
+    /// A FIFO over or underflowed, samples were lost
+    Overrun,
+    Dma(DmaError),
+}
+type Result<T> = ::core::result::Result<T, PcmError>;
+
+impl From<PcmError> for audio::Error {
+    fn from(e: PcmError) -> audio::Error {
+        match e {
+            PcmError::InvalidConfig => audio::Error::UnsupportedFormat,
+            PcmError::Timeout => audio::Error::Timeout,
+            PcmError::Overrun => audio::Error::Underrun,
+            PcmError::Dma(_) => audio::Error::Dma,
+        }
+    }
+}
+
+struct PcmInner {
+    base_addr: usize,
+    config: Config,
+    stereo: bool,
+}
+
+impl ops::Deref for PcmInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl PcmInner {
+    const fn new(base_addr: usize) -> PcmInner {
+        PcmInner {
+            base_addr,
+            config: Config::new(Role::Master, 64, 16),
+            stereo: true,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self) {
+        self.CS_A.write(CS_A::EN::SET + CS_A::STBY::SET);
+        self.clear_fifos();
+
+        let config = self.config;
+        let _ = self.configure(&config);
+    }
+
+    /// Wait for the PCM clock domain to see our last writes
+    fn sync(&self) {
+        let sync = !self.CS_A.is_set(CS_A::SYNC);
+        self.CS_A.modify(CS_A::SYNC.val(sync as u32));
+
+        for _ in 0..SYNC_TIMEOUT {
+            if self.CS_A.is_set(CS_A::SYNC) == sync {
+                return;
+            }
+
+            arch::nop();
+        }
+    }
+
+    fn clear_fifos(&self) {
+        self.CS_A.modify(CS_A::TXCLR::SET + CS_A::RXCLR::SET);
+        self.sync();
+    }
+
+    fn configure(&mut self, config: &Config) -> Result<()> {
+        if !config.is_valid() {
+            return Err(PcmError::InvalidConfig);
+        }
+
+        // The frame layout must not change under a running stream
+        self.CS_A.modify(CS_A::TXON::CLEAR + CS_A::RXON::CLEAR);
+
+        self.MODE_A.write(config.mode());
+        self.config = *config;
+        self.set_stereo(self.stereo);
+
+        Ok(())
+    }
+
+    fn set_stereo(&mut self, stereo: bool) {
+        let channels = self.config.channels(stereo);
+        self.TXC_A.write(channels);
+        self.RXC_A.write(channels);
+        self.stereo = stereo;
+    }
+
+    /// Bit clock for `sample_rate`, only meaningful in master mode
+    fn bit_clock(&self, sample_rate: u32) -> u32 {
+        sample_rate * self.config.frame_length
+    }
+
+    fn start(&mut self, tx: bool) {
+        if tx {
+            self.CS_A.modify(CS_A::TXON::SET);
+        } else {
+            self.CS_A.modify(CS_A::RXON::SET);
+        }
+    }
+
+    /// Turn the error bits into an error, clearing them
+    fn check(&self) -> Result<()> {
+        if self.CS_A.is_set(CS_A::TXERR) || self.CS_A.is_set(CS_A::RXERR) {
+            // Writing back the current value clears whichever was set
+            self.CS_A.set(self.CS_A.get());
+            return Err(PcmError::Overrun);
+        }
+
+        Ok(())
+    }
+
+    fn write_fifo(&self, samples: &[i16]) -> Result<()> {
+        let width = self.config.channel_width;
+
+        for &sample in samples {
+            let mut polls = 0;
+            while !self.CS_A.is_set(CS_A::TXD) {
+                polls += 1;
+                if polls > FIFO_TIMEOUT_US {
+                    return Err(PcmError::Timeout);
+                }
+
+                arch::nop();
+                arch::wait_usec(1);
+            }
+
+            self.FIFO_A.set(to_word(sample, width));
+        }
+
+        self.check()
+    }
+
+    fn read_fifo(&self, samples: &mut [i16]) -> Result<()> {
+        let width = self.config.channel_width;
+
+        for sample in samples.iter_mut() {
+            let mut polls = 0;
+            while !self.CS_A.is_set(CS_A::RXD) {
+                polls += 1;
+                if polls > FIFO_TIMEOUT_US {
+                    return Err(PcmError::Timeout);
+                }
+
+                arch::nop();
+                arch::wait_usec(1);
+            }
+
+            *sample = from_word(self.FIFO_A.get(), width);
+        }
+
+        self.check()
+    }
+
+    fn wait_for_tx_empty(&self) -> Result<()> {
+        for _ in 0..FIFO_TIMEOUT_US {
+            if self.CS_A.is_set(CS_A::TXE) {
+                return Ok(());
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        Err(PcmError::Timeout)
+    }
+
+    fn enable_dma(&self) {
+        // Same thresholds Linux uses
+        self.DREQ_A.write(
+            DREQ_A::TX_PANIC.val(0x10)
+                + DREQ_A::RX_PANIC.val(0x30)
+                + DREQ_A::TX.val(0x30)
+                + DREQ_A::RX.val(0x20),
+        );
+        self.CS_A.modify(CS_A::DMAEN::SET);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// The PCM/I2S block. Plays through `AudioSink` and records through `AudioSource`, the two
+/// directions share the frame layout and clocks.
+pub struct Pcm {
+    inner: NullLock<PcmInner>,
+    clocks: &'static ClockManager,
+}
+
+impl Pcm {
+    pub const unsafe fn new(base_addr: usize, clocks: &'static ClockManager) -> Pcm {
+        Pcm {
+            inner: NullLock::new(PcmInner::new(base_addr)),
+            clocks,
+        }
+    }
+
+    /// Use `config` for the frame layout and clocking. Stops both directions.
+    pub fn set_config(&self, config: &Config) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(config))
+    }
+
+    /// Set the sample rate and channel count and start `tx` or receive
+    fn start_stream(&self, format: &Format, tx: bool) -> audio::Result<u32> {
+        if format.sample_rate == 0 || format.channels == 0 || format.channels > 2 {
+            return Err(audio::Error::UnsupportedFormat);
+        }
+
+        let mut r = &self.inner;
+        let (config, bit_clock) = r.lock(|inner| {
+            inner.set_stereo(format.channels == 2);
+            (inner.config, inner.bit_clock(format.sample_rate))
+        });
+
+        // In slave mode the rate is whatever the master runs at
+        let rate = match config.role {
+            Role::Master => {
+                let actual = self
+                    .clocks
+                    .set_frequency(Clock::PCM, bit_clock, Mash::Stage1)
+                    .map_err(|_| audio::Error::UnsupportedFormat)?;
+
+                // One frame per sample
+                actual / config.frame_length
+            }
+            Role::Slave => format.sample_rate,
+        };
+
+        r.lock(|inner| {
+            inner.clear_fifos();
+            inner.start(tx);
+        });
+
+        Ok(rate)
+    }
+
+    /// Stream FIFO words from `words` through `channel`. The words are samples already shifted
+    /// for the configured channel width, one per 32-bit word.
+    pub fn start_tx_dma(
+        &self,
+        channel: DmaChannel,
+        blocks: &'static mut [ControlBlock],
+        words: &'static [u8],
+    ) -> Result<Transfer<&'static [u8]>> {
+        let mut r = &self.inner;
+        let fifo = r.lock(|inner| {
+            inner.enable_dma();
+            inner.base_addr + FIFO_OFFSET
+        });
+
+        channel
+            .mem_to_peripheral(blocks, words, fifo, Dreq::PcmTx)
+            .map_err(PcmError::Dma)
+    }
+
+    /// Record FIFO words into `words` through `channel`
+    pub fn start_rx_dma(
+        &self,
+        channel: DmaChannel,
+        blocks: &'static mut [ControlBlock],
+        words: &'static mut [u8],
+    ) -> Result<Transfer<&'static mut [u8]>> {
+        let mut r = &self.inner;
+        let fifo = r.lock(|inner| {
+            inner.enable_dma();
+            inner.base_addr + FIFO_OFFSET
+        });
+
+        channel
+            .peripheral_to_mem(blocks, fifo, Dreq::PcmRx, words)
+            .map_err(PcmError::Dma)
+    }
+
+    pub fn stop(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner
+                .CS_A
+                .modify(CS_A::TXON::CLEAR + CS_A::RXON::CLEAR + CS_A::DMAEN::CLEAR)
+        });
+    }
+}
+
+impl interface::driver::DeviceDriver for Pcm {
+    fn compatible(&self) -> &str {
+        "BCM2835 PCM/I2S"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+impl audio::AudioSink for Pcm {
+    fn configure(&self, format: &Format) -> audio::Result<u32> {
+        self.start_stream(format, true)
+    }
+
+    fn write(&self, samples: &[i16]) -> audio::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_fifo(samples))
+            .map_err(Into::into)
+    }
+
+    fn flush(&self) -> audio::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.wait_for_tx_empty())
+            .map_err(Into::into)
+    }
+}
+
+impl audio::AudioSource for Pcm {
+    fn configure(&self, format: &Format) -> audio::Result<u32> {
+        self.start_stream(format, false)
+    }
+
+    fn read(&self, samples: &mut [i16]) -> audio::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read_fifo(samples)).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CS: usize = 0x00;
+    const FIFO: usize = 0x04;
+    const MODE: usize = 0x08;
+    const RXC: usize = 0x0C;
+    const TXC: usize = 0x10;
+
+    const TXON: u32 = 1 << 2;
+    const TXD: u32 = 1 << 19;
+    const RXD: u32 = 1 << 20;
+
+    fn pcm(fake: &FakeRegisterFile, config: Config) -> PcmInner {
+        let mut pcm = PcmInner::new(fake.base_addr());
+        pcm.configure(&config).unwrap();
+        pcm
+    }
+
+    #[test]
+    fn i2s_frame_layout() {
+        let fake = FakeRegisterFile::new(0x24);
+        let mut pcm = pcm(&fake, Config::new(Role::Master, 64, 24));
+
+        // 64 bit frame, frame sync inverted and half a frame long
+        assert_eq!(fake.read(MODE), 1 << 20 | 63 << 10 | 32);
+        // 24 bits is WEX set, WID 0, channel 1 at bit 1 and channel 2 at bit 33
+        let stereo = 1 << 31 | 1 << 30 | 1 << 20 | 1 << 15 | 1 << 14 | 33 << 4;
+        assert_eq!(fake.read(TXC), stereo);
+        assert_eq!(fake.read(RXC), stereo);
+
+        pcm.set_stereo(false);
+        assert_eq!(fake.read(TXC) & 1 << 14, 0);
+
+        pcm.configure(&Config::new(Role::Slave, 64, 16)).unwrap();
+        assert_eq!(fake.read(MODE) & (1 << 23 | 1 << 21), 1 << 23 | 1 << 21);
+    }
+
+    #[test]
+    fn impossible_layouts_are_rejected() {
+        let fake = FakeRegisterFile::new(0x24);
+        let mut pcm = PcmInner::new(fake.base_addr());
+
+        for &config in [
+            Config::new(Role::Master, 32, 24),
+            Config::new(Role::Master, 64, 33),
+            Config::new(Role::Master, 2048, 16),
+        ]
+        .iter()
+        {
+            assert_eq!(pcm.configure(&config), Err(PcmError::InvalidConfig));
+        }
+    }
+
+    #[test]
+    fn samples_are_justified_to_the_channel_width() {
+        assert_eq!(to_word(-1, 24), 0x00FF_FF00);
+        assert_eq!(to_word(0x1234, 16), 0x1234);
+        assert_eq!(to_word(-0x8000, 8), 0x80);
+
+        assert_eq!(from_word(0x00FF_FF00, 24), -1);
+        assert_eq!(from_word(0x0012_3456, 24), 0x1234);
+        assert_eq!(from_word(0x80, 8), -0x8000);
+    }
+
+    #[test]
+    fn master_rate_follows_the_bit_clock() {
+        let clock_regs = FakeRegisterFile::new(0xA8);
+        let clocks: &'static ClockManager = Box::leak(Box::new(unsafe {
+            ClockManager::new(clock_regs.base_addr(), 19_200_000, 500_000_000)
+        }));
+
+        let fake = FakeRegisterFile::new(0x24);
+        let pcm = unsafe { Pcm::new(fake.base_addr(), clocks) };
+        pcm.set_config(&Config::new(Role::Master, 64, 16)).unwrap();
+
+        // 3.072MHz bit clock, the oscillator divided by 6.25
+        let sink: &dyn audio::AudioSink = &pcm;
+        assert_eq!(sink.configure(&Format::new(48_000, 2)), Ok(48_000));
+        assert_eq!(clocks.frequency(Clock::PCM), Some(3_072_000));
+        assert_eq!(fake.read(CS) & TXON, TXON);
+    }
+
+    #[test]
+    fn fifo_waits_for_room_and_data() {
+        let fake = FakeRegisterFile::new(0x24);
+        let pcm = pcm(&fake, Config::new(Role::Master, 64, 32));
+
+        fake.on_poll(|regs, polls| match polls {
+            2 => regs.set_bits(CS, TXD),
+            4 => {
+                regs.write(FIFO, 0xFFFE_0000);
+                regs.set_bits(CS, RXD);
+            }
+            _ => (),
+        });
+
+        assert_eq!(pcm.write_fifo(&[0x0102]), Ok(()));
+        assert_eq!(fake.read(FIFO), 0x0102_0000);
+
+        let mut samples = [0; 1];
+        assert_eq!(pcm.read_fifo(&mut samples), Ok(()));
+        assert_eq!(samples, [-2]);
+    }
+}
@@ -0,0 +1,440 @@
+use super::{ControlBlock, DmaChannel, DmaError, Dreq, Transfer};
+use crate::{arch, arch::sync::NullLock, interface};
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    STA [
+        STA2 OFFSET(10) NUMBITS(1) [],
+        STA1 OFFSET(9) NUMBITS(1) [],
+        /// Bus error writing to the registers too quickly, write 1 to clear
+        BERR OFFSET(8) NUMBITS(1) [],
+        GAPO2 OFFSET(5) NUMBITS(1) [],
+        GAPO1 OFFSET(4) NUMBITS(1) [],
+        /// FIFO read while empty, write 1 to clear
+        RERR1 OFFSET(3) NUMBITS(1) [],
+        /// FIFO written while full, write 1 to clear
+        WERR1 OFFSET(2) NUMBITS(1) [],
+        EMPT1 OFFSET(1) NUMBITS(1) [],
+        FULL1 OFFSET(0) NUMBITS(1) []
+    ],
+
+    DMAC [
+        ENAB OFFSET(31) NUMBITS(1) [],
+        PANIC OFFSET(8) NUMBITS(8) [],
+        DREQ OFFSET(0) NUMBITS(8) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CTL: ReadWrite<u32>,                  // 0x00
+    STA: ReadWrite<u32, STA::Register>,   // 0x04
+    DMAC: ReadWrite<u32, DMAC::Register>, // 0x08
+    __reserved_0: u32,                    // 0x0C
+    RNG1: ReadWrite<u32>,                 // 0x10
+    DAT1: ReadWrite<u32>,                 // 0x14
+    FIF1: WriteOnly<u32>,                 // 0x18
+    __reserved_1: u32,                    // 0x1C
+    RNG2: ReadWrite<u32>,                 // 0x20
+    DAT2: ReadWrite<u32>,                 // 0x24
+}
+
+// CTL holds the same eight bits for each channel, channel 2's sit 8 bits above channel 1's
+const CTL_PWEN: u32 = 1 << 0;
+const CTL_MODE: u32 = 1 << 1;
+const CTL_RPTL: u32 = 1 << 2;
+const CTL_SBIT: u32 = 1 << 3;
+const CTL_POLA: u32 = 1 << 4;
+const CTL_USEF: u32 = 1 << 5;
+const CTL_MSEN: u32 = 1 << 7;
+const CTL_CHANNEL_MASK: u32 = 0xBF;
+
+/// Flushes the FIFO, there is only the one and it is shared by both channels
+const CTL_CLRF1: u32 = 1 << 6;
+
+const FIF1_OFFSET: usize = 0x18;
+
+/// Status bits that are cleared by writing 1 to them
+const STATUS_W1C: u32 = 1 << 8 | 1 << 3 | 1 << 2;
+
+/// Give up on the FIFO after this long without it taking a word
+const FIFO_TIMEOUT_US: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Pwm1,
+    Pwm2,
+}
+
+impl Channel {
+    fn ctl_shift(self) -> u32 {
+        match self {
+            Channel::Pwm1 => 0,
+            Channel::Pwm2 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Spread the high time evenly over the period. Easiest to low-pass filter, audio uses it.
+    Balanced,
+    /// One pulse per period, high for `data` cycles then low for the rest of the range
+    MarkSpace,
+    /// Shift out the top `range` bits of each data word, MSB first
+    Serializer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelConfig {
+    pub mode: Mode,
+    /// Length of a period in PWM clock cycles, or of a word in bits when serializing
+    pub range: u32,
+    /// Take data from the FIFO instead of the channel's data register
+    pub use_fifo: bool,
+    pub invert: bool,
+    /// Output level while nothing is being sent
+    pub idle_high: bool,
+    /// Keep sending the last word while the FIFO is empty
+    pub repeat_last: bool,
+}
+
+impl ChannelConfig {
+    pub const fn new(mode: Mode, range: u32) -> ChannelConfig {
+        ChannelConfig {
+            mode,
+            range,
+            use_fifo: false,
+            invert: false,
+            idle_high: false,
+            repeat_last: false,
+        }
+    }
+
+    /// CTL bits for this configuration, at channel 1's position
+    fn ctl_bits(&self) -> u32 {
+        let mut bits = match self.mode {
+            Mode::Balanced => 0,
+            Mode::MarkSpace => CTL_MSEN,
+            Mode::Serializer => CTL_MODE,
+        };
+
+        if self.use_fifo {
+            bits |= CTL_USEF;
+        }
+        if self.invert {
+            bits |= CTL_POLA;
+        }
+        if self.idle_high {
+            bits |= CTL_SBIT;
+        }
+        if self.repeat_last {
+            bits |= CTL_RPTL;
+        }
+
+        bits
+    }
+}
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum PwmError {
+    /// A range of 0 never produces any output
+    InvalidRange,
+    /// The FIFO stopped draining
+    Timeout,
+    /// A register write was dropped by the peripheral
+    Bus,
+    Dma(DmaError),
+}
+type Result<T> = ::core::result::Result<T, PwmError>;
+
+struct PwmInner {
+    base_addr: usize,
+}
+
+impl ops::Deref for PwmInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl PwmInner {
+    const fn new(base_addr: usize) -> PwmInner {
+        PwmInner { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self) {
+        self.DMAC.set(0);
+        self.CTL.set(CTL_CLRF1);
+        self.clear_errors();
+    }
+
+    /// Only write back what is set, the other bits of STA are read only anyway
+    fn clear_errors(&self) {
+        self.STA.set(self.STA.get() & STATUS_W1C);
+    }
+
+    /// Apply `config` to `channel`. The channel is left disabled.
+    fn configure(&mut self, channel: Channel, config: &ChannelConfig) -> Result<()> {
+        if config.range == 0 {
+            return Err(PwmError::InvalidRange);
+        }
+
+        match channel {
+            Channel::Pwm1 => self.RNG1.set(config.range),
+            Channel::Pwm2 => self.RNG2.set(config.range),
+        }
+
+        let shift = channel.ctl_shift();
+        let ctl = self.CTL.get() & !(CTL_CHANNEL_MASK << shift);
+        self.CTL.set(ctl | config.ctl_bits() << shift);
+
+        Ok(())
+    }
+
+    fn set_enabled(&mut self, channel: Channel, enabled: bool) {
+        let bit = CTL_PWEN << channel.ctl_shift();
+
+        if enabled {
+            self.CTL.set(self.CTL.get() | bit);
+        } else {
+            self.CTL.set(self.CTL.get() & !bit);
+        }
+    }
+
+    fn set_data(&mut self, channel: Channel, data: u32) {
+        match channel {
+            Channel::Pwm1 => self.DAT1.set(data),
+            Channel::Pwm2 => self.DAT2.set(data),
+        }
+    }
+
+    fn clear_fifo(&mut self) {
+        self.CTL.set(self.CTL.get() | CTL_CLRF1);
+    }
+
+    /// Push `words` into the FIFO as it drains. With both channels on the FIFO, words alternate
+    /// between channel 1 and channel 2.
+    fn write_fifo(&self, words: &[u32]) -> Result<()> {
+        for &word in words {
+            let mut polls = 0;
+            while self.STA.is_set(STA::FULL1) {
+                polls += 1;
+                if polls > FIFO_TIMEOUT_US {
+                    return Err(PwmError::Timeout);
+                }
+
+                arch::nop();
+                arch::wait_usec(1);
+            }
+
+            self.FIF1.set(word);
+        }
+
+        if self.STA.is_set(STA::BERR) || self.STA.is_set(STA::WERR1) {
+            self.clear_errors();
+            return Err(PwmError::Bus);
+        }
+
+        Ok(())
+    }
+
+    fn wait_for_empty(&self) -> Result<()> {
+        for _ in 0..FIFO_TIMEOUT_US {
+            if self.STA.is_set(STA::EMPT1) {
+                return Ok(());
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        Err(PwmError::Timeout)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Pwm {
+    inner: NullLock<PwmInner>,
+}
+
+impl Pwm {
+    pub const unsafe fn new(base_addr: usize) -> Pwm {
+        Pwm {
+            inner: NullLock::new(PwmInner::new(base_addr)),
+        }
+    }
+
+    /// Apply `config` to `channel`, which is disabled until `enable` is called
+    pub fn configure(&self, channel: Channel, config: &ChannelConfig) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(channel, config))
+    }
+
+    pub fn enable(&self, channel: Channel) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_enabled(channel, true));
+    }
+
+    pub fn disable(&self, channel: Channel) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_enabled(channel, false));
+    }
+
+    /// Duty cycle (or word to serialize) for a channel that isn't fed from the FIFO
+    pub fn set_data(&self, channel: Channel, data: u32) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_data(channel, data));
+    }
+
+    pub fn clear_fifo(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.clear_fifo());
+    }
+
+    /// Push `words` into the FIFO, blocking while it is full
+    pub fn write_fifo(&self, words: &[u32]) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_fifo(words))
+    }
+
+    /// Block until the FIFO has drained
+    pub fn wait_for_empty(&self) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.wait_for_empty())
+    }
+
+    /// Feed `words` to the FIFO through `channel`. The PWM paces the transfer, so it runs as
+    /// long as the words take to play.
+    pub fn start_dma(
+        &self,
+        channel: DmaChannel,
+        blocks: &'static mut [ControlBlock],
+        words: &'static [u32],
+    ) -> Result<Transfer<&'static [u32]>> {
+        let mut r = &self.inner;
+        let fifo = r.lock(|inner| {
+            // Same thresholds Linux uses
+            inner
+                .DMAC
+                .write(DMAC::ENAB::SET + DMAC::PANIC.val(7) + DMAC::DREQ.val(3));
+
+            inner.base_addr + FIF1_OFFSET
+        });
+
+        channel
+            .mem_to_peripheral(blocks, words, fifo, Dreq::Pwm)
+            .map_err(PwmError::Dma)
+    }
+
+    /// Stop asking the DMA engine for data
+    pub fn stop_dma(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.DMAC.set(0));
+    }
+}
+
+impl interface::driver::DeviceDriver for Pwm {
+    fn compatible(&self) -> &str {
+        "BCM2835 PWM"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CTL: usize = 0x00;
+    const STA_REG: usize = 0x04;
+    const RNG1: usize = 0x10;
+    const FIF1: usize = 0x18;
+    const RNG2: usize = 0x20;
+    const DAT2: usize = 0x24;
+
+    const FULL1: u32 = 1 << 0;
+
+    #[test]
+    fn channels_are_configured_independently() {
+        let fake = FakeRegisterFile::new(0x28);
+        let mut pwm = PwmInner::new(fake.base_addr());
+
+        let mut config = ChannelConfig::new(Mode::MarkSpace, 1000);
+        config.invert = true;
+        pwm.configure(Channel::Pwm2, &config).unwrap();
+        pwm.set_enabled(Channel::Pwm2, true);
+
+        let mut config = ChannelConfig::new(Mode::Balanced, 64);
+        config.use_fifo = true;
+        pwm.configure(Channel::Pwm1, &config).unwrap();
+        pwm.set_data(Channel::Pwm2, 250);
+
+        assert_eq!(fake.read(RNG1), 64);
+        assert_eq!(fake.read(RNG2), 1000);
+        assert_eq!(fake.read(DAT2), 250);
+        assert_eq!(
+            fake.read(CTL),
+            (CTL_MSEN | CTL_POLA | CTL_PWEN) << 8 | CTL_USEF
+        );
+    }
+
+    #[test]
+    fn zero_range_is_rejected() {
+        let fake = FakeRegisterFile::new(0x28);
+        let mut pwm = PwmInner::new(fake.base_addr());
+
+        let config = ChannelConfig::new(Mode::Serializer, 0);
+        assert_eq!(
+            pwm.configure(Channel::Pwm1, &config),
+            Err(PwmError::InvalidRange)
+        );
+    }
+
+    #[test]
+    fn fifo_writes_wait_for_room() {
+        let fake = FakeRegisterFile::new(0x28);
+        let pwm = PwmInner::new(fake.base_addr());
+
+        fake.write(STA_REG, FULL1);
+        fake.on_poll(|regs, polls| {
+            if polls == 3 {
+                regs.clear_bits(STA_REG, FULL1);
+            }
+        });
+
+        assert_eq!(pwm.write_fifo(&[7, 9]), Ok(()));
+        assert_eq!(fake.read(FIF1), 9);
+    }
+
+    #[test]
+    fn full_fifo_times_out() {
+        let fake = FakeRegisterFile::new(0x28);
+        let pwm = PwmInner::new(fake.base_addr());
+
+        fake.write(STA_REG, FULL1);
+
+        assert_eq!(pwm.write_fifo(&[1]), Err(PwmError::Timeout));
+    }
+}
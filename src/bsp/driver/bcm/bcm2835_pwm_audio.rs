@@ -0,0 +1,189 @@
+use super::{
+    Clock, ClockManager, ControlBlock, DmaChannel, Mash, Pwm, PwmChannel, PwmChannelConfig,
+    PwmError, PwmMode, Transfer,
+};
+use crate::{arch::sync::NullLock, interface};
+use interface::audio::{self, Format};
+
+/// Rate the PWM clock is asked for. The range, and with it the resolution, is this divided by
+/// the sample rate: 2267 steps, a little over 11 bits, at 44.1kHz.
+const PWM_CLOCK_HZ: u32 = 100_000_000;
+
+/// Words pushed to the FIFO per call, so the lock isn't held for a whole buffer
+const CHUNK: usize = 16;
+
+/// Map a signed sample onto `0..range`, silence being half the range
+fn encode(sample: i16, range: u32) -> u32 {
+    (((sample as i32 + 0x8000) as u64 * range as u64) >> 16) as u32
+}
+
+fn audio_error(e: PwmError) -> audio::Error {
+    match e {
+        PwmError::Timeout => audio::Error::Timeout,
+        PwmError::Dma(_) => audio::Error::Dma,
+        PwmError::Bus => audio::Error::Underrun,
+        PwmError::InvalidRange => audio::Error::UnsupportedFormat,
+    }
+}
+
+struct PwmAudioInner {
+    /// PWM clock cycles per sample, 0 until configured
+    range: u32,
+    channels: u8,
+}
+
+impl PwmAudioInner {
+    const fn new() -> PwmAudioInner {
+        PwmAudioInner {
+            range: 0,
+            channels: 0,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// The headphone jack. Its left and right channels are a PWM's two outputs through an RC
+/// filter, both fed from the FIFO.
+pub struct PwmAudio {
+    inner: NullLock<PwmAudioInner>,
+    pwm: &'static Pwm,
+    clocks: &'static ClockManager,
+}
+
+impl PwmAudio {
+    pub const fn new(pwm: &'static Pwm, clocks: &'static ClockManager) -> PwmAudio {
+        PwmAudio {
+            inner: NullLock::new(PwmAudioInner::new()),
+            pwm,
+            clocks,
+        }
+    }
+
+    fn format(&self) -> (u32, u8) {
+        let mut r = &self.inner;
+        r.lock(|inner| (inner.range, inner.channels))
+    }
+
+    /// Turn interleaved `samples` into FIFO words for `start_dma`, duplicating mono samples to
+    /// both outputs. Returns the number of words written to `out`.
+    pub fn encode(&self, samples: &[i16], out: &mut [u32]) -> usize {
+        let (range, channels) = self.format();
+        let copies = if channels == 1 { 2 } else { 1 };
+
+        let words = samples
+            .iter()
+            .flat_map(|&s| core::iter::repeat(encode(s, range)).take(copies));
+
+        let mut written = 0;
+        for (o, w) in out.iter_mut().zip(words) {
+            *o = w;
+            written += 1;
+        }
+
+        written
+    }
+
+    /// Stream words produced by `encode` through `channel`
+    pub fn start_dma(
+        &self,
+        channel: DmaChannel,
+        blocks: &'static mut [ControlBlock],
+        words: &'static [u32],
+    ) -> audio::Result<Transfer<&'static [u32]>> {
+        self.pwm
+            .start_dma(channel, blocks, words)
+            .map_err(audio_error)
+    }
+}
+
+impl audio::AudioSink for PwmAudio {
+    fn configure(&self, format: &Format) -> audio::Result<u32> {
+        if format.sample_rate == 0 || format.channels == 0 || format.channels > 2 {
+            return Err(audio::Error::UnsupportedFormat);
+        }
+
+        let clock = self
+            .clocks
+            .set_frequency(Clock::PWM, PWM_CLOCK_HZ, Mash::Integer)
+            .map_err(|_| audio::Error::UnsupportedFormat)?;
+
+        let range = clock / format.sample_rate;
+        if range < 2 {
+            return Err(audio::Error::UnsupportedFormat);
+        }
+
+        let mut config = PwmChannelConfig::new(PwmMode::Balanced, range);
+        config.use_fifo = true;
+        // Hold the last level instead of clicking to zero when the stream falls behind
+        config.repeat_last = true;
+
+        for &channel in [PwmChannel::Pwm1, PwmChannel::Pwm2].iter() {
+            self.pwm.configure(channel, &config).map_err(audio_error)?;
+        }
+        self.pwm.clear_fifo();
+        self.pwm.enable(PwmChannel::Pwm1);
+        self.pwm.enable(PwmChannel::Pwm2);
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.range = range;
+            inner.channels = format.channels;
+        });
+
+        Ok(clock / range)
+    }
+
+    fn write(&self, samples: &[i16]) -> audio::Result<()> {
+        let (range, channels) = self.format();
+        if range == 0 {
+            return Err(audio::Error::UnsupportedFormat);
+        }
+
+        let mut words = [0; CHUNK];
+        let per_chunk = if channels == 1 { CHUNK / 2 } else { CHUNK };
+
+        for chunk in samples.chunks(per_chunk) {
+            let n = self.encode(chunk, &mut words);
+            self.pwm.write_fifo(&words[..n]).map_err(audio_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> audio::Result<()> {
+        self.pwm.wait_for_empty().map_err(audio_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_map_onto_the_range() {
+        assert_eq!(encode(i16::MIN, 2000), 0);
+        assert_eq!(encode(0, 2000), 1000);
+        assert_eq!(encode(i16::MAX, 2000), 1999);
+    }
+
+    #[test]
+    fn mono_is_sent_to_both_outputs() {
+        static PWM: Pwm = unsafe { Pwm::new(0) };
+        static CLOCKS: ClockManager = unsafe { ClockManager::new(0, 1, 1) };
+        let audio = PwmAudio::new(&PWM, &CLOCKS);
+
+        let mut r = &audio.inner;
+        r.lock(|inner| {
+            inner.range = 100;
+            inner.channels = 1;
+        });
+
+        let mut out = [0; 5];
+        assert_eq!(audio.encode(&[i16::MIN, 0, i16::MAX], &mut out), 5);
+        assert_eq!(out, [0, 0, 50, 50, 99]);
+    }
+}
@@ -115,3 +115,75 @@ impl interface::driver::DeviceDriver for Rng {
         Ok(())
     }
 }
+
+impl interface::driver::CharDevice for Rng {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for byte in buf.iter_mut() {
+                *byte = inner.rand(0, 256) as u8;
+            }
+        });
+
+        buf.len()
+    }
+
+    /// The generator has no seed to feed, writes are dropped
+    fn write(&self, _buf: &[u8]) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CTRL: usize = 0x00;
+    const STATUS: usize = 0x04;
+    const DATA: usize = 0x08;
+    const INT_MASK: usize = 0x10;
+
+    #[test]
+    fn init_masks_interrupts_and_enables() {
+        let fake = FakeRegisterFile::new(0x14);
+        let rng = RngInner::new(fake.base_addr());
+
+        rng.init();
+
+        assert_eq!(fake.read(INT_MASK) & 1, 1);
+        assert_eq!(fake.read(STATUS), RNG_WARMUP_COUNT);
+        assert_eq!(fake.read(CTRL) & 1, 1);
+    }
+
+    #[test]
+    fn rand_waits_for_entropy() {
+        let fake = FakeRegisterFile::new(0x14);
+        let rng = RngInner::new(fake.base_addr());
+
+        fake.write(DATA, 0x1234_5678);
+        fake.on_poll(|regs, polls| {
+            if polls == 3 {
+                regs.write(STATUS, 2 << 24);
+            }
+        });
+
+        let expected = ((0x1234_5678usize << 32) | 0x1234_5678) % 10 + 10;
+        assert_eq!(rng.rand(10, 20), expected);
+    }
+
+    #[test]
+    fn rand_stays_in_range() {
+        let fake = FakeRegisterFile::new(0x14);
+        let rng = Rng::new(fake.base_addr());
+
+        fake.write(STATUS, 1 << 24);
+
+        for data in &[0, 1, 0xFFFF_FFFF, 0xDEAD_BEEF] {
+            fake.write(DATA, *data);
+
+            let value = rng.rand(100, 107);
+            assert!(value >= 100 && value < 107);
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use super::{Completed, ControlBlock, DmaChannel, Dreq, Power, PowerHandle, Transfer};
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::cell::Cell;
+use core::ops;
+use interface::spi::{self, Config, Phase, Polarity};
+use register::{mmio::*, register_bitfields, FieldValue};
+
+register_bitfields! {
+    u32,
+
+    CS [
+        /// Chip select polarity per line, used when idle
+        CSPOL2 OFFSET(23) NUMBITS(1) [],
+        CSPOL1 OFFSET(22) NUMBITS(1) [],
+        CSPOL0 OFFSET(21) NUMBITS(1) [],
+        /// RX FIFO full, the bus stalls until it is read
+        RXF OFFSET(20) NUMBITS(1) [],
+        /// RX FIFO needs reading, it is 3/4 full
+        RXR OFFSET(19) NUMBITS(1) [],
+        /// TX FIFO can accept data
+        TXD OFFSET(18) NUMBITS(1) [],
+        /// RX FIFO holds data
+        RXD OFFSET(17) NUMBITS(1) [],
+        /// Transfer done, TX FIFO empty
+        DONE OFFSET(16) NUMBITS(1) [],
+        /// Raise an interrupt while RXR is set
+        INTR OFFSET(10) NUMBITS(1) [],
+        /// Raise an interrupt when DONE is set
+        INTD OFFSET(9) NUMBITS(1) [],
+        /// Let the DMA engine pace the FIFOs through DREQ
+        DMAEN OFFSET(8) NUMBITS(1) [],
+        /// Transfer active, chip select is asserted while set
+        TA OFFSET(7) NUMBITS(1) [],
+        /// Polarity of the active chip select
+        CSPOL OFFSET(6) NUMBITS(1) [],
+        CLEAR OFFSET(4) NUMBITS(2) [
+            Tx = 0b01,
+            Rx = 0b10,
+            Both = 0b11
+        ],
+        CPOL OFFSET(3) NUMBITS(1) [],
+        CPHA OFFSET(2) NUMBITS(1) [],
+        CS OFFSET(0) NUMBITS(2) []
+    ],
+
+    CLK [
+        /// Core clock divider, even values only, 0 means 65536
+        CDIV OFFSET(0) NUMBITS(16) []
+    ],
+
+    DLEN [
+        LEN OFFSET(0) NUMBITS(16) []
+    ],
+
+    DC [
+        RPANIC OFFSET(24) NUMBITS(8) [],
+        RDREQ OFFSET(16) NUMBITS(8) [],
+        TPANIC OFFSET(8) NUMBITS(8) [],
+        TDREQ OFFSET(0) NUMBITS(8) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CS: ReadWrite<u32, CS::Register>,     // 0x00
+    FIFO: ReadWrite<u32>,                 // 0x04
+    CLK: ReadWrite<u32, CLK::Register>,   // 0x08
+    DLEN: ReadWrite<u32, DLEN::Register>, // 0x0C
+    LTOH: ReadWrite<u32>,                 // 0x10
+    DC: ReadWrite<u32, DC::Register>,     // 0x14
+}
+
+const FIFO_OFFSET: usize = 0x04;
+
+/// Give up on a transfer after this long without the FIFOs moving
+const TRANSFER_TIMEOUT_US: usize = 100_000;
+
+/// Chip select lines CE0-CE2. Only CE0 and CE1 are routed to the header.
+const CHIP_SELECTS: u8 = 3;
+
+/// DLEN is 16 bits wide, so a DMA transfer moves at most this many bytes
+const MAX_DMA_LEN: usize = 0xFFFF;
+
+/// The largest divider, written as a CDIV of 0
+const MAX_DIVIDER: u32 = 65536;
+
+/// Divider that gets closest to `target` without going over it. Returns the CDIV value and the
+/// resulting clock. The I2C controller's CDIV works the same way with a smaller `max_divider`.
+pub(super) fn clock_divider(core_clock: u32, target: u32, max_divider: u32) -> (u32, u32) {
+    let div = (core_clock + target - 1) / target;
+
+    // Odd values are rounded down by the hardware, which would overshoot
+    let div = (div + 1) & !1;
+
+    if div <= 2 {
+        (2, core_clock / 2)
+    } else if div < max_divider {
+        (div, core_clock / div)
+    } else {
+        (0, core_clock / max_divider)
+    }
+}
+
+struct SpiInner {
+    base_addr: usize,
+    /// Set in init, the SPI clock is divided down from it
+    core_clock: u32,
+    config: Config,
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
+}
+
+impl ops::Deref for SpiInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl SpiInner {
+    const fn new(base_addr: usize) -> SpiInner {
+        SpiInner {
+            base_addr,
+            core_clock: 0,
+            config: Config::new(1_000_000, spi::MODE_0),
+            power: None,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, core_clock: u32) {
+        self.core_clock = core_clock;
+
+        self.CS.write(CS::CLEAR::Both);
+        self.DLEN.set(0);
+
+        // Same thresholds the firmware uses: ask for DMA early, panic late
+        self.DC.write(
+            DC::RPANIC.val(0x30) + DC::RDREQ.val(0x20) + DC::TPANIC.val(0x10) + DC::TDREQ.val(0x20),
+        );
+
+        let config = self.config;
+        let _ = self.configure(&config);
+    }
+
+    fn configure(&mut self, config: &Config) -> spi::Result<u32> {
+        if config.chip_select >= CHIP_SELECTS || config.frequency == 0 {
+            return Err(spi::Error::InvalidConfig);
+        }
+
+        let (div, rate) = clock_divider(self.core_clock, config.frequency, MAX_DIVIDER);
+        self.CLK.write(CLK::CDIV.val(div));
+        self.config = *config;
+
+        // Chip selects idle at the inverse of their polarity, so set it before anything runs
+        self.CS.write(self.mode());
+
+        Ok(rate)
+    }
+
+    /// CS register bits for the current configuration, without TA
+    fn mode(&self) -> FieldValue<u32, CS::Register> {
+        let config = &self.config;
+        let mut mode = CS::CS.val(config.chip_select as u32);
+
+        if config.mode.polarity == Polarity::IdleHigh {
+            mode = mode + CS::CPOL::SET;
+        }
+
+        if config.mode.phase == Phase::CaptureOnSecondTransition {
+            mode = mode + CS::CPHA::SET;
+        }
+
+        if config.cs_active_high {
+            mode = mode
+                + CS::CSPOL::SET
+                + match config.chip_select {
+                    0 => CS::CSPOL0::SET,
+                    1 => CS::CSPOL1::SET,
+                    _ => CS::CSPOL2::SET,
+                };
+        }
+
+        mode
+    }
+
+    /// Run a polled transfer of `len` bytes. `tx` supplies the byte to send at each position,
+    /// `rx` takes the byte received there.
+    fn exchange(
+        &self,
+        len: usize,
+        tx: impl Fn(usize) -> u8,
+        rx: impl Fn(usize, u8),
+    ) -> spi::Result<()> {
+        self.CS.write(self.mode() + CS::CLEAR::Both + CS::TA::SET);
+
+        let (mut tx_pos, mut rx_pos) = (0, 0);
+        let mut idle = 0;
+
+        while rx_pos < len {
+            let progress = (tx_pos, rx_pos);
+
+            while tx_pos < len && self.CS.is_set(CS::TXD) {
+                self.FIFO.set(tx(tx_pos) as u32);
+                tx_pos += 1;
+            }
+
+            while rx_pos < tx_pos && self.CS.is_set(CS::RXD) {
+                rx(rx_pos, self.FIFO.get() as u8);
+                rx_pos += 1;
+            }
+
+            if progress == (tx_pos, rx_pos) {
+                idle += 1;
+                if idle > TRANSFER_TIMEOUT_US {
+                    self.CS.write(self.mode() + CS::CLEAR::Both);
+                    return Err(spi::Error::Timeout);
+                }
+
+                arch::nop();
+                arch::wait_usec(1);
+            } else {
+                idle = 0;
+            }
+        }
+
+        let mut result = Ok(());
+        for polls in 0.. {
+            if self.CS.is_set(CS::DONE) {
+                break;
+            }
+
+            if polls == TRANSFER_TIMEOUT_US {
+                result = Err(spi::Error::Timeout);
+                break;
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        self.CS.write(self.mode());
+
+        result
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// SPI0, polled or fed by DMA. The kernel doesn't take interrupts, so INTR and INTD stay off.
+pub struct Spi {
+    inner: NullLock<SpiInner>,
+}
+
+/// A running DMA transfer. Like the DMA `Transfer`s it is built from, it owns the channels and
+/// buffers until `wait` hands them back.
+pub struct SpiDmaTransfer<'a> {
+    spi: &'a Spi,
+    tx: Transfer<&'static [u8]>,
+    rx: Transfer<&'static mut [u8]>,
+}
+
+impl<'a> SpiDmaTransfer<'a> {
+    pub fn is_done(&self) -> bool {
+        self.tx.is_done() && self.rx.is_done()
+    }
+
+    /// Block until both directions are done and release chip select
+    pub fn wait(self) -> (Completed<&'static [u8]>, Completed<&'static mut [u8]>) {
+        let rx = self.rx.wait();
+        let tx = self.tx.wait();
+
+        let mut r = &self.spi.inner;
+        r.lock(|inner| inner.CS.write(inner.mode()));
+
+        (tx, rx)
+    }
+}
+
+impl Spi {
+    pub const unsafe fn new(base_addr: usize) -> Spi {
+        Spi {
+            inner: NullLock::new(SpiInner::new(base_addr)),
+        }
+    }
+
+    /// Exchange `tx` for `rx` with the FIFOs fed by two DMA channels, `tx_channel` writing and
+    /// `rx_channel` reading. The buffers must be the same length, at most 64KB.
+    pub fn start_dma_transfer(
+        &self,
+        tx_channel: DmaChannel,
+        rx_channel: DmaChannel,
+        tx_blocks: &'static mut [ControlBlock],
+        rx_blocks: &'static mut [ControlBlock],
+        tx: &'static [u8],
+        rx: &'static mut [u8],
+    ) -> spi::Result<SpiDmaTransfer<'_>> {
+        if tx.len() != rx.len() {
+            return Err(spi::Error::LengthMismatch);
+        }
+
+        if tx.len() > MAX_DMA_LEN {
+            return Err(spi::Error::TooLong);
+        }
+
+        let mut r = &self.inner;
+        let fifo = r.lock(|inner| {
+            // The controller counts bytes against DLEN and asserts chip select on its own
+            inner.DLEN.write(DLEN::LEN.val(tx.len() as u32));
+            inner
+                .CS
+                .write(inner.mode() + CS::CLEAR::Both + CS::DMAEN::SET + CS::TA::SET);
+
+            inner.base_addr + FIFO_OFFSET
+        });
+
+        let abort = |_| {
+            let mut r = &self.inner;
+            r.lock(|inner| inner.CS.write(inner.mode()));
+
+            spi::Error::Dma
+        };
+
+        // Receive first, so nothing clocked in is missed
+        let rx = rx_channel
+            .peripheral_to_mem(rx_blocks, fifo, Dreq::SpiRx, rx)
+            .map_err(abort)?;
+        let tx = tx_channel
+            .mem_to_peripheral(tx_blocks, tx, fifo, Dreq::SpiTx)
+            .map_err(abort)?;
+
+        Ok(SpiDmaTransfer { spi: self, tx, rx })
+    }
+}
+
+impl interface::driver::DeviceDriver for Spi {
+    fn compatible(&self) -> &str {
+        "BCM2835 SPI0"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let power = bsp::power().acquire(Power::SPI).ok();
+
+        let core_clock = bsp::core_clock_rate();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.power = power;
+            inner.init(core_clock)
+        });
+
+        Ok(())
+    }
+
+    fn shutdown(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl spi::SpiBus for Spi {
+    fn configure(&self, config: &Config) -> spi::Result<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(config))
+    }
+
+    fn transfer(&self, read: &mut [u8], write: &[u8]) -> spi::Result<()> {
+        let len = read.len().max(write.len());
+        let read = Cell::from_mut(read).as_slice_of_cells();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.exchange(
+                len,
+                |i| write.get(i).copied().unwrap_or(0),
+                |i, byte| {
+                    if let Some(cell) = read.get(i) {
+                        cell.set(byte);
+                    }
+                },
+            )
+        })
+    }
+
+    fn transfer_in_place(&self, words: &mut [u8]) -> spi::Result<()> {
+        // Byte i is always sent before the reply for it is stored
+        let words = Cell::from_mut(words).as_slice_of_cells();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.exchange(
+                words.len(),
+                |i| words[i].get(),
+                |i, byte| words[i].set(byte),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CS_REG: usize = 0x00;
+    const FIFO: usize = 0x04;
+    const CLK_REG: usize = 0x08;
+
+    const TA: u32 = 1 << 7;
+    const DONE: u32 = 1 << 16;
+    const RXD: u32 = 1 << 17;
+    const TXD: u32 = 1 << 18;
+
+    fn spi(fake: &FakeRegisterFile) -> SpiInner {
+        let mut spi = SpiInner::new(fake.base_addr());
+        spi.init(250_000_000);
+        spi
+    }
+
+    #[test]
+    fn divider_never_overshoots() {
+        assert_eq!(
+            clock_divider(250_000_000, 1_000_000, MAX_DIVIDER),
+            (250, 1_000_000)
+        );
+        assert_eq!(
+            clock_divider(250_000_000, 3_000_000, MAX_DIVIDER),
+            (84, 2_976_190)
+        );
+        assert_eq!(
+            clock_divider(250_000_000, 500_000_000, MAX_DIVIDER),
+            (2, 125_000_000)
+        );
+        assert_eq!(clock_divider(250_000_000, 1_000, MAX_DIVIDER), (0, 3_814));
+        assert_eq!(clock_divider(250_000_000, 1_000, 32768), (0, 7_629));
+    }
+
+    #[test]
+    fn configure_sets_mode_and_chip_select() {
+        let fake = FakeRegisterFile::new(0x18);
+        let mut spi = spi(&fake);
+
+        let mut config = Config::new(12_500_000, spi::MODE_3);
+        config.chip_select = 1;
+        config.cs_active_high = true;
+
+        assert_eq!(spi.configure(&config), Ok(12_500_000));
+        assert_eq!(fake.read(CLK_REG), 20);
+        assert_eq!(fake.read(CS_REG), 1 << 22 | 1 << 6 | 1 << 3 | 1 << 2 | 1);
+
+        config.chip_select = 3;
+        assert_eq!(spi.configure(&config), Err(spi::Error::InvalidConfig));
+    }
+
+    #[test]
+    fn polled_exchange_moves_bytes_through_the_fifo() {
+        let fake = FakeRegisterFile::new(0x18);
+        let spi = spi(&fake);
+
+        // The byte sent lands in the fake FIFO, answer it with its complement
+        fake.on_poll(|regs, polls| match polls {
+            1 => regs.set_bits(CS_REG, TXD),
+            2 => {
+                regs.write(FIFO, !regs.read(FIFO) & 0xFF);
+                regs.set_bits(CS_REG, RXD | DONE);
+            }
+            _ => (),
+        });
+
+        let received = Cell::new(0);
+        let result = spi.exchange(1, |_| 0xA5, |_, byte| received.set(byte));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(received.get(), 0x5A);
+        assert_eq!(fake.read(CS_REG) & TA, 0);
+    }
+
+    #[test]
+    fn stalled_bus_times_out() {
+        let fake = FakeRegisterFile::new(0x18);
+        let spi = spi(&fake);
+
+        let result = spi.exchange(4, |_| 0, |_, _| ());
+
+        assert_eq!(result, Err(spi::Error::Timeout));
+        assert_eq!(fake.read(CS_REG) & TA, 0);
+    }
+}
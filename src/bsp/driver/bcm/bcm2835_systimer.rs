@@ -37,7 +37,7 @@ register_bitfields! {
         /// System Timer Match 0
         /// 0 = No Timer 0 match since last cleared.
         /// 1 = Timer 0 match detected.
-        MATCH0 OFFSET(0) NUMBITS(0) [
+        MATCH0 OFFSET(0) NUMBITS(1) [
             True = 1,
             False = 0
         ]
@@ -0,0 +1,356 @@
+use crate::{arch, arch::sync::NullLock, interface};
+use core::fmt;
+use core::ops;
+use register::{mmio::*, register_bitfields};
+
+// Every write to the PM block has to carry the password in the top byte or it is ignored
+register_bitfields! {
+    u32,
+
+    RSTC [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+        WRCFG OFFSET(4) NUMBITS(2) [
+            Clear = 0b00,
+            Set = 0b01,
+            FullReset = 0b10
+        ],
+        RESET OFFSET(0) NUMBITS(12) []
+    ],
+
+    WDOG [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+        TIME OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+const PM_PASSWORD: u32 = 0x5A00_0000;
+
+/// Value of RSTC that disarms the watchdog
+const PM_RSTC_RESET: u32 = 0x102;
+
+/// RSTS bits recording what caused the last reset. They are sticky, HADPOR stays set across
+/// watchdog resets until it is cleared.
+const PM_RSTS_HADWRF: u32 = 1 << 5;
+const PM_RSTS_HADPOR: u32 = 1 << 12;
+const PM_RSTS_HISTORY: u32 = PM_RSTS_HADWRF | PM_RSTS_HADPOR;
+
+/// The firmware reads the partition to boot from the even bits of RSTS
+const PM_RSTS_PARTITION_MASK: u32 = 0x555;
+
+/// Partition number the firmware treats as "halt" instead of booting
+const HALT_PARTITION: u8 = 63;
+
+/// The watchdog counts down in 1/65536 of a second
+const TICKS_PER_SECOND: u64 = 65536;
+const MAX_TICKS: u64 = 0xF_FFFF;
+
+/// Longest timeout the 20 bit counter can hold, almost 16 seconds
+pub const MAX_TIMEOUT_MS: u32 = (MAX_TICKS * 1000 / TICKS_PER_SECOND) as u32;
+
+/// Ticks given to the watchdog when rebooting on purpose
+const RESTART_TICKS: u32 = 10;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved0: [u32; 7],                // 0x00
+    RSTC: ReadWrite<u32, RSTC::Register>, // 0x1C
+    RSTS: ReadWrite<u32>,                 // 0x20
+    WDOG: ReadWrite<u32, WDOG::Register>, // 0x24
+}
+
+/// Why the board last came out of reset, as recorded in RSTS at boot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetReason {
+    PowerOn,
+    Watchdog,
+    /// Brought back up after `power_off()`
+    Halt,
+    Unknown,
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ResetReason::PowerOn => "power on",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Halt => "halt",
+            ResetReason::Unknown => "unknown",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Spread a partition number over the even bits of RSTS
+fn partition_to_rsts(partition: u8) -> u32 {
+    (0..6).fold(0, |rsts, bit| {
+        rsts | ((partition as u32 >> bit) & 1) << (bit * 2)
+    })
+}
+
+fn rsts_to_partition(rsts: u32) -> u8 {
+    (0..6).fold(0, |partition, bit| {
+        partition | (((rsts >> (bit * 2)) & 1) << bit) as u8
+    })
+}
+
+struct WatchdogInner {
+    base_addr: usize,
+    reset_partition: u8,
+    boot_rsts: u32,
+    timeout_ms: u32,
+}
+
+impl ops::Deref for WatchdogInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl WatchdogInner {
+    const fn new(base_addr: usize) -> WatchdogInner {
+        WatchdogInner {
+            base_addr,
+            reset_partition: 0,
+            boot_rsts: 0,
+            timeout_ms: MAX_TIMEOUT_MS,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self) {
+        // Remember this before we start writing partitions into it
+        self.boot_rsts = self.RSTS.get();
+
+        // So the next boot only sees what caused the next reset
+        self.RSTS
+            .set(PM_PASSWORD | (self.boot_rsts & !PM_RSTS_HISTORY));
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        if self.boot_rsts & PM_RSTS_PARTITION_MASK == partition_to_rsts(HALT_PARTITION) {
+            ResetReason::Halt
+        } else if self.boot_rsts & PM_RSTS_HADWRF != 0 {
+            // Checked first, HADPOR may still be left over from an earlier power on
+            ResetReason::Watchdog
+        } else if self.boot_rsts & PM_RSTS_HADPOR != 0 {
+            ResetReason::PowerOn
+        } else {
+            ResetReason::Unknown
+        }
+    }
+
+    fn set_partition(&self, partition: u8) {
+        let rsts = self.RSTS.get() & !PM_RSTS_PARTITION_MASK;
+
+        self.RSTS
+            .set(PM_PASSWORD | rsts | partition_to_rsts(partition));
+    }
+
+    /// Arm the watchdog to reset the board after `ticks`
+    fn arm(&self, ticks: u32) {
+        self.WDOG
+            .write(WDOG::PASSWD::Password + WDOG::TIME.val(ticks));
+        self.RSTC
+            .modify(RSTC::PASSWD::Password + RSTC::WRCFG::FullReset);
+    }
+
+    fn start(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+        self.pet();
+    }
+
+    fn pet(&self) {
+        let ticks = (self.timeout_ms as u64 * TICKS_PER_SECOND / 1000).min(MAX_TICKS);
+
+        self.arm(ticks as u32);
+    }
+
+    fn stop(&self) {
+        self.RSTC.set(PM_PASSWORD | PM_RSTC_RESET);
+    }
+
+    fn time_left_ms(&self) -> u32 {
+        (self.WDOG.read(WDOG::TIME) as u64 * 1000 / TICKS_PER_SECOND) as u32
+    }
+
+    fn restart(&self, partition: u8) {
+        self.set_partition(partition);
+        self.arm(RESTART_TICKS);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Watchdog {
+    inner: NullLock<WatchdogInner>,
+}
+
+impl Watchdog {
+    pub const unsafe fn new(base_addr: usize) -> Watchdog {
+        Watchdog {
+            inner: NullLock::new(WatchdogInner::new(base_addr)),
+        }
+    }
+
+    /// Start the watchdog, the board resets unless `pet()` is called within `timeout_ms`.
+    /// Timeouts are capped at `MAX_TIMEOUT_MS`.
+    pub fn start(&self, timeout_ms: u32) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.start(timeout_ms));
+    }
+
+    /// Reload the counter with the timeout last given to `start()`
+    pub fn pet(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.pet());
+    }
+
+    pub fn stop(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.stop());
+    }
+
+    /// Time left before the watchdog fires
+    pub fn time_left_ms(&self) -> u32 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.time_left_ms())
+    }
+
+    /// Select the boot partition the firmware should use after the next `reboot()`
+    pub fn set_reset_partition(&self, partition: u8) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.reset_partition = partition & 0x3F);
+    }
+
+    pub fn reset_reason(&self) -> ResetReason {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.reset_reason())
+    }
+
+    /// Partition the firmware was asked to boot from on the last reset
+    pub fn boot_partition(&self) -> u8 {
+        let mut r = &self.inner;
+        r.lock(|inner| rsts_to_partition(inner.boot_rsts))
+    }
+
+    pub fn reboot(&self) -> ! {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.restart(inner.reset_partition));
+
+        arch::wait_forever()
+    }
+
+    /// Reset into the firmware's halt partition, the board stays off until GPIO3 is pulled low
+    pub fn power_off(&self) -> ! {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.restart(HALT_PARTITION));
+
+        arch::wait_forever()
+    }
+}
+
+impl interface::driver::DeviceDriver for Watchdog {
+    fn compatible(&self) -> &str {
+        "BCM2835 PM Watchdog"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const RSTC: usize = 0x1C;
+    const RSTS: usize = 0x20;
+    const WDOG: usize = 0x24;
+
+    #[test]
+    fn restart_arms_full_reset_with_password() {
+        let fake = FakeRegisterFile::new(0x28);
+        let wdog = WatchdogInner::new(fake.base_addr());
+
+        wdog.restart(0);
+
+        assert_eq!(fake.read(WDOG), PM_PASSWORD | RESTART_TICKS);
+        assert_eq!(fake.read(RSTC) & 0xFF00_0030, PM_PASSWORD | 0x20);
+    }
+
+    #[test]
+    fn power_off_selects_halt_partition() {
+        let fake = FakeRegisterFile::new(0x28);
+        let wdog = WatchdogInner::new(fake.base_addr());
+
+        fake.write(RSTS, PM_RSTS_HADPOR);
+        wdog.restart(HALT_PARTITION);
+
+        assert_eq!(fake.read(RSTS), PM_PASSWORD | PM_RSTS_HADPOR | 0x555);
+    }
+
+    #[test]
+    fn start_converts_and_caps_timeout() {
+        let fake = FakeRegisterFile::new(0x28);
+        let mut wdog = WatchdogInner::new(fake.base_addr());
+
+        wdog.start(1000);
+        assert_eq!(fake.read(WDOG) & 0xF_FFFF, 65536);
+        assert_eq!(wdog.time_left_ms(), 1000);
+
+        wdog.start(60_000);
+        assert_eq!(fake.read(WDOG) & 0xF_FFFF, MAX_TICKS as u32);
+    }
+
+    #[test]
+    fn reset_reason_from_boot_rsts() {
+        let fake = FakeRegisterFile::new(0x28);
+        let mut wdog = WatchdogInner::new(fake.base_addr());
+
+        for (rsts, reason) in &[
+            (PM_RSTS_HADPOR, ResetReason::PowerOn),
+            (PM_RSTS_HADWRF | partition_to_rsts(2), ResetReason::Watchdog),
+            (PM_RSTS_HADPOR | PM_RSTS_HADWRF, ResetReason::Watchdog),
+            (PM_RSTS_HADWRF | 0x555, ResetReason::Halt),
+            (0, ResetReason::Unknown),
+        ] {
+            fake.write(RSTS, *rsts);
+            wdog.init();
+
+            assert_eq!(wdog.reset_reason(), *reason);
+        }
+
+        assert_eq!(rsts_to_partition(partition_to_rsts(2)), 2);
+    }
+
+    #[test]
+    fn init_clears_the_reset_history() {
+        let fake = FakeRegisterFile::new(0x28);
+        let mut wdog = WatchdogInner::new(fake.base_addr());
+
+        fake.write(RSTS, PM_RSTS_HADPOR | PM_RSTS_HADWRF | partition_to_rsts(2));
+        wdog.init();
+
+        assert_eq!(wdog.reset_reason(), ResetReason::Watchdog);
+        assert_eq!(wdog.boot_rsts & PM_RSTS_HISTORY, PM_RSTS_HISTORY);
+        assert_eq!(fake.read(RSTS), PM_PASSWORD | partition_to_rsts(2));
+    }
+}
@@ -0,0 +1,122 @@
+use super::{Clocks, Mail, Thermal};
+use crate::{arch::sync::NullLock, interface};
+use interface::cpufreq::{self, Error};
+
+type Result<T> = cpufreq::Result<T>;
+
+/// Rate to actually ask the firmware for. While the thermal driver has the board throttled
+/// nothing above the minimum is handed out.
+fn clamp(hz: u32, min: u32, max: u32, throttled: bool) -> u32 {
+    if throttled {
+        min
+    } else {
+        hz.max(min).min(max)
+    }
+}
+
+struct ArmClockInner {
+    /// Limits reported by the firmware, 0 until first asked for
+    min: u32,
+    max: u32,
+}
+
+impl ArmClockInner {
+    const fn new() -> ArmClockInner {
+        ArmClockInner { min: 0, max: 0 }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// The ARM clock, owned by the firmware and set through the mailbox. Going above the default rate
+/// makes the firmware switch to turbo and raise the core voltage by itself.
+pub struct ArmClock {
+    inner: NullLock<ArmClockInner>,
+    thermal: &'static Thermal,
+}
+
+impl ArmClock {
+    pub const fn new(thermal: &'static Thermal) -> ArmClock {
+        ArmClock {
+            inner: NullLock::new(ArmClockInner::new()),
+            thermal,
+        }
+    }
+}
+
+impl cpufreq::CpuFreq for ArmClock {
+    fn current(&self) -> Result<u32> {
+        let mut mail = Mail::new();
+        match mail.get_clock_rate(Clocks::ARM) {
+            Ok((_, rate)) => Ok(rate),
+            Err(_) => Err(Error::Firmware),
+        }
+    }
+
+    fn limits(&self) -> Result<(u32, u32)> {
+        let mut r = &self.inner;
+        let cached = r.lock(|inner| (inner.min, inner.max));
+        if cached.0 != 0 {
+            return Ok(cached);
+        }
+
+        let mut mail = Mail::new();
+        let min = mail
+            .get_min_clock_rate(Clocks::ARM)
+            .map_err(|_| Error::Firmware)?;
+        let max = mail
+            .get_max_clock_rate(Clocks::ARM)
+            .map_err(|_| Error::Firmware)?;
+
+        r.lock(|inner| {
+            inner.min = min;
+            inner.max = max;
+        });
+
+        Ok((min, max))
+    }
+
+    fn set(&self, hz: u32) -> Result<u32> {
+        let (min, max) = self.limits()?;
+        let hz = clamp(hz, min, max, self.thermal.is_throttled());
+
+        let mut mail = Mail::new();
+        match mail.set_clock_rate(Clocks::ARM, hz, 0) {
+            Ok((_, rate)) => Ok(rate),
+            Err(_) => Err(Error::Firmware),
+        }
+    }
+}
+
+impl interface::driver::DeviceDriver for ArmClock {
+    fn compatible(&self) -> &str {
+        "Raspberry Pi Firmware ARM Clock"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        // QEMU's firmware doesn't know the limits, cpufreq reports that when it starts
+        let _ = cpufreq::CpuFreq::limits(self);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_clamped_to_the_limits() {
+        assert_eq!(clamp(2_000, 600, 1_200, false), 1_200);
+        assert_eq!(clamp(100, 600, 1_200, false), 600);
+        assert_eq!(clamp(900, 600, 1_200, false), 900);
+    }
+
+    #[test]
+    fn throttling_pins_the_minimum() {
+        assert_eq!(clamp(1_200, 600, 1_200, true), 600);
+    }
+}
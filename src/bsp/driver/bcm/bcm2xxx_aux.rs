@@ -136,6 +136,16 @@ impl AuxRegisters {
         let mut r = &self.inner;
         r.lock(|inner| inner.set_mini_uart(true))
     }
+
+    pub fn enable_spi1(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_spi1(true))
+    }
+
+    pub fn enable_spi2(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_spi2(true))
+    }
 }
 
 impl interface::driver::DeviceDriver for AuxRegisters {
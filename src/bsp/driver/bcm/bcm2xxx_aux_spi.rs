@@ -0,0 +1,449 @@
+use super::AuxRegisters;
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::cell::Cell;
+use core::ops;
+use interface::spi::{self, Config, Phase, Polarity};
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    CNTL0 [
+        /// SCLK = core clock / (2 * (SPEED + 1))
+        SPEED OFFSET(20) NUMBITS(12) [],
+        /// Levels driven onto CS0-CS2 while a word is shifted
+        CHIP_SELECTS OFFSET(17) NUMBITS(3) [],
+        POST_INPUT OFFSET(16) NUMBITS(1) [],
+        VARIABLE_CS OFFSET(15) NUMBITS(1) [],
+        /// The top byte of each TX FIFO entry holds its bit count
+        VARIABLE_WIDTH OFFSET(14) NUMBITS(1) [],
+        DOUT_HOLD OFFSET(12) NUMBITS(2) [],
+        ENABLE OFFSET(11) NUMBITS(1) [],
+        /// Sample on the rising edge of SCLK
+        IN_RISING OFFSET(10) NUMBITS(1) [],
+        CLEAR_FIFOS OFFSET(9) NUMBITS(1) [],
+        /// Data changes on the rising edge of SCLK
+        OUT_RISING OFFSET(8) NUMBITS(1) [],
+        /// SCLK idles high
+        INVERT_CLK OFFSET(7) NUMBITS(1) [],
+        OUT_MS_BIT_FIRST OFFSET(6) NUMBITS(1) [],
+        SHIFT_LENGTH OFFSET(0) NUMBITS(6) []
+    ],
+
+    CNTL1 [
+        CS_HIGH_TIME OFFSET(8) NUMBITS(3) [],
+        /// Interrupt while the TX FIFO is empty
+        TX_EMPTY_IRQ OFFSET(7) NUMBITS(1) [],
+        /// Interrupt when the module goes idle
+        DONE_IRQ OFFSET(6) NUMBITS(1) [],
+        IN_MS_BIT_FIRST OFFSET(1) NUMBITS(1) [],
+        KEEP_INPUT OFFSET(0) NUMBITS(1) []
+    ],
+
+    STAT [
+        TX_LEVEL OFFSET(24) NUMBITS(8) [],
+        RX_LEVEL OFFSET(16) NUMBITS(8) [],
+        TX_FULL OFFSET(10) NUMBITS(1) [],
+        TX_EMPTY OFFSET(9) NUMBITS(1) [],
+        RX_FULL OFFSET(8) NUMBITS(1) [],
+        RX_EMPTY OFFSET(7) NUMBITS(1) [],
+        BUSY OFFSET(6) NUMBITS(1) [],
+        BIT_COUNT OFFSET(0) NUMBITS(6) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CNTL0: ReadWrite<u32, CNTL0::Register>, // 0x00
+    CNTL1: ReadWrite<u32, CNTL1::Register>, // 0x04
+    STAT: ReadOnly<u32, STAT::Register>,    // 0x08
+    PEEK: ReadOnly<u32>,                    // 0x0C
+    __reserved_0: [u32; 4],                 //
+    /// Writing releases chip select after the word, reading pops the RX FIFO
+    IO: ReadWrite<u32>, // 0x20
+    __reserved_1: [u32; 3],                 //
+    /// Writing keeps chip select asserted after the word
+    TXHOLD: ReadWrite<u32>, // 0x30
+}
+
+/// Which of the two identical masters this is, they differ in their enable and IRQ bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    Spi1,
+    Spi2,
+}
+
+/// Give up on a transfer after this long without the FIFOs moving
+const TRANSFER_TIMEOUT_US: usize = 100_000;
+
+const CHIP_SELECTS: u8 = 3;
+
+/// Longest shift a single FIFO entry can describe
+const MAX_WORD_BITS: u32 = 24;
+
+/// Bytes packed into one FIFO entry for byte streams
+const BYTES_PER_WORD: usize = 3;
+
+/// SPEED value that gets closest to `target` without going over it. Returns it and the
+/// resulting clock.
+fn clock_speed(core_clock: u32, target: u32) -> (u32, u32) {
+    let speed = ((core_clock + 2 * target - 1) / (2 * target)).max(1) - 1;
+    let speed = speed.min(0xFFF);
+
+    (speed, core_clock / (2 * (speed + 1)))
+}
+
+/// TX FIFO entry shifting out the low `bits` of `value`, MSB first
+fn tx_word(value: u32, bits: u32) -> u32 {
+    let mask = (1 << bits) - 1;
+
+    bits << 24 | (value & mask) << (MAX_WORD_BITS - bits)
+}
+
+/// A byte stream transfer in progress, bytes go three to a FIFO entry
+#[derive(Clone, Copy, Default)]
+struct Progress {
+    tx_pos: usize,
+    rx_pos: usize,
+}
+
+struct AuxSpiInner {
+    base_addr: usize,
+    /// Set in init, the SPI clock is divided down from it
+    core_clock: u32,
+    config: Config,
+}
+
+impl ops::Deref for AuxSpiInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl AuxSpiInner {
+    const fn new(base_addr: usize) -> AuxSpiInner {
+        AuxSpiInner {
+            base_addr,
+            core_clock: 0,
+            config: Config::new(1_000_000, spi::MODE_0),
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, core_clock: u32) {
+        self.core_clock = core_clock;
+
+        let config = self.config;
+        let _ = self.configure(&config);
+    }
+
+    fn configure(&mut self, config: &Config) -> spi::Result<u32> {
+        // Chip selects are always active low on these masters
+        if config.chip_select >= CHIP_SELECTS || config.cs_active_high || config.frequency == 0 {
+            return Err(spi::Error::InvalidConfig);
+        }
+
+        self.config = *config;
+        let (speed, rate) = clock_speed(self.core_clock, config.frequency);
+
+        // Sample on the first edge for CPHA = 0. The first edge is rising unless SCLK idles high.
+        let idle_high = config.mode.polarity == Polarity::IdleHigh;
+        let second_edge = config.mode.phase == Phase::CaptureOnSecondTransition;
+        let sample_rising = idle_high == second_edge;
+
+        let cntl0 = CNTL0::SPEED.val(speed)
+            + CNTL0::CHIP_SELECTS.val(0b111 & !(1 << config.chip_select))
+            + CNTL0::VARIABLE_WIDTH::SET
+            + CNTL0::ENABLE::SET
+            + CNTL0::OUT_MS_BIT_FIRST::SET
+            + if sample_rising {
+                CNTL0::IN_RISING::SET
+            } else {
+                CNTL0::OUT_RISING::SET
+            }
+            + if idle_high {
+                CNTL0::INVERT_CLK::SET
+            } else {
+                CNTL0::INVERT_CLK::CLEAR
+            };
+
+        self.CNTL0.write(cntl0 + CNTL0::CLEAR_FIFOS::SET);
+        self.CNTL0.write(cntl0);
+        self.CNTL1.write(CNTL1::IN_MS_BIT_FIRST::SET);
+
+        Ok(rate)
+    }
+
+    fn clear_fifos(&self) {
+        self.CNTL0.modify(CNTL0::CLEAR_FIFOS::SET);
+        self.CNTL0.modify(CNTL0::CLEAR_FIFOS::CLEAR);
+    }
+
+    /// Queue the low `bits` of `value`. Chip select stays asserted afterwards unless `last`.
+    fn push(&self, value: u32, bits: u32, last: bool) {
+        if last {
+            self.IO.set(tx_word(value, bits));
+        } else {
+            self.TXHOLD.set(tx_word(value, bits));
+        }
+    }
+
+    /// Queue bytes from `tx` while the FIFO has room
+    fn fill(&self, len: usize, progress: &mut Progress, tx: impl Fn(usize) -> u8) {
+        while progress.tx_pos < len && !self.STAT.is_set(STAT::TX_FULL) {
+            let count = (len - progress.tx_pos).min(BYTES_PER_WORD);
+            let value = (0..count).fold(0, |value, i| value << 8 | tx(progress.tx_pos + i) as u32);
+
+            progress.tx_pos += count;
+            self.push(value, count as u32 * 8, progress.tx_pos == len);
+        }
+    }
+
+    /// Hand received bytes to `rx` while the FIFO has any
+    fn drain(&self, len: usize, progress: &mut Progress, mut rx: impl FnMut(usize, u8)) {
+        while progress.rx_pos < progress.tx_pos && !self.STAT.is_set(STAT::RX_EMPTY) {
+            // Input is shifted in from the bottom, so a short word is right aligned
+            let count = (len - progress.rx_pos).min(BYTES_PER_WORD);
+            let value = self.IO.get();
+
+            for i in 0..count {
+                rx(progress.rx_pos + i, (value >> (8 * (count - 1 - i))) as u8);
+            }
+            progress.rx_pos += count;
+        }
+    }
+
+    /// Poll `done` for up to the transfer timeout
+    fn wait_for(&self, done: impl Fn(&Self) -> bool) -> spi::Result<()> {
+        for _ in 0..TRANSFER_TIMEOUT_US {
+            if done(self) {
+                return Ok(());
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        if done(self) {
+            Ok(())
+        } else {
+            self.clear_fifos();
+            Err(spi::Error::Timeout)
+        }
+    }
+
+    /// Run a polled transfer of `len` bytes. `tx` supplies the byte to send at each position,
+    /// `rx` takes the byte received there.
+    fn exchange(
+        &self,
+        len: usize,
+        tx: impl Fn(usize) -> u8,
+        rx: impl Fn(usize, u8),
+    ) -> spi::Result<()> {
+        let mut progress = Progress::default();
+
+        while progress.rx_pos < len {
+            self.fill(len, &mut progress, &tx);
+            self.drain(len, &mut progress, &rx);
+
+            if progress.rx_pos < len {
+                let before = (progress.tx_pos, progress.rx_pos);
+                self.wait_for(|s| {
+                    let room = before.0 < len && !s.STAT.is_set(STAT::TX_FULL);
+                    room || !s.STAT.is_set(STAT::RX_EMPTY)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shift a single word of 1 to 24 bits, returns the bits clocked in meanwhile
+    fn shift(&self, value: u32, bits: u32) -> spi::Result<u32> {
+        if bits == 0 || bits > MAX_WORD_BITS {
+            return Err(spi::Error::InvalidConfig);
+        }
+
+        self.push(value, bits, true);
+        self.wait_for(|s| !s.STAT.is_set(STAT::RX_EMPTY))?;
+
+        Ok(self.IO.get() & ((1 << bits) - 1))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// One of the two SPI masters in the AUX block. They are simpler than SPI0: no DMA, a four
+/// entry FIFO, but any word width from 1 to 24 bits.
+///
+/// Transfers are polled. The kernel doesn't take interrupts, so TX_EMPTY_IRQ and DONE_IRQ stay
+/// off.
+pub struct AuxSpi {
+    inner: NullLock<AuxSpiInner>,
+    aux: &'static AuxRegisters,
+    port: Port,
+}
+
+impl AuxSpi {
+    pub const unsafe fn new(base_addr: usize, aux: &'static AuxRegisters, port: Port) -> AuxSpi {
+        AuxSpi {
+            inner: NullLock::new(AuxSpiInner::new(base_addr)),
+            aux,
+            port,
+        }
+    }
+
+    /// Shift one word of `bits` (1 to 24) bits out of `value`, MSB first, with chip select
+    /// asserted around it. Returns the `bits` bits clocked in.
+    pub fn shift(&self, value: u32, bits: u32) -> spi::Result<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.shift(value, bits))
+    }
+}
+
+impl interface::driver::DeviceDriver for AuxSpi {
+    fn compatible(&self) -> &str {
+        match self.port {
+            Port::Spi1 => "BCM2XXX AUX SPI1",
+            Port::Spi2 => "BCM2XXX AUX SPI2",
+        }
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        // The registers only respond once the block is enabled
+        match self.port {
+            Port::Spi1 => self.aux.enable_spi1(),
+            Port::Spi2 => self.aux.enable_spi2(),
+        }
+
+        let core_clock = bsp::core_clock_rate();
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(core_clock));
+
+        Ok(())
+    }
+}
+
+impl spi::SpiBus for AuxSpi {
+    fn configure(&self, config: &Config) -> spi::Result<u32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.configure(config))
+    }
+
+    fn transfer(&self, read: &mut [u8], write: &[u8]) -> spi::Result<()> {
+        let len = read.len().max(write.len());
+        let read = Cell::from_mut(read).as_slice_of_cells();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.exchange(
+                len,
+                |i| write.get(i).copied().unwrap_or(0),
+                |i, byte| {
+                    if let Some(cell) = read.get(i) {
+                        cell.set(byte);
+                    }
+                },
+            )
+        })
+    }
+
+    fn transfer_in_place(&self, words: &mut [u8]) -> spi::Result<()> {
+        // Byte i is always sent before the reply for it is stored
+        let words = Cell::from_mut(words).as_slice_of_cells();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.exchange(
+                words.len(),
+                |i| words[i].get(),
+                |i, byte| words[i].set(byte),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CNTL0_REG: usize = 0x00;
+    const CNTL1_REG: usize = 0x04;
+    const STAT_REG: usize = 0x08;
+    const IO: usize = 0x20;
+    const TXHOLD: usize = 0x30;
+
+    const RX_EMPTY: u32 = 1 << 7;
+    const TX_FULL: u32 = 1 << 10;
+
+    fn spi(fake: &FakeRegisterFile) -> AuxSpiInner {
+        let mut spi = AuxSpiInner::new(fake.base_addr());
+        spi.init(250_000_000);
+        spi
+    }
+
+    #[test]
+    fn speed_never_overshoots() {
+        assert_eq!(clock_speed(250_000_000, 1_000_000), (124, 1_000_000));
+        assert_eq!(clock_speed(250_000_000, 3_000_000), (41, 2_976_190));
+        assert_eq!(clock_speed(250_000_000, 500_000_000), (0, 125_000_000));
+        assert_eq!(clock_speed(250_000_000, 1_000), (0xFFF, 30_517));
+    }
+
+    #[test]
+    fn configure_picks_edges_and_chip_select() {
+        let fake = FakeRegisterFile::new(0x40);
+        let mut spi = spi(&fake);
+
+        let mut config = Config::new(1_000_000, spi::MODE_2);
+        config.chip_select = 1;
+        assert_eq!(spi.configure(&config), Ok(1_000_000));
+
+        let cntl0 = fake.read(CNTL0_REG);
+        assert_eq!(cntl0 >> 20, 124);
+        assert_eq!(cntl0 >> 17 & 0b111, 0b101);
+        // CPOL = 1, CPHA = 0: idle high, sample on the falling edge
+        assert_eq!(cntl0 & (1 << 10 | 1 << 8 | 1 << 7), 1 << 8 | 1 << 7);
+        assert_eq!(cntl0 & (1 << 9), 0);
+
+        config.cs_active_high = true;
+        assert_eq!(spi.configure(&config), Err(spi::Error::InvalidConfig));
+    }
+
+    #[test]
+    fn bytes_are_packed_three_to_a_word() {
+        let fake = FakeRegisterFile::new(0x40);
+        let spi = spi(&fake);
+
+        let tx = [0x01, 0x02, 0x03, 0x04];
+        assert_eq!(spi.exchange(tx.len(), |i| tx[i], |_, _| ()), Ok(()));
+
+        // The first word keeps chip select asserted, the last one releases it
+        assert_eq!(fake.read(TXHOLD), 24 << 24 | 0x01_0203);
+        assert_eq!(fake.read(IO), 8 << 24 | 0x04_0000);
+    }
+
+    #[test]
+    fn odd_widths_are_shifted_msb_first() {
+        let fake = FakeRegisterFile::new(0x40);
+        let spi = spi(&fake);
+
+        assert_eq!(spi.shift(0x1FF, 9), Ok(0));
+        assert_eq!(fake.read(IO), 9 << 24 | 0x1FF << 15);
+        assert_eq!(spi.shift(0, 25), Err(spi::Error::InvalidConfig));
+
+        fake.write(STAT_REG, RX_EMPTY | TX_FULL);
+        assert_eq!(spi.shift(1, 8), Err(spi::Error::Timeout));
+    }
+}
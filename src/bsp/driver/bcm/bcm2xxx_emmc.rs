@@ -0,0 +1,896 @@
+use super::{Power, PowerHandle};
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::ops;
+use interface::block::{self, BLOCK_SIZE};
+use register::{mmio::*, register_bitfields, Field};
+
+// SD host controller. The Arasan EMMC block of the BCM2837 and the EMMC2 block of the BCM2711
+// are both close enough to the SDHCI spec that one driver covers them.
+
+register_bitfields! {
+    u32,
+
+    BLKSIZECNT [
+        BLKCNT OFFSET(16) NUMBITS(16) [],
+        BLKSIZE OFFSET(0) NUMBITS(10) []
+    ],
+
+    CMDTM [
+        CMD_INDEX OFFSET(24) NUMBITS(6) [],
+        CMD_TYPE OFFSET(22) NUMBITS(2) [
+            Normal = 0b00,
+            Suspend = 0b01,
+            Resume = 0b10,
+            Abort = 0b11
+        ],
+        CMD_ISDATA OFFSET(21) NUMBITS(1) [],
+        /// Check that the response carries the index of the command
+        CMD_IXCHK_EN OFFSET(20) NUMBITS(1) [],
+        CMD_CRCCHK_EN OFFSET(19) NUMBITS(1) [],
+        CMD_RSPNS_TYPE OFFSET(16) NUMBITS(2) [
+            None = 0b00,
+            Bits136 = 0b01,
+            Bits48 = 0b10,
+            Bits48Busy = 0b11
+        ],
+        TM_MULTI_BLOCK OFFSET(5) NUMBITS(1) [],
+        TM_DAT_DIR OFFSET(4) NUMBITS(1) [
+            HostToCard = 0,
+            CardToHost = 1
+        ],
+        /// Have the controller send CMD12 by itself once the block count runs out
+        TM_AUTO_CMD_EN OFFSET(2) NUMBITS(2) [
+            None = 0b00,
+            Cmd12 = 0b01,
+            Cmd23 = 0b10
+        ],
+        TM_BLKCNT_EN OFFSET(1) NUMBITS(1) []
+    ],
+
+    STATUS [
+        CARD_INSERTED OFFSET(16) NUMBITS(1) [],
+        DAT_ACTIVE OFFSET(2) NUMBITS(1) [],
+        DAT_INHIBIT OFFSET(1) NUMBITS(1) [],
+        CMD_INHIBIT OFFSET(0) NUMBITS(1) []
+    ],
+
+    CONTROL0 [
+        SD_BUS_VOLTAGE OFFSET(9) NUMBITS(3) [
+            V3_3 = 0b111
+        ],
+        /// Only wired up on EMMC2, the Arasan block ignores the power control bits
+        SD_BUS_POWER OFFSET(8) NUMBITS(1) [],
+        HCTL_8BIT OFFSET(5) NUMBITS(1) [],
+        HCTL_HS_EN OFFSET(2) NUMBITS(1) [],
+        /// 4 bit data bus
+        HCTL_DWIDTH OFFSET(1) NUMBITS(1) []
+    ],
+
+    CONTROL1 [
+        SRST_DATA OFFSET(26) NUMBITS(1) [],
+        SRST_CMD OFFSET(25) NUMBITS(1) [],
+        SRST_HC OFFSET(24) NUMBITS(1) [],
+        /// Data timeout, TMCLK * 2^(DATA_TOUNIT + 13)
+        DATA_TOUNIT OFFSET(16) NUMBITS(4) [],
+        CLK_FREQ8 OFFSET(8) NUMBITS(8) [],
+        CLK_FREQ_MS2 OFFSET(6) NUMBITS(2) [],
+        CLK_GENSEL OFFSET(5) NUMBITS(1) [],
+        CLK_EN OFFSET(2) NUMBITS(1) [],
+        CLK_STABLE OFFSET(1) NUMBITS(1) [],
+        CLK_INTLEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Write 1 to clear
+    INTERRUPT [
+        ACMD_ERR OFFSET(24) NUMBITS(1) [],
+        DEND_ERR OFFSET(22) NUMBITS(1) [],
+        DCRC_ERR OFFSET(21) NUMBITS(1) [],
+        DTO_ERR OFFSET(20) NUMBITS(1) [],
+        CBAD_ERR OFFSET(19) NUMBITS(1) [],
+        CEND_ERR OFFSET(18) NUMBITS(1) [],
+        CCRC_ERR OFFSET(17) NUMBITS(1) [],
+        CTO_ERR OFFSET(16) NUMBITS(1) [],
+        ERR OFFSET(15) NUMBITS(1) [],
+        READ_RDY OFFSET(5) NUMBITS(1) [],
+        WRITE_RDY OFFSET(4) NUMBITS(1) [],
+        DATA_DONE OFFSET(1) NUMBITS(1) [],
+        CMD_DONE OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    ARG2: ReadWrite<u32>,                             // 0x00
+    BLKSIZECNT: ReadWrite<u32, BLKSIZECNT::Register>, // 0x04
+    ARG1: ReadWrite<u32>,                             // 0x08
+    CMDTM: ReadWrite<u32, CMDTM::Register>,           // 0x0C
+    RESP: [ReadOnly<u32>; 4],                         // 0x10
+    DATA: ReadWrite<u32>,                             // 0x20
+    STATUS: ReadOnly<u32, STATUS::Register>,          // 0x24
+    CONTROL0: ReadWrite<u32, CONTROL0::Register>,     // 0x28
+    CONTROL1: ReadWrite<u32, CONTROL1::Register>,     // 0x2C
+    INTERRUPT: ReadWrite<u32, INTERRUPT::Register>,   // 0x30
+    IRPT_MASK: ReadWrite<u32>,                        // 0x34
+    IRPT_EN: ReadWrite<u32>,                          // 0x38
+    CONTROL2: ReadWrite<u32>,                         // 0x3C
+    __reserved0: [u32; 47],                           // 0x40
+    SLOTISR_VER: ReadOnly<u32>,                       // 0xFC
+}
+
+/// Identification has to happen below 400kHz
+const IDENTIFICATION_CLOCK: u32 = 400_000;
+const DEFAULT_SPEED_CLOCK: u32 = 25_000_000;
+const HIGH_SPEED_CLOCK: u32 = 50_000_000;
+
+/// Software timeouts, the controller flags most timeouts itself long before these
+const RESET_TIMEOUT_US: usize = 100_000;
+const COMMAND_TIMEOUT_US: usize = 100_000;
+const DATA_TIMEOUT_US: usize = 500_000;
+
+/// ACMD41 is retried every 10ms for up to a second while the card powers up
+const OP_COND_RETRIES: usize = 100;
+
+// SEND_IF_COND argument, 2.7-3.6V and a check pattern the card echoes back
+const IF_COND_ARG: u32 = 0x1AA;
+
+// OCR bits
+const OCR_BUSY: u32 = 1 << 31;
+const OCR_CCS: u32 = 1 << 30;
+const OCR_HCS: u32 = 1 << 30;
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+/// R1 card status bits that signal an error
+const R1_ERRORS: u32 = 0xFDF9_0008;
+
+/// CMD6 argument: switch function group 1 (access mode) to high speed, leave the rest alone
+const SWITCH_HIGH_SPEED: u32 = 0x80FF_FFF1;
+const SWITCH_STATUS_SIZE: usize = 64;
+
+const MAX_BLOCKS_PER_COMMAND: usize = 0xFFFF;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Response {
+    None,
+    R1,
+    R1b,
+    R2,
+    R3,
+    R6,
+    R7,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+struct Command {
+    index: u32,
+    response: Response,
+}
+
+const GO_IDLE_STATE: Command = Command {
+    index: 0,
+    response: Response::None,
+};
+const ALL_SEND_CID: Command = Command {
+    index: 2,
+    response: Response::R2,
+};
+const SEND_RELATIVE_ADDR: Command = Command {
+    index: 3,
+    response: Response::R6,
+};
+const SWITCH_FUNC: Command = Command {
+    index: 6,
+    response: Response::R1,
+};
+const SELECT_CARD: Command = Command {
+    index: 7,
+    response: Response::R1b,
+};
+const SEND_IF_COND: Command = Command {
+    index: 8,
+    response: Response::R7,
+};
+const SEND_CSD: Command = Command {
+    index: 9,
+    response: Response::R2,
+};
+const SET_BLOCKLEN: Command = Command {
+    index: 16,
+    response: Response::R1,
+};
+const READ_SINGLE_BLOCK: Command = Command {
+    index: 17,
+    response: Response::R1,
+};
+const READ_MULTIPLE_BLOCK: Command = Command {
+    index: 18,
+    response: Response::R1,
+};
+const WRITE_BLOCK: Command = Command {
+    index: 24,
+    response: Response::R1,
+};
+const WRITE_MULTIPLE_BLOCK: Command = Command {
+    index: 25,
+    response: Response::R1,
+};
+const APP_CMD: Command = Command {
+    index: 55,
+    response: Response::R1,
+};
+
+// Application commands, sent after APP_CMD
+const SET_BUS_WIDTH: Command = Command {
+    index: 6,
+    response: Response::R1,
+};
+const SD_SEND_OP_COND: Command = Command {
+    index: 41,
+    response: Response::R3,
+};
+
+/// Divisor for the SDHCI 10 bit divided clock mode, SDCLK = base / (2 * divisor). Rounds up so
+/// the card is never clocked faster than asked.
+fn clock_divisor(base_clock: u32, target: u32) -> u32 {
+    if target >= base_clock {
+        return 0;
+    }
+
+    let divisor = (base_clock + 2 * target - 1) / (2 * target);
+
+    divisor.min(0x3FF)
+}
+
+/// Card capacity in blocks from a CSD register. The controller strips the CRC, so `resp`
+/// holds CSD bits 127:8 shifted down by 8.
+fn csd_block_count(resp: [u32; 4]) -> u64 {
+    match (resp[3] >> 22) & 0x3 {
+        // Standard capacity, capacity = (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN
+        0 => {
+            let read_bl_len = (resp[2] >> 8) & 0xF;
+            let c_size = ((resp[2] & 0x3) << 10) | (resp[1] >> 22);
+            let c_size_mult = (resp[1] >> 7) & 0x7;
+
+            ((c_size as u64 + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u64
+        }
+        // High capacity, counted in 512KB units
+        1 => {
+            let c_size = (resp[1] >> 8) & 0x3F_FFFF;
+
+            (c_size as u64 + 1) * 1024
+        }
+        _ => 0,
+    }
+}
+
+/// What we know about the card after identification
+#[derive(Clone, Copy)]
+struct Card {
+    rca: u32,
+    /// SDHC/SDXC cards are addressed in blocks, older cards in bytes
+    high_capacity: bool,
+    blocks: u64,
+}
+
+struct EmmcInner {
+    base_addr: usize,
+    card_detect: bool,
+    base_clock: u32,
+    card: Option<Card>,
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
+}
+
+impl ops::Deref for EmmcInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl EmmcInner {
+    const fn new(base_addr: usize, card_detect: bool) -> EmmcInner {
+        EmmcInner {
+            base_addr,
+            card_detect,
+            base_clock: 0,
+            card: None,
+            power: None,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    /// Poll `done` for up to `timeout_us`
+    fn wait_for(&self, timeout_us: usize, done: impl Fn(&Self) -> bool) -> bool {
+        for _ in 0..timeout_us {
+            if done(self) {
+                return true;
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        done(self)
+    }
+
+    fn card_present(&self) -> bool {
+        !self.card_detect || self.STATUS.is_set(STATUS::CARD_INSERTED)
+    }
+
+    fn reset_host(&mut self, base_clock: u32) -> block::Result<()> {
+        self.base_clock = base_clock;
+
+        self.CONTROL0.set(0);
+        self.CONTROL1.modify(CONTROL1::SRST_HC::SET);
+
+        if !self.wait_for(RESET_TIMEOUT_US, |s| !s.CONTROL1.is_set(CONTROL1::SRST_HC)) {
+            return Err(block::Error::Timeout);
+        }
+
+        self.CONTROL0
+            .write(CONTROL0::SD_BUS_POWER::SET + CONTROL0::SD_BUS_VOLTAGE::V3_3);
+        self.CONTROL1.modify(CONTROL1::DATA_TOUNIT.val(0xE));
+
+        self.set_clock(IDENTIFICATION_CLOCK)?;
+
+        // Status bits latch with the mask set, we poll them instead of taking interrupts
+        self.IRPT_EN.set(0);
+        self.IRPT_MASK.set(0xFFFF_FFFF);
+        self.INTERRUPT.set(self.INTERRUPT.get());
+
+        Ok(())
+    }
+
+    /// Recover the command or data state machine after an error
+    fn reset_line(&self, line: Field<u32, CONTROL1::Register>) {
+        self.CONTROL1.modify(line.val(1));
+        self.wait_for(RESET_TIMEOUT_US, |s| s.CONTROL1.read(line) == 0);
+    }
+
+    fn set_clock(&self, hz: u32) -> block::Result<()> {
+        let idle = |s: &Self| {
+            !s.STATUS.is_set(STATUS::CMD_INHIBIT) && !s.STATUS.is_set(STATUS::DAT_INHIBIT)
+        };
+        if !self.wait_for(COMMAND_TIMEOUT_US, idle) {
+            return Err(block::Error::Timeout);
+        }
+
+        // The divisor may only change while the card clock is off
+        self.CONTROL1.modify(CONTROL1::CLK_EN::CLEAR);
+        arch::wait_usec(10);
+
+        let divisor = clock_divisor(self.base_clock, hz);
+        self.CONTROL1.modify(
+            CONTROL1::CLK_FREQ8.val(divisor & 0xFF)
+                + CONTROL1::CLK_FREQ_MS2.val(divisor >> 8)
+                + CONTROL1::CLK_INTLEN::SET,
+        );
+
+        let stable = self.wait_for(RESET_TIMEOUT_US, |s| {
+            s.CONTROL1.is_set(CONTROL1::CLK_STABLE)
+        });
+        if !stable {
+            return Err(block::Error::Timeout);
+        }
+
+        self.CONTROL1.modify(CONTROL1::CLK_EN::SET);
+        arch::wait_usec(10);
+
+        Ok(())
+    }
+
+    /// Wait for one of the `bits` or an error in INTERRUPT, and acknowledge the bits
+    fn wait_interrupt(&self, bits: u32, timeout_us: usize) -> block::Result<()> {
+        let mask = bits | INTERRUPT::ERR::SET.value;
+
+        if !self.wait_for(timeout_us, |s| s.INTERRUPT.get() & mask != 0) {
+            return Err(block::Error::Timeout);
+        }
+
+        let status = self.INTERRUPT.get();
+
+        if status & INTERRUPT::ERR::SET.value != 0 {
+            // Clear everything that went wrong so the next command starts clean
+            self.INTERRUPT.set(status);
+
+            let timeout = INTERRUPT::CTO_ERR::SET.value | INTERRUPT::DTO_ERR::SET.value;
+            if status & timeout != 0 {
+                return Err(block::Error::Timeout);
+            }
+
+            return Err(block::Error::Io);
+        }
+
+        self.INTERRUPT.set(status & bits);
+
+        Ok(())
+    }
+
+    /// Send a command, with an optional data phase of `blocks` blocks of `block_size` bytes.
+    /// Returns the first response word.
+    fn command(
+        &self,
+        cmd: &Command,
+        arg: u32,
+        data: Option<(Direction, usize, usize)>,
+    ) -> block::Result<u32> {
+        let busy = data.is_some() || cmd.response == Response::R1b;
+        let ready = |s: &Self| {
+            !(s.STATUS.is_set(STATUS::CMD_INHIBIT)
+                || (busy && s.STATUS.is_set(STATUS::DAT_INHIBIT)))
+        };
+
+        if !self.wait_for(COMMAND_TIMEOUT_US, ready) {
+            return Err(block::Error::Timeout);
+        }
+
+        // Drop whatever is left over from the previous command
+        self.INTERRUPT.set(self.INTERRUPT.get());
+
+        let mut cmdtm = CMDTM::CMD_INDEX.val(cmd.index)
+            + match cmd.response {
+                Response::None => CMDTM::CMD_RSPNS_TYPE::None,
+                Response::R2 => CMDTM::CMD_RSPNS_TYPE::Bits136 + CMDTM::CMD_CRCCHK_EN::SET,
+                Response::R3 => CMDTM::CMD_RSPNS_TYPE::Bits48,
+                Response::R1b => {
+                    CMDTM::CMD_RSPNS_TYPE::Bits48Busy
+                        + CMDTM::CMD_CRCCHK_EN::SET
+                        + CMDTM::CMD_IXCHK_EN::SET
+                }
+                _ => {
+                    CMDTM::CMD_RSPNS_TYPE::Bits48
+                        + CMDTM::CMD_CRCCHK_EN::SET
+                        + CMDTM::CMD_IXCHK_EN::SET
+                }
+            };
+
+        if let Some((direction, blocks, block_size)) = data {
+            self.BLKSIZECNT.write(
+                BLKSIZECNT::BLKCNT.val(blocks as u32) + BLKSIZECNT::BLKSIZE.val(block_size as u32),
+            );
+
+            cmdtm = cmdtm + CMDTM::CMD_ISDATA::SET + CMDTM::TM_BLKCNT_EN::SET;
+            if direction == Direction::Read {
+                cmdtm = cmdtm + CMDTM::TM_DAT_DIR::CardToHost;
+            }
+            if blocks > 1 {
+                cmdtm = cmdtm + CMDTM::TM_MULTI_BLOCK::SET + CMDTM::TM_AUTO_CMD_EN::Cmd12;
+            }
+        }
+
+        self.ARG1.set(arg);
+        self.CMDTM.write(cmdtm);
+
+        if let Err(e) = self.wait_interrupt(INTERRUPT::CMD_DONE::SET.value, COMMAND_TIMEOUT_US) {
+            self.reset_line(CONTROL1::SRST_CMD);
+            return Err(e);
+        }
+
+        let response = self.RESP[0].get();
+
+        match cmd.response {
+            Response::R1 | Response::R1b if response & R1_ERRORS != 0 => Err(block::Error::Io),
+            _ => Ok(response),
+        }
+    }
+
+    /// Send an application specific command, prefixed by APP_CMD
+    fn app_command(&self, cmd: &Command, rca: u32, arg: u32) -> block::Result<u32> {
+        self.command(&APP_CMD, rca << 16, None)?;
+        self.command(cmd, arg, None)
+    }
+
+    fn read_data(&self, buf: &mut [u8], block_size: usize) -> block::Result<()> {
+        for chunk in buf.chunks_mut(block_size) {
+            self.wait_interrupt(INTERRUPT::READ_RDY::SET.value, DATA_TIMEOUT_US)?;
+
+            for word in chunk.chunks_mut(4) {
+                word.copy_from_slice(&self.DATA.get().to_le_bytes()[..word.len()]);
+            }
+        }
+
+        self.wait_interrupt(INTERRUPT::DATA_DONE::SET.value, DATA_TIMEOUT_US)
+    }
+
+    fn write_data(&self, buf: &[u8], block_size: usize) -> block::Result<()> {
+        for chunk in buf.chunks(block_size) {
+            self.wait_interrupt(INTERRUPT::WRITE_RDY::SET.value, DATA_TIMEOUT_US)?;
+
+            for word in chunk.chunks(4) {
+                let mut bytes = [0; 4];
+                bytes[..word.len()].copy_from_slice(word);
+                self.DATA.set(u32::from_le_bytes(bytes));
+            }
+        }
+
+        // DATA_DONE only fires once the card finished programming
+        self.wait_interrupt(INTERRUPT::DATA_DONE::SET.value, DATA_TIMEOUT_US)
+    }
+
+    /// Move the card into high speed mode with CMD6, returns false if the card can't
+    fn switch_high_speed(&self) -> block::Result<bool> {
+        let mut status = [0u8; SWITCH_STATUS_SIZE];
+
+        self.command(
+            &SWITCH_FUNC,
+            SWITCH_HIGH_SPEED,
+            Some((Direction::Read, 1, SWITCH_STATUS_SIZE)),
+        )?;
+        self.read_data(&mut status, SWITCH_STATUS_SIZE)?;
+
+        // Bits 379:376 hold the function group 1 selection, 0xF means the switch failed
+        Ok(status[16] & 0xF == 1)
+    }
+
+    /// Run the SD identification sequence and bring the card up to transfer state
+    fn init_card(&mut self) -> block::Result<Card> {
+        if !self.card_present() {
+            return Err(block::Error::NoMedia);
+        }
+
+        self.set_clock(IDENTIFICATION_CLOCK)?;
+        self.CONTROL0
+            .modify(CONTROL0::HCTL_DWIDTH::CLEAR + CONTROL0::HCTL_HS_EN::CLEAR);
+
+        self.command(&GO_IDLE_STATE, 0, None)?;
+
+        // Version 2.00 cards echo the check pattern, older cards don't answer at all
+        let version2 = match self.command(&SEND_IF_COND, IF_COND_ARG, None) {
+            Ok(response) if response & 0xFFF == IF_COND_ARG => true,
+            Ok(_) => return Err(block::Error::Io),
+            Err(block::Error::Timeout) => false,
+            Err(e) => return Err(e),
+        };
+
+        let arg = OCR_VOLTAGE_WINDOW | if version2 { OCR_HCS } else { 0 };
+        let mut ocr = 0;
+        for _ in 0..OP_COND_RETRIES {
+            ocr = self.app_command(&SD_SEND_OP_COND, 0, arg)?;
+            if ocr & OCR_BUSY != 0 {
+                break;
+            }
+
+            arch::wait_usec(10_000);
+        }
+
+        if ocr & OCR_BUSY == 0 {
+            return Err(block::Error::Timeout);
+        }
+
+        self.command(&ALL_SEND_CID, 0, None)?;
+        let rca = self.command(&SEND_RELATIVE_ADDR, 0, None)? >> 16;
+
+        self.command(&SEND_CSD, rca << 16, None)?;
+        let csd = [
+            self.RESP[0].get(),
+            self.RESP[1].get(),
+            self.RESP[2].get(),
+            self.RESP[3].get(),
+        ];
+
+        self.command(&SELECT_CARD, rca << 16, None)?;
+
+        let high_capacity = ocr & OCR_CCS != 0;
+        if !high_capacity {
+            self.command(&SET_BLOCKLEN, BLOCK_SIZE as u32, None)?;
+        }
+
+        // Every SD card supports the 4 bit bus
+        self.app_command(&SET_BUS_WIDTH, rca, 2)?;
+        self.CONTROL0.modify(CONTROL0::HCTL_DWIDTH::SET);
+
+        // Version 1.0 cards don't know CMD6, stay at default speed if anything goes wrong
+        match self.switch_high_speed() {
+            Ok(true) => {
+                self.CONTROL0.modify(CONTROL0::HCTL_HS_EN::SET);
+                self.set_clock(HIGH_SPEED_CLOCK)?;
+            }
+            Ok(false) => self.set_clock(DEFAULT_SPEED_CLOCK)?,
+            Err(_) => {
+                self.reset_line(CONTROL1::SRST_DATA);
+                self.set_clock(DEFAULT_SPEED_CLOCK)?;
+            }
+        }
+
+        Ok(Card {
+            rca,
+            high_capacity,
+            blocks: csd_block_count(csd),
+        })
+    }
+
+    /// Check the request against the card and return it
+    fn check_request(&self, lba: u64, len: usize) -> block::Result<Card> {
+        let card = match self.card {
+            Some(card) if self.card_present() => card,
+            _ => return Err(block::Error::NoMedia),
+        };
+
+        if len % BLOCK_SIZE != 0 {
+            return Err(block::Error::BadBuffer);
+        }
+
+        if lba + (len / BLOCK_SIZE) as u64 > card.blocks {
+            return Err(block::Error::OutOfRange);
+        }
+
+        Ok(card)
+    }
+
+    fn address(card: &Card, lba: u64) -> u32 {
+        if card.high_capacity {
+            lba as u32
+        } else {
+            (lba * BLOCK_SIZE as u64) as u32
+        }
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+        let card = self.check_request(lba, buf.len())?;
+        let mut lba = lba;
+
+        for chunk in buf.chunks_mut(MAX_BLOCKS_PER_COMMAND * BLOCK_SIZE) {
+            let blocks = chunk.len() / BLOCK_SIZE;
+            let cmd = if blocks > 1 {
+                &READ_MULTIPLE_BLOCK
+            } else {
+                &READ_SINGLE_BLOCK
+            };
+            let data = Some((Direction::Read, blocks, BLOCK_SIZE));
+
+            let result = self
+                .command(cmd, Self::address(&card, lba), data)
+                .and_then(|_| self.read_data(chunk, BLOCK_SIZE));
+
+            if result.is_err() {
+                self.reset_line(CONTROL1::SRST_DATA);
+                return result;
+            }
+
+            lba += blocks as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> block::Result<()> {
+        let card = self.check_request(lba, buf.len())?;
+        let mut lba = lba;
+
+        for chunk in buf.chunks(MAX_BLOCKS_PER_COMMAND * BLOCK_SIZE) {
+            let blocks = chunk.len() / BLOCK_SIZE;
+            let cmd = if blocks > 1 {
+                &WRITE_MULTIPLE_BLOCK
+            } else {
+                &WRITE_BLOCK
+            };
+            let data = Some((Direction::Write, blocks, BLOCK_SIZE));
+
+            let result = self
+                .command(cmd, Self::address(&card, lba), data)
+                .and_then(|_| self.write_data(chunk, BLOCK_SIZE));
+
+            if result.is_err() {
+                self.reset_line(CONTROL1::SRST_DATA);
+                return result;
+            }
+
+            lba += blocks as u64;
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Emmc {
+    inner: NullLock<EmmcInner>,
+}
+
+impl Emmc {
+    /// `card_detect` says whether the controller's card detect input is wired to the slot
+    pub const unsafe fn new(base_addr: usize, card_detect: bool) -> Emmc {
+        Emmc {
+            inner: NullLock::new(EmmcInner::new(base_addr, card_detect)),
+        }
+    }
+
+    pub fn card_present(&self) -> bool {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.card_present())
+    }
+
+    /// Identify the card again, e.g. after it was swapped
+    pub fn rescan(&self) -> block::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.card = None;
+            inner.card = Some(inner.init_card()?);
+
+            Ok(())
+        })
+    }
+}
+
+impl interface::block::BlockDevice for Emmc {
+    fn block_count(&self) -> u64 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.card.map_or(0, |card| card.blocks))
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> block::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read_blocks(lba, buf))
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> block::Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_blocks(lba, buf))
+    }
+}
+
+impl interface::driver::DeviceDriver for Emmc {
+    fn compatible(&self) -> &str {
+        "BCM2XXX EMMC"
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        // Power the card and wait for it to be stable
+        let power = bsp::power().acquire(Power::SDCard).ok();
+
+        let base_clock = bsp::emmc_clock_rate();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.power = power;
+            if inner.reset_host(base_clock).is_err() {
+                return Err(());
+            }
+
+            // An empty slot is not a driver failure, reads will report NoMedia
+            inner.card = inner.init_card().ok();
+
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const ARG1: usize = 0x08;
+    const CMDTM: usize = 0x0C;
+    const RESP0: usize = 0x10;
+    const DATA: usize = 0x20;
+    const INTERRUPT: usize = 0x30;
+
+    const CMD_DONE: u32 = 1 << 0;
+    const DATA_DONE: u32 = 1 << 1;
+    const READ_RDY: u32 = 1 << 5;
+    const ERR: u32 = 1 << 15;
+    const CTO_ERR: u32 = 1 << 16;
+
+    fn card(blocks: u64) -> Option<Card> {
+        Some(Card {
+            rca: 0x1234,
+            high_capacity: true,
+            blocks,
+        })
+    }
+
+    #[test]
+    fn clock_divisor_never_overclocks() {
+        assert_eq!(clock_divisor(200_000_000, 400_000), 250);
+        assert_eq!(clock_divisor(200_000_000, 50_000_000), 2);
+        assert_eq!(clock_divisor(250_000_000, 25_000_000), 5);
+        assert_eq!(clock_divisor(100_000_000, 30_000_000), 2);
+        assert_eq!(clock_divisor(50_000_000, 50_000_000), 0);
+        assert_eq!(clock_divisor(250_000_000, 100_000), 0x3FF);
+    }
+
+    #[test]
+    fn csd_capacity_for_both_versions() {
+        // 16GB SDHC card, C_SIZE = 30436
+        assert_eq!(csd_block_count([0, 30436 << 8, 0, 1 << 22]), 30437 * 1024);
+
+        // 1GB standard capacity card, READ_BL_LEN = 10, C_SIZE = 0xF15, C_SIZE_MULT = 7
+        let c_size = 0xF15;
+        let resp = [
+            0,
+            ((c_size & 0x3FF) << 22) | (7 << 7),
+            (10 << 8) | (c_size >> 10),
+            0,
+        ];
+        assert_eq!(csd_block_count(resp), (0xF16u64 << 9 << 10) / 512);
+    }
+
+    #[test]
+    fn command_returns_response() {
+        let fake = FakeRegisterFile::new(0x100);
+        let emmc = EmmcInner::new(fake.base_addr(), false);
+
+        fake.write(RESP0, 0x1AA);
+        fake.on_poll(|regs, _| regs.set_bits(INTERRUPT, CMD_DONE));
+
+        assert_eq!(emmc.command(&SEND_IF_COND, IF_COND_ARG, None), Ok(0x1AA));
+        assert_eq!(fake.read(ARG1), IF_COND_ARG);
+        // Index 8, 48 bit response with CRC and index check
+        assert_eq!(
+            fake.read(CMDTM),
+            (8 << 24) | (1 << 20) | (1 << 19) | (0b10 << 16)
+        );
+    }
+
+    #[test]
+    fn command_timeout_is_reported() {
+        let fake = FakeRegisterFile::new(0x100);
+        let emmc = EmmcInner::new(fake.base_addr(), false);
+
+        fake.on_poll(|regs, _| regs.set_bits(INTERRUPT, ERR | CTO_ERR));
+
+        assert_eq!(
+            emmc.command(&SEND_IF_COND, IF_COND_ARG, None),
+            Err(block::Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn read_single_block() {
+        let fake = FakeRegisterFile::new(0x100);
+        let mut emmc = EmmcInner::new(fake.base_addr(), false);
+        emmc.card = card(1024);
+
+        fake.write(DATA, 0x0403_0201);
+        fake.on_poll(|regs, _| regs.set_bits(INTERRUPT, CMD_DONE | READ_RDY | DATA_DONE));
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(emmc.read_blocks(7, &mut buf), Ok(()));
+
+        assert_eq!(fake.read(ARG1), 7);
+        assert_eq!(fake.read(CMDTM) >> 24, 17);
+        assert_eq!(&buf[..8], &[1, 2, 3, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn requests_are_checked_against_the_card() {
+        let fake = FakeRegisterFile::new(0x100);
+        let mut emmc = EmmcInner::new(fake.base_addr(), false);
+        let mut buf = [0u8; 2 * BLOCK_SIZE];
+
+        assert_eq!(emmc.read_blocks(0, &mut buf), Err(block::Error::NoMedia));
+
+        emmc.card = card(16);
+        assert_eq!(
+            emmc.read_blocks(15, &mut buf),
+            Err(block::Error::OutOfRange)
+        );
+        assert_eq!(
+            emmc.write_blocks(0, &buf[..100]),
+            Err(block::Error::BadBuffer)
+        );
+    }
+}
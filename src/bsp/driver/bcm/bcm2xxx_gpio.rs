@@ -0,0 +1,146 @@
+// Pin configuration shared by the BCM2837 and BCM2711 GPIO blocks
+
+/// Function select values, the alternate functions are not numbered in order
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+/// Replace the three function select bits of `pin` in a GPFSEL register value
+pub fn with_function(value: u32, pin: usize, function: Function) -> u32 {
+    let shift = (pin % 10) * 3;
+
+    (value & !(0b111 << shift)) | ((function as u32) << shift)
+}
+
+/// Write the level of each of the first `pins` pins as an ASCII '0' or '1', followed by a
+/// newline, starting `offset` bytes into that line. Stops early when `buf` is full, returns the
+/// number of bytes written, which is 0 once `offset` is past the newline.
+pub fn format_levels(
+    pins: usize,
+    level: impl Fn(usize) -> bool,
+    offset: u64,
+    buf: &mut [u8],
+) -> usize {
+    let line = (0..pins)
+        .map(|pin| if level(pin) { b'1' } else { b'0' })
+        .chain(Some(b'\n'))
+        .skip(offset as usize);
+
+    let mut written = 0;
+    for (byte, c) in buf.iter_mut().zip(line) {
+        *byte = c;
+        written += 1;
+    }
+
+    written
+}
+
+/// Hand every `<pin>=<0|1>` line in `buf` to `drive`. Stops before the first line that names a
+/// pin in `claimed`, returns the number of bytes taken up to there.
+pub fn apply_assignments(
+    buf: &[u8],
+    pins: usize,
+    claimed: u64,
+    mut drive: impl FnMut(usize, bool),
+) -> usize {
+    let mut taken = 0;
+    for line in buf.split(|&byte| byte == b'\n') {
+        if let Some((pin, high)) = parse_assignment(line, pins) {
+            if claimed & (1 << pin) != 0 {
+                return taken;
+            }
+            drive(pin, high);
+        }
+        taken += line.len() + 1;
+    }
+
+    buf.len()
+}
+
+/// Parse one `<pin>=<0|1>` line, surrounding whitespace is ignored
+fn parse_assignment(line: &[u8], pins: usize) -> Option<(usize, bool)> {
+    let line = core::str::from_utf8(line).ok()?.trim();
+    let mut parts = line.splitn(2, '=');
+
+    let pin = parts.next()?.trim().parse::<usize>().ok()?;
+    let high = match parts.next()?.trim() {
+        "0" => false,
+        "1" => true,
+        _ => return None,
+    };
+
+    if pin < pins {
+        Some((pin, high))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_printed_as_digits() {
+        let mut buf = [0; 8];
+
+        assert_eq!(format_levels(4, |pin| pin % 2 == 1, 0, &mut buf), 5);
+        assert_eq!(&buf[..5], b"0101\n");
+
+        assert_eq!(format_levels(4, |_| true, 0, &mut buf[..2]), 2);
+        assert_eq!(&buf[..2], b"11");
+    }
+
+    #[test]
+    fn levels_continue_from_the_offset_until_the_end() {
+        let mut buf = [0; 8];
+
+        assert_eq!(format_levels(4, |pin| pin % 2 == 1, 2, &mut buf), 3);
+        assert_eq!(&buf[..3], b"01\n");
+        assert_eq!(format_levels(4, |_| true, 5, &mut buf), 0);
+    }
+
+    #[test]
+    fn claimed_pins_are_not_driven() {
+        let mut driven = Vec::new();
+        let buf = b"4=1\n14=0\n5=1\n";
+
+        assert_eq!(
+            apply_assignments(buf, 54, 1 << 14, |pin, high| driven.push((pin, high))),
+            4
+        );
+        assert_eq!(driven, [(4, true)]);
+
+        driven.clear();
+        assert_eq!(
+            apply_assignments(b"4=1\n5=0", 54, 0, |pin, high| driven.push((pin, high))),
+            7
+        );
+        assert_eq!(driven, [(4, true), (5, false)]);
+    }
+
+    #[test]
+    fn assignments_are_parsed() {
+        assert_eq!(parse_assignment(b"17=1", 54), Some((17, true)));
+        assert_eq!(parse_assignment(b" 4 = 0\r", 54), Some((4, false)));
+        assert_eq!(parse_assignment(b"54=1", 54), None);
+        assert_eq!(parse_assignment(b"4=2", 54), None);
+        assert_eq!(parse_assignment(b"4", 54), None);
+    }
+}
@@ -162,3 +162,77 @@ impl interface::driver::DeviceDriver for Mbox {
         "BCM2XXX Mailbox"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const READ0: usize = 0x00;
+    const STATUS: usize = 0x18;
+    const WRITE0: usize = 0x20;
+
+    const FULL: u32 = 1 << 31;
+    const EMPTY: u32 = 1 << 30;
+
+    /// Play the VideoCore: drain the mailbox after a couple of polls, then answer whatever was
+    /// written by posting it back on the read side.
+    fn firmware(fake: &FakeRegisterFile) {
+        fake.write(STATUS, FULL | EMPTY);
+
+        fake.on_poll(|regs, polls| {
+            if polls == 2 {
+                regs.clear_bits(STATUS, FULL);
+            }
+
+            let request = regs.read(WRITE0);
+            if request != 0 {
+                regs.write(WRITE0, 0);
+                regs.write(READ0, request);
+                regs.clear_bits(STATUS, EMPTY);
+            }
+        });
+    }
+
+    #[test]
+    fn call_succeeds_on_success_response() {
+        let fake = FakeRegisterFile::new(0x30);
+        let mbox = MboxInner::new(fake.base_addr());
+        firmware(&fake);
+
+        let mut mail = Mail::new();
+        mail.buffer[1] = Response::Success as u32;
+
+        assert!(mbox.call(&mut mail, Channel::ArmToVCProperty).is_ok());
+    }
+
+    #[test]
+    fn call_reports_response_error() {
+        let fake = FakeRegisterFile::new(0x30);
+        let mbox = MboxInner::new(fake.base_addr());
+        firmware(&fake);
+
+        let mut mail = Mail::new();
+        mail.buffer[1] = Response::Error as u32;
+
+        match mbox.call(&mut mail, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn call_tags_the_buffer_address_with_the_channel() {
+        let fake = FakeRegisterFile::new(0x30);
+        let mbox = MboxInner::new(fake.base_addr());
+        firmware(&fake);
+
+        let mut mail = Mail::new();
+        mail.buffer[1] = Response::Success as u32;
+        let buf_ptr = mail.buffer.as_ptr() as u32;
+
+        mbox.call(&mut mail, Channel::ArmToVCProperty).unwrap();
+
+        assert_eq!(fake.read(READ0), buf_ptr | Channel::ArmToVCProperty as u32);
+    }
+}
@@ -109,6 +109,7 @@ pub enum Tag {
     End = 0,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u32)]
 pub enum Power {
     SDCard = 0,
@@ -122,6 +123,62 @@ pub enum Power {
     CCP2TX = 8,
 }
 
+impl Power {
+    /// Every domain the firmware knows about, in id order
+    pub const ALL: [Power; 9] = [
+        Power::SDCard,
+        Power::Uart0,
+        Power::Uart1,
+        Power::USBHCD,
+        Power::I2C0,
+        Power::I2C1,
+        Power::I2C2,
+        Power::SPI,
+        Power::CCP2TX,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Power::SDCard => "SD card",
+            Power::Uart0 => "UART0",
+            Power::Uart1 => "UART1",
+            Power::USBHCD => "USB HCD",
+            Power::I2C0 => "I2C0",
+            Power::I2C1 => "I2C1",
+            Power::I2C2 => "I2C2",
+            Power::SPI => "SPI",
+            Power::CCP2TX => "CCP2TX",
+        }
+    }
+}
+
+/// State word of the power tags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerState {
+    pub on: bool,
+    /// Set in responses for a domain this board doesn't have
+    pub missing: bool,
+}
+
+impl PowerState {
+    const ON: u32 = 1 << 0;
+    /// In requests, wait for the device to be stable before answering
+    const WAIT: u32 = 1 << 1;
+    /// In responses, the device doesn't exist
+    const MISSING: u32 = 1 << 1;
+
+    fn request(on: bool, wait: bool) -> u32 {
+        (if on { Self::ON } else { 0 }) | (if wait { Self::WAIT } else { 0 })
+    }
+
+    fn from_response(state: u32) -> PowerState {
+        PowerState {
+            on: state & Self::ON != 0,
+            missing: state & Self::MISSING != 0,
+        }
+    }
+}
+
 #[repr(u32)]
 pub enum Clocks {
     _reserved = 0,
@@ -325,8 +382,41 @@ impl Mail {
     //TODO Get Clocks
     //pub fn get_clocks(&mut self) -> Result<> {}
 
-    //TODO
-    // pub fn get_command_line(&mut self)
+    /// Copy the kernel command line handed over by the firmware into `cmdline`, returning the
+    /// number of bytes copied. The response has to fit in the mail buffer, so anything past the
+    /// first 120 bytes is cut off.
+    pub fn get_command_line(&mut self, cmdline: &mut [u8]) -> Result<usize> {
+        let capacity = (self.buffer.len() - 6) * 4;
+
+        self.buffer[0] = (self.buffer.len() as u32) * 4;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetCommandLine as u32;
+        self.buffer[3] = capacity as u32;
+        self.buffer[4] = 0;
+        for word in self.buffer[5..].iter_mut() {
+            *word = 0;
+        }
+        self.buffer[self.buffer.len() - 1] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match mailbox().call(self, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                // The response length has bit 31 set, the firmware reports the full length even
+                // when it did not fit
+                let length = (self.buffer[4] & !(1 << 31)) as usize;
+                let length = length.min(capacity).min(cmdline.len());
+
+                for (i, byte) in cmdline[..length].iter_mut().enumerate() {
+                    *byte = (self.buffer[5 + i / 4] >> ((i % 4) * 8)) as u8;
+                }
+
+                Ok(length)
+            }
+        }
+    }
 
     pub fn get_dma_channels(&mut self) -> Result<u32> {
         self.buffer[0] = 7 * 4;
@@ -349,8 +439,7 @@ impl Mail {
         }
     }
 
-    // TODO this should maybe return its own struct instead of a tuple
-    pub fn get_power_state(&mut self, device: Power) -> Result<(u32, u32)> {
+    pub fn get_power_state(&mut self, device: Power) -> Result<PowerState> {
         self.buffer[0] = 8 * 4;
         self.buffer[1] = Request::Request as u32;
         self.buffer[2] = Tag::GetPowerState as u32;
@@ -366,20 +455,21 @@ impl Mail {
             Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
             Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
             Ok(()) => {
-                let result: (u32, u32) = (self.buffer[5], self.buffer[6]);
+                let result = PowerState::from_response(self.buffer[6]);
                 Ok(result)
             }
         }
     }
-    // TODO This should have a state that is it's own type probably
-    pub fn get_timing(&mut self, device: Power, state: u32) -> Result<(u32, u32)> {
+
+    /// Microseconds `device` needs after being switched on before it is usable
+    pub fn get_timing(&mut self, device: Power) -> Result<u32> {
         self.buffer[0] = 8 * 4;
         self.buffer[1] = Request::Request as u32;
         self.buffer[2] = Tag::GetTiming as u32;
         self.buffer[3] = 8;
         self.buffer[4] = 0;
         self.buffer[5] = device as u32;
-        self.buffer[6] = state;
+        self.buffer[6] = 0;
         self.buffer[7] = Tag::End as u32;
 
         compiler_fence(Ordering::Release);
@@ -388,22 +478,22 @@ impl Mail {
             Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
             Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
             Ok(()) => {
-                let result: (u32, u32) = (self.buffer[5], self.buffer[6]);
+                let result: u32 = self.buffer[6];
                 Ok(result)
             }
         }
     }
 
-    // TODO this should maybe return its own struct instead of a tuple
-    // TODO This should have a state that is it's own type probably
-    pub fn set_power_state(&mut self, device: Power, state: u32) -> Result<(u32, u32)> {
+    /// Switch `device` on or off. With `wait` set the firmware only answers once the device is
+    /// stable, otherwise the caller has to give it `get_timing()` microseconds.
+    pub fn set_power_state(&mut self, device: Power, on: bool, wait: bool) -> Result<PowerState> {
         self.buffer[0] = 8 * 4;
         self.buffer[1] = Request::Request as u32;
         self.buffer[2] = Tag::SetPowerState as u32;
         self.buffer[3] = 8;
         self.buffer[4] = 0;
         self.buffer[5] = device as u32;
-        self.buffer[6] = state;
+        self.buffer[6] = PowerState::request(on, wait);
         self.buffer[7] = Tag::End as u32;
 
         compiler_fence(Ordering::Release);
@@ -412,7 +502,7 @@ impl Mail {
             Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
             Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
             Ok(()) => {
-                let result: (u32, u32) = (self.buffer[5], self.buffer[6]);
+                let result = PowerState::from_response(self.buffer[6]);
                 Ok(result)
             }
         }
@@ -518,5 +608,97 @@ impl Mail {
         }
     }
 
+    /// Highest rate `clock` may be set to, in Hz
+    pub fn get_max_clock_rate(&mut self, clock: Clocks) -> Result<u32> {
+        self.buffer[0] = 8 * 4;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetMaxClockRate as u32;
+        self.buffer[3] = 8;
+        self.buffer[4] = 0;
+        self.buffer[5] = clock as u32;
+        self.buffer[6] = 0;
+        self.buffer[7] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match mailbox().call(self, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                let result: u32 = self.buffer[6];
+                Ok(result)
+            }
+        }
+    }
+
+    /// Lowest rate `clock` may be set to, in Hz
+    pub fn get_min_clock_rate(&mut self, clock: Clocks) -> Result<u32> {
+        self.buffer[0] = 8 * 4;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetMinClockRate as u32;
+        self.buffer[3] = 8;
+        self.buffer[4] = 0;
+        self.buffer[5] = clock as u32;
+        self.buffer[6] = 0;
+        self.buffer[7] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match mailbox().call(self, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                let result: u32 = self.buffer[6];
+                Ok(result)
+            }
+        }
+    }
+
+    /// SoC temperature in thousandths of a degree Celsius. `id` is 0, there is only one sensor
+    pub fn get_temperature(&mut self, id: u32) -> Result<u32> {
+        self.buffer[0] = 8 * 4;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetTemperature as u32;
+        self.buffer[3] = 8;
+        self.buffer[4] = 0;
+        self.buffer[5] = id;
+        self.buffer[6] = 0;
+        self.buffer[7] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match mailbox().call(self, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                let result: u32 = self.buffer[6];
+                Ok(result)
+            }
+        }
+    }
+
+    /// Temperature at which the firmware starts throttling, in thousandths of a degree Celsius
+    pub fn get_max_temperature(&mut self, id: u32) -> Result<u32> {
+        self.buffer[0] = 8 * 4;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetMaxTemperature as u32;
+        self.buffer[3] = 8;
+        self.buffer[4] = 0;
+        self.buffer[5] = id;
+        self.buffer[6] = 0;
+        self.buffer[7] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match mailbox().call(self, Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                let result: u32 = self.buffer[6];
+                Ok(result)
+            }
+        }
+    }
+
     // TODO All of the other functionality of the mailbox is yet to be implemented.
 }
@@ -1,4 +1,5 @@
-use crate::{arch, arch::sync::NullLock, interface};
+use super::{Power, PowerHandle};
+use crate::{arch, arch::sync::NullLock, bsp, interface};
 use core::fmt;
 use core::ops;
 use register::{mmio::*, register_bitfields};
@@ -200,6 +201,8 @@ pub struct RegisterBlock {
 struct MiniUartInner {
     base_addr: usize,
     chars_written: usize,
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
 }
 
 impl ops::Deref for MiniUartInner {
@@ -215,6 +218,7 @@ impl MiniUartInner {
         MiniUartInner {
             base_addr,
             chars_written: 0,
+            power: None,
         }
     }
 
@@ -254,12 +258,18 @@ impl MiniUartInner {
 
     fn read_char(&self) -> char {
         loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
-                break;
+            if let Some(c) = self.try_read_char() {
+                return c;
             }
 
             arch::nop();
         }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        if !self.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
+            return None;
+        }
 
         let mut ret = self.AUX_MU_IO.get() as u8 as char;
 
@@ -267,7 +277,7 @@ impl MiniUartInner {
             ret = '\n'
         }
 
-        ret
+        Some(ret)
     }
 }
 
@@ -310,8 +320,21 @@ impl interface::driver::DeviceDriver for MiniUart {
     }
 
     fn init(&self) -> interface::driver::Result {
+        let power = bsp::power().acquire(Power::Uart1).ok();
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.power = power;
+            inner.init()
+        })
+    }
+
+    fn shutdown(&self) -> interface::driver::Result {
         let mut r = &self.inner;
-        r.lock(|inner| inner.init())
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
     }
 }
 
@@ -332,6 +355,36 @@ impl interface::console::Read for MiniUart {
         let mut r = &self.inner;
         r.lock(|inner| inner.read_char())
     }
+
+    fn try_read_char(&self) -> Option<char> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.try_read_char())
+    }
+}
+
+impl interface::driver::CharDevice for MiniUart {
+    /// Blocks until one character arrives, reads hand back at most one byte
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        let mut r = &self.inner;
+        buf[0] = r.lock(|inner| inner.read_char()) as u8;
+
+        1
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for &byte in buf {
+                inner.write_char(byte as char);
+            }
+        });
+
+        buf.len()
+    }
 }
 
 impl interface::console::Statistics for MiniUart {
@@ -340,3 +393,71 @@ impl interface::console::Statistics for MiniUart {
         r.lock(|inner| inner.chars_written)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const AUX_MU_IO: usize = 0x00;
+    const AUX_MU_LSR: usize = 0x14;
+    const AUX_MU_CNTL: usize = 0x20;
+    const AUX_MU_BAUD: usize = 0x28;
+
+    const DATA_READY: u32 = 1 << 0;
+    const TX_EMPTY: u32 = 1 << 5;
+
+    #[test]
+    fn init_sets_115200_baud_and_enables() {
+        let fake = FakeRegisterFile::new(0x2C);
+        let uart = MiniUartInner::new(fake.base_addr());
+
+        uart.init().unwrap();
+
+        assert_eq!(fake.read(AUX_MU_BAUD), 270);
+        assert_eq!(fake.read(AUX_MU_CNTL), 0b11);
+    }
+
+    #[test]
+    fn read_char_waits_for_data() {
+        let fake = FakeRegisterFile::new(0x2C);
+        let uart = MiniUartInner::new(fake.base_addr());
+
+        fake.on_poll(|regs, polls| {
+            if polls == 4 {
+                regs.write(AUX_MU_IO, '\r' as u32);
+                regs.set_bits(AUX_MU_LSR, DATA_READY);
+            }
+        });
+
+        assert_eq!(uart.read_char(), '\n');
+    }
+
+    #[test]
+    fn try_read_char_does_not_wait() {
+        let fake = FakeRegisterFile::new(0x2C);
+        let uart = MiniUartInner::new(fake.base_addr());
+
+        assert_eq!(uart.try_read_char(), None);
+
+        fake.write(AUX_MU_IO, 'q' as u32);
+        fake.write(AUX_MU_LSR, DATA_READY);
+        assert_eq!(uart.try_read_char(), Some('q'));
+    }
+
+    #[test]
+    fn write_char_waits_for_transmitter() {
+        let fake = FakeRegisterFile::new(0x2C);
+        let mut uart = MiniUartInner::new(fake.base_addr());
+
+        fake.on_poll(|regs, polls| {
+            if polls == 2 {
+                regs.set_bits(AUX_MU_LSR, TX_EMPTY);
+            }
+        });
+
+        uart.write_char('y');
+
+        assert_eq!(fake.read(AUX_MU_IO), 'y' as u32);
+    }
+}
@@ -0,0 +1,202 @@
+use super::{Mail, Power, PowerState};
+use crate::{arch::sync::NullLock, bsp, interface};
+
+const DOMAINS: usize = Power::ALL.len();
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum PowerError {
+    Mailbox,
+    /// The board doesn't have this domain
+    Missing,
+    /// Still off after waiting as long as the firmware said it takes
+    Timeout,
+    /// Released more often than it was acquired
+    NotAcquired,
+}
+type Result<T> = ::core::result::Result<T, PowerError>;
+
+/// What the firmware and the manager know about a domain
+#[derive(Clone, Copy, Debug)]
+pub struct DomainStatus {
+    pub domain: Power,
+    /// Drivers currently holding the domain
+    pub users: u16,
+    /// As reported by the firmware, `None` if it couldn't be asked
+    pub state: Option<PowerState>,
+}
+
+/// A reference on a domain, handed out by a successful `PowerManager::acquire` and given back
+/// to `release`. Drivers keep it in an `Option`, so shutdown only releases what init got. Most
+/// carry on without one, the firmware usually leaves their domain on anyway.
+#[derive(Debug)]
+pub struct PowerHandle {
+    domain: Power,
+}
+
+struct PowerManagerInner {
+    users: [u16; DOMAINS],
+}
+
+impl PowerManagerInner {
+    const fn new() -> PowerManagerInner {
+        PowerManagerInner {
+            users: [0; DOMAINS],
+        }
+    }
+
+    /// Count a new user, returns true if it is the first and the domain has to be switched on
+    fn acquire(&mut self, domain: Power) -> bool {
+        let users = &mut self.users[domain as usize];
+        *users += 1;
+
+        *users == 1
+    }
+
+    /// Drop a user, returns true if it was the last and the domain can be switched off
+    fn release(&mut self, domain: Power) -> Result<bool> {
+        let users = &mut self.users[domain as usize];
+        if *users == 0 {
+            return Err(PowerError::NotAcquired);
+        }
+        *users -= 1;
+
+        Ok(*users == 0)
+    }
+}
+
+fn power_on(domain: Power) -> Result<()> {
+    let mut mail = Mail::new();
+
+    let state = mail
+        .set_power_state(domain, true, true)
+        .map_err(|_| PowerError::Mailbox)?;
+    if state.missing {
+        return Err(PowerError::Missing);
+    }
+    if state.on {
+        return Ok(());
+    }
+
+    // The firmware answered before the domain came up, give it the time it says it needs
+    let delay = mail.get_timing(domain).map_err(|_| PowerError::Mailbox)?;
+    bsp::wait_usec(delay as u64);
+
+    match mail.get_power_state(domain) {
+        Ok(state) if state.on => Ok(()),
+        Ok(_) => Err(PowerError::Timeout),
+        Err(_) => Err(PowerError::Mailbox),
+    }
+}
+
+fn power_off(domain: Power) -> Result<()> {
+    let mut mail = Mail::new();
+
+    match mail.set_power_state(domain, false, true) {
+        Ok(state) if state.missing => Err(PowerError::Missing),
+        Ok(_) => Ok(()),
+        Err(_) => Err(PowerError::Mailbox),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// Reference counts the firmware's power domains, so a domain shared by several drivers stays on
+/// until the last of them lets go
+pub struct PowerManager {
+    inner: NullLock<PowerManagerInner>,
+}
+
+impl PowerManager {
+    pub const fn new() -> PowerManager {
+        PowerManager {
+            inner: NullLock::new(PowerManagerInner::new()),
+        }
+    }
+
+    /// Take a reference on `domain`, switching it on if nobody held it yet
+    pub fn acquire(&self, domain: Power) -> Result<PowerHandle> {
+        let mut r = &self.inner;
+        if !r.lock(|inner| inner.acquire(domain)) {
+            return Ok(PowerHandle { domain });
+        }
+
+        if let Err(e) = power_on(domain) {
+            crate::warn!("Powering on {} failed: {:?}", domain.name(), e);
+            r.lock(|inner| inner.users[domain as usize] -= 1);
+            return Err(e);
+        }
+
+        Ok(PowerHandle { domain })
+    }
+
+    /// Give back the reference `handle` stands for, switching the domain off once nobody holds it
+    pub fn release(&self, handle: PowerHandle) -> Result<()> {
+        let domain = handle.domain;
+
+        let mut r = &self.inner;
+        if r.lock(|inner| inner.release(domain))? {
+            power_off(domain)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn users(&self, domain: Power) -> u16 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.users[domain as usize])
+    }
+
+    pub fn status(&self, domain: Power) -> DomainStatus {
+        let mut mail = Mail::new();
+
+        DomainStatus {
+            domain,
+            users: self.users(domain),
+            state: mail.get_power_state(domain).ok(),
+        }
+    }
+
+    /// Call `f` with the status of every domain
+    pub fn report(&self, f: &mut dyn FnMut(&DomainStatus)) {
+        for &domain in Power::ALL.iter() {
+            f(&self.status(domain));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_user_powers_on() {
+        let mut power = PowerManagerInner::new();
+
+        assert!(power.acquire(Power::I2C1));
+        assert!(!power.acquire(Power::I2C1));
+        assert!(power.acquire(Power::SPI));
+        assert_eq!(power.users[Power::I2C1 as usize], 2);
+    }
+
+    #[test]
+    fn only_the_last_user_powers_off() {
+        let mut power = PowerManagerInner::new();
+        power.acquire(Power::SDCard);
+        power.acquire(Power::SDCard);
+
+        assert_eq!(power.release(Power::SDCard), Ok(false));
+        assert_eq!(power.release(Power::SDCard), Ok(true));
+    }
+
+    #[test]
+    fn releasing_an_unused_domain_fails() {
+        let mut power = PowerManagerInner::new();
+
+        assert_eq!(power.release(Power::USBHCD), Err(PowerError::NotAcquired));
+        assert_eq!(power.users[Power::USBHCD as usize], 0);
+    }
+}
@@ -0,0 +1,501 @@
+use super::{Clocks, Mail};
+use crate::{arch::sync::NullLock, interface};
+use register::{mmio::*, register_bitfields};
+
+register_bitfields! {
+    u32,
+
+    TSENSCTL [
+        /// Enable the sensor's voltage regulator
+        REGULEN OFFSET(26) NUMBITS(1) [],
+        /// Cycles the sensor is held in reset between conversions
+        RSTDELAY OFFSET(18) NUMBITS(8) [],
+        /// Reading at which INTERRUPT is raised
+        THOLD OFFSET(8) NUMBITS(10) [],
+        CLR_INT OFFSET(7) NUMBITS(1) [],
+        DIRECT OFFSET(6) NUMBITS(1) [],
+        EN_INT OFFSET(5) NUMBITS(1) [],
+        CTRL OFFSET(2) NUMBITS(3) [],
+        /// Release the sensor from reset, it converts continuously from then on
+        RSTB OFFSET(1) NUMBITS(1) [],
+        PRWDW OFFSET(0) NUMBITS(1) []
+    ],
+
+    TSENSSTAT [
+        INTERRUPT OFFSET(11) NUMBITS(1) [],
+        VALID OFFSET(10) NUMBITS(1) [],
+        DATA OFFSET(0) NUMBITS(10) []
+    ],
+
+    AVS_RO_TEMP_STATUS [
+        /// Both valid bits have to be set for DATA to mean anything
+        VALID OFFSET(16) NUMBITS(1) [],
+        DATA_VALID OFFSET(10) NUMBITS(1) [],
+        DATA OFFSET(0) NUMBITS(10) []
+    ]
+}
+
+/// Value the firmware and Linux program into CTRL, the field is otherwise undocumented
+const TSENSCTL_CTRL_DEFAULT: u32 = 1;
+const TSENSCTL_RSTDELAY_DEFAULT: u32 = 0xFE;
+
+/// Number of trip points a sensor can watch
+pub const MAX_TRIPS: usize = 4;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct TsensRegisterBlock {
+    TSENSCTL: ReadWrite<u32, TSENSCTL::Register>,  // 0x00
+    TSENSSTAT: ReadOnly<u32, TSENSSTAT::Register>, // 0x04
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct AvsRegisterBlock {
+    __reserved0: [u32; 128],                                     // 0x000
+    RO_TEMP_STATUS: ReadOnly<u32, AVS_RO_TEMP_STATUS::Register>, // 0x200
+}
+
+/// Which of the two sensor blocks the SoC has
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sensor {
+    /// TSENS block of the BCM2835/6/7
+    Tsens,
+    /// Ring oscillator in the BCM2711 AVS monitor, the TSENS block is gone on that chip
+    Avs,
+}
+
+impl Sensor {
+    /// Temperature in thousandths of a degree is `offset + slope * reading`
+    fn coefficients(self) -> (i32, i32) {
+        match self {
+            Sensor::Tsens => (412_000, -538),
+            Sensor::Avs => (410_040, -487),
+        }
+    }
+}
+
+fn to_millicelsius(sensor: Sensor, raw: u32) -> i32 {
+    let (offset, slope) = sensor.coefficients();
+
+    offset + slope * raw as i32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TripKind {
+    /// Only raises an event
+    Active,
+    /// Drops the ARM clock to its minimum while tripped
+    Passive,
+    /// Raises an event, the handler is expected to shut the board down
+    Critical,
+}
+
+/// Temperatures are in thousandths of a degree Celsius. A trip clears once the temperature falls
+/// `hysteresis` below `temperature`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TripPoint {
+    pub kind: TripKind,
+    pub temperature: i32,
+    pub hysteresis: i32,
+}
+
+impl TripPoint {
+    pub const fn new(kind: TripKind, temperature: i32, hysteresis: i32) -> TripPoint {
+        TripPoint {
+            kind,
+            temperature,
+            hysteresis,
+        }
+    }
+}
+
+/// Handed to the handler when a trip point is crossed, `trip` is its index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThermalEvent {
+    Tripped {
+        trip: usize,
+        kind: TripKind,
+        temperature: i32,
+    },
+    Cleared {
+        trip: usize,
+        kind: TripKind,
+        temperature: i32,
+    },
+}
+
+/// Throttle at the firmware's default soft limit, before it starts throttling on its own
+const DEFAULT_TRIPS: [Option<TripPoint>; MAX_TRIPS] = [
+    Some(TripPoint::new(TripKind::Passive, 80_000, 5_000)),
+    Some(TripPoint::new(TripKind::Critical, 85_000, 2_000)),
+    None,
+    None,
+];
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum ThermalError {
+    /// The sensor has not finished a conversion
+    NotReady,
+    Mailbox,
+    InvalidTrip,
+}
+type Result<T> = ::core::result::Result<T, ThermalError>;
+
+struct ThermalInner {
+    base_addr: usize,
+    sensor: Sensor,
+    trips: [Option<TripPoint>; MAX_TRIPS],
+    tripped: [bool; MAX_TRIPS],
+    handler: Option<fn(ThermalEvent)>,
+    /// ARM clock rate to go back to once no passive trip is tripped
+    throttled_from: Option<u32>,
+}
+
+impl ThermalInner {
+    const fn new(base_addr: usize, sensor: Sensor) -> ThermalInner {
+        ThermalInner {
+            base_addr,
+            sensor,
+            trips: DEFAULT_TRIPS,
+            tripped: [false; MAX_TRIPS],
+            handler: None,
+            throttled_from: None,
+        }
+    }
+
+    fn tsens(&self) -> &TsensRegisterBlock {
+        unsafe { &*(self.base_addr as *const _) }
+    }
+
+    fn avs(&self) -> &AvsRegisterBlock {
+        unsafe { &*(self.base_addr as *const _) }
+    }
+
+    fn init(&self) {
+        // The firmware normally has the sensor running already
+        if self.sensor != Sensor::Tsens || self.tsens().TSENSCTL.is_set(TSENSCTL::RSTB) {
+            return;
+        }
+
+        let regs = self.tsens();
+        regs.TSENSCTL.write(
+            TSENSCTL::CTRL.val(TSENSCTL_CTRL_DEFAULT)
+                + TSENSCTL::REGULEN::SET
+                + TSENSCTL::RSTDELAY.val(TSENSCTL_RSTDELAY_DEFAULT),
+        );
+        regs.TSENSCTL.modify(TSENSCTL::RSTB::SET);
+    }
+
+    fn read_sensor(&self) -> Result<i32> {
+        let raw = match self.sensor {
+            Sensor::Tsens => {
+                let stat = self.tsens().TSENSSTAT.extract();
+                if !stat.is_set(TSENSSTAT::VALID) {
+                    return Err(ThermalError::NotReady);
+                }
+                stat.read(TSENSSTAT::DATA)
+            }
+            Sensor::Avs => {
+                let stat = self.avs().RO_TEMP_STATUS.extract();
+                if !stat.is_set(AVS_RO_TEMP_STATUS::VALID)
+                    || !stat.is_set(AVS_RO_TEMP_STATUS::DATA_VALID)
+                {
+                    return Err(ThermalError::NotReady);
+                }
+                stat.read(AVS_RO_TEMP_STATUS::DATA)
+            }
+        };
+
+        Ok(to_millicelsius(self.sensor, raw))
+    }
+
+    fn set_trip(&mut self, index: usize, trip: Option<TripPoint>) -> Result<()> {
+        if index >= MAX_TRIPS {
+            return Err(ThermalError::InvalidTrip);
+        }
+
+        self.trips[index] = trip;
+        self.tripped[index] = false;
+
+        Ok(())
+    }
+
+    /// Compare `temperature` against every trip point, returning the ones that changed state
+    fn update(&mut self, temperature: i32) -> [Option<ThermalEvent>; MAX_TRIPS] {
+        let mut events = [None; MAX_TRIPS];
+
+        for (i, trip) in self.trips.iter().enumerate() {
+            let trip = match trip {
+                Some(trip) => trip,
+                None => continue,
+            };
+
+            if !self.tripped[i] && temperature >= trip.temperature {
+                self.tripped[i] = true;
+                events[i] = Some(ThermalEvent::Tripped {
+                    trip: i,
+                    kind: trip.kind,
+                    temperature,
+                });
+            } else if self.tripped[i] && temperature < trip.temperature - trip.hysteresis {
+                self.tripped[i] = false;
+                events[i] = Some(ThermalEvent::Cleared {
+                    trip: i,
+                    kind: trip.kind,
+                    temperature,
+                });
+            }
+        }
+
+        events
+    }
+
+    fn wants_throttle(&self) -> bool {
+        self.trips
+            .iter()
+            .zip(self.tripped.iter())
+            .any(|(trip, &tripped)| tripped && trip.map(|t| t.kind) == Some(TripKind::Passive))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Thermal {
+    inner: NullLock<ThermalInner>,
+    sensor: Sensor,
+}
+
+impl Thermal {
+    pub const unsafe fn new(base_addr: usize, sensor: Sensor) -> Thermal {
+        Thermal {
+            inner: NullLock::new(ThermalInner::new(base_addr, sensor)),
+            sensor,
+        }
+    }
+
+    /// Temperature in thousandths of a degree Celsius, straight from the sensor
+    pub fn read_sensor(&self) -> Result<i32> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read_sensor())
+    }
+
+    /// Temperature as measured by the firmware
+    pub fn firmware_temperature(&self) -> Result<i32> {
+        let mut mail = Mail::new();
+        match mail.get_temperature(0) {
+            Ok(temperature) => Ok(temperature as i32),
+            Err(_) => Err(ThermalError::Mailbox),
+        }
+    }
+
+    /// Temperature at which the firmware throttles the board itself
+    pub fn firmware_limit(&self) -> Result<i32> {
+        let mut mail = Mail::new();
+        match mail.get_max_temperature(0) {
+            Ok(temperature) => Ok(temperature as i32),
+            Err(_) => Err(ThermalError::Mailbox),
+        }
+    }
+
+    /// Read the sensor, falling back to the firmware while it has no valid conversion
+    pub fn temperature(&self) -> Result<i32> {
+        match self.read_sensor() {
+            Err(ThermalError::NotReady) => self.firmware_temperature(),
+            result => result,
+        }
+    }
+
+    /// Replace trip point `index`, `None` removes it. The trip starts out cleared.
+    pub fn set_trip(&self, index: usize, trip: Option<TripPoint>) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_trip(index, trip))
+    }
+
+    /// Called from `poll()` every time a trip point is crossed
+    pub fn set_handler(&self, handler: fn(ThermalEvent)) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.handler = Some(handler));
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.throttled_from.is_some())
+    }
+
+    /// Check the temperature against the trip points, raise events for the ones crossed and
+    /// throttle or restore the ARM clock. Returns the temperature read.
+    pub fn poll(&self) -> Result<i32> {
+        let temperature = self.temperature()?;
+
+        let mut r = &self.inner;
+        let (events, handler, throttle) = r.lock(|inner| {
+            let events = inner.update(temperature);
+            (events, inner.handler, inner.wants_throttle())
+        });
+
+        if let Some(handler) = handler {
+            for event in events.iter().flatten() {
+                handler(*event);
+            }
+        }
+
+        self.throttle(throttle)?;
+
+        Ok(temperature)
+    }
+
+    fn throttle(&self, throttle: bool) -> Result<()> {
+        let mut r = &self.inner;
+        let throttled_from = r.lock(|inner| inner.throttled_from);
+
+        let mut mail = Mail::new();
+        match (throttle, throttled_from) {
+            (true, None) => {
+                let (_, rate) = mail
+                    .get_clock_rate(Clocks::ARM)
+                    .map_err(|_| ThermalError::Mailbox)?;
+                let min_rate = mail
+                    .get_min_clock_rate(Clocks::ARM)
+                    .map_err(|_| ThermalError::Mailbox)?;
+                mail.set_clock_rate(Clocks::ARM, min_rate, 0)
+                    .map_err(|_| ThermalError::Mailbox)?;
+
+                crate::warn!(
+                    "Overheating, ARM clock lowered from {} to {} MHz",
+                    rate / 1_000_000,
+                    min_rate / 1_000_000
+                );
+                r.lock(|inner| inner.throttled_from = Some(rate));
+            }
+            (false, Some(rate)) => {
+                mail.set_clock_rate(Clocks::ARM, rate, 0)
+                    .map_err(|_| ThermalError::Mailbox)?;
+
+                crate::info!("Cooled down, ARM clock back at {} MHz", rate / 1_000_000);
+                r.lock(|inner| inner.throttled_from = None);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl interface::driver::DeviceDriver for Thermal {
+    fn compatible(&self) -> &str {
+        match self.sensor {
+            Sensor::Tsens => "BCM2835 Thermal Sensor",
+            Sensor::Avs => "BCM2711 AVS Thermal Sensor",
+        }
+    }
+
+    fn init(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const TSENSCTL: usize = 0x00;
+    const TSENSSTAT: usize = 0x04;
+    const AVS_RO_TEMP_STATUS: usize = 0x200;
+
+    #[test]
+    fn init_starts_a_stopped_sensor() {
+        let fake = FakeRegisterFile::new(0x08);
+        let thermal = ThermalInner::new(fake.base_addr(), Sensor::Tsens);
+
+        thermal.init();
+
+        let ctl = fake.read(TSENSCTL);
+        assert_eq!(ctl & 0b10, 0b10);
+        assert_eq!(ctl & (1 << 26), 1 << 26);
+        assert_eq!((ctl >> 18) & 0xFF, TSENSCTL_RSTDELAY_DEFAULT);
+    }
+
+    #[test]
+    fn readings_are_converted_to_millicelsius() {
+        let fake = FakeRegisterFile::new(0x08);
+        let thermal = ThermalInner::new(fake.base_addr(), Sensor::Tsens);
+
+        assert_eq!(thermal.read_sensor(), Err(ThermalError::NotReady));
+
+        fake.write(TSENSSTAT, (1 << 10) | 600);
+        assert_eq!(thermal.read_sensor(), Ok(89_200));
+    }
+
+    #[test]
+    fn avs_needs_both_valid_bits() {
+        let fake = FakeRegisterFile::new(0x204);
+        let thermal = ThermalInner::new(fake.base_addr(), Sensor::Avs);
+
+        fake.write(AVS_RO_TEMP_STATUS, (1 << 16) | 700);
+        assert_eq!(thermal.read_sensor(), Err(ThermalError::NotReady));
+
+        fake.write(AVS_RO_TEMP_STATUS, (1 << 16) | (1 << 10) | 700);
+        assert_eq!(thermal.read_sensor(), Ok(69_140));
+    }
+
+    #[test]
+    fn trips_clear_below_the_hysteresis() {
+        let mut thermal = ThermalInner::new(0, Sensor::Tsens);
+
+        let events = thermal.update(81_000);
+        assert_eq!(
+            events[0],
+            Some(ThermalEvent::Tripped {
+                trip: 0,
+                kind: TripKind::Passive,
+                temperature: 81_000
+            })
+        );
+        assert_eq!(events[1], None);
+        assert!(thermal.wants_throttle());
+
+        // Still within the hysteresis, nothing changes
+        assert_eq!(thermal.update(76_000), [None; MAX_TRIPS]);
+        assert!(thermal.wants_throttle());
+
+        let events = thermal.update(74_000);
+        assert_eq!(
+            events[0],
+            Some(ThermalEvent::Cleared {
+                trip: 0,
+                kind: TripKind::Passive,
+                temperature: 74_000
+            })
+        );
+        assert!(!thermal.wants_throttle());
+    }
+
+    #[test]
+    fn trips_are_configurable() {
+        let mut thermal = ThermalInner::new(0, Sensor::Tsens);
+
+        assert_eq!(
+            thermal.set_trip(MAX_TRIPS, None),
+            Err(ThermalError::InvalidTrip)
+        );
+
+        thermal.set_trip(0, None).unwrap();
+        thermal
+            .set_trip(2, Some(TripPoint::new(TripKind::Active, 60_000, 1_000)))
+            .unwrap();
+
+        let events = thermal.update(82_000);
+        assert_eq!(events[0], None);
+        assert!(events[2].is_some());
+        assert!(!thermal.wants_throttle());
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Clocks, Mail};
+use super::{Clocks, Mail, Power, PowerHandle};
 use crate::bsp;
 use crate::{arch, arch::sync::NullLock, interface};
 use core::fmt;
@@ -112,6 +112,8 @@ type Result<T> = ::core::result::Result<T, UartError>;
 struct UartInner {
     base_addr: usize,
     chars_written: usize,
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
 }
 
 impl ops::Deref for UartInner {
@@ -127,6 +129,7 @@ impl UartInner {
         UartInner {
             base_addr,
             chars_written: 0,
+            power: None,
         }
     }
 
@@ -139,8 +142,11 @@ impl UartInner {
 
         let mut mail = Mail::new();
 
-        if mail.set_clock_rate(Clocks::UART, clock_speed, 0).unwrap().1 != 4_000_000 {
-            arch::wait_forever();
+        // QEMU does not model the UART clock, whatever the mailbox reports back there is meaningless
+        match mail.set_clock_rate(Clocks::UART, clock_speed, 0) {
+            Ok((_, rate)) if rate == clock_speed => {}
+            _ if bsp::EMULATED => {}
+            _ => arch::wait_forever(),
         }
 
         self.ICR.write(ICR::ALL::CLEAR);
@@ -172,14 +178,20 @@ impl UartInner {
     fn read_char(&self) -> char {
         // wait until something is in the buffer
         loop {
-            if !self.FR.is_set(FR::RXFE) {
-                break;
+            if let Some(c) = self.try_read_char() {
+                return c;
             }
 
             arch::nop();
         }
+    }
+
+    /// Receive a character if one is in the buffer
+    fn try_read_char(&self) -> Option<char> {
+        if self.FR.is_set(FR::RXFE) {
+            return None;
+        }
 
-        // read it and return
         let mut ret = self.DR.get() as u8 as char;
 
         // convert carrige return to newline
@@ -187,18 +199,20 @@ impl UartInner {
             ret = '\n'
         }
 
-        ret
+        Some(ret)
     }
 }
 
 impl fmt::Write for UartInner {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Spelled out, on `&mut self` a plain `self.write_char` picks `fmt::Write::write_char`,
+        // which calls back into `write_str`
         for c in s.chars() {
             if c == '\n' {
-                self.write_char('\r');
+                UartInner::write_char(self, '\r');
             }
 
-            self.write_char(c);
+            UartInner::write_char(self, c);
         }
 
         self.chars_written += s.len();
@@ -230,8 +244,21 @@ impl interface::driver::DeviceDriver for Uart {
     }
 
     fn init(&self) -> interface::driver::Result {
+        let power = bsp::power().acquire(Power::Uart0).ok();
+
         let mut r = &self.inner;
-        r.lock(|inner| inner.init(4_000_000))
+        r.lock(|inner| {
+            inner.power = power;
+            inner.init(4_000_000)
+        })
+    }
+
+    fn shutdown(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
     }
 }
 
@@ -252,6 +279,36 @@ impl interface::console::Read for Uart {
         let mut r = &self.inner;
         r.lock(|inner| inner.read_char())
     }
+
+    fn try_read_char(&self) -> Option<char> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.try_read_char())
+    }
+}
+
+impl interface::driver::CharDevice for Uart {
+    /// Blocks until one character arrives, reads hand back at most one byte
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        let mut r = &self.inner;
+        buf[0] = r.lock(|inner| inner.read_char()) as u8;
+
+        1
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for &byte in buf {
+                inner.write_char(byte as char);
+            }
+        });
+
+        buf.len()
+    }
 }
 
 impl interface::console::Statistics for Uart {
@@ -260,3 +317,82 @@ impl interface::console::Statistics for Uart {
         r.lock(|inner| inner.chars_written)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const DR: usize = 0x00;
+    const FR: usize = 0x18;
+
+    const RXFE: u32 = 1 << 4;
+    const TXFF: u32 = 1 << 5;
+
+    #[test]
+    fn read_char_waits_for_data() {
+        let fake = FakeRegisterFile::new(0x48);
+        let uart = UartInner::new(fake.base_addr());
+
+        fake.write(FR, RXFE);
+        fake.on_poll(|regs, polls| {
+            if polls == 5 {
+                regs.write(DR, 'a' as u32);
+                regs.clear_bits(FR, RXFE);
+            }
+        });
+
+        assert_eq!(uart.read_char(), 'a');
+    }
+
+    #[test]
+    fn read_char_converts_carriage_return() {
+        let fake = FakeRegisterFile::new(0x48);
+        let uart = UartInner::new(fake.base_addr());
+
+        fake.write(DR, '\r' as u32);
+
+        assert_eq!(uart.read_char(), '\n');
+    }
+
+    #[test]
+    fn try_read_char_does_not_wait() {
+        let fake = FakeRegisterFile::new(0x48);
+        let uart = UartInner::new(fake.base_addr());
+
+        fake.write(FR, RXFE);
+        assert_eq!(uart.try_read_char(), None);
+
+        fake.write(DR, 'b' as u32);
+        fake.write(FR, 0);
+        assert_eq!(uart.try_read_char(), Some('b'));
+    }
+
+    #[test]
+    fn write_char_waits_for_fifo_space() {
+        let fake = FakeRegisterFile::new(0x48);
+        let uart = UartInner::new(fake.base_addr());
+
+        fake.write(FR, TXFF);
+        fake.on_poll(|regs, polls| {
+            if polls == 3 {
+                regs.clear_bits(FR, TXFF);
+            }
+        });
+
+        uart.write_char('x');
+
+        assert_eq!(fake.read(DR), 'x' as u32);
+    }
+
+    #[test]
+    fn write_str_counts_characters() {
+        let fake = FakeRegisterFile::new(0x48);
+        let mut uart = UartInner::new(fake.base_addr());
+
+        fmt::Write::write_str(&mut uart, "hi\n").unwrap();
+
+        assert_eq!(uart.chars_written, 3);
+        assert_eq!(fake.read(DR), '\n' as u32);
+    }
+}
@@ -1,15 +1,49 @@
-use crate::{arch, arch::sync::NullLock, interface};
+use super::{Power, PowerHandle};
+use crate::{arch, arch::sync::NullLock, bsp, interface};
 use core::ops;
-use register::{mmio::*, register_bitfields};
+use core::sync::atomic::{compiler_fence, Ordering};
+use interface::usb::{self, Direction, Endpoint, SetupPacket, Speed, TransferType};
+use register::{mmio::*, register_bitfields, FieldValue, LocalRegisterCopy};
 
+// Requested FIFO sizes in 32-bit words, see `fifo_layout` for when the core has less FIFO RAM
 const RECEIVE_FIFO_SIZE: u32 = 20480;
 const NON_PERIDOIC_FIFO_SIZE: u32 = 20480;
 const PERIODIC_FIFO_SIZE: u32 = 20480;
 const CONTROL_MESSAGE_TIMEOUT: u32 = 10;
 const HOSTPORTMASK: u32 = !0x2E;
-const DWC_NUM_CHANNELS: u32 = 8;
+const DWC_NUM_CHANNELS: usize = 8;
 const USB2_MAX_PACKET_SIZE: u32 = 1024;
 
+/// GSNPSID reads "OT" followed by the core revision
+const DWC_VENDOR_ID: u32 = 0x4F54_0000;
+const DWC_VENDOR_ID_MASK: u32 = 0xFFFF_0000;
+
+const RESET_TIMEOUT_US: usize = 100_000;
+/// The PHY clock needs time to come back after a core soft reset
+const RESET_SETTLE_US: usize = 100_000;
+/// Switching to host mode takes up to 25ms, give it some slack
+const MODE_SWITCH_TIMEOUT_US: usize = 100_000;
+/// USB 2.0 7.1.7.5, root ports are held in reset for at least 50ms
+const PORT_RESET_US: usize = 50_000;
+/// Reset recovery time before the device has to answer, 10ms by the spec
+const PORT_RECOVERY_US: usize = 20_000;
+const PORT_ENABLE_TIMEOUT_US: usize = 100_000;
+
+/// Write-1-to-clear change bits of the host port register
+const HOST_PORT_CHANGES: u32 = 0x2A;
+
+/// A channel that hasn't halted by then is stopped and the transfer given up
+const TRANSFER_TIMEOUT_US: usize = 100_000;
+/// Limits on the tries counted in `USB_SEND_CONTROL`: transmission errors, NYETs to a complete
+/// split, and NAKs or restarted splits
+const MAX_PACKET_TRIES: u32 = 3;
+const MAX_SPLIT_TRIES: u32 = 10;
+const MAX_GLOBAL_TRIES: u32 = 100;
+/// Retry a NAKed transaction a frame later
+const LONGER_DELAY_US: usize = 1_000;
+/// Retry a complete split the next microframe
+const SPLIT_DELAY_US: usize = 125;
+
 register_bitfields! {
     u32,
 
@@ -96,8 +130,8 @@ register_bitfields! {
         TOUTCAL OFFSET(0) NUMBITS(3) [],
         PHY_INTERFACE OFFSET(3) NUMBITS(1) [],
         MODE_SELECT OFFSET(4) NUMBITS(1) [
-            ULPI = 0,
-            UTMI = 1
+            UTMI = 0,
+            ULPI = 1
         ],
         FSINTF OFFSET(5) NUMBITS(1) [],
         PHYSEL OFFSET(6) NUMBITS(1) [],
@@ -416,23 +450,23 @@ register_bitfields! {
 }
 
 enum CoreFifoFlush {
-    FlushNonPeriodic = 0,
-    FlushPeriodic1 = 1,
-    FlushPeriodic2 = 2,
-    FlushPeriodic3 = 3,
-    FlushPeriodic4 = 4,
-    FlushPeriodic5 = 5,
-    FlushPeriodic6 = 6,
-    FlushPeriodic7 = 7,
-    FlushPeriodic8 = 8,
-    FlushPeriodic9 = 9,
-    FlushPeriodic10 = 10,
-    FlushPeriodic11 = 11,
-    FlushPeriodic12 = 12,
-    FlushPeriodic13 = 13,
-    FlushPeriodic14 = 14,
-    FlushPeriodic15 = 15,
-    FlushAll = 16,
+    NonPeriodic = 0,
+    Periodic1 = 1,
+    Periodic2 = 2,
+    Periodic3 = 3,
+    Periodic4 = 4,
+    Periodic5 = 5,
+    Periodic6 = 6,
+    Periodic7 = 7,
+    Periodic8 = 8,
+    Periodic9 = 9,
+    Periodic10 = 10,
+    Periodic11 = 11,
+    Periodic12 = 12,
+    Periodic13 = 13,
+    Periodic14 = 14,
+    Periodic15 = 15,
+    All = 16,
 }
 
 #[allow(non_snake_case)]
@@ -505,13 +539,191 @@ struct RegisterBlock {
     __reserved5: [u32; 8],                                         // 0x420
     DwcHostPort: ReadWrite<u32, HOST_PORT::Register>,              // 0x440
     __reserved6: [u32; 47],
-    DwcHostChannel: HostChannel,
-    __reserved7: [u32; 568],
+    DwcHostChannel: [HostChannel; DWC_NUM_CHANNELS], // 0x500
+    __reserved7: [u32; 512],                         // 0x600
     DwcPowerAndClock: ReadWrite<u32, POWER_REG::Register>, // 0xE00
 }
 
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum UsbError {
+    /// Not a DWC OTG core, carries the ID register
+    UnknownCore(u32),
+    Timeout,
+    /// Nothing plugged into the root port
+    NotConnected,
+}
+type Result<T> = ::core::result::Result<T, UsbError>;
+
+impl From<UsbError> for usb::Error {
+    fn from(e: UsbError) -> usb::Error {
+        match e {
+            UsbError::NotConnected => usb::Error::NotConnected,
+            _ => usb::Error::Timeout,
+        }
+    }
+}
+
+/// Retry bookkeeping for one transaction
+type SendControl = LocalRegisterCopy<u32, USB_SEND_CONTROL::Register>;
+
+/// `LocalRegisterCopy` of the register crate we are on has no `modify`
+fn update(control: &mut SendControl, field: FieldValue<u32, USB_SEND_CONTROL::Register>) {
+    *control = SendControl::new(field.modify(control.get()));
+}
+
+/// What `run_channel` sends, everything but the phase stays the same over the tries
+struct Transaction<'a> {
+    channel: usize,
+    endpoint: &'a Endpoint,
+    direction: Direction,
+    pid: FieldValue<u32, HOST_TRANSFER_SIZE::Register>,
+    len: usize,
+    packets: usize,
+}
+
+/// What a transaction is currently doing
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Normal,
+    StartSplit,
+    CompleteSplit,
+}
+
+/// Fold the interrupts a channel halted with into `control`: count the try and set the bit for
+/// what to do next, `SUCCESS`, `ACTION_RETRY` or `ACTION_RESEND_SPLIT`. `LONGER_DELAY` asks for
+/// a frame's pause before going again. Gives up with an error once a limit is hit.
+fn next_action(
+    control: &mut SendControl,
+    interrupts: u32,
+    phase: Phase,
+    periodic: bool,
+) -> usb::Result<()> {
+    let halted = LocalRegisterCopy::<u32, CHANNEL_INTERRUPTS::Register>::new(interrupts);
+    update(
+        control,
+        USB_SEND_CONTROL::SUCCESS::CLEAR
+            + USB_SEND_CONTROL::ACTION_RETRY::CLEAR
+            + USB_SEND_CONTROL::ACTION_RESEND_SPLIT::CLEAR
+            + USB_SEND_CONTROL::LONGER_DELAY::CLEAR,
+    );
+
+    if halted.is_set(CHANNEL_INTERRUPTS::STALL) {
+        return Err(usb::Error::Stall);
+    }
+    if halted.is_set(CHANNEL_INTERRUPTS::AHB_ERROR) {
+        return Err(usb::Error::Bus);
+    }
+    if halted.is_set(CHANNEL_INTERRUPTS::BABBLE_ERROR) {
+        return Err(usb::Error::Babble);
+    }
+    if halted.is_set(CHANNEL_INTERRUPTS::DATA_TOGGLE_ERROR) {
+        return Err(usb::Error::DataToggle);
+    }
+
+    if halted.is_set(CHANNEL_INTERRUPTS::TRANSACTION_ERROR)
+        || halted.is_set(CHANNEL_INTERRUPTS::FRAME_OVERRUN)
+    {
+        let tries = control.read(USB_SEND_CONTROL::PACKET_TRIES) + 1;
+        if tries >= MAX_PACKET_TRIES {
+            return Err(usb::Error::Transaction);
+        }
+        update(control, USB_SEND_CONTROL::PACKET_TRIES.val(tries));
+    } else if halted.is_set(CHANNEL_INTERRUPTS::NEGATIVE_ACKNOWLEDGEMENT) {
+        // An interrupt endpoint NAKs when it has nothing to report, that is an answer
+        if periodic {
+            return Err(usb::Error::Nak);
+        }
+        let tries = control.read(USB_SEND_CONTROL::GLOBAL_TRIES) + 1;
+        if tries >= MAX_GLOBAL_TRIES {
+            return Err(usb::Error::Nak);
+        }
+        update(
+            control,
+            USB_SEND_CONTROL::GLOBAL_TRIES.val(tries) + USB_SEND_CONTROL::LONGER_DELAY::SET,
+        );
+    } else if halted.is_set(CHANNEL_INTERRUPTS::NOT_YET) && phase == Phase::CompleteSplit {
+        // The hub hasn't heard back from the device yet, ask again. After too many tries start
+        // over with a new start split.
+        let tries = control.read(USB_SEND_CONTROL::SPLIT_TRIES) + 1;
+        if tries < MAX_SPLIT_TRIES {
+            update(
+                control,
+                USB_SEND_CONTROL::SPLIT_TRIES.val(tries) + USB_SEND_CONTROL::ACTION_RETRY::SET,
+            );
+            return Ok(());
+        }
+
+        let tries = control.read(USB_SEND_CONTROL::GLOBAL_TRIES) + 1;
+        if tries >= MAX_GLOBAL_TRIES {
+            return Err(usb::Error::Transaction);
+        }
+        update(
+            control,
+            USB_SEND_CONTROL::GLOBAL_TRIES.val(tries)
+                + USB_SEND_CONTROL::SPLIT_TRIES.val(0)
+                + USB_SEND_CONTROL::ACTION_RESEND_SPLIT::SET,
+        );
+        return Ok(());
+    } else if halted.is_set(CHANNEL_INTERRUPTS::TRANSFER_COMPLETE)
+        || halted.is_set(CHANNEL_INTERRUPTS::ACKNOWLEDGEMENT)
+        || halted.is_set(CHANNEL_INTERRUPTS::NOT_YET)
+    {
+        // A NYET outside a split means the data was taken, the device just wants a PING next
+        update(control, USB_SEND_CONTROL::SUCCESS::SET);
+        return Ok(());
+    } else {
+        // Halted without saying why
+        let tries = control.read(USB_SEND_CONTROL::PACKET_TRIES) + 1;
+        if tries >= MAX_PACKET_TRIES {
+            return Err(usb::Error::Transaction);
+        }
+        update(control, USB_SEND_CONTROL::PACKET_TRIES.val(tries));
+    }
+
+    if phase != Phase::Normal {
+        update(control, USB_SEND_CONTROL::ACTION_RESEND_SPLIT::SET);
+    } else {
+        update(control, USB_SEND_CONTROL::ACTION_RETRY::SET);
+    }
+
+    Ok(())
+}
+
+/// Where a channel's DMA goes, the core wants word aligned buffers
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct DmaBuffer([u8; USB2_MAX_PACKET_SIZE as usize]);
+
+/// Split `ram` words of FIFO RAM between the receive, non-periodic and periodic transmit FIFOs.
+/// The requested sizes are shrunk in proportion when the core has less RAM than they add up to.
+fn fifo_layout(ram: u32) -> (u32, u32, u32) {
+    let requested = RECEIVE_FIFO_SIZE + NON_PERIDOIC_FIFO_SIZE + PERIODIC_FIFO_SIZE;
+    let scale = |size: u32| {
+        if requested <= ram {
+            size
+        } else {
+            (size as u64 * ram as u64 / requested as u64) as u32
+        }
+    };
+
+    (
+        scale(RECEIVE_FIFO_SIZE),
+        scale(NON_PERIDOIC_FIFO_SIZE),
+        scale(PERIODIC_FIFO_SIZE),
+    )
+}
+
 struct USBInner {
     base_addr: usize,
+    /// Host channels the core was built with, known after `init`
+    channels: u32,
+    port_speed: Option<Speed>,
+    /// Bit n set while channel n runs a transfer
+    busy: u8,
+    buffers: [DmaBuffer; DWC_NUM_CHANNELS],
+    /// Held from init to shutdown
+    power: Option<PowerHandle>,
 }
 
 impl ops::Deref for USBInner {
@@ -523,17 +735,521 @@ impl ops::Deref for USBInner {
 
 impl USBInner {
     const fn new(base_addr: usize) -> USBInner {
-        USBInner { base_addr }
+        USBInner {
+            base_addr,
+            channels: 0,
+            port_speed: None,
+            busy: 0,
+            buffers: [DmaBuffer([0; USB2_MAX_PACKET_SIZE as usize]); DWC_NUM_CHANNELS],
+            power: None,
+        }
     }
 
     fn ptr(&self) -> *const RegisterBlock {
         self.base_addr as *const _
     }
+
+    fn wait_for(&self, timeout_us: usize, done: impl Fn(&Self) -> bool) -> bool {
+        for _ in 0..timeout_us {
+            if done(self) {
+                return true;
+            }
+
+            arch::nop();
+            arch::wait_usec(1);
+        }
+
+        done(self)
+    }
+
+    fn core_reset(&self) -> Result<()> {
+        // The AHB master has to be idle before the core can be reset
+        let idle = self.wait_for(RESET_TIMEOUT_US, |s| {
+            s.DwcCoreReset.is_set(CORE_RESET::AHB_MASTER_IDLE)
+        });
+        if !idle {
+            return Err(UsbError::Timeout);
+        }
+
+        self.DwcCoreReset.write(CORE_RESET::CORE_SOFT::SET);
+        let reset = self.wait_for(RESET_TIMEOUT_US, |s| {
+            !s.DwcCoreReset.is_set(CORE_RESET::CORE_SOFT)
+        });
+        if !reset {
+            return Err(UsbError::Timeout);
+        }
+
+        arch::wait_usec(RESET_SETTLE_US);
+
+        Ok(())
+    }
+
+    /// Use the UTMI+ PHY with its 8-bit interface. The full speed lines of a ULPI PHY need
+    /// their own settings if there is a dedicated full speed PHY next to it.
+    fn select_phy(&self) {
+        let hardware = self.DwcCoreHardware1.extract();
+        let fsls = hardware.read(CORE_HARDWARE1::HIGH_SPEED_PHYSICAL) == 2
+            && hardware.read(CORE_HARDWARE1::FULL_SPEED_PHYSICAL) == 1;
+
+        self.DwcCoreControl.modify(
+            USB_CONTROL::MODE_SELECT::UTMI
+                + USB_CONTROL::PHY_INTERFACE::CLEAR
+                + USB_CONTROL::ULPI_DRIVE_EXTERNAL_VBUS::CLEAR
+                + USB_CONTROL::TS_DLINE_PULSE_EANBLE::CLEAR
+                + USB_CONTROL::ULPI_FSLS.val(fsls as u32)
+                + USB_CONTROL::ULPI_CLK_SUS_M.val(fsls as u32),
+        );
+    }
+
+    fn force_host_mode(&self) -> Result<()> {
+        self.DwcCoreControl.modify(
+            USB_CONTROL::FORCE_DEV_MODE::CLEAR
+                + USB_CONTROL::FORCE_HOST_MODE::SET
+                + USB_CONTROL::HNP_CAPABLE::CLEAR
+                + USB_CONTROL::SRP_CAPABLE::CLEAR,
+        );
+
+        let host_mode = self.wait_for(MODE_SWITCH_TIMEOUT_US, |s| {
+            s.DwcCoreInterrupt.is_set(CORE_INTERRUPTS::CURRENT_MODE)
+        });
+        if !host_mode {
+            return Err(UsbError::Timeout);
+        }
+
+        Ok(())
+    }
+
+    fn size_fifos(&self) {
+        let ram = self.DwcCoreHardware2.read(CORE_HARDWARE2::FIFO_DEPTH);
+        let (receive, non_periodic, periodic) = fifo_layout(ram);
+
+        self.DwcCoreReceivesize.set(receive);
+        self.DwcCoreNonPeriodicInfo
+            .Size
+            .write(FIFO_SIZE::START_ADDRESS.val(receive) + FIFO_SIZE::DEPTH.val(non_periodic));
+        self.DwcCorePeriodicInfo.HostSize.write(
+            FIFO_SIZE::START_ADDRESS.val(receive + non_periodic) + FIFO_SIZE::DEPTH.val(periodic),
+        );
+    }
+
+    fn flush_tx_fifo(&self, fifo: CoreFifoFlush) -> Result<()> {
+        self.DwcCoreReset.write(
+            CORE_RESET::TRANSMIT_FIFO_FLUSH::SET
+                + CORE_RESET::TRANSMIT_FIFO_FLUSH_NUMBER.val(fifo as u32),
+        );
+
+        let flushed = self.wait_for(RESET_TIMEOUT_US, |s| {
+            !s.DwcCoreReset.is_set(CORE_RESET::TRANSMIT_FIFO_FLUSH)
+        });
+        if !flushed {
+            return Err(UsbError::Timeout);
+        }
+
+        Ok(())
+    }
+
+    fn flush_rx_fifo(&self) -> Result<()> {
+        self.DwcCoreReset.write(CORE_RESET::RECEIVE_FIFO_FLUSH::SET);
+
+        let flushed = self.wait_for(RESET_TIMEOUT_US, |s| {
+            !s.DwcCoreReset.is_set(CORE_RESET::RECEIVE_FIFO_FLUSH)
+        });
+        if !flushed {
+            return Err(UsbError::Timeout);
+        }
+
+        Ok(())
+    }
+
+    /// Write the host port register without touching its write-1-to-clear bits
+    fn write_port(&self, set: u32, clear: u32) {
+        let port = self.DwcHostPort.get() & HOSTPORTMASK;
+
+        self.DwcHostPort.set((port | set) & !clear);
+    }
+
+    fn init_host(&self) -> Result<()> {
+        // Restart the PHY clock
+        self.DwcPowerAndClock.set(0);
+
+        let rate = if self.DwcCoreControl.is_set(USB_CONTROL::ULPI_FSLS) {
+            ClockRate::Clock48MHz
+        } else {
+            ClockRate::Clock30_60MHz
+        };
+        self.DwcHostConfig
+            .modify(HOST_CONFIG::CLOCK_RATE.val(rate as u32) + HOST_CONFIG::FSLS_ONLY::CLEAR);
+
+        self.flush_tx_fifo(CoreFifoFlush::All)?;
+        self.flush_rx_fifo()?;
+
+        if !self.DwcHostPort.is_set(HOST_PORT::POWER) {
+            self.write_port(HOST_PORT::POWER::SET.value, 0);
+        }
+
+        Ok(())
+    }
+
+    fn reset_port(&mut self) -> Result<Speed> {
+        self.port_speed = None;
+
+        if !self.DwcHostPort.is_set(HOST_PORT::CONNECT) {
+            return Err(UsbError::NotConnected);
+        }
+
+        self.write_port(HOST_PORT::RESET::SET.value, 0);
+        arch::wait_usec(PORT_RESET_US);
+        self.write_port(0, HOST_PORT::RESET::SET.value);
+        arch::wait_usec(PORT_RECOVERY_US);
+
+        let enabled = self.wait_for(PORT_ENABLE_TIMEOUT_US, |s| {
+            s.DwcHostPort.is_set(HOST_PORT::ENABLE)
+        });
+        if !enabled {
+            return Err(UsbError::Timeout);
+        }
+        self.write_port(HOST_PORT_CHANGES, 0);
+
+        let speed = match self.DwcHostPort.read(HOST_PORT::SPEED) {
+            0 => Speed::High,
+            1 => Speed::Full,
+            _ => Speed::Low,
+        };
+        self.port_speed = Some(speed);
+
+        Ok(speed)
+    }
+
+    fn init(&mut self) -> Result<()> {
+        let id = self.DwcCoreVendorID.get();
+        if id & DWC_VENDOR_ID_MASK != DWC_VENDOR_ID {
+            return Err(UsbError::UnknownCore(id));
+        }
+
+        // Nothing is interrupt driven yet, keep everything masked
+        self.DwcCoreAhb.modify(CORE_AHB::INTERRUPT_ENABLE::CLEAR);
+        self.DwcCoreInterruptMask.set(0);
+
+        self.select_phy();
+        self.core_reset()?;
+
+        let channels = self
+            .DwcCoreHardware1
+            .read(CORE_HARDWARE1::HOST_CHANNEL_COUNT)
+            + 1;
+        self.channels = channels.min(DWC_NUM_CHANNELS as u32);
+
+        // Let the channels fetch and store their data themselves if the core has a DMA engine
+        if self.DwcCoreHardware1.read(CORE_HARDWARE1::ARCHITECTURE) == 2 {
+            self.DwcCoreAhb.modify(
+                CORE_AHB::DMA_ENABLE::SET
+                    + CORE_AHB::WAIT_FOR_AXI_WRITES::SET
+                    + CORE_AHB::AXI_BURST_LENGTH::Length4,
+            );
+        }
+
+        self.force_host_mode()?;
+        self.size_fifos();
+        self.init_host()?;
+
+        // Acknowledge whatever was raised while setting up
+        self.DwcCoreInterrupt.set(!0);
+
+        match self.reset_port() {
+            Ok(_) | Err(UsbError::NotConnected) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The first idle channel. The whole controller sits behind one lock that a transfer holds
+    /// until it is done, so this always hands out channel 0.
+    fn alloc_channel(&mut self) -> usb::Result<usize> {
+        let channel = (0..self.channels as usize)
+            .find(|&channel| self.busy & (1 << channel) == 0)
+            .ok_or(usb::Error::NoChannel)?;
+        self.busy |= 1 << channel;
+
+        Ok(channel)
+    }
+
+    fn free_channel(&mut self, channel: usize) {
+        self.busy &= !(1 << channel);
+    }
+
+    /// Stop a channel that is still running
+    fn halt_channel(&self, channel: usize) {
+        let hc = &self.DwcHostChannel[channel];
+
+        hc.Characteristics.modify(
+            HOST_CHANNEL_CHARACTERISTICS::CHANNEL_ENABLE::True
+                + HOST_CHANNEL_CHARACTERISTICS::CHANNEL_DISABLE::True,
+        );
+        self.wait_for(TRANSFER_TIMEOUT_US, |s| {
+            s.DwcHostChannel[channel]
+                .Interrupt
+                .is_set(CHANNEL_INTERRUPTS::HALT)
+        });
+        hc.Interrupt.set(hc.Interrupt.get());
+    }
+
+    /// Run one try of a transaction on `channel` and wait for it to halt. Returns what it halted
+    /// with and the bytes left of `len`.
+    fn run_channel(&self, transaction: &Transaction, phase: Phase) -> usb::Result<(u32, usize)> {
+        let Transaction {
+            channel,
+            endpoint,
+            direction,
+            pid,
+            len,
+            packets,
+        } = *transaction;
+        let hc = &self.DwcHostChannel[channel];
+
+        hc.Interrupt.set(hc.Interrupt.get());
+        hc.InterruptMask.write(CHANNEL_INTERRUPTS::HALT::SET);
+
+        match endpoint.split {
+            Some(split) => hc.SplitCtrl.write(
+                HOST_CHANNEL_SPLIT_CONTROL::SPLIT_ENABLE::True
+                    + HOST_CHANNEL_SPLIT_CONTROL::HUB_ADDRESS.val(split.hub_address as u32)
+                    + HOST_CHANNEL_SPLIT_CONTROL::PORT_ADDRESS.val(split.port as u32)
+                    + HOST_CHANNEL_SPLIT_CONTROL::TRANSACTION_POSITION::All
+                    + HOST_CHANNEL_SPLIT_CONTROL::COMPLETE_SPLIT
+                        .val((phase == Phase::CompleteSplit) as u32),
+            ),
+            None => hc.SplitCtrl.set(0),
+        }
+
+        hc.TransferSize.write(
+            HOST_TRANSFER_SIZE::SIZE.val(len as u32)
+                + HOST_TRANSFER_SIZE::PACKET_COUNT.val(packets as u32)
+                + pid,
+        );
+        hc.DmaAddr
+            .set(bsp::bus_address(self.buffers[channel].0.as_ptr() as usize));
+
+        // Periodic transactions go out in the next frame
+        let odd_frame = self
+            .DwcHostFrameControl
+            .read(HOST_FRAME_CONTROL::FRAME_NUMBER)
+            & 1
+            == 0;
+        let periodic =
+            endpoint.kind == TransferType::Interrupt || endpoint.kind == TransferType::Isochronous;
+
+        // The buffer has to be filled before the core starts reading it
+        compiler_fence(Ordering::Release);
+        hc.Characteristics.write(
+            HOST_CHANNEL_CHARACTERISTICS::MAX_PACKET_SIZE.val(endpoint.max_packet_size as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::ENDPOINT_NUMBER.val(endpoint.number as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::ENDPOINT_DIRECTION
+                    .val((direction == Direction::Out) as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::LOW_SPEED
+                    .val((endpoint.speed == Speed::Low) as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::ENDPOINT_TYPE.val(endpoint.kind as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::PACKETS_PER_FRAME.val(1)
+                + HOST_CHANNEL_CHARACTERISTICS::DEVICE_ADDRESS.val(endpoint.device as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::ODD_FRAME.val((periodic && odd_frame) as u32)
+                + HOST_CHANNEL_CHARACTERISTICS::CHANNEL_ENABLE::True,
+        );
+
+        let halted = self.wait_for(TRANSFER_TIMEOUT_US, |s| {
+            s.DwcHostChannel[channel]
+                .Interrupt
+                .is_set(CHANNEL_INTERRUPTS::HALT)
+        });
+        if !halted {
+            self.halt_channel(channel);
+            return Err(usb::Error::Timeout);
+        }
+        compiler_fence(Ordering::Acquire);
+
+        let interrupts = hc.Interrupt.get();
+        hc.Interrupt.set(interrupts);
+
+        Ok((
+            interrupts,
+            hc.TransferSize.read(HOST_TRANSFER_SIZE::SIZE) as usize,
+        ))
+    }
+
+    /// Move up to a bounce buffer's worth of data, retrying as `next_action` says. Returns the
+    /// bytes left of `len`.
+    fn transaction(
+        &self,
+        channel: usize,
+        endpoint: &Endpoint,
+        direction: Direction,
+        pid: FieldValue<u32, HOST_TRANSFER_SIZE::Register>,
+        len: usize,
+    ) -> usb::Result<usize> {
+        let max_packet = (endpoint.max_packet_size as usize).max(1);
+        let packets = ((len + max_packet - 1) / max_packet).max(1);
+        let periodic =
+            endpoint.kind == TransferType::Interrupt || endpoint.kind == TransferType::Isochronous;
+
+        let first = if endpoint.split.is_some() {
+            Phase::StartSplit
+        } else {
+            Phase::Normal
+        };
+
+        let transaction = Transaction {
+            channel,
+            endpoint,
+            direction,
+            pid,
+            len,
+            packets,
+        };
+
+        let mut control = SendControl::new(0);
+        let mut phase = first;
+        loop {
+            let (interrupts, left) = self.run_channel(&transaction, phase)?;
+            next_action(&mut control, interrupts, phase, periodic)?;
+
+            if control.is_set(USB_SEND_CONTROL::SUCCESS) {
+                // The hub took the start split, collect the result with a complete split
+                if phase == Phase::StartSplit {
+                    phase = Phase::CompleteSplit;
+                    continue;
+                }
+
+                return Ok(left);
+            }
+
+            if control.is_set(USB_SEND_CONTROL::ACTION_RESEND_SPLIT) {
+                phase = first;
+            }
+            if control.is_set(USB_SEND_CONTROL::LONGER_DELAY) {
+                arch::wait_usec(LONGER_DELAY_US);
+            } else if phase == Phase::CompleteSplit {
+                arch::wait_usec(SPLIT_DELAY_US);
+            }
+        }
+    }
+
+    /// One stage of a transfer: `data` in or out in as many transactions as it takes. The setup
+    /// stage goes out with the SETUP PID, everything else with the endpoint's data toggle.
+    fn stage(
+        &mut self,
+        channel: usize,
+        endpoint: &mut Endpoint,
+        direction: Direction,
+        setup: bool,
+        data: &mut [u8],
+    ) -> usb::Result<usize> {
+        let max_packet = (endpoint.max_packet_size as usize).max(1);
+        // Split transactions carry a single packet
+        let chunk = if endpoint.split.is_some() {
+            max_packet
+        } else {
+            (self.buffers[channel].0.len() / max_packet).max(1) * max_packet
+        };
+
+        let mut done = 0;
+        loop {
+            let len = chunk.min(data.len() - done);
+            if direction == Direction::Out {
+                self.buffers[channel].0[..len].copy_from_slice(&data[done..done + len]);
+            }
+
+            let pid = match (setup, endpoint.toggle) {
+                (true, _) => HOST_TRANSFER_SIZE::PACKET_ID::USB_PID_SETUP,
+                (false, true) => HOST_TRANSFER_SIZE::PACKET_ID::USB_PID_DATA1,
+                (false, false) => HOST_TRANSFER_SIZE::PACKET_ID::USB_PID_DATA0,
+            };
+            let left = self.transaction(channel, endpoint, direction, pid, len)?;
+
+            let moved = match direction {
+                Direction::In => len.saturating_sub(left),
+                Direction::Out => len,
+            };
+            if direction == Direction::In {
+                data[done..done + moved].copy_from_slice(&self.buffers[channel].0[..moved]);
+            }
+            done += moved;
+
+            // Every packet flips the toggle, a zero length one included
+            let packets = ((moved + max_packet - 1) / max_packet).max(1);
+            if !setup && packets % 2 == 1 {
+                endpoint.toggle = !endpoint.toggle;
+            }
+
+            if moved < len || done == data.len() {
+                return Ok(done);
+            }
+        }
+    }
+
+    fn control(
+        &mut self,
+        endpoint: &mut Endpoint,
+        setup: &SetupPacket,
+        data: &mut [u8],
+    ) -> usb::Result<usize> {
+        let channel = self.alloc_channel()?;
+        let result = self.control_on(channel, endpoint, setup, data);
+        self.free_channel(channel);
+
+        result
+    }
+
+    fn control_on(
+        &mut self,
+        channel: usize,
+        endpoint: &mut Endpoint,
+        setup: &SetupPacket,
+        data: &mut [u8],
+    ) -> usb::Result<usize> {
+        self.stage(
+            channel,
+            endpoint,
+            Direction::Out,
+            true,
+            &mut setup.to_bytes(),
+        )?;
+
+        // Data and status stages both start with DATA1
+        endpoint.toggle = true;
+        let len = (setup.length as usize).min(data.len());
+        let moved = if len > 0 {
+            self.stage(
+                channel,
+                endpoint,
+                setup.direction(),
+                false,
+                &mut data[..len],
+            )?
+        } else {
+            0
+        };
+
+        // The status stage goes the other way, IN if there was no data
+        endpoint.toggle = true;
+        let status = match (len, setup.direction()) {
+            (0, _) | (_, Direction::Out) => Direction::In,
+            (_, Direction::In) => Direction::Out,
+        };
+        self.stage(channel, endpoint, status, false, &mut [])?;
+
+        Ok(moved)
+    }
+
+    fn transfer(&mut self, endpoint: &mut Endpoint, data: &mut [u8]) -> usb::Result<usize> {
+        let channel = self.alloc_channel()?;
+        let result = self.stage(channel, endpoint, endpoint.direction, false, data);
+        self.free_channel(channel);
+
+        result
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 // OS interface implementations
 ////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
 
 pub struct USB {
     inner: NullLock<USBInner>,
@@ -545,6 +1261,38 @@ impl USB {
             inner: NullLock::new(USBInner::new(base_addr)),
         }
     }
+
+    pub fn channels(&self) -> u32 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.channels)
+    }
+}
+
+impl usb::HostController for USB {
+    fn port_speed(&self) -> Option<Speed> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.port_speed)
+    }
+
+    fn reset_port(&self) -> usb::Result<Speed> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.reset_port()).map_err(usb::Error::from)
+    }
+
+    fn control(
+        &self,
+        endpoint: &mut Endpoint,
+        setup: &SetupPacket,
+        data: &mut [u8],
+    ) -> usb::Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.control(endpoint, setup, data))
+    }
+
+    fn transfer(&self, endpoint: &mut Endpoint, data: &mut [u8]) -> usb::Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.transfer(endpoint, data))
+    }
 }
 
 impl interface::driver::DeviceDriver for USB {
@@ -553,7 +1301,214 @@ impl interface::driver::DeviceDriver for USB {
     }
 
     fn init(&self) -> interface::driver::Result {
-        // TODO
+        let power = bsp::power().acquire(Power::USBHCD).map_err(|_| ())?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.power = Some(power);
+            if let Err(e) = inner.init() {
+                // Everything else works without USB, don't take the boot down with it
+                crate::warn!("USB host controller unusable: {:?}", e);
+            }
+        });
+
         Ok(())
     }
+
+    fn shutdown(&self) -> interface::driver::Result {
+        let mut r = &self.inner;
+        match r.lock(|inner| inner.power.take()) {
+            Some(power) => bsp::power().release(power).map_err(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::driver::mock::FakeRegisterFile;
+
+    const CORE_RESET: usize = 0x10;
+    const CORE_INTERRUPT: usize = 0x14;
+    const RECEIVE_SIZE: usize = 0x24;
+    const NON_PERIODIC_SIZE: usize = 0x28;
+    const VENDOR_ID: usize = 0x40;
+    const HARDWARE2: usize = 0x4C;
+    const PERIODIC_SIZE: usize = 0x100;
+    const HOST_PORT: usize = 0x440;
+    const CHANNEL_CHARACTERISTICS: usize = 0x500;
+    const CHANNEL_INTERRUPT: usize = 0x508;
+    const CHANNEL_TRANSFER_SIZE: usize = 0x510;
+
+    const HALTED_COMPLETE: u32 = 0b11;
+    const HALTED_NAK: u32 = (1 << 4) | (1 << 1);
+    const HALTED_NYET: u32 = (1 << 6) | (1 << 1);
+    const HALTED_ERROR: u32 = (1 << 7) | (1 << 1);
+
+    /// A core that finishes resets and flushes at once, with 4080 words of FIFO RAM
+    fn core() -> FakeRegisterFile {
+        let fake = FakeRegisterFile::new(0xE04);
+        fake.write(VENDOR_ID, 0x4F54_280A);
+        fake.write(HARDWARE2, 4080 << 16);
+        fake.write(CORE_RESET, 1 << 31);
+
+        fake.on_poll(|regs, _| {
+            regs.write(CORE_RESET, 1 << 31);
+            regs.set_bits(CORE_INTERRUPT, 1);
+            if regs.read(HOST_PORT) & 1 != 0 {
+                regs.set_bits(HOST_PORT, 1 << 2);
+            }
+        });
+
+        fake
+    }
+
+    #[test]
+    fn fifos_are_shrunk_to_fit() {
+        assert_eq!(fifo_layout(4080), (1360, 1360, 1360));
+        assert_eq!(fifo_layout(65535), (20480, 20480, 20480));
+    }
+
+    #[test]
+    fn init_sizes_fifos_and_powers_the_port() {
+        let fake = core();
+        let mut usb = USBInner::new(fake.base_addr());
+
+        assert_eq!(usb.init(), Ok(()));
+
+        assert_eq!(fake.read(RECEIVE_SIZE), 1360);
+        assert_eq!(fake.read(NON_PERIODIC_SIZE), (1360 << 16) | 1360);
+        assert_eq!(fake.read(PERIODIC_SIZE), (1360 << 16) | 2720);
+        assert_eq!(fake.read(HOST_PORT) & (1 << 12), 1 << 12);
+        assert_eq!(usb.port_speed, None);
+    }
+
+    #[test]
+    fn port_reset_detects_speed() {
+        let fake = core();
+        let mut usb = USBInner::new(fake.base_addr());
+
+        fake.write(HOST_PORT, 1 | (1 << 17));
+        assert_eq!(usb.init(), Ok(()));
+
+        assert_eq!(usb.port_speed, Some(Speed::Full));
+        assert_eq!(fake.read(HOST_PORT) & (1 << 8), 0);
+    }
+
+    #[test]
+    fn other_cores_are_rejected() {
+        let fake = core();
+        let mut usb = USBInner::new(fake.base_addr());
+
+        fake.write(VENDOR_ID, 0x1234_5678);
+        assert_eq!(usb.init(), Err(UsbError::UnknownCore(0x1234_5678)));
+    }
+
+    #[test]
+    fn naks_retry_unless_periodic() {
+        let mut control = SendControl::new(0);
+
+        assert_eq!(
+            next_action(&mut control, HALTED_NAK, Phase::Normal, false),
+            Ok(())
+        );
+        assert!(control.is_set(USB_SEND_CONTROL::ACTION_RETRY));
+        assert!(control.is_set(USB_SEND_CONTROL::LONGER_DELAY));
+
+        let result = next_action(&mut control, HALTED_NAK, Phase::Normal, true);
+        assert_eq!(result, Err(usb::Error::Nak));
+    }
+
+    #[test]
+    fn transaction_errors_give_up_after_the_packet_tries() {
+        let mut control = SendControl::new(0);
+
+        for _ in 1..MAX_PACKET_TRIES {
+            let result = next_action(&mut control, HALTED_ERROR, Phase::StartSplit, false);
+            assert_eq!(result, Ok(()));
+            assert!(control.is_set(USB_SEND_CONTROL::ACTION_RESEND_SPLIT));
+        }
+
+        let result = next_action(&mut control, HALTED_ERROR, Phase::StartSplit, false);
+        assert_eq!(result, Err(usb::Error::Transaction));
+    }
+
+    #[test]
+    fn complete_splits_are_repeated_then_restarted() {
+        let mut control = SendControl::new(0);
+
+        for _ in 1..MAX_SPLIT_TRIES {
+            next_action(&mut control, HALTED_NYET, Phase::CompleteSplit, false).unwrap();
+            assert!(control.is_set(USB_SEND_CONTROL::ACTION_RETRY));
+        }
+
+        next_action(&mut control, HALTED_NYET, Phase::CompleteSplit, false).unwrap();
+        assert!(control.is_set(USB_SEND_CONTROL::ACTION_RESEND_SPLIT));
+        assert_eq!(control.read(USB_SEND_CONTROL::SPLIT_TRIES), 0);
+        assert_eq!(control.read(USB_SEND_CONTROL::GLOBAL_TRIES), 1);
+    }
+
+    #[test]
+    fn channels_run_out() {
+        let fake = core();
+        let mut usb = USBInner::new(fake.base_addr());
+        usb.channels = DWC_NUM_CHANNELS as u32;
+
+        for channel in 0..DWC_NUM_CHANNELS {
+            assert_eq!(usb.alloc_channel(), Ok(channel));
+        }
+        assert_eq!(usb.alloc_channel(), Err(usb::Error::NoChannel));
+
+        usb.free_channel(3);
+        assert_eq!(usb.alloc_channel(), Ok(3));
+    }
+
+    #[test]
+    fn bulk_in_stops_at_a_short_packet() {
+        const REPORT: [u8; 18] = [
+            18, 1, 0, 2, 9, 0, 1, 64, 0x24, 4, 0x14, 0xEC, 0, 1, 0, 0, 0, 1,
+        ];
+
+        let fake = FakeRegisterFile::new(0xE04);
+        let mut usb = USBInner::new(fake.base_addr());
+        usb.channels = 1;
+
+        // Channel 0 answers with a single short packet
+        let buffer = usb.buffers[0].0.as_mut_ptr() as usize;
+        fake.on_poll(move |regs, _| {
+            let characteristics = regs.read(CHANNEL_CHARACTERISTICS);
+            if characteristics & (1 << 31) == 0 {
+                return;
+            }
+
+            let buffer = unsafe { std::slice::from_raw_parts_mut(buffer as *mut u8, 18) };
+            buffer.copy_from_slice(&REPORT);
+
+            let size = regs.read(CHANNEL_TRANSFER_SIZE);
+            regs.write(CHANNEL_TRANSFER_SIZE, size - 18);
+            regs.write(CHANNEL_CHARACTERISTICS, characteristics & !(1 << 31));
+            regs.write(CHANNEL_INTERRUPT, HALTED_COMPLETE);
+        });
+
+        let mut endpoint = Endpoint {
+            number: 2,
+            kind: TransferType::Bulk,
+            direction: Direction::In,
+            ..Endpoint::control(3, 64, Speed::High, None)
+        };
+        let mut data = [0; 512];
+
+        assert_eq!(usb.transfer(&mut endpoint, &mut data), Ok(18));
+        assert_eq!(&data[..18], &REPORT[..]);
+        assert!(endpoint.toggle);
+        assert_eq!(usb.busy, 0);
+
+        // All of the buffer asked for as DATA0 from endpoint 2 of device 3
+        assert_eq!(fake.read(CHANNEL_TRANSFER_SIZE), (8 << 19) | (512 - 18));
+        assert_eq!(
+            fake.read(CHANNEL_CHARACTERISTICS) & 0x1FFF_FFFF,
+            (3 << 22) | (2 << 18) | (1 << 20) | (2 << 11) | 64
+        );
+    }
 }
@@ -0,0 +1,85 @@
+//! In-memory stand-in for a peripheral's MMIO window.
+//!
+//! Drivers only ever see a `base_addr`, so handing them the address of a plain buffer is enough
+//! for them to run on the host. Hardware side effects are scripted through `on_poll`, which runs
+//! every time the driver spins in `arch::nop()` waiting for the device.
+
+use crate::arch;
+
+pub struct FakeRegisterFile {
+    // Boxed so the address handed to the driver stays put when the fake is moved around
+    words: Box<[u32]>,
+}
+
+/// Handle on a `FakeRegisterFile` that can be moved into a poll hook
+#[derive(Clone, Copy)]
+pub struct Registers {
+    base_addr: usize,
+}
+
+impl Registers {
+    /// Read the register at a byte offset into the block
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base_addr + offset) as *const u32) }
+    }
+
+    /// Write the register at a byte offset into the block
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base_addr + offset) as *mut u32, value) }
+    }
+
+    pub fn set_bits(&self, offset: usize, mask: u32) {
+        self.write(offset, self.read(offset) | mask);
+    }
+
+    pub fn clear_bits(&self, offset: usize, mask: u32) {
+        self.write(offset, self.read(offset) & !mask);
+    }
+}
+
+impl FakeRegisterFile {
+    /// Allocate a zeroed register file `size` bytes long
+    pub fn new(size: usize) -> FakeRegisterFile {
+        FakeRegisterFile {
+            words: vec![0u32; (size + 3) / 4].into_boxed_slice(),
+        }
+    }
+
+    /// Address to hand to a driver's `new()`
+    pub fn base_addr(&self) -> usize {
+        self.words.as_ptr() as usize
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            base_addr: self.base_addr(),
+        }
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        self.registers().read(offset)
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        self.registers().write(offset, value)
+    }
+
+    /// Run `script` every time the driver polls. It is handed the registers and the number of
+    /// polls so far, so sequences like "set the ready bit on the third poll" are easy to express.
+    pub fn on_poll(&self, mut script: impl FnMut(Registers, usize) + 'static) {
+        let regs = self.registers();
+        let mut polls = 0;
+
+        arch::set_poll_hook(move || {
+            polls += 1;
+            script(regs, polls);
+        });
+    }
+}
+
+impl Drop for FakeRegisterFile {
+    fn drop(&mut self) {
+        // The hook holds our address, make sure it can never outlive us
+        arch::clear_poll_hook();
+    }
+}
@@ -12,6 +12,14 @@ pub const BOOT_CORE_ID: u64 = 0;
 pub const BOOT_CORE_STACK_START: u64 = 0x80_000;
 pub const CORE_MASK: u64 = 0x3;
 
+/// True when running under `qemu-system-aarch64 -M raspi3b`. Drivers use this to skip checks
+/// against peripherals that QEMU does not model (clock rates, power domains, ...).
+pub const EMULATED: bool = cfg!(feature = "bsp_qemu_raspi3");
+
+/// Clock manager sources with a fixed rate, the crystal and the PLLD peripheral output
+const OSCILLATOR_HZ: u32 = 19_200_000;
+const PLLD_HZ: u32 = 500_000_000;
+
 ////////////////////////////////////////////////////////////////////////////////
 // Global BSP driver instances
 ////////////////////////////////////////////////////////////////////////////////
@@ -19,25 +27,72 @@ pub const CORE_MASK: u64 = 0x3;
 static GPIO: driver::GPIO = unsafe { driver::GPIO::new(memory_map::mmio::GPIO_BASE) };
 static SYSTIMER: driver::SysTimer =
     unsafe { driver::SysTimer::new(memory_map::mmio::SYSTIMER_BASE) };
+static WATCHDOG: driver::Watchdog =
+    unsafe { driver::Watchdog::new(memory_map::mmio::WATCHDOG_BASE) };
+static CLOCKS: driver::ClockManager =
+    unsafe { driver::ClockManager::new(memory_map::mmio::CPRMAN_BASE, OSCILLATOR_HZ, PLLD_HZ) };
 static AUX_REGS: driver::AuxRegisters =
     unsafe { driver::AuxRegisters::new(memory_map::mmio::AUX_BASE) };
 static RNG: driver::Rng = unsafe { driver::Rng::new(memory_map::mmio::RANDOM_BASE) };
 static MINI_UART: driver::MiniUart = unsafe { driver::MiniUart::new(memory_map::mmio::UART1_BASE) };
+static SPI1: driver::AuxSpi = unsafe {
+    driver::AuxSpi::new(
+        memory_map::mmio::SPI1_BASE,
+        &AUX_REGS,
+        driver::AuxSpiPort::Spi1,
+    )
+};
+static SPI2: driver::AuxSpi = unsafe {
+    driver::AuxSpi::new(
+        memory_map::mmio::SPI2_BASE,
+        &AUX_REGS,
+        driver::AuxSpiPort::Spi2,
+    )
+};
+static POWER: driver::PowerManager = driver::PowerManager::new();
 static MBOX: driver::Mbox = unsafe { driver::Mbox::new(memory_map::mmio::MAILBOX_BASE) };
 static UART0: driver::Uart = unsafe { driver::Uart::new(memory_map::mmio::UART0_BASE) };
+static DMA: driver::DmaController =
+    unsafe { driver::DmaController::new(memory_map::mmio::DMA_BASE) };
+static SPI0: driver::Spi = unsafe { driver::Spi::new(memory_map::mmio::SPI0_BASE) };
+static I2C0: driver::I2c =
+    unsafe { driver::I2c::new(memory_map::mmio::I2C0_BASE, driver::I2cPort::Bsc0) };
+static I2C1: driver::I2c =
+    unsafe { driver::I2c::new(memory_map::mmio::I2C1_BASE, driver::I2cPort::Bsc1) };
+static I2C2: driver::I2c =
+    unsafe { driver::I2c::new(memory_map::mmio::I2C2_BASE, driver::I2cPort::Bsc2) };
+static BSC_SLAVE: driver::BscSlave =
+    unsafe { driver::BscSlave::new(memory_map::mmio::BSC_SLAVE_BASE) };
+static PWM: driver::Pwm = unsafe { driver::Pwm::new(memory_map::mmio::PWM_BASE) };
+static AUDIO: driver::PwmAudio = driver::PwmAudio::new(&PWM, &CLOCKS);
+static PCM: driver::Pcm = unsafe { driver::Pcm::new(memory_map::mmio::I2S_BASE, &CLOCKS) };
+static THERMAL: driver::Thermal =
+    unsafe { driver::Thermal::new(memory_map::mmio::THERMAL_BASE, driver::ThermalSensor::Tsens) };
+static ARM_CLOCK: driver::ArmClock = driver::ArmClock::new(&THERMAL);
+static EMMC: driver::Emmc = unsafe { driver::Emmc::new(memory_map::mmio::MMC1_BASE, false) };
 static USB: driver::USB = unsafe { driver::USB::new(memory_map::mmio::USB_BASE) };
 
 ////////////////////////////////////////////////////////////////////////////////
 // Global BSP driver getters
 ////////////////////////////////////////////////////////////////////////////////
 
-pub fn device_drivers() -> [&'static dyn interface::driver::DeviceDriver; 8] {
+pub fn device_drivers() -> [&'static dyn interface::driver::DeviceDriver; 23] {
     [
-        &GPIO, &SYSTIMER, &AUX_REGS, &RNG, &MINI_UART, &MBOX, &UART0, &USB,
+        &GPIO, &SYSTIMER, &WATCHDOG, &CLOCKS, &AUX_REGS, &RNG, &MINI_UART, &SPI1, &SPI2, &MBOX,
+        &UART0, &DMA, &SPI0, &I2C0, &I2C1, &I2C2, &BSC_SLAVE, &PWM, &PCM, &THERMAL, &ARM_CLOCK,
+        &EMMC, &USB,
     ]
 }
 
 pub fn init() {
+    // The SD slot is wired to pins 48-53, which the firmware leaves on the SDHOST controller
+    GPIO.map_emmc();
+    GPIO.map_spi0();
+    GPIO.map_spi1();
+    GPIO.map_i2c0();
+    GPIO.map_i2c1();
+    GPIO.map_pwm_audio();
+
     for i in device_drivers().iter() {
         if let Err(()) = i.init() {
             // This message will only be readable if, at the time of failure,
@@ -47,9 +102,45 @@ pub fn init() {
         }
     }
 
+    THERMAL.set_handler(thermal_event);
     GPIO.map_mini_uart();
 }
 
+/// Trip point handler. Passive trips throttle in the driver itself, a critical one means the
+/// board is about to overheat.
+fn thermal_event(event: driver::ThermalEvent) {
+    match event {
+        driver::ThermalEvent::Tripped {
+            kind: driver::TripKind::Critical,
+            temperature,
+            ..
+        } => {
+            crate::error!(
+                "SoC at {}.{:03} C, powering off",
+                temperature / 1000,
+                temperature % 1000
+            );
+            power_off()
+        }
+        event => crate::debug!("{:?}", event),
+    }
+}
+
+/// Devices published under /dev, by file name
+pub fn char_devices() -> [(&'static str, &'static dyn interface::driver::CharDevice); 4] {
+    [
+        ("console", &MINI_UART),
+        ("uart0", &UART0),
+        ("random", &RNG),
+        ("gpio", &GPIO),
+    ]
+}
+
+/// The BSC controllers, by name. I2C2 is the HDMI DDC channel and has no header pins.
+pub fn i2c_buses() -> [(&'static str, &'static dyn interface::i2c::I2cBus); 3] {
+    [("i2c0", &I2C0), ("i2c1", &I2C1), ("i2c2", &I2C2)]
+}
+
 // Returns a ready-to-use `console::Write` implementation.
 pub fn console() -> &'static impl interface::console::All {
     &MINI_UART
@@ -59,10 +150,82 @@ pub fn mailbox() -> &'static driver::Mbox {
     &MBOX
 }
 
+/// Reference counted power domains, drivers acquire theirs in `init`
+pub fn power() -> &'static driver::PowerManager {
+    &POWER
+}
+
 pub fn gpio() -> &'static driver::GPIO {
     &GPIO
 }
 
+pub fn watchdog() -> &'static driver::Watchdog {
+    &WATCHDOG
+}
+
+pub fn clock_manager() -> &'static driver::ClockManager {
+    &CLOCKS
+}
+
+pub fn dma() -> &'static driver::DmaController {
+    &DMA
+}
+
+pub fn emmc() -> &'static driver::Emmc {
+    &EMMC
+}
+
+pub fn i2c1() -> &'static driver::I2c {
+    &I2C1
+}
+
+/// Not muxed by default, `gpio().map_bsc_slave()` hands it pins 18 and 19
+pub fn bsc_slave() -> &'static driver::BscSlave {
+    &BSC_SLAVE
+}
+
+/// The headphone jack
+pub fn audio() -> &'static driver::PwmAudio {
+    &AUDIO
+}
+
+pub fn pwm() -> &'static driver::Pwm {
+    &PWM
+}
+
+/// For I2S DACs and microphones. Not muxed by default, `gpio().map_pcm()` hands it pins 18-21,
+/// two of which the BSC slave also uses.
+pub fn pcm() -> &'static driver::Pcm {
+    &PCM
+}
+
+pub fn thermal() -> &'static driver::Thermal {
+    &THERMAL
+}
+
+/// The clock the ARM cores run from
+pub fn cpufreq() -> &'static dyn interface::cpufreq::CpuFreq {
+    &ARM_CLOCK
+}
+
+/// The DWC OTG controller, its root port is wired to the LAN9514 hub
+pub fn usb_host() -> Option<&'static dyn interface::usb::HostController> {
+    Some(&USB)
+}
+
+pub fn spi0() -> &'static driver::Spi {
+    &SPI0
+}
+
+pub fn spi1() -> &'static driver::AuxSpi {
+    &SPI1
+}
+
+/// SPI2 sits on pins 40-45, which are left alone since the audio PWM uses some of them
+pub fn spi2() -> &'static driver::AuxSpi {
+    &SPI2
+}
+
 pub fn uart0() -> &'static impl interface::console::All {
     &UART0
 }
@@ -72,7 +235,11 @@ pub fn uart0() -> &'static impl interface::console::All {
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn board_name() -> &'static str {
-    "Raspberry Pi 3"
+    if EMULATED {
+        "Raspberry Pi 3 (QEMU)"
+    } else {
+        "Raspberry Pi 3"
+    }
 }
 
 // This is kind of an ugly solution, this could be pushed into the Mail implementation
@@ -82,6 +249,20 @@ pub fn board_mac() -> u64 {
     mail.get_board_mac().unwrap()
 }
 
+/// Current rate of the EMMC controller clock, used to derive the SD card clock divider
+pub fn emmc_clock_rate() -> u32 {
+    let mut mail = driver::Mail::new();
+
+    mail.get_clock_rate(driver::Clocks::EMMC).unwrap().1
+}
+
+/// Clock of the VPU core, which the SPI, I2C and AUX peripherals divide down from
+pub fn core_clock_rate() -> u32 {
+    let mut mail = driver::Mail::new();
+
+    mail.get_clock_rate(driver::Clocks::CORE).unwrap().1
+}
+
 pub fn rand(min: usize, max: usize) -> usize {
     RNG.rand(min, max)
 }
@@ -89,3 +270,42 @@ pub fn rand(min: usize, max: usize) -> usize {
 pub fn wait_usec(n: u64) {
     SYSTIMER.wait_usec(n);
 }
+
+/// Microseconds since the system timer started counting at power on
+pub fn uptime_usec() -> u64 {
+    SYSTIMER.get_systimer()
+}
+
+/// Fill `buf` with the kernel command line from `cmdline.txt`, returns the number of bytes copied
+pub fn command_line(buf: &mut [u8]) -> usize {
+    let mut mail = driver::Mail::new();
+
+    mail.get_command_line(buf).unwrap_or(0)
+}
+
+/// Translate an ARM physical address into the bus address DMA engines and the VideoCore use
+pub fn bus_address(addr: usize) -> u32 {
+    if addr >= memory_map::mmio::BASE {
+        (addr - memory_map::mmio::BASE + memory_map::bus::PERIPHERAL_BASE) as u32
+    } else {
+        (addr | memory_map::bus::MEMORY_ALIAS) as u32
+    }
+}
+
+pub fn reboot() -> ! {
+    WATCHDOG.reboot()
+}
+
+pub fn power_off() -> ! {
+    for driver in device_drivers().iter().rev() {
+        let _ = driver.shutdown();
+    }
+
+    WATCHDOG.power_off()
+}
+
+/// Move along the devices that have work to do without being asked. The kernel doesn't take
+/// interrupts, so its idle loop calls this.
+pub fn service_devices() {
+    BSC_SLAVE.service();
+}
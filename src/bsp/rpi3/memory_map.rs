@@ -1,6 +1,14 @@
 // This is the codified Device Tree for the RPI3 this should give us every
 //base address we could need for implementing peripherals
 
+/// Addresses as seen by the VideoCore and the DMA engines
+pub mod bus {
+    /// The peripherals appear here on the bus instead of at `mmio::BASE`
+    pub const PERIPHERAL_BASE: usize = 0x7E00_0000;
+    /// Uncached alias of SDRAM, DMA through it bypasses the VideoCore L2 cache
+    pub const MEMORY_ALIAS: usize = 0xC000_0000;
+}
+
 pub mod mmio {
     pub const BASE: usize = 0x3F00_0000;
     pub const SYSTIMER_BASE: usize = BASE + 0x0000_3000;
@@ -24,6 +32,7 @@ pub mod mmio {
     pub const DSI0_BASE: usize = BASE + 0x0020_9000;
     pub const PWM_BASE: usize = BASE + 0x0020_C000;
     pub const THERMAL_BASE: usize = BASE + 0x0021_2000;
+    pub const BSC_SLAVE_BASE: usize = BASE + 0x0021_4000;
     pub const AUX_BASE: usize = BASE + 0x0021_5000;
     pub const UART1_BASE: usize = BASE + 0x0021_5040;
     pub const SPI1_BASE: usize = BASE + 0x0021_5080;
@@ -0,0 +1,81 @@
+// This is the codified Device Tree for the RPI4 this should give us every
+// base address we could need for implementing peripherals. The BCM2711 keeps the
+// BCM2837 peripheral layout, just moved up to 0xFE00_0000 ("low peripheral" mode).
+
+// The RPI4 ships with 1, 2, 4 or 8GB of RAM. The first GB is split with the VideoCore
+// (the exact split is decided by the firmware, query it with `Mail::get_arm_memory`),
+// everything above that is ARM only memory.
+pub mod memory {
+    pub const RAM_START: usize = 0x0000_0000;
+    /// End of the first GB, the VideoCore carve out lives at the top of this region
+    pub const LOW_RAM_END: usize = 0x3C00_0000;
+    /// Memory from 1GB up to the peripheral window, present on the 2GB+ boards
+    pub const HIGH_RAM_START: usize = 0x4000_0000;
+    pub const HIGH_RAM_END: usize = 0xFC00_0000;
+    /// Memory above 4GB, present on the 8GB board only
+    pub const EXTENDED_RAM_START: usize = 0x1_0000_0000;
+    pub const EXTENDED_RAM_END: usize = 0x2_0000_0000;
+}
+
+/// Addresses as seen by the VideoCore and the DMA engines
+pub mod bus {
+    /// The peripherals appear here on the bus instead of at `mmio::BASE`
+    pub const PERIPHERAL_BASE: usize = 0x7E00_0000;
+    /// Uncached alias of SDRAM, DMA through it bypasses the VideoCore L2 cache
+    pub const MEMORY_ALIAS: usize = 0xC000_0000;
+}
+
+pub mod mmio {
+    pub const BASE: usize = 0xFE00_0000;
+    pub const SYSTIMER_BASE: usize = BASE + 0x0000_3000;
+    pub const TXP_BASE: usize = BASE + 0x0000_4000;
+    pub const DMA_BASE: usize = BASE + 0x0000_7000;
+    pub const INTERRUPT_CTRL_BASE: usize = BASE + 0x0000_B200;
+    pub const VCHIQ_BASE: usize = BASE + 0x0000_B840;
+    pub const MAILBOX_BASE: usize = BASE + 0x0000_B880;
+    pub const WATCHDOG_BASE: usize = BASE + 0x0010_0000;
+    pub const CPRMAN_BASE: usize = BASE + 0x0010_1000;
+    pub const RANDOM_BASE: usize = BASE + 0x0010_4000;
+    pub const GPIO_BASE: usize = BASE + 0x0020_0000;
+    pub const UART0_BASE: usize = BASE + 0x0020_1000;
+    pub const UART2_BASE: usize = BASE + 0x0020_1400;
+    pub const UART3_BASE: usize = BASE + 0x0020_1600;
+    pub const UART4_BASE: usize = BASE + 0x0020_1800;
+    pub const UART5_BASE: usize = BASE + 0x0020_1A00;
+    pub const MMC0_BASE: usize = BASE + 0x0020_2000;
+    pub const I2S_BASE: usize = BASE + 0x0020_3000;
+    pub const SPI0_BASE: usize = BASE + 0x0020_4000;
+    pub const I2C0_BASE: usize = BASE + 0x0020_5000;
+    pub const PIXELVALVE0_BASE: usize = BASE + 0x0020_6000;
+    pub const PIXELVALVE1_BASE: usize = BASE + 0x0020_7000;
+    pub const DPI_BASE: usize = BASE + 0x0020_8000;
+    pub const DSI0_BASE: usize = BASE + 0x0020_9000;
+    pub const PWM_BASE: usize = BASE + 0x0020_C000;
+    pub const PWM1_BASE: usize = BASE + 0x0020_C800;
+    pub const THERMAL_BASE: usize = BASE + 0x0021_2000;
+    pub const BSC_SLAVE_BASE: usize = BASE + 0x0021_4000;
+    pub const AUX_BASE: usize = BASE + 0x0021_5000;
+    pub const UART1_BASE: usize = BASE + 0x0021_5040;
+    pub const SPI1_BASE: usize = BASE + 0x0021_5080;
+    pub const SPI2_BASE: usize = BASE + 0x0021_50C0;
+    pub const MMC1_BASE: usize = BASE + 0x0030_0000;
+    pub const EMMC2_BASE: usize = BASE + 0x0034_0000;
+    pub const HVS_BASE: usize = BASE + 0x0040_0000;
+    pub const SMI_BASE: usize = BASE + 0x0060_0000;
+    pub const DSI1_BASE: usize = BASE + 0x0070_0000;
+    pub const CSI0_BASE: usize = BASE + 0x0080_0000;
+    pub const CSI1_BASE: usize = BASE + 0x0080_1000;
+    pub const I2C1_BASE: usize = BASE + 0x0080_4000;
+    pub const VEC_BASE: usize = BASE + 0x0080_6000;
+    pub const PIXELVALVE2_BASE: usize = BASE + 0x0080_7000;
+    pub const HDMI_BASE: usize = BASE + 0x0090_2000;
+    pub const USB_BASE: usize = BASE + 0x0098_0000;
+    pub const V3D_BASE: usize = BASE + 0x00C0_0000;
+
+    // These live outside of the legacy peripheral window
+    pub const AVS_MONITOR_BASE: usize = 0xFD5D_2000;
+    pub const ARM_LOCAL_BASE: usize = 0xFF80_0000;
+    pub const GIC_BASE: usize = 0xFF84_0000;
+    pub const GICD_BASE: usize = GIC_BASE + 0x0000_1000;
+    pub const GICC_BASE: usize = GIC_BASE + 0x0000_2000;
+}
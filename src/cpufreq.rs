@@ -0,0 +1,375 @@
+//! CPU frequency scaling, on top of the BSP's `interface::cpufreq::CpuFreq`.
+//!
+//! A governor picks the rate the ARM cores run at:
+//!
+//! - `performance` keeps them at the maximum
+//! - `powersave` keeps them at the minimum
+//! - `ondemand` follows the load, jumping to the maximum once the cores are busy more than
+//!   `UP_THRESHOLD` percent of the time and scaling linearly between the limits below that
+//!
+//! There is no scheduler yet, so the load is the share of time spent outside `idle_enter()` and
+//! `idle_exit()`, which whatever waits for work puts around the wait. `update()` takes a sample
+//! at most every `SAMPLING_PERIOD_US` and applies the governor.
+//!
+//! The governor is picked on the kernel command line with `cpufreq=powersave`, ondemand is the
+//! default. Rate changes are logged at the debug level, `log=cpufreq:debug` shows them.
+
+use crate::{arch::sync::NullLock, bsp, interface};
+use core::fmt;
+use interface::cpufreq::{CpuFreq, Error, Result};
+
+/// Load in percent above which ondemand goes straight to the maximum
+const UP_THRESHOLD: u32 = 80;
+
+/// Shortest time ondemand averages the load over
+const SAMPLING_PERIOD_US: u64 = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Governor {
+    Performance,
+    Powersave,
+    Ondemand,
+}
+
+impl Governor {
+    pub fn from_name(name: &str) -> Option<Governor> {
+        match name {
+            "performance" => Some(Governor::Performance),
+            "powersave" => Some(Governor::Powersave),
+            "ondemand" => Some(Governor::Ondemand),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Governor::Performance => "performance",
+            Governor::Powersave => "powersave",
+            Governor::Ondemand => "ondemand",
+        }
+    }
+}
+
+impl fmt::Display for Governor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Rate ondemand asks for when the cores were busy `load` percent of the last sample
+fn ondemand_target(load: u32, min: u32, max: u32) -> u32 {
+    if load >= UP_THRESHOLD {
+        return max;
+    }
+
+    min + ((max - min) as u64 * load as u64 / 100) as u32
+}
+
+struct Policy {
+    driver: Option<&'static dyn CpuFreq>,
+    governor: Governor,
+    min: u32,
+    max: u32,
+    /// Last rate set or read back, in Hz
+    current: u32,
+    transitions: u32,
+    last_sample: u64,
+    /// Idle time since `last_sample`, not counting an idle period still going on
+    idle_us: u64,
+    idle_since: Option<u64>,
+}
+
+impl Policy {
+    const fn new() -> Policy {
+        Policy {
+            driver: None,
+            governor: Governor::Ondemand,
+            min: 0,
+            max: 0,
+            current: 0,
+            transitions: 0,
+            last_sample: 0,
+            idle_us: 0,
+            idle_since: None,
+        }
+    }
+
+    fn register(&mut self, driver: &'static dyn CpuFreq, now: u64) -> Result<()> {
+        let (min, max) = driver.limits()?;
+
+        self.current = driver.current()?;
+        self.min = min;
+        self.max = max;
+        self.driver = Some(driver);
+        self.last_sample = now;
+
+        Ok(())
+    }
+
+    fn idle_enter(&mut self, now: u64) {
+        if self.idle_since.is_none() {
+            self.idle_since = Some(now);
+        }
+    }
+
+    fn idle_exit(&mut self, now: u64) {
+        if let Some(since) = self.idle_since.take() {
+            self.idle_us += now.saturating_sub(since);
+        }
+    }
+
+    /// Percentage of the time since the last sample the cores were busy, `None` until a whole
+    /// sampling period has gone by
+    fn sample(&mut self, now: u64) -> Option<u32> {
+        let elapsed = now.saturating_sub(self.last_sample);
+        if elapsed < SAMPLING_PERIOD_US {
+            return None;
+        }
+
+        // An idle period that is still going on counts up to now, the rest goes to the next sample
+        if let Some(since) = self.idle_since {
+            self.idle_us += now.saturating_sub(since);
+            self.idle_since = Some(now);
+        }
+
+        let busy = elapsed.saturating_sub(self.idle_us);
+        self.idle_us = 0;
+        self.last_sample = now;
+
+        Some((busy * 100 / elapsed) as u32)
+    }
+
+    /// Rate the governor wants, `None` if it has no opinion yet
+    fn target(&self, load: Option<u32>) -> Option<u32> {
+        match self.governor {
+            Governor::Performance => Some(self.max),
+            Governor::Powersave => Some(self.min),
+            Governor::Ondemand => load.map(|load| ondemand_target(load, self.min, self.max)),
+        }
+    }
+
+    fn set_rate(&mut self, hz: u32) -> Result<()> {
+        let driver = self.driver.ok_or(Error::NoDriver)?;
+
+        // Someone else, the thermal driver for one, may have changed the clock under us
+        self.current = driver.current()?;
+        if hz == self.current {
+            return Ok(());
+        }
+
+        let rate = driver.set(hz)?;
+        if rate != self.current {
+            crate::debug!(
+                "{} -> {} MHz ({})",
+                self.current / 1_000_000,
+                rate / 1_000_000,
+                self.governor
+            );
+
+            self.current = rate;
+            self.transitions += 1;
+        }
+
+        Ok(())
+    }
+
+    fn update(&mut self, now: u64) -> Result<()> {
+        let load = self.sample(now);
+
+        match self.target(load) {
+            Some(hz) if self.driver.is_some() => self.set_rate(hz),
+            _ => Ok(()),
+        }
+    }
+}
+
+static POLICY: NullLock<Policy> = NullLock::new(Policy::new());
+
+////////////////////////////////////////////////////////////////////////////////
+// Public interface
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// Take over the BSP's CPU clock and apply the governor from the kernel command line
+pub fn init() {
+    let mut cmdline = [0u8; 128];
+    let len = bsp::command_line(&mut cmdline);
+
+    let governor = core::str::from_utf8(&cmdline[..len])
+        .ok()
+        .and_then(|cmdline| {
+            cmdline
+                .split_whitespace()
+                .find(|option| option.starts_with("cpufreq="))
+        })
+        .and_then(|option| Governor::from_name(&option["cpufreq=".len()..]))
+        .unwrap_or(Governor::Ondemand);
+
+    let now = bsp::uptime_usec();
+    let mut r = &POLICY;
+    let registered = r.lock(|policy| {
+        policy.governor = governor;
+        policy.register(bsp::cpufreq(), now)
+    });
+
+    match registered {
+        Ok(()) => {
+            let (min, max) = limits();
+            crate::info!(
+                "{} governor, {}-{} MHz",
+                governor,
+                min / 1_000_000,
+                max / 1_000_000
+            );
+
+            if let Err(e) = update() {
+                crate::warn!("Applying the {} governor failed: {:?}", governor, e);
+            }
+        }
+        Err(e) => crate::warn!("No CPU frequency scaling: {:?}", e),
+    }
+}
+
+/// Switch governors. Performance and powersave take effect right away, ondemand at its next
+/// sample.
+pub fn set_governor(governor: Governor) -> Result<()> {
+    let mut r = &POLICY;
+    let previous = r.lock(|policy| core::mem::replace(&mut policy.governor, governor));
+
+    if previous != governor {
+        crate::info!("Governor changed from {} to {}", previous, governor);
+    }
+
+    update()
+}
+
+pub fn governor() -> Governor {
+    let mut r = &POLICY;
+    r.lock(|policy| policy.governor)
+}
+
+/// Rate the cores were last set to, in Hz
+pub fn current() -> u32 {
+    let mut r = &POLICY;
+    r.lock(|policy| policy.current)
+}
+
+/// Lowest and highest rate the cores can run at, in Hz
+pub fn limits() -> (u32, u32) {
+    let mut r = &POLICY;
+    r.lock(|policy| (policy.min, policy.max))
+}
+
+/// Number of rate changes made since boot
+pub fn transitions() -> u32 {
+    let mut r = &POLICY;
+    r.lock(|policy| policy.transitions)
+}
+
+/// The cores are about to wait for work
+pub fn idle_enter() {
+    let now = bsp::uptime_usec();
+
+    let mut r = &POLICY;
+    r.lock(|policy| policy.idle_enter(now));
+}
+
+/// The wait is over
+pub fn idle_exit() {
+    let now = bsp::uptime_usec();
+
+    let mut r = &POLICY;
+    r.lock(|policy| policy.idle_exit(now));
+}
+
+/// Sample the load and let the governor pick a rate. Cheap to call often, nothing happens until
+/// a sampling period has passed.
+pub fn update() -> Result<()> {
+    let now = bsp::uptime_usec();
+
+    let mut r = &POLICY;
+    r.lock(|policy| policy.update(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeClock {
+        rate: NullLock<u32>,
+    }
+
+    impl CpuFreq for FakeClock {
+        fn current(&self) -> Result<u32> {
+            let mut r = &self.rate;
+            Ok(r.lock(|rate| *rate))
+        }
+
+        fn limits(&self) -> Result<(u32, u32)> {
+            Ok((600_000_000, 1_200_000_000))
+        }
+
+        fn set(&self, hz: u32) -> Result<u32> {
+            let mut r = &self.rate;
+            Ok(r.lock(|rate| {
+                *rate = hz.max(600_000_000).min(1_200_000_000);
+                *rate
+            }))
+        }
+    }
+
+    #[test]
+    fn ondemand_scales_with_load() {
+        assert_eq!(ondemand_target(0, 600, 1200), 600);
+        assert_eq!(ondemand_target(50, 600, 1200), 900);
+        assert_eq!(ondemand_target(UP_THRESHOLD, 600, 1200), 1200);
+        assert_eq!(ondemand_target(100, 600, 1200), 1200);
+    }
+
+    #[test]
+    fn load_counts_time_outside_idle() {
+        let mut policy = Policy::new();
+
+        policy.idle_enter(10_000);
+        policy.idle_exit(40_000);
+        assert_eq!(policy.sample(50_000), None);
+
+        // Idle from 90ms on, still idle when the sample is taken
+        policy.idle_enter(90_000);
+        assert_eq!(policy.sample(100_000), Some(60));
+
+        // The rest of that idle period belongs to the next sample
+        policy.idle_exit(150_000);
+        assert_eq!(policy.sample(200_000), Some(50));
+    }
+
+    #[test]
+    fn governors_are_parsed_by_name() {
+        assert_eq!(Governor::from_name("powersave"), Some(Governor::Powersave));
+        assert_eq!(Governor::from_name("ondemand"), Some(Governor::Ondemand));
+        assert_eq!(Governor::from_name("turbo"), None);
+        assert_eq!(Governor::Performance.to_string(), "performance");
+    }
+
+    #[test]
+    fn transitions_are_counted() {
+        static CLOCK: FakeClock = FakeClock {
+            rate: NullLock::new(1_000_000_000),
+        };
+
+        let mut policy = Policy::new();
+        policy.governor = Governor::Powersave;
+        policy.register(&CLOCK, 0).unwrap();
+
+        policy.update(0).unwrap();
+        assert_eq!(policy.current, 600_000_000);
+
+        // Already there, nothing to do
+        policy.update(0).unwrap();
+        policy.governor = Governor::Performance;
+        policy.update(0).unwrap();
+
+        assert_eq!(policy.current, 1_200_000_000);
+        assert_eq!(policy.transitions, 2);
+    }
+}
@@ -0,0 +1,41 @@
+//! File systems, on top of the block devices from `block`.
+//!
+//! `init` builds the kernel's directory tree: a tmpfs at "/" and the BSP's character devices
+//! under "/dev". Everything is reached through `vfs()`.
+
+pub mod devfs;
+pub mod fat;
+pub mod tmpfs;
+mod vfs;
+
+pub use vfs::{
+    DirEntry, Error, Fd, FileSystem, FileType, Ino, Metadata, OpenFlags, Result, SeekFrom, Vfs,
+};
+
+use crate::bsp;
+
+static VFS: Vfs = Vfs::new();
+static ROOT_FS: tmpfs::TmpFs = tmpfs::TmpFs::new();
+static DEV_FS: devfs::DevFs = devfs::DevFs::new();
+
+/// Mount the root and device file systems
+pub fn init() {
+    let mounted = VFS
+        .mount("/", &ROOT_FS)
+        .and_then(|_| VFS.create_dir("/dev"))
+        .and_then(|_| VFS.mount("/dev", &DEV_FS));
+
+    if let Err(e) = mounted {
+        panic!("Setting up the root file system failed: {:?}", e);
+    }
+
+    for &(name, device) in bsp::char_devices().iter() {
+        if let Err(e) = DEV_FS.register(name, device) {
+            crate::warn!("Not adding /dev/{}: {:?}", name, e);
+        }
+    }
+}
+
+pub fn vfs() -> &'static Vfs {
+    &VFS
+}
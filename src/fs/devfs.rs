@@ -0,0 +1,227 @@
+//! Character devices as files, usually mounted at /dev.
+//!
+//! A flat directory: inode 0 is the root, inode `n` is the `n`th registered device. Devices are
+//! streams, so file offsets are ignored.
+
+use super::vfs::{DirEntry, Error, FileSystem, FileType, Ino, Metadata, Result};
+use crate::{arch::sync::NullLock, interface};
+use interface::driver::CharDevice;
+
+const MAX_DEVICES: usize = 16;
+
+const ROOT: Ino = 0;
+
+type Device = (&'static str, &'static dyn CharDevice);
+
+struct DevFsInner {
+    devices: [Option<Device>; MAX_DEVICES],
+}
+
+impl DevFsInner {
+    const fn new() -> DevFsInner {
+        DevFsInner {
+            devices: [None; MAX_DEVICES],
+        }
+    }
+
+    fn device(&self, ino: Ino) -> Result<Device> {
+        match (ino as usize)
+            .checked_sub(1)
+            .and_then(|i| self.devices.get(i))
+        {
+            Some(&Some(device)) => Ok(device),
+            _ if ino == ROOT => Err(Error::IsADirectory),
+            _ => Err(Error::NotFound),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct DevFs {
+    inner: NullLock<DevFsInner>,
+}
+
+impl DevFs {
+    pub const fn new() -> DevFs {
+        DevFs {
+            inner: NullLock::new(DevFsInner::new()),
+        }
+    }
+
+    /// Publish `device` as the file `name`
+    pub fn register(&self, name: &'static str, device: &'static dyn CharDevice) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            if inner.devices.iter().flatten().any(|&(n, _)| n == name) {
+                return Err(Error::Exists);
+            }
+
+            let slot = inner
+                .devices
+                .iter_mut()
+                .find(|d| d.is_none())
+                .ok_or(Error::NoSpace)?;
+            *slot = Some((name, device));
+
+            Ok(())
+        })
+    }
+
+    fn device(&self, ino: Ino) -> Result<&'static dyn CharDevice> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.device(ino).map(|(_, device)| device))
+    }
+}
+
+impl FileSystem for DevFs {
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        if dir != ROOT {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner
+                .devices
+                .iter()
+                .position(|d| d.map_or(false, |(n, _)| n == name))
+                .map(|i| i as Ino + 1)
+                .ok_or(Error::NotFound)
+        })
+    }
+
+    fn metadata(&self, ino: Ino) -> Result<Metadata> {
+        let kind = match self.device(ino) {
+            Ok(_) => FileType::CharDevice,
+            Err(Error::IsADirectory) => FileType::Directory,
+            Err(e) => return Err(e),
+        };
+
+        Ok(Metadata { ino, kind, size: 0 })
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.device(ino)?.read(offset, buf))
+    }
+
+    fn write(&self, ino: Ino, _offset: u64, buf: &[u8]) -> Result<usize> {
+        Ok(self.device(ino)?.write(buf))
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        if dir != ROOT {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let entry = inner
+                .devices
+                .iter()
+                .enumerate()
+                .filter_map(|(i, d)| d.map(|(name, _)| (i, name)))
+                .nth(index);
+
+            match entry {
+                Some((i, name)) => {
+                    DirEntry::new(name, i as Ino + 1, FileType::CharDevice).map(Some)
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// The size of a device is meaningless, opening with TRUNCATE is fine
+    fn truncate(&self, ino: Ino, _size: u64) -> Result<()> {
+        self.device(ino).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{OpenFlags, Vfs};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Reads return 'z's, counts the bytes written
+    struct Sink {
+        written: AtomicUsize,
+    }
+
+    impl interface::driver::DeviceDriver for Sink {
+        fn compatible(&self) -> &str {
+            "Sink"
+        }
+    }
+
+    impl CharDevice for Sink {
+        fn read(&self, _offset: u64, buf: &mut [u8]) -> usize {
+            buf.iter_mut().for_each(|b| *b = b'z');
+            buf.len()
+        }
+
+        fn write(&self, buf: &[u8]) -> usize {
+            self.written.fetch_add(buf.len(), Ordering::Relaxed);
+            buf.len()
+        }
+    }
+
+    fn sink() -> &'static Sink {
+        Box::leak(Box::new(Sink {
+            written: AtomicUsize::new(0),
+        }))
+    }
+
+    #[test]
+    fn devices_are_listed() {
+        let devfs = DevFs::new();
+        devfs.register("null", sink()).unwrap();
+        devfs.register("zero", sink()).unwrap();
+
+        assert_eq!(devfs.register("null", sink()), Err(Error::Exists));
+        assert_eq!(devfs.lookup(ROOT, "zero"), Ok(2));
+        assert_eq!(devfs.lookup(2, "zero"), Err(Error::NotADirectory));
+        assert_eq!(devfs.metadata(2).unwrap().kind, FileType::CharDevice);
+        assert_eq!(devfs.metadata(3), Err(Error::NotFound));
+
+        let mut names = Vec::new();
+        while let Some(entry) = devfs.read_dir(ROOT, names.len()).unwrap() {
+            names.push(String::from(entry.name()));
+        }
+        assert_eq!(names, ["null", "zero"]);
+    }
+
+    #[test]
+    fn files_reach_the_device() {
+        let devfs: &'static DevFs = Box::leak(Box::new(DevFs::new()));
+        let device = sink();
+        devfs.register("sink", device).unwrap();
+
+        let vfs = Vfs::new();
+        vfs.mount("/", devfs).unwrap();
+
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::TRUNCATE;
+        let fd = vfs.open("/sink", flags).unwrap();
+        assert_eq!(vfs.write(fd, b"hello"), Ok(5));
+        assert_eq!(vfs.write(fd, b"!"), Ok(1));
+        assert_eq!(device.written.load(Ordering::Relaxed), 6);
+
+        let mut buf = [0; 3];
+        assert_eq!(vfs.read(fd, &mut buf), Ok(3));
+        assert_eq!(&buf, b"zzz");
+
+        assert_eq!(vfs.open("/", OpenFlags::READ), Err(Error::IsADirectory));
+        assert_eq!(
+            vfs.open("/new", OpenFlags::CREATE),
+            Err(Error::NotSupported)
+        );
+    }
+}
@@ -0,0 +1,1772 @@
+//! FAT12/16/32 with long file names.
+//!
+//! Everything goes straight to the block device, a sector at a time. Mount on top of a
+//! `block::BufferCache` to avoid rereading the FAT for every cluster that gets followed.
+//!
+//! Paths are `/` separated and relative to the root whether or not they start with `/`. Names
+//! match case insensitively (ASCII only) against both the long and the 8.3 name of an entry.
+//! There is no clock to stamp entries with, new and modified entries get `DEFAULT_DATE`.
+
+use crate::{arch::sync::NullLock, interface};
+use core::char;
+use interface::block::{self, BlockDevice, BLOCK_SIZE};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// Cluster counts below these make a FAT12 or FAT16 volume, everything else is FAT32
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (BLOCK_SIZE / DIR_ENTRY_SIZE) as u32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a free record, and of the record after the last one in use
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+
+/// Case flags Windows NT keeps in byte 12 of a short entry for all-lowercase names
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Byte offsets of the 13 UCS-2 characters in a long name record
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_UNITS: usize = 255;
+const MAX_LFN_ENTRIES: usize = (MAX_NAME_UNITS + LFN_CHARS - 1) / LFN_CHARS;
+/// Long names are decoded to UTF-8, where a UCS-2 unit takes at most 3 bytes
+const MAX_NAME_BYTES: usize = MAX_NAME_UNITS * 3;
+
+/// 2020-01-01 in FAT date format
+const DEFAULT_DATE: u16 = ((2020 - 1980) << 9) | (1 << 5) | 1;
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Io(block::Error),
+    /// Block 0 doesn't hold a FAT boot sector we can handle
+    NotFat,
+    NotFound,
+    Exists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    /// The name can't be stored, it is empty, too long or has characters FAT doesn't allow
+    InvalidName,
+    /// No free clusters left
+    DiskFull,
+    /// The FAT12/16 root directory has a fixed number of entries
+    DirectoryFull,
+    /// Files are limited to 4GB
+    FileTooLarge,
+    /// A cluster chain points somewhere it can't
+    Corrupt,
+}
+
+type Result<T> = ::core::result::Result<T, Error>;
+
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Names
+////////////////////////////////////////////////////////////////////////////////
+
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&b)
+}
+
+/// Whether `name` can be stored at all
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && name.encode_utf16().count() <= MAX_NAME_UNITS
+        && name.chars().all(|c| c >= ' ' && !"\"*/:<>?\\|".contains(c))
+}
+
+/// The 8.3 form of `name` and its NT case flags, if it fits without a long name
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(0) => return None,
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+
+    let parts = [(base, 0, NT_LOWERCASE_BASE), (ext, 8, NT_LOWERCASE_EXT)];
+
+    for (part, offset, lowercase_flag) in parts.iter() {
+        let bytes = part.as_bytes();
+
+        if !bytes.iter().all(|&b| is_short_char(b)) {
+            return None;
+        }
+
+        // Only a part that is all one case can be described by the flags
+        let upper = bytes.iter().any(|b| b.is_ascii_uppercase());
+        let lower = bytes.iter().any(|b| b.is_ascii_lowercase());
+        if upper && lower {
+            return None;
+        }
+        if lower {
+            case |= lowercase_flag;
+        }
+
+        for (i, b) in bytes.iter().enumerate() {
+            short[offset + i] = b.to_ascii_uppercase();
+        }
+    }
+
+    Some((short, case))
+}
+
+/// Generated 8.3 alias of a long name, "BASE~N.EXT"
+fn numbered_short_name(name: &str, n: u32) -> [u8; 11] {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+
+    let clean = |c: char| match c {
+        ' ' | '.' => None,
+        c if c.is_ascii() && is_short_char(c as u8) => Some(c.to_ascii_uppercase() as u8),
+        _ => Some(b'_'),
+    };
+
+    let mut digits = [0u8; 10];
+    let mut digit_count = 0;
+    let mut value = n;
+    loop {
+        digits[digit_count] = b'0' + (value % 10) as u8;
+        digit_count += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+
+    let mut short = [b' '; 11];
+    let mut len = 0;
+
+    for b in base.chars().filter_map(clean).take(7 - digit_count) {
+        short[len] = b;
+        len += 1;
+    }
+
+    short[len] = b'~';
+    len += 1;
+    for &d in digits[..digit_count].iter().rev() {
+        short[len] = d;
+        len += 1;
+    }
+
+    for (i, b) in ext.chars().filter_map(clean).take(3).enumerate() {
+        short[8 + i] = b;
+    }
+
+    short
+}
+
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// Long name record `ord` (1 based) of `units`
+fn lfn_record(units: &[u16], ord: usize, last: bool, checksum: u8) -> [u8; DIR_ENTRY_SIZE] {
+    let mut record = [0; DIR_ENTRY_SIZE];
+
+    record[0] = ord as u8 | if last { LFN_LAST } else { 0 };
+    record[11] = ATTR_LONG_NAME;
+    record[13] = checksum;
+
+    // The name is terminated by a NUL and padded with 0xFFFF
+    let base = (ord - 1) * LFN_CHARS;
+    for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+        let unit = match base + i {
+            n if n < units.len() => units[n],
+            n if n == units.len() => 0,
+            _ => 0xFFFF,
+        };
+        write_u16(&mut record, offset, unit);
+    }
+
+    record
+}
+
+fn short_record(short: &[u8; 11], case: u8, attr: u8, cluster: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut record = [0; DIR_ENTRY_SIZE];
+
+    record[..11].copy_from_slice(short);
+    record[11] = attr;
+    record[12] = case;
+    write_u16(&mut record, 16, DEFAULT_DATE);
+    write_u16(&mut record, 18, DEFAULT_DATE);
+    write_u16(&mut record, 20, (cluster >> 16) as u16);
+    write_u16(&mut record, 24, DEFAULT_DATE);
+    write_u16(&mut record, 26, cluster as u16);
+
+    record
+}
+
+/// Collects the long name records that precede a short entry
+struct LongName {
+    units: [u16; MAX_LFN_ENTRIES * LFN_CHARS],
+    count: u8,
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    fn new() -> LongName {
+        LongName {
+            units: [0; MAX_LFN_ENTRIES * LFN_CHARS],
+            count: 0,
+            next: 0,
+            checksum: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.next = 0;
+    }
+
+    /// Records come last part first, anything out of order drops what we have so far
+    fn push(&mut self, record: &[u8]) {
+        let ord = record[0] & 0x1F;
+
+        if record[0] & LFN_LAST != 0 {
+            if ord == 0 || ord as usize > MAX_LFN_ENTRIES {
+                self.reset();
+                return;
+            }
+            self.count = ord;
+            self.checksum = record[13];
+        } else if self.count == 0 || ord == 0 || ord != self.next || record[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        let base = (ord as usize - 1) * LFN_CHARS;
+        for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+            self.units[base + i] = read_u16(record, offset);
+        }
+
+        self.next = ord - 1;
+    }
+
+    /// All parts were seen and they belong to `short`
+    fn complete(&self, short: &[u8]) -> bool {
+        self.count != 0 && self.next == 0 && lfn_checksum(short) == self.checksum
+    }
+
+    fn units<'a>(&'a self) -> impl Iterator<Item = u16> + 'a {
+        self.units[..self.count as usize * LFN_CHARS]
+            .iter()
+            .cloned()
+            .take_while(|&u| u != 0 && u != 0xFFFF)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Directories and files
+////////////////////////////////////////////////////////////////////////////////
+
+/// Location of a 32 byte directory record on disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Slot {
+    lba: u64,
+    offset: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DirStart {
+    /// The fixed root directory region of FAT12/16
+    Root,
+    Cluster(u32),
+}
+
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME_BYTES],
+    name_len: usize,
+    short: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    /// Record index of the short entry within its directory
+    index: u32,
+    /// Long name records in front of the short entry
+    lfn_count: u32,
+    slot: Slot,
+}
+
+impl DirEntry {
+    fn new(index: u32, slot: Slot, record: &[u8], long_name: Option<&LongName>) -> DirEntry {
+        let mut short = [0; 11];
+        short.copy_from_slice(&record[..11]);
+
+        let mut entry = DirEntry {
+            name: [0; MAX_NAME_BYTES],
+            name_len: 0,
+            short,
+            attr: record[11],
+            first_cluster: ((read_u16(record, 20) as u32) << 16) | read_u16(record, 26) as u32,
+            size: read_u32(record, 28),
+            index,
+            lfn_count: long_name.map_or(0, |l| l.count as u32),
+            slot,
+        };
+
+        match long_name {
+            Some(long_name) => {
+                for c in char::decode_utf16(long_name.units()) {
+                    entry.push_char(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+            }
+            None => {
+                let case = record[12];
+                let base = &short[..8];
+                let ext = &short[8..];
+
+                let parts = [
+                    (base, case & NT_LOWERCASE_BASE != 0),
+                    (ext, case & NT_LOWERCASE_EXT != 0),
+                ];
+
+                for (part, lowercase) in parts.iter() {
+                    if part[0] == b' ' {
+                        continue;
+                    }
+                    if part.len() == 3 {
+                        entry.push_char('.');
+                    }
+
+                    for (i, &b) in part.iter().enumerate().filter(|&(_, &b)| b != b' ') {
+                        // 0x05 stands in for a leading 0xE5, which would mark the entry deleted
+                        let b = if i == 0 && b == 0x05 { 0xE5 } else { b };
+                        let c = match b {
+                            b if !b.is_ascii() => char::REPLACEMENT_CHARACTER,
+                            b if *lowercase => b.to_ascii_lowercase() as char,
+                            b => b as char,
+                        };
+                        entry.push_char(c);
+                    }
+                }
+            }
+        }
+
+        entry
+    }
+
+    fn push_char(&mut self, c: char) {
+        if self.name_len + c.len_utf8() <= MAX_NAME_BYTES {
+            c.encode_utf8(&mut self.name[self.name_len..]);
+            self.name_len += c.len_utf8();
+        }
+    }
+
+    /// The long name if there is one, the 8.3 name otherwise
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Size in bytes, always 0 for directories
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name)
+            || short_name(name).map_or(false, |(short, _)| short == self.short)
+    }
+}
+
+/// Position in a directory listing, see `FileSystem::read_dir`
+pub struct Dir {
+    start: DirStart,
+    next: u32,
+}
+
+/// An open file. The handle holds no borrow of the file system, so it has to be passed back to
+/// the same `FileSystem` for reading and writing.
+pub struct File {
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    /// The short entry, updated when the size or first cluster change
+    entry: Slot,
+    /// Cluster holding `position` and its index in the chain, saves walking from the start
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl File {
+    fn new(entry: &DirEntry) -> File {
+        File {
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            position: 0,
+            entry: entry.slot,
+            cluster: entry.first_cluster,
+            cluster_index: 0,
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Move to `position`, clamped to the end of the file. Returns the new position.
+    pub fn seek(&mut self, position: u32) -> u32 {
+        self.position = position.min(self.size);
+        self.position
+    }
+}
+
+/// Split a path into its parent directory and last component
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// File system
+////////////////////////////////////////////////////////////////////////////////
+
+/// Free space bookkeeping, mirrored to the FAT32 FSInfo sector on `sync`
+struct FsInner {
+    next_free: u32,
+    free_count: Option<u32>,
+    fsinfo_dirty: bool,
+}
+
+use interface::sync::Mutex;
+
+pub struct FileSystem<'a> {
+    device: &'a dyn BlockDevice,
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_lba: u64,
+    fat_sectors: u64,
+    fat_count: u32,
+    root_lba: u64,
+    root_sectors: u32,
+    root_cluster: u32,
+    data_lba: u64,
+    cluster_count: u32,
+    fsinfo_lba: Option<u64>,
+    inner: NullLock<FsInner>,
+}
+
+impl<'a> FileSystem<'a> {
+    pub fn mount(device: &'a dyn BlockDevice) -> Result<FileSystem<'a>> {
+        let mut sector = [0; BLOCK_SIZE];
+        device.read_blocks(0, &mut sector)?;
+
+        if sector[510..512] != BOOT_SIGNATURE {
+            return Err(Error::NotFat);
+        }
+
+        let bytes_per_sector = read_u16(&sector, 11) as usize;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = read_u16(&sector, 14) as u64;
+        let fat_count = sector[16] as u32;
+        let root_entries = read_u16(&sector, 17) as u32;
+        let total = match read_u16(&sector, 19) {
+            0 => read_u32(&sector, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match read_u16(&sector, 22) {
+            0 => read_u32(&sector, 36) as u64,
+            n => n as u64,
+        };
+
+        if bytes_per_sector != BLOCK_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(Error::NotFat);
+        }
+
+        let root_sectors =
+            (root_entries * DIR_ENTRY_SIZE as u32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        let root_lba = reserved + fat_count as u64 * fat_sectors;
+        let data_lba = root_lba + root_sectors as u64;
+
+        if total <= data_lba {
+            return Err(Error::NotFat);
+        }
+
+        // The cluster count alone decides the FAT type
+        let cluster_count = ((total - data_lba) / sectors_per_cluster as u64) as u32;
+        let fat_type = match cluster_count {
+            n if n < FAT12_MAX_CLUSTERS => FatType::Fat12,
+            n if n < FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        let mut fs = FileSystem {
+            device,
+            fat_type,
+            sectors_per_cluster,
+            fat_lba: reserved,
+            fat_sectors,
+            fat_count,
+            root_lba,
+            root_sectors,
+            root_cluster: 0,
+            data_lba,
+            cluster_count,
+            fsinfo_lba: None,
+            inner: NullLock::new(FsInner {
+                next_free: 2,
+                free_count: None,
+                fsinfo_dirty: false,
+            }),
+        };
+
+        let (last_offset, width) = fs.fat_location(cluster_count + 1);
+        if (last_offset + width) as u64 > fat_sectors * BLOCK_SIZE as u64 {
+            return Err(Error::NotFat);
+        }
+
+        if fat_type == FatType::Fat32 {
+            fs.root_cluster = read_u32(&sector, 44);
+            if !fs.valid_cluster(fs.root_cluster) {
+                return Err(Error::NotFat);
+            }
+
+            let fsinfo_lba = read_u16(&sector, 48) as u64;
+            if fsinfo_lba != 0 && fsinfo_lba < reserved {
+                device.read_blocks(fsinfo_lba, &mut sector)?;
+
+                if read_u32(&sector, 0) == FSINFO_LEAD_SIGNATURE
+                    && read_u32(&sector, 484) == FSINFO_STRUCT_SIGNATURE
+                {
+                    let free = read_u32(&sector, 488);
+                    let next = read_u32(&sector, 492);
+                    let mut r = &fs.inner;
+                    r.lock(|inner| {
+                        if free <= cluster_count {
+                            inner.free_count = Some(free);
+                        }
+                        if next >= 2 && next < cluster_count + 2 {
+                            inner.next_free = next;
+                        }
+                    });
+
+                    fs.fsinfo_lba = Some(fsinfo_lba);
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Cluster size in bytes
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    /// Number of free clusters. Counting them means reading the whole FAT, which only happens
+    /// once, and not at all for FAT32 volumes with a valid FSInfo sector.
+    pub fn free_clusters(&self) -> Result<u32> {
+        let mut r = &self.inner;
+        if let Some(count) = r.lock(|inner| inner.free_count) {
+            return Ok(count);
+        }
+
+        let mut count = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                count += 1;
+            }
+        }
+
+        r.lock(|inner| inner.free_count = Some(count));
+
+        Ok(count)
+    }
+
+    /// Write back the FSInfo sector and flush the device
+    pub fn sync(&self) -> Result<()> {
+        let mut r = &self.inner;
+        let (dirty, free_count, next_free) =
+            r.lock(|inner| (inner.fsinfo_dirty, inner.free_count, inner.next_free));
+
+        if let (Some(lba), true) = (self.fsinfo_lba, dirty) {
+            let mut sector = [0; BLOCK_SIZE];
+            self.device.read_blocks(lba, &mut sector)?;
+            write_u32(&mut sector, 488, free_count.unwrap_or(FSINFO_UNKNOWN));
+            write_u32(&mut sector, 492, next_free);
+            self.device.write_blocks(lba, &sector)?;
+
+            r.lock(|inner| inner.fsinfo_dirty = false);
+        }
+
+        self.device.flush()?;
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // FAT
+    ////////////////////////////////////////////////////////////////////////////
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_lba + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+
+    /// Byte offset of the FAT entry for `cluster` and how many bytes it touches
+    fn fat_location(&self, cluster: u32) -> (usize, usize) {
+        let cluster = cluster as usize;
+
+        match self.fat_type {
+            FatType::Fat12 => (cluster + cluster / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Read the FAT sectors holding an entry, FAT12 entries may straddle two
+    fn read_fat_sectors(
+        &self,
+        lba: u64,
+        offset: usize,
+        width: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let count = if offset + width > BLOCK_SIZE { 2 } else { 1 };
+        self.device
+            .read_blocks(lba, &mut buf[..count * BLOCK_SIZE])?;
+
+        Ok(count)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let (offset, width) = self.fat_location(cluster);
+        let lba = self.fat_lba + (offset / BLOCK_SIZE) as u64;
+        let offset = offset % BLOCK_SIZE;
+        let mut sectors = [0; 2 * BLOCK_SIZE];
+
+        self.read_fat_sectors(lba, offset, width, &mut sectors)?;
+
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster & 1 == 1 => (read_u16(&sectors, offset) >> 4) as u32,
+            FatType::Fat12 => (read_u16(&sectors, offset) & 0xFFF) as u32,
+            FatType::Fat16 => read_u16(&sectors, offset) as u32,
+            FatType::Fat32 => read_u32(&sectors, offset) & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Update the entry for `cluster` in every copy of the FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        let (offset, width) = self.fat_location(cluster);
+        let mut sectors = [0; 2 * BLOCK_SIZE];
+
+        for copy in 0..self.fat_count as u64 {
+            let lba = self.fat_lba + copy * self.fat_sectors + (offset / BLOCK_SIZE) as u64;
+            let offset = offset % BLOCK_SIZE;
+            let count = self.read_fat_sectors(lba, offset, width, &mut sectors)?;
+
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let old = read_u16(&sectors, offset);
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000F) | ((value as u16) << 4)
+                    } else {
+                        (old & 0xF000) | (value as u16 & 0x0FFF)
+                    };
+                    write_u16(&mut sectors, offset, new);
+                }
+                FatType::Fat16 => write_u16(&mut sectors, offset, value as u16),
+                // The top four bits are reserved and must be preserved
+                FatType::Fat32 => {
+                    let old = read_u32(&sectors, offset);
+                    write_u32(
+                        &mut sectors,
+                        offset,
+                        (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+                    );
+                }
+            }
+
+            self.device
+                .write_blocks(lba, &sectors[..count * BLOCK_SIZE])?;
+        }
+
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+
+        if next >= self.end_of_chain() - 7 {
+            Ok(None)
+        } else if self.valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::Corrupt)
+        }
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<()> {
+        let zero = [0; BLOCK_SIZE];
+        let lba = self.cluster_lba(cluster);
+
+        for i in 0..self.sectors_per_cluster as u64 {
+            self.device.write_blocks(lba + i, &zero)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take a free cluster, link it behind `previous` unless that is 0
+    fn allocate(&self, previous: u32, zero: bool) -> Result<u32> {
+        let mut r = &self.inner;
+        let start = r.lock(|inner| inner.next_free);
+
+        let mut found = None;
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (start - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(Error::DiskFull)?;
+
+        self.set_fat_entry(cluster, self.end_of_chain())?;
+        if previous != 0 {
+            self.set_fat_entry(previous, cluster)?;
+        }
+
+        let next_free = if cluster + 1 < self.cluster_count + 2 {
+            cluster + 1
+        } else {
+            2
+        };
+        r.lock(|inner| {
+            inner.next_free = next_free;
+            inner.free_count = inner.free_count.map(|n| n.saturating_sub(1));
+            inner.fsinfo_dirty = true;
+        });
+
+        if zero {
+            self.zero_cluster(cluster)?;
+        }
+
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<()> {
+        let mut cluster = first;
+        let mut freed = 0;
+
+        loop {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            freed += 1;
+
+            match next {
+                Some(n) => cluster = n,
+                None => break,
+            }
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.free_count = inner.free_count.map(|n| n + freed);
+            inner.fsinfo_dirty = true;
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Directory records
+    ////////////////////////////////////////////////////////////////////////////
+
+    fn root(&self) -> DirStart {
+        match self.fat_type {
+            FatType::Fat32 => DirStart::Cluster(self.root_cluster),
+            _ => DirStart::Root,
+        }
+    }
+
+    /// ".." entries and directories in the root refer to it as cluster 0
+    fn dir_start(&self, cluster: u32) -> DirStart {
+        if cluster == 0 {
+            self.root()
+        } else {
+            DirStart::Cluster(cluster)
+        }
+    }
+
+    /// Call `f` on every 32 byte record of `dir` from index `start` on, until it returns true.
+    /// Returns whether `f` stopped the walk.
+    fn for_each_record(
+        &self,
+        dir: DirStart,
+        start: u32,
+        mut f: impl FnMut(u32, Slot, &[u8]) -> bool,
+    ) -> Result<bool> {
+        let mut sector = [0; BLOCK_SIZE];
+        let mut sector_index = start / ENTRIES_PER_SECTOR;
+        let mut cluster = match dir {
+            DirStart::Root => 0,
+            DirStart::Cluster(c) => c,
+        };
+        let mut cluster_index = 0;
+
+        loop {
+            let lba = match dir {
+                DirStart::Root if sector_index < self.root_sectors => {
+                    self.root_lba + sector_index as u64
+                }
+                DirStart::Root => return Ok(false),
+                DirStart::Cluster(_) => {
+                    while cluster_index < sector_index / self.sectors_per_cluster {
+                        match self.next_cluster(cluster)? {
+                            Some(next) => cluster = next,
+                            None => return Ok(false),
+                        }
+                        cluster_index += 1;
+                    }
+
+                    self.cluster_lba(cluster) + (sector_index % self.sectors_per_cluster) as u64
+                }
+            };
+
+            self.device.read_blocks(lba, &mut sector)?;
+
+            for (i, record) in sector.chunks(DIR_ENTRY_SIZE).enumerate() {
+                let index = sector_index * ENTRIES_PER_SECTOR + i as u32;
+                let slot = Slot {
+                    lba,
+                    offset: i * DIR_ENTRY_SIZE,
+                };
+
+                if index >= start && f(index, slot, record) {
+                    return Ok(true);
+                }
+            }
+
+            sector_index += 1;
+        }
+    }
+
+    /// Call `f` on every file and directory in `dir` from record `start` on, until it returns
+    /// true. Long names are put together on the way, volume labels are skipped.
+    fn for_each_entry(
+        &self,
+        dir: DirStart,
+        start: u32,
+        mut f: impl FnMut(&DirEntry) -> bool,
+    ) -> Result<bool> {
+        let mut long_name = LongName::new();
+        let mut ended = false;
+
+        let stopped = self.for_each_record(dir, start, |index, slot, record| {
+            match record[0] {
+                ENTRY_END => {
+                    ended = true;
+                    return true;
+                }
+                ENTRY_DELETED => {
+                    long_name.reset();
+                    return false;
+                }
+                _ => {}
+            }
+
+            if record[11] & 0x3F == ATTR_LONG_NAME {
+                long_name.push(record);
+                return false;
+            }
+
+            if record[11] & ATTR_VOLUME_ID != 0 {
+                long_name.reset();
+                return false;
+            }
+
+            let long = if long_name.complete(record) {
+                Some(&long_name)
+            } else {
+                None
+            };
+            let entry = DirEntry::new(index, slot, record, long);
+            long_name.reset();
+
+            f(&entry)
+        })?;
+
+        Ok(stopped && !ended)
+    }
+
+    fn find(&self, dir: DirStart, name: &str) -> Result<Option<DirEntry>> {
+        let mut found = None;
+
+        self.for_each_entry(dir, 0, |entry| {
+            if entry.matches(name) {
+                found = Some(*entry);
+            }
+            found.is_some()
+        })?;
+
+        Ok(found)
+    }
+
+    fn update_record(&self, slot: Slot, f: impl FnOnce(&mut [u8])) -> Result<()> {
+        let mut sector = [0; BLOCK_SIZE];
+
+        self.device.read_blocks(slot.lba, &mut sector)?;
+        f(&mut sector[slot.offset..slot.offset + DIR_ENTRY_SIZE]);
+        self.device.write_blocks(slot.lba, &sector)?;
+
+        Ok(())
+    }
+
+    fn slot_at(&self, dir: DirStart, index: u32) -> Result<Slot> {
+        let mut found = None;
+
+        self.for_each_record(dir, index, |_, slot, _| {
+            found = Some(slot);
+            true
+        })?;
+
+        found.ok_or(Error::Corrupt)
+    }
+
+    fn short_name_taken(&self, dir: DirStart, short: &[u8; 11]) -> Result<bool> {
+        let mut ended = false;
+
+        let taken = self.for_each_record(dir, 0, |_, _, record| {
+            if record[0] == ENTRY_END {
+                ended = true;
+                return true;
+            }
+
+            record[0] != ENTRY_DELETED
+                && record[11] & 0x3F != ATTR_LONG_NAME
+                && record[..11] == short[..]
+        })?;
+
+        Ok(taken && !ended)
+    }
+
+    /// Index of the first run of `count` free records in `dir`, growing the directory by a
+    /// cluster when there is none
+    fn find_free_records(&self, dir: DirStart, count: u32) -> Result<u32> {
+        loop {
+            let mut run_start = 0;
+            let mut run = 0;
+
+            let found = self.for_each_record(dir, 0, |index, _, record| {
+                if record[0] == ENTRY_END || record[0] == ENTRY_DELETED {
+                    if run == 0 {
+                        run_start = index;
+                    }
+                    run += 1;
+                } else {
+                    run = 0;
+                }
+
+                run == count
+            })?;
+
+            if found {
+                return Ok(run_start);
+            }
+
+            let mut last = match dir {
+                DirStart::Root => return Err(Error::DirectoryFull),
+                DirStart::Cluster(first) => first,
+            };
+            while let Some(next) = self.next_cluster(last)? {
+                last = next;
+            }
+
+            // Zeroed, so the new records read as the end of the directory
+            self.allocate(last, true)?;
+        }
+    }
+
+    /// Add an entry for `name` to `dir`, with long name records if it doesn't fit 8.3
+    fn create_entry(&self, dir: DirStart, name: &str, attr: u8, cluster: u32) -> Result<DirEntry> {
+        if !valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+
+        if self.find(dir, name)?.is_some() {
+            return Err(Error::Exists);
+        }
+
+        let mut units = [0; MAX_NAME_UNITS];
+        let mut unit_count = 0;
+        for unit in name.encode_utf16() {
+            units[unit_count] = unit;
+            unit_count += 1;
+        }
+
+        let (short, case, lfn_count) = match short_name(name) {
+            Some((short, case)) => (short, case, 0),
+            None => {
+                let mut n = 1;
+                let short = loop {
+                    let candidate = numbered_short_name(name, n);
+                    if !self.short_name_taken(dir, &candidate)? {
+                        break candidate;
+                    }
+                    n += 1;
+                };
+
+                (short, 0, (unit_count + LFN_CHARS - 1) / LFN_CHARS)
+            }
+        };
+
+        let start = self.find_free_records(dir, lfn_count as u32 + 1)?;
+        let checksum = lfn_checksum(&short);
+
+        for i in 0..lfn_count {
+            let record = lfn_record(&units[..unit_count], lfn_count - i, i == 0, checksum);
+            let slot = self.slot_at(dir, start + i as u32)?;
+            self.update_record(slot, |r| r.copy_from_slice(&record))?;
+        }
+
+        let record = short_record(&short, case, attr, cluster);
+        let slot = self.slot_at(dir, start + lfn_count as u32)?;
+        self.update_record(slot, |r| r.copy_from_slice(&record))?;
+
+        self.find(dir, name)?.ok_or(Error::Corrupt)
+    }
+
+    fn resolve_dir(&self, path: &str) -> Result<DirStart> {
+        let mut dir = self.root();
+
+        for name in path.split('/').filter(|&c| !c.is_empty() && c != ".") {
+            // The root has no ".." entry
+            if name == ".." && dir == self.root() {
+                continue;
+            }
+
+            let entry = self.find(dir, name)?.ok_or(Error::NotFound)?;
+            if !entry.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+
+            dir = self.dir_start(entry.first_cluster);
+        }
+
+        Ok(dir)
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Public operations
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// The entry `path` refers to. The root has none.
+    pub fn metadata(&self, path: &str) -> Result<DirEntry> {
+        let (parent, name) = split_path(path);
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+
+        let dir = self.resolve_dir(parent)?;
+
+        self.find(dir, name)?.ok_or(Error::NotFound)
+    }
+
+    /// Start listing the directory at `path`, including its "." and ".." entries
+    pub fn read_dir(&self, path: &str) -> Result<Dir> {
+        Ok(Dir {
+            start: self.resolve_dir(path)?,
+            next: 0,
+        })
+    }
+
+    /// The next entry of a listing, None once it is exhausted
+    pub fn next_entry(&self, dir: &mut Dir) -> Result<Option<DirEntry>> {
+        let mut found = None;
+
+        self.for_each_entry(dir.start, dir.next, |entry| {
+            found = Some(*entry);
+            true
+        })?;
+
+        if let Some(entry) = &found {
+            dir.next = entry.index + 1;
+        }
+
+        Ok(found)
+    }
+
+    pub fn open(&self, path: &str) -> Result<File> {
+        let entry = self.metadata(path)?;
+
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+
+        Ok(File::new(&entry))
+    }
+
+    /// Create an empty file, failing if `path` already exists
+    pub fn create(&self, path: &str) -> Result<File> {
+        let (parent, name) = split_path(path);
+        let dir = self.resolve_dir(parent)?;
+        let entry = self.create_entry(dir, name, ATTR_ARCHIVE, 0)?;
+
+        Ok(File::new(&entry))
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        let (parent, name) = split_path(path);
+        let dir = self.resolve_dir(parent)?;
+
+        // Check before allocating so a bad name doesn't cost a cluster
+        if !valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+        if self.find(dir, name)?.is_some() {
+            return Err(Error::Exists);
+        }
+
+        let cluster = self.allocate(0, true)?;
+        if let Err(e) = self.create_entry(dir, name, ATTR_DIRECTORY, cluster) {
+            self.free_chain(cluster)?;
+            return Err(e);
+        }
+
+        let parent_cluster = match dir {
+            DirStart::Cluster(c) if dir != self.root() => c,
+            _ => 0,
+        };
+
+        let mut dot = [b' '; 11];
+        dot[0] = b'.';
+        let dot_record = short_record(&dot, 0, ATTR_DIRECTORY, cluster);
+        dot[1] = b'.';
+        let dotdot_record = short_record(&dot, 0, ATTR_DIRECTORY, parent_cluster);
+
+        let lba = self.cluster_lba(cluster);
+        self.update_record(Slot { lba, offset: 0 }, |r| r.copy_from_slice(&dot_record))?;
+        self.update_record(
+            Slot {
+                lba,
+                offset: DIR_ENTRY_SIZE,
+            },
+            |r| r.copy_from_slice(&dotdot_record),
+        )?;
+
+        Ok(())
+    }
+
+    /// Delete a file or an empty directory and free its clusters
+    pub fn remove(&self, path: &str) -> Result<()> {
+        let entry = self.metadata(path)?;
+        let (parent, _) = split_path(path);
+        let dir = self.resolve_dir(parent)?;
+
+        if entry.name() == "." || entry.name() == ".." {
+            return Err(Error::InvalidName);
+        }
+
+        if entry.is_dir() {
+            let mut empty = true;
+            self.for_each_entry(self.dir_start(entry.first_cluster), 0, |e| {
+                empty = e.name() == "." || e.name() == "..";
+                !empty
+            })?;
+
+            if !empty {
+                return Err(Error::NotEmpty);
+            }
+        }
+
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+
+        for index in entry.index - entry.lfn_count..=entry.index {
+            let slot = self.slot_at(dir, index)?;
+            self.update_record(slot, |r| r[0] = ENTRY_DELETED)?;
+        }
+
+        Ok(())
+    }
+
+    /// Cluster `index` of `file`, extending the chain if `grow` is set
+    fn file_cluster(&self, file: &mut File, index: u32, grow: bool) -> Result<Option<u32>> {
+        if file.first_cluster == 0 {
+            if !grow {
+                return Ok(None);
+            }
+
+            file.first_cluster = self.allocate(0, false)?;
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+
+        if index < file.cluster_index {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+
+        while file.cluster_index < index {
+            file.cluster = match self.next_cluster(file.cluster)? {
+                Some(next) => next,
+                None if grow => self.allocate(file.cluster, false)?,
+                None => return Ok(None),
+            };
+            file.cluster_index += 1;
+        }
+
+        Ok(Some(file.cluster))
+    }
+
+    /// Block holding byte `position` of `file`
+    fn file_block(&self, file: &mut File, position: u32, grow: bool) -> Result<u64> {
+        let cluster_size = self.cluster_size();
+        let cluster = self
+            .file_cluster(file, position / cluster_size, grow)?
+            .ok_or(Error::Corrupt)?;
+
+        Ok(self.cluster_lba(cluster) + ((position % cluster_size) as usize / BLOCK_SIZE) as u64)
+    }
+
+    /// Read from the current position, returns the number of bytes read, 0 at the end
+    pub fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min((file.size - file.position) as usize);
+        let mut done = 0;
+        let mut sector = [0; BLOCK_SIZE];
+
+        while done < len {
+            let lba = self.file_block(file, file.position, false)?;
+            let offset = file.position as usize % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - offset).min(len - done);
+
+            if n == BLOCK_SIZE {
+                self.device.read_blocks(lba, &mut buf[done..done + n])?;
+            } else {
+                self.device.read_blocks(lba, &mut sector)?;
+                buf[done..done + n].copy_from_slice(&sector[offset..offset + n]);
+            }
+
+            done += n;
+            file.position += n as u32;
+        }
+
+        Ok(done)
+    }
+
+    /// Write at the current position, growing the file as needed
+    pub fn write(&self, file: &mut File, buf: &[u8]) -> Result<usize> {
+        if file.position as u64 + buf.len() as u64 > u32::max_value() as u64 {
+            return Err(Error::FileTooLarge);
+        }
+
+        let first_cluster = file.first_cluster;
+        let mut done = 0;
+        let mut sector = [0; BLOCK_SIZE];
+
+        let result = loop {
+            if done == buf.len() {
+                break Ok(done);
+            }
+
+            let lba = match self.file_block(file, file.position, true) {
+                Ok(lba) => lba,
+                Err(e) => break Err(e),
+            };
+            let offset = file.position as usize % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - offset).min(buf.len() - done);
+
+            let written = if n == BLOCK_SIZE {
+                self.device.write_blocks(lba, &buf[done..done + n])
+            } else {
+                // Partial block, keep what is around the new data
+                self.device.read_blocks(lba, &mut sector).and_then(|_| {
+                    sector[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+                    self.device.write_blocks(lba, &sector)
+                })
+            };
+            if let Err(e) = written {
+                break Err(e.into());
+            }
+
+            done += n;
+            file.position += n as u32;
+        };
+
+        // Record whatever made it to disk, even if the write stopped early
+        if file.position > file.size || file.first_cluster != first_cluster {
+            file.size = file.size.max(file.position);
+
+            let (cluster, size) = (file.first_cluster, file.size);
+            self.update_record(file.entry, |r| {
+                write_u16(r, 20, (cluster >> 16) as u16);
+                write_u16(r, 24, DEFAULT_DATE);
+                write_u16(r, 26, cluster as u16);
+                write_u32(r, 28, size);
+                r[11] |= ATTR_ARCHIVE;
+            })?;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::mock::DiskImage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LABEL: &str = "RASPIOS";
+
+    fn blocks(fat_type: FatType) -> usize {
+        match fat_type {
+            FatType::Fat12 => 2880,
+            FatType::Fat16 => 32768,
+            // Just over the 65525 clusters FAT32 needs with 512 byte clusters
+            FatType::Fat32 => 70000,
+        }
+    }
+
+    /// A fresh image from `mkfs.fat`, only for the ignored tests that need dosfstools
+    fn mkfs(fat_type: FatType) -> DiskImage {
+        static IMAGES: AtomicUsize = AtomicUsize::new(0);
+
+        let bits = match fat_type {
+            FatType::Fat12 => "12",
+            FatType::Fat16 => "16",
+            FatType::Fat32 => "32",
+        };
+        let path = std::env::temp_dir().join(format!(
+            "fat{}-{}-{}.img",
+            bits,
+            std::process::id(),
+            IMAGES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let kilobytes = (blocks(fat_type) / 2).to_string();
+
+        let output = std::process::Command::new("mkfs.fat")
+            .args(&["-C", "-F", bits, "-n", LABEL])
+            .arg(&path)
+            .arg(&kilobytes)
+            .output()
+            .expect("running mkfs.fat, install dosfstools");
+        assert!(output.status.success(), "mkfs.fat failed: {:?}", output);
+
+        let image = DiskImage::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        image
+    }
+
+    /// An empty file system laid out by hand with the parameters mkfs.fat picks for these
+    /// sizes, so the tests run without dosfstools. `matches_mkfs_fat` checks the two agree.
+    fn format(fat_type: FatType) -> DiskImage {
+        let blocks = blocks(fat_type);
+        let (sectors_per_cluster, reserved, root_entries, entry_bits) = match fat_type {
+            FatType::Fat12 => (1, 1, 224, 12),
+            FatType::Fat16 => (4, 4, 512, 16),
+            FatType::Fat32 => (1, 32, 0, 32),
+        };
+        let root_sectors = root_entries * DIR_ENTRY_SIZE / BLOCK_SIZE;
+
+        // Smallest FAT that covers every cluster
+        let mut fat_sectors = 1;
+        let clusters = loop {
+            let clusters =
+                (blocks - reserved - 2 * fat_sectors - root_sectors) / sectors_per_cluster;
+            if (clusters + 2) * entry_bits <= fat_sectors * BLOCK_SIZE * 8 {
+                break clusters;
+            }
+            fat_sectors += 1;
+        };
+
+        let image = DiskImage::new(blocks);
+        let mut boot = [0; BLOCK_SIZE];
+        boot[..11].copy_from_slice(b"\xEB\x3C\x90mkfs.fat");
+        write_u16(&mut boot, 11, BLOCK_SIZE as u16);
+        boot[13] = sectors_per_cluster as u8;
+        write_u16(&mut boot, 14, reserved as u16);
+        boot[16] = 2;
+        write_u16(&mut boot, 17, root_entries as u16);
+        if blocks < 0x10000 {
+            write_u16(&mut boot, 19, blocks as u16);
+        } else {
+            write_u32(&mut boot, 32, blocks as u32);
+        }
+        boot[21] = 0xF8;
+        boot[510..512].copy_from_slice(&BOOT_SIGNATURE);
+
+        let label = b"RASPIOS    ";
+        let mut fat = vec![0xF8, 0xFF, 0xFF, 0xFF];
+        let root_lba = reserved + 2 * fat_sectors;
+
+        if fat_type == FatType::Fat32 {
+            write_u32(&mut boot, 36, fat_sectors as u32);
+            write_u32(&mut boot, 44, 2);
+            write_u16(&mut boot, 48, 1);
+            write_u16(&mut boot, 50, 6);
+            boot[66] = 0x29;
+            boot[71..82].copy_from_slice(label);
+            boot[82..90].copy_from_slice(b"FAT32   ");
+
+            let mut fsinfo = [0; BLOCK_SIZE];
+            write_u32(&mut fsinfo, 0, FSINFO_LEAD_SIGNATURE);
+            write_u32(&mut fsinfo, 484, FSINFO_STRUCT_SIGNATURE);
+            write_u32(&mut fsinfo, 488, clusters as u32 - 1);
+            write_u32(&mut fsinfo, 492, 3);
+            fsinfo[510..512].copy_from_slice(&BOOT_SIGNATURE);
+            image.write_at(BLOCK_SIZE, &fsinfo);
+            image.write_at(6 * BLOCK_SIZE, &boot);
+
+            // Entry 1 and the root directory's cluster 2
+            fat = vec![
+                0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F,
+            ];
+        } else {
+            write_u16(&mut boot, 22, fat_sectors as u16);
+            boot[38] = 0x29;
+            boot[43..54].copy_from_slice(label);
+            boot[54..62].copy_from_slice(if fat_type == FatType::Fat12 {
+                b"FAT12   "
+            } else {
+                b"FAT16   "
+            });
+
+            if fat_type == FatType::Fat12 {
+                fat.truncate(3);
+            }
+        }
+
+        image.write_at(0, &boot);
+        for copy in 0..2 {
+            image.write_at((reserved + copy * fat_sectors) * BLOCK_SIZE, &fat);
+        }
+
+        let mut volume = [0; DIR_ENTRY_SIZE];
+        volume[..11].copy_from_slice(label);
+        volume[11] = ATTR_VOLUME_ID;
+        image.write_at(root_lba * BLOCK_SIZE, &volume);
+
+        image
+    }
+
+    fn names(fs: &FileSystem, path: &str) -> Vec<String> {
+        let mut dir = fs.read_dir(path).unwrap();
+        let mut names = Vec::new();
+
+        while let Some(entry) = fs.next_entry(&mut dir).unwrap() {
+            names.push(entry.name().to_string());
+        }
+
+        names
+    }
+
+    #[test]
+    fn mounts_every_fat_type() {
+        for &fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32].iter() {
+            let image = format(fat_type);
+            let fs = FileSystem::mount(&image).unwrap();
+
+            assert_eq!(fs.fat_type(), fat_type);
+            assert!(fs.free_clusters().unwrap() > 0);
+            // The volume label is not a file
+            assert!(names(&fs, "/").is_empty());
+        }
+
+        assert_eq!(
+            FileSystem::mount(&DiskImage::new(16)).err(),
+            Some(Error::NotFat)
+        );
+    }
+
+    #[test]
+    fn reads_files_it_did_not_write() {
+        let image = format(FatType::Fat12);
+        let fs = FileSystem::mount(&image).unwrap();
+        let cluster_size = fs.cluster_size() as usize;
+
+        // "readme.txt" as Linux writes it: an 8.3 name with the lowercase flags, over two
+        // clusters that are not adjacent
+        let case = NT_LOWERCASE_BASE | NT_LOWERCASE_EXT;
+        let mut record = short_record(b"README  TXT", case, ATTR_ARCHIVE, 5);
+        let size = cluster_size + 100;
+        write_u32(&mut record, 28, size as u32);
+        image.write_at(fs.root_lba as usize * BLOCK_SIZE + DIR_ENTRY_SIZE, &record);
+        fs.set_fat_entry(5, 9).unwrap();
+        fs.set_fat_entry(9, 0xFFF).unwrap();
+
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        image.write_at(
+            fs.cluster_lba(5) as usize * BLOCK_SIZE,
+            &data[..cluster_size],
+        );
+        image.write_at(
+            fs.cluster_lba(9) as usize * BLOCK_SIZE,
+            &data[cluster_size..],
+        );
+
+        assert_eq!(names(&fs, "/"), ["readme.txt"]);
+
+        let mut file = fs.open("/README.TXT").unwrap();
+        let mut buf = vec![0; size + 10];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), size);
+        assert_eq!(&buf[..size], &data[..]);
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn long_names() {
+        let image = format(FatType::Fat16);
+        let fs = FileSystem::mount(&image).unwrap();
+
+        fs.create("A long file name.txt").unwrap();
+        fs.create("A long file name 2.txt").unwrap();
+        fs.create("config.txt").unwrap();
+
+        assert_eq!(
+            names(&fs, "/"),
+            [
+                "A long file name.txt",
+                "A long file name 2.txt",
+                "config.txt"
+            ]
+        );
+        assert!(fs.open("a LONG file NAME.txt").is_ok());
+        assert!(fs.open("ALONGF~2.TXT").is_ok());
+        assert_eq!(fs.create("CONFIG.TXT").err(), Some(Error::Exists));
+        assert_eq!(fs.create("bad:name").err(), Some(Error::InvalidName));
+
+        // Label, two long name records, the alias. The last long name record comes first.
+        let root = fs.root_lba as usize * BLOCK_SIZE;
+        let bytes = image.bytes();
+        let record = |i: usize| &bytes[root + i * DIR_ENTRY_SIZE..root + (i + 1) * DIR_ENTRY_SIZE];
+        assert_eq!(record(1)[0], LFN_LAST | 2);
+        assert_eq!(record(2)[0], 1);
+        assert_eq!(&record(3)[..11], b"ALONGF~1TXT");
+        assert_eq!(record(1)[13], lfn_checksum(b"ALONGF~1TXT"));
+        // "config.txt" needs no long name
+        assert_eq!(&record(7)[..11], b"CONFIG  TXT");
+        assert_eq!(record(7)[12], NT_LOWERCASE_BASE | NT_LOWERCASE_EXT);
+    }
+
+    #[test]
+    fn write_and_read_back_in_subdirectories() {
+        let image = format(FatType::Fat32);
+        let data: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+
+        {
+            let fs = FileSystem::mount(&image).unwrap();
+            fs.create_dir("/logs").unwrap();
+            fs.create_dir("/logs/old").unwrap();
+
+            let mut file = fs.create("/logs/boot.log").unwrap();
+            for chunk in data.chunks(700) {
+                assert_eq!(fs.write(&mut file, chunk).unwrap(), chunk.len());
+            }
+            assert_eq!(file.len(), 3000);
+
+            // Overwrite in the middle without growing
+            file.seek(1000);
+            fs.write(&mut file, &[0xAA; 10]).unwrap();
+            assert_eq!(file.len(), 3000);
+
+            fs.sync().unwrap();
+        }
+
+        let fs = FileSystem::mount(&image).unwrap();
+        assert_eq!(names(&fs, "/logs"), [".", "..", "old", "boot.log"]);
+        assert_eq!(names(&fs, "/logs/old/../.."), ["logs"]);
+        assert_eq!(fs.open("/logs").err(), Some(Error::IsADirectory));
+        assert_eq!(
+            fs.read_dir("/logs/boot.log").err(),
+            Some(Error::NotADirectory)
+        );
+
+        let mut file = fs.open("logs/boot.log").unwrap();
+        let mut buf = vec![0; 4096];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 3000);
+        assert_eq!(&buf[..1000], &data[..1000]);
+        assert_eq!(&buf[1000..1010], &[0xAA; 10]);
+        assert_eq!(&buf[1010..3000], &data[1010..]);
+
+        // Both FATs were kept in sync
+        let fat = fs.fat_lba as usize * BLOCK_SIZE;
+        let fat_size = fs.fat_sectors as usize * BLOCK_SIZE;
+        let bytes = image.bytes();
+        assert_eq!(
+            bytes[fat..fat + fat_size],
+            bytes[fat + fat_size..fat + 2 * fat_size]
+        );
+    }
+
+    #[test]
+    fn remove_frees_clusters() {
+        let image = format(FatType::Fat32);
+        let fs = FileSystem::mount(&image).unwrap();
+        let free = fs.free_clusters().unwrap();
+
+        fs.create_dir("/tmp").unwrap();
+        let mut file = fs.create("/tmp/a file with a long name").unwrap();
+        fs.write(&mut file, &[1; 2000]).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 1 - 4);
+
+        assert_eq!(fs.remove("/tmp").err(), Some(Error::NotEmpty));
+        fs.remove("/tmp/A FILE WITH A LONG NAME").unwrap();
+        fs.remove("/tmp").unwrap();
+
+        assert_eq!(fs.free_clusters().unwrap(), free);
+        assert_eq!(
+            fs.open("/tmp/a file with a long name").err(),
+            Some(Error::NotFound)
+        );
+        assert!(names(&fs, "/").is_empty());
+
+        // The count survives a remount through FSInfo
+        fs.sync().unwrap();
+        assert_eq!(
+            FileSystem::mount(&image).unwrap().free_clusters().unwrap(),
+            free
+        );
+    }
+
+    #[test]
+    fn fixed_root_directory_fills_up() {
+        let image = format(FatType::Fat12);
+        let fs = FileSystem::mount(&image).unwrap();
+        let capacity = fs.root_sectors * ENTRIES_PER_SECTOR;
+
+        // One record is taken by the volume label
+        for i in 0..capacity - 1 {
+            fs.create(&format!("F{}", i)).unwrap();
+        }
+
+        assert_eq!(fs.create("ONEMORE").err(), Some(Error::DirectoryFull));
+    }
+
+    // Needs mkfs.fat from dosfstools, run with `cargo test ... -- --ignored`
+    #[test]
+    #[ignore]
+    fn matches_mkfs_fat() {
+        for &fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32].iter() {
+            let real = mkfs(fat_type);
+            let fs = FileSystem::mount(&real).unwrap();
+            let ours = format(fat_type);
+            let expected = FileSystem::mount(&ours).unwrap();
+
+            assert_eq!(fs.fat_type(), fat_type);
+            assert_eq!(fs.cluster_size(), expected.cluster_size());
+            assert_eq!(fs.fat_lba, expected.fat_lba);
+            assert_eq!(fs.fat_sectors, expected.fat_sectors);
+            assert_eq!(fs.root_lba, expected.root_lba);
+            assert_eq!(
+                fs.free_clusters().unwrap(),
+                expected.free_clusters().unwrap()
+            );
+            assert!(names(&fs, "/").is_empty());
+
+            let mut file = fs.create("A long file name.txt").unwrap();
+            fs.write(&mut file, &[0x5A; 3000]).unwrap();
+            fs.sync().unwrap();
+
+            let fs = FileSystem::mount(&real).unwrap();
+            let mut file = fs.open("a long file name.txt").unwrap();
+            let mut buf = vec![0; 4096];
+            assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 3000);
+            assert_eq!(&buf[..3000], &[0x5A; 3000][..]);
+        }
+    }
+}
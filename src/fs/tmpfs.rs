@@ -0,0 +1,439 @@
+//! File system kept entirely in RAM, gone on reboot.
+//!
+//! Without a heap, nodes and file data come from fixed pools. Inode 0 is the root directory,
+//! inode `n` is node `n - 1`. File data lives in pool blocks, which are zeroed when freed so
+//! holes and grown files read back as zeroes.
+
+use super::vfs::{DirEntry, Error, FileSystem, FileType, Ino, Metadata, Result};
+use crate::{arch::sync::NullLock, interface};
+use core::str;
+
+/// Files and directories, not counting the root
+const MAX_NODES: usize = 64;
+
+const NAME_LEN: usize = 32;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Blocks shared by all files
+const POOL_BLOCKS: usize = 128;
+
+/// Largest file is MAX_FILE_BLOCKS * BLOCK_SIZE bytes
+const MAX_FILE_BLOCKS: usize = 32;
+
+/// Block index of a hole, reads as zeroes
+const NO_BLOCK: u16 = u16::MAX;
+
+const ROOT: Ino = 0;
+
+#[derive(Clone, Copy)]
+struct Node {
+    used: bool,
+    kind: FileType,
+    parent: Ino,
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    size: usize,
+    blocks: [u16; MAX_FILE_BLOCKS],
+}
+
+const EMPTY_NODE: Node = Node {
+    used: false,
+    kind: FileType::File,
+    parent: ROOT,
+    name: [0; NAME_LEN],
+    name_len: 0,
+    size: 0,
+    blocks: [NO_BLOCK; MAX_FILE_BLOCKS],
+};
+
+impl Node {
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+}
+
+struct TmpFsInner {
+    nodes: [Node; MAX_NODES],
+    data: [[u8; BLOCK_SIZE]; POOL_BLOCKS],
+    block_used: [bool; POOL_BLOCKS],
+}
+
+impl TmpFsInner {
+    const fn new() -> TmpFsInner {
+        TmpFsInner {
+            nodes: [EMPTY_NODE; MAX_NODES],
+            data: [[0; BLOCK_SIZE]; POOL_BLOCKS],
+            block_used: [false; POOL_BLOCKS],
+        }
+    }
+
+    fn node(&self, ino: Ino) -> Result<&Node> {
+        match (ino as usize)
+            .checked_sub(1)
+            .and_then(|i| self.nodes.get(i))
+        {
+            Some(node) if node.used => Ok(node),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut Node> {
+        self.node(ino)?;
+
+        Ok(&mut self.nodes[ino as usize - 1])
+    }
+
+    fn kind(&self, ino: Ino) -> Result<FileType> {
+        if ino == ROOT {
+            Ok(FileType::Directory)
+        } else {
+            self.node(ino).map(|node| node.kind)
+        }
+    }
+
+    fn check_dir(&self, ino: Ino) -> Result<()> {
+        match self.kind(ino)? {
+            FileType::Directory => Ok(()),
+            _ => Err(Error::NotADirectory),
+        }
+    }
+
+    fn check_file(&self, ino: Ino) -> Result<&Node> {
+        let node = self.node(ino).map_err(|e| match ino {
+            ROOT => Error::IsADirectory,
+            _ => e,
+        })?;
+
+        match node.kind {
+            FileType::Directory => Err(Error::IsADirectory),
+            _ => Ok(node),
+        }
+    }
+
+    /// Inodes of the entries of `dir`, in creation slot order
+    fn children(&self, dir: Ino) -> impl Iterator<Item = Ino> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(_, node)| node.used && node.parent == dir)
+            .map(|(i, _)| i as Ino + 1)
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        self.check_dir(dir)?;
+
+        self.children(dir)
+            .find(|&ino| self.nodes[ino as usize - 1].name() == name)
+            .ok_or(Error::NotFound)
+    }
+
+    fn allocate_block(&mut self) -> Result<u16> {
+        let index = self
+            .block_used
+            .iter()
+            .position(|used| !used)
+            .ok_or(Error::NoSpace)?;
+
+        self.block_used[index] = true;
+
+        Ok(index as u16)
+    }
+
+    fn free_block(&mut self, block: u16) {
+        if block != NO_BLOCK {
+            self.data[block as usize] = [0; BLOCK_SIZE];
+            self.block_used[block as usize] = false;
+        }
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let node = self.check_file(ino)?;
+
+        let offset = offset as usize;
+        if offset >= node.size {
+            return Ok(0);
+        }
+
+        let count = buf.len().min(node.size - offset);
+        for (pos, byte) in (offset..).zip(buf[..count].iter_mut()) {
+            *byte = match node.blocks[pos / BLOCK_SIZE] {
+                NO_BLOCK => 0,
+                block => self.data[block as usize][pos % BLOCK_SIZE],
+            };
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize> {
+        self.check_file(ino)?;
+
+        let offset = offset as usize;
+        let max_size = MAX_FILE_BLOCKS * BLOCK_SIZE;
+        if offset >= max_size && !buf.is_empty() {
+            return Err(Error::NoSpace);
+        }
+
+        let mut count = 0;
+        for (pos, &byte) in (offset..max_size).zip(buf.iter()) {
+            let index = pos / BLOCK_SIZE;
+
+            let mut block = self.nodes[ino as usize - 1].blocks[index];
+            if block == NO_BLOCK {
+                block = match self.allocate_block() {
+                    Ok(block) => block,
+                    // Report a short write if anything made it in
+                    Err(e) if count == 0 => return Err(e),
+                    Err(_) => break,
+                };
+                self.nodes[ino as usize - 1].blocks[index] = block;
+            }
+
+            self.data[block as usize][pos % BLOCK_SIZE] = byte;
+            count += 1;
+        }
+
+        let node = self.node_mut(ino)?;
+        node.size = node.size.max(offset + count);
+
+        Ok(count)
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, kind: FileType) -> Result<Ino> {
+        if name.is_empty() || name.len() > NAME_LEN || name.contains('/') {
+            return Err(Error::InvalidPath);
+        }
+
+        if kind == FileType::CharDevice {
+            return Err(Error::NotSupported);
+        }
+
+        match self.lookup(dir, name) {
+            Ok(_) => return Err(Error::Exists),
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        let index = self
+            .nodes
+            .iter()
+            .position(|node| !node.used)
+            .ok_or(Error::NoSpace)?;
+
+        let node = &mut self.nodes[index];
+        *node = EMPTY_NODE;
+        node.used = true;
+        node.kind = kind;
+        node.parent = dir;
+        node.name[..name.len()].copy_from_slice(name.as_bytes());
+        node.name_len = name.len();
+
+        Ok(index as Ino + 1)
+    }
+
+    fn remove(&mut self, dir: Ino, name: &str) -> Result<()> {
+        let ino = self.lookup(dir, name)?;
+
+        if self.children(ino).next().is_some() {
+            return Err(Error::NotEmpty);
+        }
+
+        self.truncate(ino, 0).or_else(|e| match e {
+            Error::IsADirectory => Ok(()),
+            e => Err(e),
+        })?;
+        self.nodes[ino as usize - 1] = EMPTY_NODE;
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, ino: Ino, size: u64) -> Result<()> {
+        let node = *self.check_file(ino)?;
+
+        let size = size as usize;
+        if size > MAX_FILE_BLOCKS * BLOCK_SIZE {
+            return Err(Error::NoSpace);
+        }
+
+        // Whole blocks past the new end go back to the pool
+        let keep = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        for &block in &node.blocks[keep..] {
+            self.free_block(block);
+        }
+
+        // Zero the tail of the last block, so growing the file again reads zeroes
+        if size % BLOCK_SIZE != 0 && size < node.size {
+            let block = node.blocks[size / BLOCK_SIZE];
+            if block != NO_BLOCK {
+                for byte in self.data[block as usize][size % BLOCK_SIZE..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+
+        let node = self.node_mut(ino)?;
+        for block in node.blocks[keep..].iter_mut() {
+            *block = NO_BLOCK;
+        }
+        node.size = size;
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct TmpFs {
+    inner: NullLock<TmpFsInner>,
+}
+
+impl TmpFs {
+    pub const fn new() -> TmpFs {
+        TmpFs {
+            inner: NullLock::new(TmpFsInner::new()),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.lookup(dir, name))
+    }
+
+    fn metadata(&self, ino: Ino) -> Result<Metadata> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let kind = inner.kind(ino)?;
+            let size = match kind {
+                FileType::File => inner.node(ino)?.size as u64,
+                _ => 0,
+            };
+
+            Ok(Metadata { ino, kind, size })
+        })
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read(ino, offset, buf))
+    }
+
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write(ino, offset, buf))
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.check_dir(dir)?;
+
+            match inner.children(dir).nth(index) {
+                Some(ino) => {
+                    let node = inner.node(ino)?;
+                    DirEntry::new(node.name(), ino, node.kind).map(Some)
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn create(&self, dir: Ino, name: &str, kind: FileType) -> Result<Ino> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.create(dir, name, kind))
+    }
+
+    fn remove(&self, dir: Ino, name: &str) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.remove(dir, name))
+    }
+
+    fn truncate(&self, ino: Ino, size: u64) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.truncate(ino, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories_nest() {
+        let fs = TmpFs::new();
+
+        let dir = fs.create(ROOT, "etc", FileType::Directory).unwrap();
+        let file = fs.create(dir, "motd", FileType::File).unwrap();
+
+        assert_eq!(fs.lookup(ROOT, "etc"), Ok(dir));
+        assert_eq!(fs.lookup(dir, "motd"), Ok(file));
+        assert_eq!(fs.lookup(ROOT, "motd"), Err(Error::NotFound));
+        assert_eq!(fs.lookup(file, "x"), Err(Error::NotADirectory));
+        assert_eq!(fs.create(dir, "motd", FileType::File), Err(Error::Exists));
+        assert_eq!(
+            fs.create(ROOT, "a/b", FileType::File),
+            Err(Error::InvalidPath)
+        );
+
+        let entry = fs.read_dir(dir, 0).unwrap().unwrap();
+        assert_eq!((entry.name(), entry.ino), ("motd", file));
+        assert!(fs.read_dir(dir, 1).unwrap().is_none());
+
+        assert_eq!(fs.remove(ROOT, "etc"), Err(Error::NotEmpty));
+        fs.remove(dir, "motd").unwrap();
+        fs.remove(ROOT, "etc").unwrap();
+        assert!(fs.read_dir(ROOT, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn holes_and_truncated_tails_read_as_zeroes() {
+        let fs = TmpFs::new();
+        let file = fs.create(ROOT, "f", FileType::File).unwrap();
+
+        assert_eq!(fs.write(file, 1000, b"abc"), Ok(3));
+        assert_eq!(fs.metadata(file).unwrap().size, 1003);
+
+        let mut buf = [0xFF; 8];
+        assert_eq!(fs.read(file, 998, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"\0\0abc");
+
+        fs.truncate(file, 1001).unwrap();
+        fs.truncate(file, 1003).unwrap();
+        assert_eq!(fs.read(file, 1000, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"a\0\0");
+
+        // Only the written block came from the pool
+        let mut r = &fs.inner;
+        assert_eq!(
+            r.lock(|inner| inner.block_used.iter().filter(|&&u| u).count()),
+            1
+        );
+    }
+
+    #[test]
+    fn full_pool_gives_short_writes() {
+        let fs = TmpFs::new();
+        let data = [0x42; MAX_FILE_BLOCKS * BLOCK_SIZE];
+
+        for name in &["a", "b", "c", "d"] {
+            let file = fs.create(ROOT, name, FileType::File).unwrap();
+            assert_eq!(fs.write(file, 0, &data), Ok(data.len()));
+        }
+
+        let file = fs.create(ROOT, "e", FileType::File).unwrap();
+        assert_eq!(fs.write(file, 0, b"x"), Err(Error::NoSpace));
+
+        fs.remove(ROOT, "a").unwrap();
+        assert_eq!(fs.write(file, 0, &data), Ok(data.len()));
+        assert_eq!(fs.write(file, data.len() as u64, b"x"), Err(Error::NoSpace));
+    }
+}
@@ -0,0 +1,732 @@
+use crate::{arch::sync::NullLock, interface};
+use core::{fmt, ops, str};
+
+/// File systems mounted at the same time
+const MAX_MOUNTS: usize = 8;
+
+/// Files open at the same time, across the whole kernel
+const MAX_OPEN_FILES: usize = 32;
+
+/// Longest normalized path, including the leading '/'
+pub const PATH_MAX: usize = 128;
+
+/// Longest file name a directory entry can carry
+pub const NAME_MAX: usize = 64;
+
+/// Inode number, only meaningful within the file system that handed it out
+pub type Ino = u64;
+
+// Custom errors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    NotFound,
+    Exists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    /// Relative path, empty or too long component, or too long overall
+    InvalidPath,
+    /// Bad seek target or flag combination
+    InvalidArgument,
+    NoSpace,
+    TooManyOpenFiles,
+    BadDescriptor,
+    ReadOnly,
+    NotSupported,
+    /// Still mounted on or held open
+    Busy,
+    Io,
+}
+
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    pub ino: Ino,
+    pub kind: FileType,
+    /// Length in bytes, zero for directories and devices
+    pub size: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    name: [u8; NAME_MAX],
+    name_len: usize,
+    pub ino: Ino,
+    pub kind: FileType,
+}
+
+impl DirEntry {
+    pub fn new(name: &str, ino: Ino, kind: FileType) -> Result<DirEntry> {
+        if name.len() > NAME_MAX {
+            return Err(Error::InvalidPath);
+        }
+
+        let mut entry = DirEntry {
+            name: [0; NAME_MAX],
+            name_len: name.len(),
+            ino,
+            kind,
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        Ok(entry)
+    }
+
+    pub fn name(&self) -> &str {
+        // Only ever filled from a &str
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("name", &self.name())
+            .field("ino", &self.ino)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it doesn't exist yet
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Cut the file to zero length, needs WRITE
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the current end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Handle to an open file, valid until `close`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fd(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// What every mountable file system implements. Files are addressed by inode number, the VFS
+/// does the path walking and keeps the file positions.
+pub trait FileSystem: Sync {
+    fn root(&self) -> Ino;
+
+    /// Find `name` in the directory `dir`
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino>;
+
+    fn metadata(&self, ino: Ino) -> Result<Metadata>;
+
+    /// Read from `offset`, returns the number of bytes read, zero at the end of the file
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// Write at `offset`, growing the file as needed. Returns the number of bytes written.
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize>;
+
+    /// The `index`th entry of `dir`, `None` past the last one
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>>;
+
+    fn create(&self, _dir: Ino, _name: &str, _kind: FileType) -> Result<Ino> {
+        Err(Error::NotSupported)
+    }
+
+    fn remove(&self, _dir: Ino, _name: &str) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+
+    fn truncate(&self, _ino: Ino, _size: u64) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+}
+
+/// An absolute path with `.`, `..` and repeated slashes resolved
+struct NormalPath {
+    buf: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl NormalPath {
+    fn new(path: &str) -> Result<NormalPath> {
+        if !path.starts_with('/') {
+            return Err(Error::InvalidPath);
+        }
+
+        let mut normal = NormalPath {
+            buf: [0; PATH_MAX],
+            len: 0,
+        };
+
+        for component in path.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => {
+                    // Drop the last component, the root is its own parent
+                    let parent = normal.as_str().rfind('/').unwrap_or(0);
+                    normal.len = parent;
+                }
+                _ => {
+                    let end = normal.len + 1 + component.len();
+                    if component.len() > NAME_MAX || end > PATH_MAX {
+                        return Err(Error::InvalidPath);
+                    }
+
+                    normal.buf[normal.len] = b'/';
+                    normal.buf[normal.len + 1..end].copy_from_slice(component.as_bytes());
+                    normal.len = end;
+                }
+            }
+        }
+
+        if normal.len == 0 {
+            normal.buf[0] = b'/';
+            normal.len = 1;
+        }
+
+        Ok(normal)
+    }
+
+    fn as_str(&self) -> &str {
+        // Built from &str pieces split at ASCII slashes
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("/")
+    }
+
+    /// Split into the parent directory and the last component, `None` for the root
+    fn split_last(&self) -> Option<(&str, &str)> {
+        let path = self.as_str();
+        let slash = path.rfind('/')?;
+
+        match (&path[..slash], &path[slash + 1..]) {
+            (_, "") => None,
+            ("", name) => Some(("/", name)),
+            (parent, name) => Some((parent, name)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Mount {
+    path: [u8; PATH_MAX],
+    len: usize,
+    fs: &'static dyn FileSystem,
+}
+
+impl Mount {
+    fn path(&self) -> &str {
+        str::from_utf8(&self.path[..self.len]).unwrap_or("")
+    }
+
+    /// The rest of `path` below this mount point, `None` if it's not below it
+    fn strip<'p>(&self, path: &'p str) -> Option<&'p str> {
+        let mount = self.path();
+        if mount == "/" {
+            return Some(path);
+        }
+
+        if !path.starts_with(mount) {
+            return None;
+        }
+
+        let rest = &path[mount.len()..];
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    mount: usize,
+    ino: Ino,
+    offset: u64,
+    flags: OpenFlags,
+}
+
+struct VfsInner {
+    mounts: [Option<Mount>; MAX_MOUNTS],
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+impl VfsInner {
+    const fn new() -> VfsInner {
+        VfsInner {
+            mounts: [None; MAX_MOUNTS],
+            files: [None; MAX_OPEN_FILES],
+        }
+    }
+
+    /// Walk `path` from the deepest mount point above it, returns the mount index and inode
+    fn resolve(&self, path: &str) -> Result<(usize, Ino)> {
+        let (index, rest) = self
+            .mounts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| {
+                m.as_ref()
+                    .and_then(|m| m.strip(path).map(|rest| (i, m, rest)))
+            })
+            .max_by_key(|(_, m, _)| m.len)
+            .map(|(i, _, rest)| (i, rest))
+            .ok_or(Error::NotFound)?;
+
+        let fs = self.fs(index)?;
+        let mut ino = fs.root();
+
+        for name in rest.split('/').filter(|name| !name.is_empty()) {
+            if fs.metadata(ino)?.kind != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+
+            ino = fs.lookup(ino, name)?;
+        }
+
+        Ok((index, ino))
+    }
+
+    /// Resolve the parent of `path`, which has to be a directory
+    fn resolve_parent<'p>(&self, path: &'p NormalPath) -> Result<(usize, Ino, &'p str)> {
+        let (parent, name) = path.split_last().ok_or(Error::InvalidPath)?;
+        let (mount, dir) = self.resolve(parent)?;
+
+        if self.fs(mount)?.metadata(dir)?.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        Ok((mount, dir, name))
+    }
+
+    fn fs(&self, mount: usize) -> Result<&'static dyn FileSystem> {
+        self.mounts[mount].map(|m| m.fs).ok_or(Error::NotFound)
+    }
+
+    fn file(&mut self, fd: Fd) -> Result<&mut OpenFile> {
+        self.files
+            .get_mut(fd.0)
+            .and_then(|f| f.as_mut())
+            .ok_or(Error::BadDescriptor)
+    }
+
+    fn is_open(&self, mount: usize, ino: Ino) -> bool {
+        self.files
+            .iter()
+            .flatten()
+            .any(|f| f.mount == mount && f.ino == ino)
+    }
+
+    fn is_mount_point(&self, path: &str) -> bool {
+        self.mounts.iter().flatten().any(|m| m.path() == path)
+    }
+
+    fn mount(&mut self, path: &str, fs: &'static dyn FileSystem) -> Result<()> {
+        let path = NormalPath::new(path)?;
+
+        if self.is_mount_point(path.as_str()) {
+            return Err(Error::Busy);
+        }
+
+        // Everything but the first file system needs a directory to cover
+        if path.as_str() != "/" {
+            let (mount, ino) = self.resolve(path.as_str())?;
+            if self.fs(mount)?.metadata(ino)?.kind != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+        }
+
+        let slot = self
+            .mounts
+            .iter_mut()
+            .find(|m| m.is_none())
+            .ok_or(Error::NoSpace)?;
+
+        *slot = Some(Mount {
+            path: path.buf,
+            len: path.len,
+            fs,
+        });
+
+        Ok(())
+    }
+
+    fn unmount(&mut self, path: &str) -> Result<()> {
+        let path = NormalPath::new(path)?;
+
+        let index = self
+            .mounts
+            .iter()
+            .position(|m| m.map_or(false, |m| m.path() == path.as_str()))
+            .ok_or(Error::NotFound)?;
+
+        let nested = self
+            .mounts
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != index)
+            .filter_map(|(_, m)| m.as_ref())
+            .any(|m| self.mounts[index].map_or(false, |own| own.strip(m.path()).is_some()));
+
+        if nested || self.files.iter().flatten().any(|f| f.mount == index) {
+            return Err(Error::Busy);
+        }
+
+        self.mounts[index] = None;
+
+        Ok(())
+    }
+
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Fd> {
+        let path = NormalPath::new(path)?;
+
+        if flags.contains(OpenFlags::TRUNCATE) && !flags.contains(OpenFlags::WRITE) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let slot = self
+            .files
+            .iter()
+            .position(|f| f.is_none())
+            .ok_or(Error::TooManyOpenFiles)?;
+
+        let (mount, ino) = match self.resolve(path.as_str()) {
+            Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                let (mount, dir, name) = self.resolve_parent(&path)?;
+                (mount, self.fs(mount)?.create(dir, name, FileType::File)?)
+            }
+            result => result?,
+        };
+
+        let fs = self.fs(mount)?;
+        if fs.metadata(ino)?.kind == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
+
+        if flags.contains(OpenFlags::TRUNCATE) {
+            fs.truncate(ino, 0)?;
+        }
+
+        self.files[slot] = Some(OpenFile {
+            mount,
+            ino,
+            offset: 0,
+            flags,
+        });
+
+        Ok(Fd(slot))
+    }
+
+    fn read(&mut self, fd: Fd, buf: &mut [u8]) -> Result<usize> {
+        let file = *self.file(fd)?;
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(Error::BadDescriptor);
+        }
+
+        let count = self.fs(file.mount)?.read(file.ino, file.offset, buf)?;
+        self.file(fd)?.offset += count as u64;
+
+        Ok(count)
+    }
+
+    fn write(&mut self, fd: Fd, buf: &[u8]) -> Result<usize> {
+        let mut file = *self.file(fd)?;
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::BadDescriptor);
+        }
+
+        let fs = self.fs(file.mount)?;
+        if file.flags.contains(OpenFlags::APPEND) {
+            file.offset = fs.metadata(file.ino)?.size;
+        }
+
+        let count = fs.write(file.ino, file.offset, buf)?;
+        self.file(fd)?.offset = file.offset + count as u64;
+
+        Ok(count)
+    }
+
+    fn seek(&mut self, fd: Fd, pos: SeekFrom) -> Result<u64> {
+        let file = *self.file(fd)?;
+
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (file.offset, delta),
+            SeekFrom::End(delta) => (self.fs(file.mount)?.metadata(file.ino)?.size, delta),
+        };
+
+        let offset = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        } else {
+            base.checked_add(delta as u64)
+        }
+        .ok_or(Error::InvalidArgument)?;
+
+        self.file(fd)?.offset = offset;
+
+        Ok(offset)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// One directory tree over all mounted file systems, plus the table of open files
+pub struct Vfs {
+    inner: NullLock<VfsInner>,
+}
+
+impl Vfs {
+    pub const fn new() -> Vfs {
+        Vfs {
+            inner: NullLock::new(VfsInner::new()),
+        }
+    }
+
+    /// Attach `fs` at `path`, which must be an existing directory unless it is "/"
+    pub fn mount(&self, path: &str, fs: &'static dyn FileSystem) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.mount(path, fs))
+    }
+
+    /// Fails with `Busy` while files on it are open or other file systems are mounted below it
+    pub fn unmount(&self, path: &str) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.unmount(path))
+    }
+
+    pub fn open(&self, path: &str, flags: OpenFlags) -> Result<Fd> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.open(path, flags))
+    }
+
+    pub fn close(&self, fd: Fd) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.file(fd)?;
+            inner.files[fd.0] = None;
+
+            Ok(())
+        })
+    }
+
+    pub fn read(&self, fd: Fd, buf: &mut [u8]) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read(fd, buf))
+    }
+
+    pub fn write(&self, fd: Fd, buf: &[u8]) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write(fd, buf))
+    }
+
+    /// Move the file position, returns the new offset from the start. Seeking past the end is
+    /// allowed, a write there leaves a hole.
+    pub fn seek(&self, fd: Fd, pos: SeekFrom) -> Result<u64> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.seek(fd, pos))
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata> {
+        let path = NormalPath::new(path)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let (mount, ino) = inner.resolve(path.as_str())?;
+            inner.fs(mount)?.metadata(ino)
+        })
+    }
+
+    /// The `index`th entry of the directory at `path`, `None` past the last one
+    pub fn read_dir(&self, path: &str, index: usize) -> Result<Option<DirEntry>> {
+        let path = NormalPath::new(path)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let (mount, ino) = inner.resolve(path.as_str())?;
+            let fs = inner.fs(mount)?;
+
+            if fs.metadata(ino)?.kind != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+
+            fs.read_dir(ino, index)
+        })
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        let path = NormalPath::new(path)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let (mount, dir, name) = inner.resolve_parent(&path)?;
+            inner.fs(mount)?.create(dir, name, FileType::Directory)?;
+
+            Ok(())
+        })
+    }
+
+    /// Remove a file or an empty directory. Mount points and open files can't be removed.
+    pub fn remove(&self, path: &str) -> Result<()> {
+        let path = NormalPath::new(path)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            if inner.is_mount_point(path.as_str()) {
+                return Err(Error::Busy);
+            }
+
+            let (mount, ino) = inner.resolve(path.as_str())?;
+            if inner.is_open(mount, ino) {
+                return Err(Error::Busy);
+            }
+
+            let (mount, dir, name) = inner.resolve_parent(&path)?;
+            inner.fs(mount)?.remove(dir, name)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tmpfs::TmpFs;
+
+    fn tmpfs() -> &'static TmpFs {
+        Box::leak(Box::new(TmpFs::new()))
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        let normal = |path| NormalPath::new(path).map(|p| String::from(p.as_str()));
+
+        assert_eq!(normal("/"), Ok(String::from("/")));
+        assert_eq!(normal("//a/./b/"), Ok(String::from("/a/b")));
+        assert_eq!(normal("/a/../../b/.."), Ok(String::from("/")));
+        assert_eq!(normal("a/b"), Err(Error::InvalidPath));
+
+        let path = NormalPath::new("/a/b").unwrap();
+        assert_eq!(path.split_last(), Some(("/a", "b")));
+        assert_eq!(
+            NormalPath::new("/a").unwrap().split_last(),
+            Some(("/", "a"))
+        );
+        assert_eq!(NormalPath::new("/").unwrap().split_last(), None);
+    }
+
+    #[test]
+    fn files_are_read_back_through_descriptors() {
+        let vfs = Vfs::new();
+        vfs.mount("/", tmpfs()).unwrap();
+
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+        let fd = vfs.open("/hello", flags).unwrap();
+        assert_eq!(vfs.write(fd, b"hello world"), Ok(11));
+
+        let mut buf = [0; 16];
+        assert_eq!(vfs.seek(fd, SeekFrom::Start(6)), Ok(6));
+        assert_eq!(vfs.read(fd, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(vfs.read(fd, &mut buf), Ok(0));
+
+        assert_eq!(vfs.seek(fd, SeekFrom::End(-11)), Ok(0));
+        assert_eq!(
+            vfs.seek(fd, SeekFrom::Current(-1)),
+            Err(Error::InvalidArgument)
+        );
+        vfs.close(fd).unwrap();
+
+        assert_eq!(vfs.read(fd, &mut buf), Err(Error::BadDescriptor));
+        assert_eq!(vfs.metadata("/./hello").map(|m| m.size), Ok(11));
+        assert_eq!(vfs.open("/missing", OpenFlags::READ), Err(Error::NotFound));
+
+        let fd = vfs
+            .open("/hello", OpenFlags::WRITE | OpenFlags::APPEND)
+            .unwrap();
+        vfs.write(fd, b"!").unwrap();
+        assert_eq!(vfs.read(fd, &mut buf), Err(Error::BadDescriptor));
+        assert_eq!(vfs.metadata("/hello").map(|m| m.size), Ok(12));
+    }
+
+    #[test]
+    fn mounts_cover_directories() {
+        let vfs = Vfs::new();
+        let (root, inner) = (tmpfs(), tmpfs());
+
+        assert_eq!(vfs.mount("/mnt", inner), Err(Error::NotFound));
+        vfs.mount("/", root).unwrap();
+        vfs.create_dir("/mnt").unwrap();
+        vfs.mount("/mnt", inner).unwrap();
+        assert_eq!(vfs.mount("/mnt/", inner), Err(Error::Busy));
+
+        let fd = vfs
+            .open("/mnt/file", OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap();
+        assert!(inner.lookup(inner.root(), "file").is_ok());
+        assert_eq!(root.lookup(root.root(), "file"), Err(Error::NotFound));
+
+        // `..` is resolved on the path, so it leaves the mounted file system
+        assert_eq!(
+            vfs.metadata("/mnt/..").map(|m| m.kind),
+            Ok(FileType::Directory)
+        );
+        assert_eq!(vfs.read_dir("/mnt", 0).unwrap().unwrap().name(), "file");
+
+        assert_eq!(vfs.remove("/mnt/file"), Err(Error::Busy));
+        assert_eq!(vfs.unmount("/mnt"), Err(Error::Busy));
+        assert_eq!(vfs.unmount("/"), Err(Error::Busy));
+        vfs.close(fd).unwrap();
+
+        vfs.remove("/mnt/file").unwrap();
+        assert_eq!(vfs.remove("/mnt"), Err(Error::Busy));
+        vfs.unmount("/mnt").unwrap();
+        assert!(vfs.read_dir("/mnt", 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn descriptors_run_out() {
+        let vfs = Vfs::new();
+        vfs.mount("/", tmpfs()).unwrap();
+        vfs.open("/f", OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap();
+
+        for _ in 1..MAX_OPEN_FILES {
+            vfs.open("/f", OpenFlags::READ).unwrap();
+        }
+
+        assert_eq!(
+            vfs.open("/f", OpenFlags::READ),
+            Err(Error::TooManyOpenFiles)
+        );
+        assert_eq!(vfs.open("/", OpenFlags::READ), Err(Error::TooManyOpenFiles));
+
+        vfs.close(Fd(3)).unwrap();
+        assert_eq!(vfs.open("/", OpenFlags::READ), Err(Error::IsADirectory));
+        assert_eq!(vfs.open("/f", OpenFlags::READ), Ok(Fd(3)));
+    }
+}
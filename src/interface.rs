@@ -32,6 +32,11 @@ pub mod console {
         fn read_char(&self) -> char {
             ' '
         }
+
+        /// The next character if one has arrived, without waiting for it
+        fn try_read_char(&self) -> Option<char> {
+            None
+        }
     }
 
     pub trait Statistics {
@@ -67,5 +72,513 @@ pub mod driver {
         fn init(&self) -> Result {
             Ok(())
         }
+
+        // Called before the board is switched off, undoes `init`
+        fn shutdown(&self) -> Result {
+            Ok(())
+        }
+    }
+
+    /// Devices that move a stream of bytes. They live in statics and devfs exposes them as
+    /// files.
+    pub trait CharDevice: DeviceDriver + Sync {
+        /// Read into `buf` from `offset` bytes into the file, returns the number of bytes read.
+        /// Streams like the UARTs have no position and ignore `offset`.
+        fn read(&self, offset: u64, buf: &mut [u8]) -> usize;
+
+        /// Write out `buf`, returns the number of bytes taken
+        fn write(&self, buf: &[u8]) -> usize;
+    }
+}
+
+/// Block storage, e.g. an SD card.
+pub mod block {
+    /// Size of a block in bytes. Everything we deal with uses 512 byte sectors.
+    pub const BLOCK_SIZE: usize = 512;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// No medium in the device
+        NoMedia,
+        /// The device did not answer in time
+        Timeout,
+        /// The transfer failed (CRC error, bad response, ...)
+        Io,
+        /// Access past the end of the device
+        OutOfRange,
+        /// The buffer is not a whole number of blocks
+        BadBuffer,
+        /// The device is write protected
+        ReadOnly,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    pub trait BlockDevice {
+        /// Size of a block in bytes
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+
+        /// Number of `BLOCK_SIZE` blocks on the device
+        fn block_count(&self) -> u64;
+
+        /// Read `buf.len() / BLOCK_SIZE` blocks starting at `lba`
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()>;
+
+        /// Write `buf.len() / BLOCK_SIZE` blocks starting at `lba`
+        fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()>;
+
+        /// Make sure everything written so far has reached the medium
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+}
+
+/// SPI buses. Shaped after embedded-hal's `SpiBus`, so device drivers written against it don't
+/// care which controller they sit behind.
+pub mod spi {
+    /// Clock level while idle
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Polarity {
+        IdleLow,
+        IdleHigh,
+    }
+
+    /// Clock edge on which data is sampled
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Phase {
+        CaptureOnFirstTransition,
+        CaptureOnSecondTransition,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Mode {
+        pub polarity: Polarity,
+        pub phase: Phase,
+    }
+
+    /// CPOL = 0, CPHA = 0
+    pub const MODE_0: Mode = Mode {
+        polarity: Polarity::IdleLow,
+        phase: Phase::CaptureOnFirstTransition,
+    };
+
+    /// CPOL = 0, CPHA = 1
+    pub const MODE_1: Mode = Mode {
+        polarity: Polarity::IdleLow,
+        phase: Phase::CaptureOnSecondTransition,
+    };
+
+    /// CPOL = 1, CPHA = 0
+    pub const MODE_2: Mode = Mode {
+        polarity: Polarity::IdleHigh,
+        phase: Phase::CaptureOnFirstTransition,
+    };
+
+    /// CPOL = 1, CPHA = 1
+    pub const MODE_3: Mode = Mode {
+        polarity: Polarity::IdleHigh,
+        phase: Phase::CaptureOnSecondTransition,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Config {
+        /// Requested clock in Hz, the bus picks the closest rate not above it
+        pub frequency: u32,
+        pub mode: Mode,
+        /// Chip select line asserted during transfers
+        pub chip_select: u8,
+        pub cs_active_high: bool,
+    }
+
+    impl Config {
+        /// Chip select 0, active low
+        pub const fn new(frequency: u32, mode: Mode) -> Config {
+            Config {
+                frequency,
+                mode,
+                chip_select: 0,
+                cs_active_high: false,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// The controller stopped making progress
+        Timeout,
+        /// Chip select or frequency the controller can't do
+        InvalidConfig,
+        /// Read and write buffers must match in length for this kind of transfer
+        LengthMismatch,
+        /// More data than a single transfer can move
+        TooLong,
+        /// The DMA engine failed the transfer
+        Dma,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// A full-duplex SPI master. Chip select is asserted for the length of each call.
+    pub trait SpiBus {
+        /// Use `config` for the following transfers, returns the clock frequency actually set
+        fn configure(&self, config: &Config) -> Result<u32>;
+
+        /// Clock out `write` while clocking in `read`. If the lengths differ the longer one
+        /// wins, missing write bytes go out as zero and extra read bytes are dropped.
+        fn transfer(&self, read: &mut [u8], write: &[u8]) -> Result<()>;
+
+        /// Send `words` and replace them with what came back
+        fn transfer_in_place(&self, words: &mut [u8]) -> Result<()>;
+
+        /// Clock in `words`, sending zeroes
+        fn read(&self, words: &mut [u8]) -> Result<()> {
+            self.transfer(words, &[])
+        }
+
+        /// Send `words`, ignoring what comes back
+        fn write(&self, words: &[u8]) -> Result<()> {
+            self.transfer(&mut [], words)
+        }
+
+        /// Wait for anything still in flight
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+}
+
+/// I2C buses, master side.
+pub mod i2c {
+    /// Target address, without the R/W bit
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Address {
+        SevenBit(u8),
+        TenBit(u16),
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Config {
+        /// Requested SCL frequency in Hz, the bus picks the closest rate not above it
+        pub frequency: u32,
+        /// How long a target may hold SCL low before the transfer is abandoned
+        pub clock_stretch_timeout_us: u32,
+    }
+
+    impl Config {
+        /// 35ms stretch timeout, the SMBus limit
+        pub const fn new(frequency: u32) -> Config {
+            Config {
+                frequency,
+                clock_stretch_timeout_us: 35_000,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// Nobody acknowledged the address or a data byte
+        Nack,
+        /// The target held SCL low for longer than the stretch timeout
+        ClockStretchTimeout,
+        /// The controller stopped making progress
+        Timeout,
+        /// Address out of range for its width
+        InvalidAddress,
+        /// Frequency the controller can't do
+        InvalidConfig,
+        /// More data than a single transfer can move
+        TooLong,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// First and last 7-bit addresses that aren't reserved by the I2C spec
+    pub const SCAN_RANGE: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+    /// An I2C master. Every call is a complete transaction from START to STOP.
+    pub trait I2cBus {
+        /// Use `config` for the following transfers, returns the SCL frequency actually set
+        fn configure(&self, config: &Config) -> Result<u32>;
+
+        /// Send `write`, then issue a repeated START and fill `read`. Either may be empty, in
+        /// which case that half of the transaction is left out.
+        fn write_read(&self, address: Address, write: &[u8], read: &mut [u8]) -> Result<()>;
+
+        fn read(&self, address: Address, read: &mut [u8]) -> Result<()> {
+            self.write_read(address, &[], read)
+        }
+
+        fn write(&self, address: Address, write: &[u8]) -> Result<()> {
+            self.write_read(address, write, &mut [])
+        }
+
+        /// Whether anything acknowledges `address`. Reads a byte rather than sending a quick
+        /// write, which some devices take as a command.
+        fn probe(&self, address: u8) -> bool {
+            self.read(Address::SevenBit(address), &mut [0]).is_ok()
+        }
+
+        /// Call `found` with every 7-bit address in `SCAN_RANGE` that responds. Gives up on the
+        /// bus at the first timeout, an empty address NACKs straight away, so a timeout means
+        /// the bus or the controller is stuck and every further probe would wait it out too.
+        fn scan(&self, found: &mut dyn FnMut(u8)) {
+            for address in SCAN_RANGE {
+                match self.read(Address::SevenBit(address), &mut [0]) {
+                    Ok(()) => found(address),
+                    Err(Error::Timeout) | Err(Error::ClockStretchTimeout) => return,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    /// The other side of an `I2cBus`, for when an external master addresses us. Accesses follow
+    /// the usual register map convention: the first byte of a write selects a register, further
+    /// bytes go to it and the ones after it, and reads carry on from where the last access
+    /// stopped.
+    pub trait I2cTarget: Sync {
+        /// Value of `register`. Can be asked for before the master actually reads it, since the
+        /// controller has to have the byte queued by the time it is clocked out.
+        fn read_register(&self, register: u8) -> u8;
+
+        fn write_register(&self, register: u8, value: u8);
+    }
+}
+
+/// PCM audio streams.
+pub mod audio {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Format {
+        /// Frames per second
+        pub sample_rate: u32,
+        /// Samples per frame, interleaved
+        pub channels: u8,
+    }
+
+    impl Format {
+        pub const fn new(sample_rate: u32, channels: u8) -> Format {
+            Format {
+                sample_rate,
+                channels,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// Sample rate or channel count the hardware can't do
+        UnsupportedFormat,
+        /// The hardware stopped taking or producing samples
+        Timeout,
+        /// Samples were lost, the stream fell behind or the hardware dropped them
+        Underrun,
+        /// The DMA engine failed the transfer
+        Dma,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// Somewhere to play signed 16-bit samples
+    pub trait AudioSink {
+        /// Set up for `format`, returns the sample rate actually used
+        fn configure(&self, format: &Format) -> Result<u32>;
+
+        /// Queue interleaved `samples`, blocking until all of them are in the hardware
+        fn write(&self, samples: &[i16]) -> Result<()>;
+
+        /// Block until everything queued has been played
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Somewhere to record signed 16-bit samples from
+    pub trait AudioSource {
+        /// Set up for `format`, returns the sample rate actually used
+        fn configure(&self, format: &Format) -> Result<u32>;
+
+        /// Fill `samples` with interleaved frames, blocking until they have all arrived
+        fn read(&self, samples: &mut [i16]) -> Result<()>;
+    }
+}
+
+/// Scaling the clock of the CPU cores.
+pub mod cpufreq {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// Whatever owns the clock refused or failed the request
+        Firmware,
+        /// No clock has been registered yet
+        NoDriver,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// The clock all cores run from. Rates are in Hz.
+    pub trait CpuFreq: Sync {
+        fn current(&self) -> Result<u32>;
+
+        /// Lowest and highest rate `set` accepts
+        fn limits(&self) -> Result<(u32, u32)>;
+
+        /// Ask for `hz`, clamped to the limits. Returns the rate actually set.
+        fn set(&self, hz: u32) -> Result<u32>;
+    }
+}
+
+/// USB host controllers.
+pub mod usb {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Speed {
+        High,
+        Full,
+        Low,
+    }
+
+    /// Values as used in endpoint descriptors and by the controllers
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum TransferType {
+        Control = 0,
+        Isochronous = 1,
+        Bulk = 2,
+        Interrupt = 3,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Direction {
+        Out,
+        In,
+    }
+
+    /// The high speed hub port a full or low speed device hangs off, its transactions have to be
+    /// split by that hub
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Split {
+        pub hub_address: u8,
+        pub port: u8,
+    }
+
+    /// Everything the controller needs to know to talk to an endpoint
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Endpoint {
+        pub device: u8,
+        pub number: u8,
+        pub kind: TransferType,
+        /// Ignored for control endpoints, every stage says which way it goes
+        pub direction: Direction,
+        pub max_packet_size: u16,
+        pub speed: Speed,
+        pub split: Option<Split>,
+        /// Whether the next data packet is DATA1, flipped by every packet that goes through
+        pub toggle: bool,
+    }
+
+    impl Endpoint {
+        /// Endpoint 0 of `device`
+        pub const fn control(
+            device: u8,
+            max_packet_size: u16,
+            speed: Speed,
+            split: Option<Split>,
+        ) -> Endpoint {
+            Endpoint {
+                device,
+                number: 0,
+                kind: TransferType::Control,
+                direction: Direction::Out,
+                max_packet_size,
+                speed,
+                split,
+                toggle: false,
+            }
+        }
+    }
+
+    /// The 8 bytes sent in the setup stage of a control transfer
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct SetupPacket {
+        /// Bit 7 set for device to host, the data stage direction
+        pub request_type: u8,
+        pub request: u8,
+        pub value: u16,
+        pub index: u16,
+        /// Bytes in the data stage
+        pub length: u16,
+    }
+
+    impl SetupPacket {
+        pub fn direction(&self) -> Direction {
+            if self.request_type & 0x80 != 0 {
+                Direction::In
+            } else {
+                Direction::Out
+            }
+        }
+
+        /// Wire format, little endian
+        pub fn to_bytes(&self) -> [u8; 8] {
+            let value = self.value.to_le_bytes();
+            let index = self.index.to_le_bytes();
+            let length = self.length.to_le_bytes();
+
+            [
+                self.request_type,
+                self.request,
+                value[0],
+                value[1],
+                index[0],
+                index[1],
+                length[0],
+                length[1],
+            ]
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Error {
+        /// The endpoint refused the request or is halted
+        Stall,
+        /// Nothing to report on a periodic endpoint, or still busy after all the retries
+        Nak,
+        /// Transmission errors persisted through the retries
+        Transaction,
+        /// The device sent more than the packet could hold
+        Babble,
+        DataToggle,
+        /// The controller's DMA failed
+        Bus,
+        /// The controller stopped making progress
+        Timeout,
+        /// Every channel is in use
+        NoChannel,
+        /// Nothing plugged into the root port
+        NotConnected,
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// A USB host controller with a single root port. Transfers block until they are done.
+    pub trait HostController: Sync {
+        /// Speed of the device on the root port, `None` if nothing is plugged in
+        fn port_speed(&self) -> Option<Speed>;
+
+        /// Reset the root port and find out how fast the device on it is
+        fn reset_port(&self) -> Result<Speed>;
+
+        /// Run a control transfer: the setup stage, `setup.length` bytes of data in or out of
+        /// `data`, then the status stage. Returns the number of bytes moved in the data stage.
+        fn control(
+            &self,
+            endpoint: &mut Endpoint,
+            setup: &SetupPacket,
+            data: &mut [u8],
+        ) -> Result<usize>;
+
+        /// Run a bulk or interrupt transfer in the direction of `endpoint`. IN transfers end
+        /// early on a short packet. Returns the number of bytes moved.
+        fn transfer(&self, endpoint: &mut Endpoint, data: &mut [u8]) -> Result<usize>;
     }
 }
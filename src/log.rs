@@ -0,0 +1,485 @@
+//! Kernel logging facilities.
+//!
+//! Every record is tagged with a timestamp (microseconds since power on, from the system timer),
+//! the core that emitted it and the module it came from, e.g.
+//!
+//! ```
+//! [    1.204311] 0 INFO  kernel::bsp: Booting on <Raspberry Pi 3>
+//! ```
+//!
+//! Records always land in an in-memory ring buffer, and are echoed to the console once
+//! `console_ready()` has been called. `dmesg()` dumps the ring buffer, so anything logged before
+//! the console came up is not lost.
+//!
+//! Levels are filtered per module from the kernel command line (`cmdline.txt`):
+//!
+//! ```
+//! loglevel=debug log=bsp::driver:trace,print:warn
+//! ```
+//!
+//! `loglevel` sets the default, `log` overrides it for every module path starting with the given
+//! prefix. The longest matching prefix wins.
+
+use crate::{arch, arch::sync::NullLock, bsp, interface};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Logs at the error level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Error, module_path!(), format_args!($($arg)*)));
+}
+
+/// Logs at the warn level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Warn, module_path!(), format_args!($($arg)*)));
+}
+
+/// Logs at the info level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Info, module_path!(), format_args!($($arg)*)));
+}
+
+/// Logs at the debug level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Debug, module_path!(), format_args!($($arg)*)));
+}
+
+/// Logs at the trace level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Trace, module_path!(), format_args!($($arg)*)));
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Level {
+        match value {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Off => "OFF  ",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Per module filtering
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_FILTERS: usize = 8;
+const MAX_FILTER_LEN: usize = 64;
+const DEFAULT_LEVEL: Level = Level::Info;
+
+#[derive(Clone, Copy)]
+struct Filter {
+    module: [u8; MAX_FILTER_LEN],
+    len: usize,
+    level: Level,
+}
+
+impl Filter {
+    const fn empty() -> Filter {
+        Filter {
+            module: [0; MAX_FILTER_LEN],
+            len: 0,
+            level: Level::Off,
+        }
+    }
+
+    fn module(&self) -> &[u8] {
+        &self.module[..self.len]
+    }
+}
+
+struct Filters {
+    default: Level,
+    entries: [Filter; MAX_FILTERS],
+    count: usize,
+}
+
+impl Filters {
+    const fn new() -> Filters {
+        Filters {
+            default: DEFAULT_LEVEL,
+            entries: [Filter::empty(); MAX_FILTERS],
+            count: 0,
+        }
+    }
+
+    /// Add (or replace) the level for everything under `module`, returns false if the table is full
+    fn insert(&mut self, module: &str, level: Level) -> bool {
+        let module = module.as_bytes();
+        if module.len() > MAX_FILTER_LEN {
+            return false;
+        }
+
+        let index = match self.entries[..self.count]
+            .iter()
+            .position(|f| f.module() == module)
+        {
+            Some(index) => index,
+            None if self.count < MAX_FILTERS => {
+                self.count += 1;
+                self.count - 1
+            }
+            None => return false,
+        };
+
+        let entry = &mut self.entries[index];
+        entry.module[..module.len()].copy_from_slice(module);
+        entry.len = module.len();
+        entry.level = level;
+
+        true
+    }
+
+    /// Level that applies to `module`, module paths are matched without the crate name
+    fn level_for(&self, module: &str) -> Level {
+        let module = match module.find("::") {
+            Some(i) => &module[i + 2..],
+            None => "",
+        }
+        .as_bytes();
+
+        let mut best: Option<&Filter> = None;
+        for filter in self.entries[..self.count].iter() {
+            let prefix = filter.module();
+            let matches = module.starts_with(prefix)
+                && (module.len() == prefix.len() || module[prefix.len()..].starts_with(b"::"));
+
+            if matches && best.map_or(true, |b| b.len < filter.len) {
+                best = Some(filter);
+            }
+        }
+
+        best.map_or(self.default, |f| f.level)
+    }
+
+    /// Most verbose level any module can log at, lets `_log` bail out before formatting
+    fn max_level(&self) -> Level {
+        self.entries[..self.count]
+            .iter()
+            .fold(
+                self.default,
+                |max, f| if f.level > max { f.level } else { max },
+            )
+    }
+
+    /// Apply `loglevel=` and `log=` options from a kernel command line, everything else is ignored
+    fn parse(&mut self, cmdline: &str) {
+        for option in cmdline.split_whitespace() {
+            if option.starts_with("loglevel=") {
+                if let Some(level) = Level::from_name(&option["loglevel=".len()..]) {
+                    self.default = level;
+                }
+            } else if option.starts_with("log=") {
+                for directive in option["log=".len()..].split(',') {
+                    let mut parts = directive.rsplitn(2, ':');
+                    let level = parts.next().and_then(Level::from_name);
+
+                    if let (Some(level), Some(module)) = (level, parts.next()) {
+                        self.insert(module, level);
+                    }
+                }
+            }
+        }
+    }
+}
+
+static FILTERS: NullLock<Filters> = NullLock::new(Filters::new());
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+////////////////////////////////////////////////////////////////////////////////
+// Ring buffer
+////////////////////////////////////////////////////////////////////////////////
+
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Byte ring behind a `NullLock` like every other global. No atomic read-modify-write here: the
+/// MMU is off, so all memory is Device memory and exclusive loads and stores never succeed on it.
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    /// Bytes pushed since boot, the ring holds the last `LOG_BUFFER_SIZE` of them
+    head: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> LogBuffer {
+        LogBuffer {
+            data: [0; LOG_BUFFER_SIZE],
+            head: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[self.head % LOG_BUFFER_SIZE] = byte;
+            self.head += 1;
+        }
+    }
+
+    /// Hand the buffered bytes, oldest first, to `f` in at most two contiguous chunks
+    fn read(&self, mut f: impl FnMut(&[u8])) {
+        let head = self.head;
+        let start = head.saturating_sub(LOG_BUFFER_SIZE);
+        let data = &self.data;
+
+        let first = start % LOG_BUFFER_SIZE;
+        let last = head % LOG_BUFFER_SIZE;
+
+        if head - start < LOG_BUFFER_SIZE || first == 0 {
+            f(&data[first..first + (head - start)]);
+        } else {
+            f(&data[first..]);
+            f(&data[..last]);
+        }
+    }
+}
+
+static LOG_BUFFER: NullLock<LogBuffer> = NullLock::new(LogBuffer::new());
+static CONSOLE_READY: AtomicBool = AtomicBool::new(false);
+
+////////////////////////////////////////////////////////////////////////////////
+// Record formatting
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_RECORD_LEN: usize = 256;
+
+/// Fixed size line buffer, records longer than `MAX_RECORD_LEN` are truncated
+struct Record {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl Record {
+    fn new() -> Record {
+        Record {
+            buf: [0; MAX_RECORD_LEN],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole UTF-8 sequences are ever copied in by write_str
+        unsafe { core::str::from_utf8_unchecked(self.as_bytes()) }
+    }
+}
+
+impl fmt::Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Keep one byte spare for the trailing newline
+        let space = MAX_RECORD_LEN - 1 - self.len;
+
+        let mut n = s.len().min(space);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Public interface
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// Read the logging options from the kernel command line. Needs a working mailbox.
+pub fn init() {
+    let mut cmdline = [0u8; 128];
+    let len = bsp::command_line(&mut cmdline);
+
+    if let Ok(cmdline) = core::str::from_utf8(&cmdline[..len]) {
+        set_filters(cmdline);
+    }
+}
+
+/// Apply `loglevel=`/`log=` options, see the module documentation for the syntax
+pub fn set_filters(options: &str) {
+    let mut r = &FILTERS;
+    let max = r.lock(|filters| {
+        filters.parse(options);
+        filters.max_level()
+    });
+
+    MAX_LEVEL.store(max as u8, Ordering::Relaxed);
+}
+
+/// From now on records are echoed to the console as well as the ring buffer
+pub fn console_ready() {
+    CONSOLE_READY.store(true, Ordering::Release);
+}
+
+/// Print the content of the ring buffer to the console
+pub fn dmesg() {
+    use interface::console::Write;
+
+    let console = bsp::console();
+
+    // Writing to the console doesn't log, so the ring can stay locked while it is dumped
+    let mut r = &LOG_BUFFER;
+    r.lock(|ring| {
+        let mut skip_partial = ring.head > LOG_BUFFER_SIZE;
+
+        ring.read(|chunk| {
+            for &byte in chunk {
+                // Once the ring wrapped the oldest record is cut, start at the next full one
+                if skip_partial {
+                    skip_partial = byte != b'\n';
+                    continue;
+                }
+
+                if byte == b'\n' {
+                    console.write_char('\r');
+                }
+                console.write_char(byte as char);
+            }
+        });
+    });
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    use fmt::Write;
+
+    if level > Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed)) {
+        return;
+    }
+
+    let mut r = &FILTERS;
+    if level > r.lock(|filters| filters.level_for(module)) {
+        return;
+    }
+
+    let timestamp = bsp::uptime_usec();
+    let mut record = Record::new();
+
+    let _ = write!(
+        record,
+        "[{:>5}.{:06}] {} {} {}: ",
+        timestamp / 1_000_000,
+        timestamp % 1_000_000,
+        arch::core_id(),
+        level.name(),
+        module
+    );
+    let _ = record.write_fmt(args);
+
+    record.buf[record.len] = b'\n';
+    record.len += 1;
+
+    let mut r = &LOG_BUFFER;
+    r.lock(|ring| ring.push(record.as_bytes()));
+
+    if CONSOLE_READY.load(Ordering::Acquire) {
+        crate::print!("{}", record.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_level_applies_without_filters() {
+        let filters = Filters::new();
+
+        assert_eq!(filters.level_for("kernel::bsp::driver"), DEFAULT_LEVEL);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut filters = Filters::new();
+        filters.parse("loglevel=warn log=bsp:info,bsp::driver:trace");
+
+        assert_eq!(filters.level_for("kernel"), Level::Warn);
+        assert_eq!(filters.level_for("kernel::bsp"), Level::Info);
+        assert_eq!(filters.level_for("kernel::bsp::rpi3"), Level::Info);
+        assert_eq!(filters.level_for("kernel::bsp::driver::bcm"), Level::Trace);
+        assert_eq!(filters.level_for("kernel::bspx"), Level::Warn);
+        assert_eq!(filters.max_level(), Level::Trace);
+    }
+
+    #[test]
+    fn unknown_options_are_ignored() {
+        let mut filters = Filters::new();
+        filters.parse("console=ttyS0 loglevel=loud log=print:nope,print:error root=/dev/mmcblk0p2");
+
+        assert_eq!(filters.default, DEFAULT_LEVEL);
+        assert_eq!(filters.count, 1);
+        assert_eq!(filters.level_for("kernel::print"), Level::Error);
+    }
+
+    #[test]
+    fn record_truncates_on_char_boundary() {
+        use fmt::Write;
+
+        let mut record = Record::new();
+        for _ in 0..MAX_RECORD_LEN {
+            let _ = record.write_str("é");
+        }
+
+        assert!(record.len < MAX_RECORD_LEN);
+        assert!(core::str::from_utf8(record.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_newest_bytes() {
+        let mut ring = LogBuffer::new();
+        ring.push(&[b'a'; LOG_BUFFER_SIZE - 2]);
+        ring.push(b"bcde");
+
+        let mut out = std::vec::Vec::new();
+        ring.read(|chunk| out.extend_from_slice(chunk));
+
+        assert_eq!(out.len(), LOG_BUFFER_SIZE);
+        assert_eq!(&out[out.len() - 4..], b"bcde");
+        assert_eq!(out[0], b'a');
+    }
+}
@@ -1,8 +1,17 @@
+#![feature(asm)]
+#![feature(const_fn)]
 #![feature(format_args_nl)]
 #![feature(panic_info_message)]
 #![feature(trait_alias)]
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+
+// Builds with the nightly pinned in rust-toolchain. The locked cortex-a and register crates need
+// its LLVM style `asm!` and `const_fn`, so newer library APIs are off limits.
+// The driver unit tests run on the build machine against fake register files:
+//   cargo test --features bsp_rpi3 --target x86_64-unknown-linux-gnu
+// With QEMU_KERNEL pointing at a `bsp_qemu_raspi3` image, `-- --ignored kernel_boots` boots it
+// under QEMU instead, see src/qemu_boot_test.rs.
 
 // These imports are essentially in the order of execution for the kernel
 // First we have the _start() function which is architecture specific and should be able to stay the same across
@@ -10,6 +19,7 @@
 mod arch;
 
 // Runtime init currently just zero's our BSS but then jumps to the kernel_entry
+#[cfg(not(test))]
 mod runtime_init;
 
 // Our BSP will include code to support the specific board that we are using
@@ -18,36 +28,146 @@ mod bsp;
 // This includes our kernel code for driver interfaces and panic behavior. If a panic happens prior to the kernel booting, we won't
 // know about it, and it will try to call non-existant functions. This should be prevented by prior to this, error handling should
 // be done with wait_forever()
+mod backtrace;
+mod block;
+mod cpufreq;
+mod fs;
 mod interface;
+mod log;
+mod monitor;
 mod panic_wait;
 mod print;
+#[cfg(test)]
+mod qemu_boot_test;
+mod thermal;
+mod usb;
 mod utils;
 
+/// Whatever is due while nothing else is going on, done on every pass of the idle loop
+fn poll() {
+    let _ = cpufreq::update();
+    thermal::update();
+    usb::poll();
+    bsp::service_devices();
+}
+
+/// Wait for a character without starving `poll`, the wait counts as idle time. Kiosks have a USB
+/// keyboard and no serial cable, so both are read and whichever has a character wins.
+fn wait_for_char() -> char {
+    use interface::console::Read;
+
+    cpufreq::idle_enter();
+    let c = loop {
+        if let Some(c) = usb::input()
+            .try_read_char()
+            .or_else(|| bsp::console().try_read_char())
+        {
+            break c;
+        }
+
+        poll();
+    };
+    cpufreq::idle_exit();
+
+    c
+}
+
 fn kernel_entry() -> ! {
     use interface::console::All;
     bsp::init();
 
-    loop {
-        if bsp::console().read_char() == '\n' {
-            break;
-        }
-    }
+    log::init();
+    log::console_ready();
+    panic_wait::init();
+    fs::init();
+    cpufreq::init();
+    usb::init();
+
+    while wait_for_char() != '\n' {}
 
-    println!("[0] Booting on <{}>", bsp::board_name());
+    info!("Booting on <{}>", bsp::board_name());
+    info!("Last reset: {}", bsp::watchdog().reset_reason());
 
-    println!("[1] Drivers loaded:");
+    info!("Drivers loaded:");
     for (i, driver) in bsp::device_drivers().iter().enumerate() {
-        println!("    {}. {}", i + 1, driver.compatible());
+        info!("    {}. {}", i + 1, driver.compatible());
     }
 
-    println!(
-        "[3] Characters written : {}",
-        bsp::console().chars_written()
+    match bsp::thermal().temperature() {
+        Ok(t) => info!("SoC temperature: {}.{:03} C", t / 1000, t % 1000),
+        Err(e) => warn!("Could not read the SoC temperature: {:?}", e),
+    }
+    info!(
+        "ARM clock: {} MHz, {} governor",
+        cpufreq::current() / 1_000_000,
+        cpufreq::governor()
     );
 
-    println!("[4] Echoing input now.");
+    info!("Power domains:");
+    bsp::power().report(&mut |status| match status.state {
+        Some(state) if state.missing => {}
+        Some(state) => info!(
+            "    {}: {}, {} users",
+            status.domain.name(),
+            if state.on { "on" } else { "off" },
+            status.users
+        ),
+        None => info!("    {}: unknown", status.domain.name()),
+    });
+
+    match block::PartitionTable::read(bsp::emmc()) {
+        Ok(table) => {
+            info!("SD card partitions:");
+            for (i, p) in table.iter().enumerate() {
+                info!(
+                    "    {}. {} at block {}, {} blocks",
+                    i + 1,
+                    p.kind,
+                    p.first_lba,
+                    p.block_count
+                );
+            }
+
+            // The firmware only boots from a FAT first partition
+            if let Some(boot) = table.partition(bsp::emmc(), 0) {
+                match fs::fat::FileSystem::mount(&boot) {
+                    Ok(fat) => info!("Boot partition is {:?}", fat.fat_type()),
+                    Err(e) => warn!("Boot partition not mountable: {:?}", e),
+                }
+            }
+        }
+        Err(e) => warn!("No partition table on the SD card: {:?}", e),
+    }
+
+    info!("USB devices:");
+    usb::bus().devices(&mut |device| {
+        info!(
+            "    {}. {:04x}:{:04x}, class {:#04x}, {:?} speed",
+            device.address,
+            device.descriptor.vendor,
+            device.descriptor.product,
+            device.descriptor.class,
+            device.speed
+        )
+    });
+
+    for (name, bus) in bsp::i2c_buses().iter() {
+        info!("Devices on {}:", name);
+        bus.scan(&mut |address| info!("    {:#04x}", address));
+    }
+
+    info!("Devices in /dev:");
+    let mut index = 0;
+    while let Ok(Some(entry)) = fs::vfs().read_dir("/dev", index) {
+        info!("    {}", entry.name());
+        index += 1;
+    }
+
+    debug!("Characters written : {}", bsp::console().chars_written());
+
+    info!("Echoing input now.");
     loop {
-        let c = bsp::console().read_char();
+        let c = wait_for_char();
         bsp::console().write_char(c);
     }
 
@@ -0,0 +1,139 @@
+//! Minimal debug monitor on the serial console.
+//!
+//! This is where the panic handler ends up with `panic=monitor`. It only relies on the console,
+//! so it keeps working while the rest of the kernel is in an unknown state.
+
+use crate::{arch, backtrace, bsp, cpufreq, log, print, println};
+
+const MAX_LINE_LEN: usize = 80;
+
+const HELP: &str = "\
+Commands:
+    help                 show this text
+    regs                 dump registers
+    bt                   print a backtrace of the monitor
+    dmesg                print the kernel log buffer
+    peek <addr>          read a 32 bit word
+    poke <addr> <value>  write a 32 bit word
+    cpufreq [governor]   show the ARM clock, or switch governors
+    reboot               reset the board
+    halt                 stop the core";
+
+/// Read a line from the console with echo and backspace handling, returns its length
+fn read_line(buf: &mut [u8]) -> usize {
+    use crate::interface::console::{Read, Write};
+
+    let console = bsp::console();
+    let mut len = 0;
+
+    loop {
+        match console.read_char() {
+            '\r' | '\n' => {
+                println!();
+                return len;
+            }
+            // Backspace and delete, depending on the terminal
+            '\x08' | '\x7f' => {
+                if len > 0 {
+                    len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            c if c.is_ascii() && !c.is_ascii_control() && len < buf.len() => {
+                buf[len] = c as u8;
+                len += 1;
+                console.write_char(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Print a register snapshot
+pub fn dump_registers(regs: &arch::Registers) {
+    println!("Registers:");
+    println!("    SP   : {:#018x}", regs.sp);
+    println!("    FP   : {:#018x}", regs.fp);
+    println!("    LR   : {:#018x}", regs.lr);
+    println!("    DAIF : {:#018x}", regs.daif);
+    println!("    MPIDR: {:#018x}", regs.mpidr);
+}
+
+fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+
+    match (args.next(), args.next(), args.next()) {
+        (None, ..) => {}
+        (Some("help"), ..) => println!("{}", HELP),
+        (Some("regs"), ..) => dump_registers(&arch::registers()),
+        (Some("bt"), ..) => {
+            let regs = arch::registers();
+            backtrace::print_backtrace(regs.fp, regs.sp);
+        }
+        (Some("dmesg"), ..) => log::dmesg(),
+        (Some("peek"), Some(addr), None) => match parse_number(addr) {
+            Some(addr) if addr % 4 == 0 => {
+                let value = unsafe { core::ptr::read_volatile(addr as *const u32) };
+                println!("{:#010x}: {:#010x}", addr, value);
+            }
+            _ => println!("peek: bad address '{}'", addr),
+        },
+        (Some("poke"), Some(addr), Some(value)) => {
+            match (parse_number(addr), parse_number(value)) {
+                (Some(addr), Some(value)) if addr % 4 == 0 => unsafe {
+                    core::ptr::write_volatile(addr as *mut u32, value as u32);
+                },
+                _ => println!("poke: bad arguments '{} {}'", addr, value),
+            }
+        }
+        (Some("cpufreq"), None, _) => {
+            let (min, max) = cpufreq::limits();
+            println!(
+                "{} MHz ({}-{} MHz), {} governor, {} transitions",
+                cpufreq::current() / 1_000_000,
+                min / 1_000_000,
+                max / 1_000_000,
+                cpufreq::governor(),
+                cpufreq::transitions()
+            );
+        }
+        (Some("cpufreq"), Some(name), None) => match cpufreq::Governor::from_name(name) {
+            Some(governor) => {
+                if let Err(e) = cpufreq::set_governor(governor) {
+                    println!("cpufreq: {:?}", e);
+                }
+            }
+            None => println!("cpufreq: unknown governor '{}'", name),
+        },
+        (Some("reboot"), ..) => bsp::reboot(),
+        (Some("halt"), ..) => arch::wait_forever(),
+        (Some(cmd), ..) => println!("Unknown command '{}', try 'help'", cmd),
+    }
+}
+
+/// Run the monitor, only returns by way of `reboot` or `halt`
+pub fn run() -> ! {
+    let mut line = [0u8; MAX_LINE_LEN];
+
+    println!("Entering debug monitor, 'help' lists commands");
+
+    loop {
+        print!("mon> ");
+
+        let len = read_line(&mut line);
+
+        // read_line only stores printable ASCII
+        match core::str::from_utf8(&line[..len]) {
+            Ok(line) => execute(line),
+            Err(_) => continue,
+        }
+    }
+}
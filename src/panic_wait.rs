@@ -1,15 +1,112 @@
-//! A panic handler that infinitely waits.
+//! Kernel panic handling.
+//!
+//! Prints everything we know about the failure and then follows the panic policy, which can be
+//! set at runtime or from the command line with `panic=halt|reboot|monitor`.
 
-use crate::{arch, println};
+use crate::{arch, backtrace, bsp, monitor, println};
+#[cfg(not(test))]
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
+/// What to do once the panic report has been printed
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Policy {
+    /// Spin forever, the default
+    Halt = 0,
+    /// Reset the board through the watchdog
+    Reboot = 1,
+    /// Drop into the serial debug monitor
+    Monitor = 2,
+}
+
+impl Policy {
+    fn from_u8(value: u8) -> Policy {
+        match value {
+            1 => Policy::Reboot,
+            2 => Policy::Monitor,
+            _ => Policy::Halt,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "halt" => Some(Policy::Halt),
+            "reboot" => Some(Policy::Reboot),
+            "monitor" => Some(Policy::Monitor),
+            _ => None,
+        }
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Halt as u8);
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// Read the panic policy from the kernel command line. Needs a working mailbox.
+pub fn init() {
+    let mut cmdline = [0u8; 128];
+    let len = bsp::command_line(&mut cmdline);
+
+    if let Ok(cmdline) = core::str::from_utf8(&cmdline[..len]) {
+        for option in cmdline.split_whitespace() {
+            if option.starts_with("panic=") {
+                if let Some(policy) = Policy::from_name(&option["panic=".len()..]) {
+                    set_policy(policy);
+                }
+            }
+        }
+    }
+}
+
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn policy() -> Policy {
+    Policy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Grab these first, before printing clobbers anything
+    let regs = arch::registers();
+
+    // Panicking again while reporting means the console or the stack is broken, stop here. A
+    // load and a store, not a swap: the kernel runs on one core and with the MMU off exclusive
+    // accesses never succeed.
+    if PANICKED.load(Ordering::Relaxed) {
+        arch::wait_forever()
+    }
+    PANICKED.store(true, Ordering::Relaxed);
+
     if let Some(args) = info.message() {
         println!("Kernel panic: {}", args);
     } else {
         println!("Kernel panic!");
     }
 
-    arch::wait_forever()
+    if let Some(location) = info.location() {
+        println!(
+            "    at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+
+    println!(
+        "    core {}, EL{}",
+        arch::core_id(),
+        arch::exception_level()
+    );
+
+    monitor::dump_registers(&regs);
+    backtrace::print_backtrace(regs.fp, regs.sp);
+
+    match policy() {
+        Policy::Halt => arch::wait_forever(),
+        Policy::Reboot => bsp::reboot(),
+        Policy::Monitor => monitor::run(),
+    }
 }
@@ -0,0 +1,25 @@
+//! Boots a kernel image under QEMU with `qemu_boot_test.py`, as part of the host tests. QEMU and
+//! the aarch64 image aren't around on every build machine, so the test is ignored by default and
+//! has to be asked for, with `QEMU_KERNEL` naming the image:
+//!
+//!   cargo build --features bsp_qemu_raspi3 --target aarch64-unknown-none
+//!   QEMU_KERNEL=<kernel image> cargo test --features bsp_rpi3 --target x86_64-unknown-linux-gnu \
+//!       -- --ignored kernel_boots
+
+use std::env;
+use std::process::Command;
+
+#[test]
+#[ignore]
+fn kernel_boots_to_the_echo_loop() {
+    let kernel = env::var("QEMU_KERNEL").expect("QEMU_KERNEL must name the kernel image");
+
+    let script = concat!(env!("CARGO_MANIFEST_DIR"), "/qemu_boot_test.py");
+    let status = Command::new("python3")
+        .arg(script)
+        .arg(&kernel)
+        .status()
+        .expect("running python3");
+
+    assert!(status.success(), "{} failed to boot under QEMU", kernel);
+}
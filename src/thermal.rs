@@ -0,0 +1,58 @@
+//! Temperature checks, on top of the BSP's thermal driver.
+//!
+//! The driver only compares the temperature with its trip points when it is polled, so
+//! `update()` has it read the sensor at most every `POLL_PERIOD_US`. Throttling on passive trips
+//! is done by the driver, what happens on the others is up to the handler the BSP installed.
+
+use crate::{arch::sync::NullLock, bsp, interface};
+
+/// How often the sensor is read, it takes seconds for the SoC to heat up by a degree
+const POLL_PERIOD_US: u64 = 1_000_000;
+
+/// Uptime the next poll is due at
+static NEXT_POLL: NullLock<u64> = NullLock::new(0);
+
+/// Whether a poll is due at `now`, moves `next_poll` on if so
+fn due(next_poll: &mut u64, now: u64) -> bool {
+    if now < *next_poll {
+        return false;
+    }
+
+    *next_poll = now + POLL_PERIOD_US;
+    true
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Public interface
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+/// Poll the thermal driver if it is due. Cheap to call often, nothing happens until a poll
+/// period has passed.
+pub fn update() {
+    let now = bsp::uptime_usec();
+
+    let mut r = &NEXT_POLL;
+    if !r.lock(|next_poll| due(next_poll, now)) {
+        return;
+    }
+
+    if let Err(e) = bsp::thermal().poll() {
+        crate::debug!("Reading the temperature failed: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polls_once_per_period() {
+        let mut next_poll = 0;
+
+        assert!(due(&mut next_poll, 5));
+        assert!(!due(&mut next_poll, 6));
+        assert!(!due(&mut next_poll, POLL_PERIOD_US + 4));
+        assert!(due(&mut next_poll, POLL_PERIOD_US + 5));
+    }
+}
@@ -0,0 +1,76 @@
+//! USB devices, on top of the BSP's `interface::usb::HostController`.
+//!
+//! `init` enumerates whatever is on the root port: the device gets an address, its first
+//! configuration is selected and each of its interfaces is offered to the registered class
+//! drivers. Hubs are a class driver like any other, so the LAN9514 on the Pi 3 and everything
+//! plugged into it come up the same way.
+//!
+//! Keyboards and mice feed the `input()` queue, which can stand in for the UART as the console
+//! input with `input().read_char()`.
+//!
+//! There are no interrupts yet. `poll()` has to be called regularly, it lets the class drivers do
+//! their work and the hub driver notice devices coming and going.
+
+mod bus;
+pub mod descriptor;
+pub mod hid;
+pub mod hub;
+pub mod request;
+
+#[cfg(test)]
+mod mock;
+
+pub use bus::{Bus, ClassDriver, Device, Error, Result};
+
+use crate::bsp;
+
+static BUS: Bus = Bus::new();
+static HUBS: hub::HubDriver = hub::HubDriver::new();
+/// A queue per device class, so mouse movement nobody reads can't crowd out key presses
+static KEYS: hid::EventQueue = hid::EventQueue::new();
+static POINTER: hid::EventQueue = hid::EventQueue::new();
+static KEYBOARDS: hid::KeyboardDriver = hid::KeyboardDriver::new(&KEYS);
+static MICE: hid::MouseDriver = hid::MouseDriver::new(&POINTER);
+
+/// Register the class drivers and enumerate the device on the root port
+pub fn init() {
+    let host = match bsp::usb_host() {
+        Some(host) => host,
+        None => {
+            crate::info!("No USB host controller");
+            return;
+        }
+    };
+
+    BUS.set_host(host);
+    let drivers: [&'static dyn ClassDriver; 3] = [&HUBS, &KEYBOARDS, &MICE];
+    for driver in drivers.iter() {
+        if let Err(e) = BUS.register_driver(*driver) {
+            crate::warn!("Registering the {} driver failed: {:?}", driver.name(), e);
+        }
+    }
+
+    match host.port_speed() {
+        Some(speed) => {
+            if let Err(e) = BUS.attach(None, speed, None) {
+                crate::warn!("Enumerating the root port failed: {:?}", e);
+            }
+        }
+        None => crate::info!("Nothing plugged into the USB root port"),
+    }
+}
+
+pub fn bus() -> &'static Bus {
+    &BUS
+}
+
+/// Key presses and releases from every keyboard
+pub fn input() -> &'static hid::EventQueue {
+    &KEYS
+}
+
+/// Let the class drivers look for new data and hot-plug events. Cheap to call often, the drivers
+/// only go to the devices when they are due.
+pub fn poll() {
+    BUS.poll(bsp::uptime_usec());
+}
@@ -0,0 +1,536 @@
+//! The devices on the bus and the class drivers that can take them over.
+
+use super::descriptor::{
+    self, ConfigurationDescriptor, Descriptor, Descriptors, DeviceDescriptor, EndpointDescriptor,
+    InterfaceDescriptor,
+};
+use super::request;
+use crate::{arch, arch::sync::NullLock, interface};
+use interface::usb::{Endpoint, HostController, SetupPacket, Speed, Split};
+
+/// Devices on the bus, hubs included
+const MAX_DEVICES: usize = 16;
+
+const MAX_DRIVERS: usize = 8;
+
+/// Endpoints handed to a class driver with its interface, more are ignored
+const MAX_ENDPOINTS: usize = 4;
+
+/// Configuration descriptor sets longer than this are cut short
+const CONFIGURATION_BUFFER: usize = 512;
+
+/// USB 2.0 9.2.6.3, a device gets 2ms to take on its new address
+const SET_ADDRESS_RECOVERY_US: usize = 2_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// No host controller on this board
+    NoController,
+    Transfer(interface::usb::Error),
+    /// A descriptor that doesn't make sense
+    BadDescriptor,
+    /// The device table or the driver registry is full
+    NoSpace,
+}
+
+impl From<interface::usb::Error> for Error {
+    fn from(e: interface::usb::Error) -> Error {
+        Error::Transfer(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// An addressed and configured device
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub address: u8,
+    pub speed: Speed,
+    /// Hub address and port the device is plugged into, `None` for the root port
+    pub parent: Option<(u8, u8)>,
+    /// Hub that splits the device's transactions, for full and low speed devices behind a high
+    /// speed hub
+    pub split: Option<Split>,
+    pub descriptor: DeviceDescriptor,
+    /// `value` of the selected configuration
+    pub configuration: u8,
+}
+
+impl Device {
+    /// The default control endpoint
+    pub fn ep0(&self) -> Endpoint {
+        Endpoint::control(
+            self.address,
+            self.descriptor.max_packet_size0 as u16,
+            self.speed,
+            self.split,
+        )
+    }
+
+    /// The endpoint `descriptor` describes, starting with DATA0
+    pub fn endpoint(&self, descriptor: &EndpointDescriptor) -> Endpoint {
+        Endpoint {
+            device: self.address,
+            number: descriptor.number(),
+            kind: descriptor.kind(),
+            direction: descriptor.direction(),
+            max_packet_size: descriptor.max_packet_size & 0x7FF,
+            speed: self.speed,
+            split: self.split,
+            toggle: false,
+        }
+    }
+}
+
+/// Drivers for a class of interfaces, hubs or keyboards for example. They live in statics and
+/// are registered with `Bus::register_driver`.
+pub trait ClassDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the driver wants `interface` of `device`
+    fn probe(&self, device: &Device, interface: &InterfaceDescriptor) -> bool;
+
+    /// Take over `interface`, `endpoints` are the endpoint descriptors that came with it
+    fn attach(
+        &self,
+        bus: &Bus,
+        device: &Device,
+        interface: &InterfaceDescriptor,
+        endpoints: &[EndpointDescriptor],
+    ) -> Result<()>;
+
+    /// The device at `address` is gone, drop everything that belongs to it
+    fn detach(&self, address: u8);
+
+    /// Called from `Bus::poll` with the current uptime
+    fn poll(&self, _bus: &Bus, _now_us: u64) {}
+}
+
+struct BusInner {
+    host: Option<&'static dyn HostController>,
+    devices: [Option<Device>; MAX_DEVICES],
+    drivers: [Option<&'static dyn ClassDriver>; MAX_DRIVERS],
+}
+
+impl BusInner {
+    const fn new() -> BusInner {
+        BusInner {
+            host: None,
+            devices: [None; MAX_DEVICES],
+            drivers: [None; MAX_DRIVERS],
+        }
+    }
+
+    /// Lowest address nobody has, if there is room for another device
+    fn free_address(&self) -> Option<u8> {
+        if self.devices.iter().all(|d| d.is_some()) {
+            return None;
+        }
+
+        (1..128).find(|&address| !self.devices.iter().flatten().any(|d| d.address == address))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct Bus {
+    inner: NullLock<BusInner>,
+}
+
+impl Bus {
+    pub const fn new() -> Bus {
+        Bus {
+            inner: NullLock::new(BusInner::new()),
+        }
+    }
+
+    pub fn set_host(&self, host: &'static dyn HostController) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.host = Some(host));
+    }
+
+    pub fn host(&self) -> Result<&'static dyn HostController> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.host).ok_or(Error::NoController)
+    }
+
+    /// Offer the interfaces of devices enumerated from now on to `driver`
+    pub fn register_driver(&self, driver: &'static dyn ClassDriver) -> Result<()> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let slot = inner
+                .drivers
+                .iter_mut()
+                .find(|d| d.is_none())
+                .ok_or(Error::NoSpace)?;
+            *slot = Some(driver);
+
+            Ok(())
+        })
+    }
+
+    pub fn device(&self, address: u8) -> Option<Device> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner
+                .devices
+                .iter()
+                .flatten()
+                .find(|d| d.address == address)
+                .copied()
+        })
+    }
+
+    /// Call `f` with every device on the bus
+    pub fn devices(&self, f: &mut dyn FnMut(&Device)) {
+        let mut r = &self.inner;
+        let devices = r.lock(|inner| inner.devices);
+
+        for device in devices.iter().flatten() {
+            f(device);
+        }
+    }
+
+    /// Run a control transfer on the default endpoint of `device`
+    pub fn control(&self, device: &Device, setup: &SetupPacket, data: &mut [u8]) -> Result<usize> {
+        let mut ep0 = device.ep0();
+
+        Ok(self.host()?.control(&mut ep0, setup, data)?)
+    }
+
+    /// Run a bulk or interrupt transfer on `endpoint`
+    pub fn transfer(&self, endpoint: &mut Endpoint, data: &mut [u8]) -> Result<usize> {
+        Ok(self.host()?.transfer(endpoint, data)?)
+    }
+
+    /// Read string descriptor `index` of `device` as ASCII into `buf`, returns its length
+    pub fn string(&self, device: &Device, index: u8, buf: &mut [u8]) -> Result<usize> {
+        let mut raw = [0; 255];
+
+        // The first language the device lists is as good as any
+        let language = match self.control(
+            device,
+            &request::get_descriptor(descriptor::STRING, 0, 0, 4),
+            &mut raw[..4],
+        ) {
+            Ok(4) => u16::from_le_bytes([raw[2], raw[3]]),
+            _ => request::LANGUAGE_EN_US,
+        };
+
+        let len = self.control(
+            device,
+            &request::get_descriptor(descriptor::STRING, index, language, raw.len() as u16),
+            &mut raw,
+        )?;
+
+        descriptor::decode_string(&raw[..len], buf)
+    }
+
+    /// Enumerate a device that was just reset: give it an address, select its first
+    /// configuration and offer its interfaces to the class drivers. Returns the address.
+    pub fn attach(
+        &self,
+        parent: Option<(u8, u8)>,
+        speed: Speed,
+        split: Option<Split>,
+    ) -> Result<u8> {
+        let host = self.host()?;
+        let mut r = &self.inner;
+        let address = r.lock(|inner| inner.free_address()).ok_or(Error::NoSpace)?;
+
+        // Until it knows better, the device has to take packets of 8 bytes at full and low speed
+        let max_packet = if speed == Speed::High { 64 } else { 8 };
+        let mut ep0 = Endpoint::control(0, max_packet, speed, split);
+
+        let mut bytes = [0; DeviceDescriptor::LEN];
+        let setup = request::get_descriptor(descriptor::DEVICE, 0, 0, 8);
+        if host.control(&mut ep0, &setup, &mut bytes[..8])? < 8 {
+            return Err(Error::BadDescriptor);
+        }
+        match bytes[7] {
+            8 | 16 | 32 | 64 => ep0.max_packet_size = bytes[7] as u16,
+            _ => return Err(Error::BadDescriptor),
+        }
+
+        host.control(&mut ep0, &request::set_address(address), &mut [])?;
+        arch::wait_usec(SET_ADDRESS_RECOVERY_US);
+        ep0.device = address;
+
+        let setup = request::get_descriptor(descriptor::DEVICE, 0, 0, bytes.len() as u16);
+        let len = host.control(&mut ep0, &setup, &mut bytes)?;
+        let descriptor = DeviceDescriptor::parse(&bytes[..len])?;
+
+        let mut configuration = [0; CONFIGURATION_BUFFER];
+        let setup = request::get_descriptor(
+            descriptor::CONFIGURATION,
+            0,
+            0,
+            ConfigurationDescriptor::LEN as u16,
+        );
+        let len = host.control(&mut ep0, &setup, &mut configuration)?;
+        let header = ConfigurationDescriptor::parse(&configuration[..len])?;
+
+        let total = (header.total_length as usize).min(configuration.len());
+        let setup = request::get_descriptor(descriptor::CONFIGURATION, 0, 0, total as u16);
+        let len = host.control(&mut ep0, &setup, &mut configuration[..total])?;
+
+        host.control(&mut ep0, &request::set_configuration(header.value), &mut [])?;
+
+        let device = Device {
+            address,
+            speed,
+            parent,
+            split,
+            descriptor,
+            configuration: header.value,
+        };
+        r.lock(|inner| {
+            if let Some(slot) = inner.devices.iter_mut().find(|d| d.is_none()) {
+                *slot = Some(device);
+            }
+        });
+
+        let mut name = [0; 64];
+        let len_name = match descriptor.product_string {
+            0 => 0,
+            index => self.string(&device, index, &mut name).unwrap_or(0),
+        };
+        crate::debug!(
+            "USB device {}: {:04x}:{:04x} {} ({:?} speed)",
+            address,
+            descriptor.vendor,
+            descriptor.product,
+            core::str::from_utf8(&name[..len_name]).unwrap_or(""),
+            speed
+        );
+
+        self.bind(&device, &configuration[..len]);
+
+        Ok(address)
+    }
+
+    /// Offer every interface in `configuration` to the class drivers, first taker wins
+    fn bind(&self, device: &Device, configuration: &[u8]) {
+        let mut interface: Option<InterfaceDescriptor> = None;
+        let mut endpoints = [EndpointDescriptor::default(); MAX_ENDPOINTS];
+        let mut count = 0;
+
+        for d in Descriptors::new(configuration) {
+            match d {
+                Descriptor::Interface(next) => {
+                    if let Some(current) = interface {
+                        self.offer(device, &current, &endpoints[..count]);
+                    }
+
+                    // Alternate settings aren't supported, stick with the default
+                    interface = if next.alternate == 0 {
+                        Some(next)
+                    } else {
+                        None
+                    };
+                    count = 0;
+                }
+                Descriptor::Endpoint(endpoint) if interface.is_some() && count < MAX_ENDPOINTS => {
+                    endpoints[count] = endpoint;
+                    count += 1;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(current) = interface {
+            self.offer(device, &current, &endpoints[..count]);
+        }
+    }
+
+    fn offer(
+        &self,
+        device: &Device,
+        interface: &InterfaceDescriptor,
+        endpoints: &[EndpointDescriptor],
+    ) {
+        // Copied out, drivers call back into the bus
+        let mut r = &self.inner;
+        let drivers = r.lock(|inner| inner.drivers);
+
+        let driver = drivers
+            .iter()
+            .flatten()
+            .find(|driver| driver.probe(device, interface));
+
+        if let Some(driver) = driver {
+            if let Err(e) = driver.attach(self, device, interface, endpoints) {
+                crate::warn!(
+                    "USB device {}: {} driver failed on interface {}: {:?}",
+                    device.address,
+                    driver.name(),
+                    interface.number,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Forget the device plugged into `port` of the hub at `hub`, and everything behind it
+    pub fn detach_port(&self, hub: u8, port: u8) {
+        let mut r = &self.inner;
+        let device = r.lock(|inner| {
+            inner
+                .devices
+                .iter()
+                .flatten()
+                .find(|d| d.parent == Some((hub, port)))
+                .map(|d| d.address)
+        });
+
+        if let Some(address) = device {
+            self.detach(address);
+        }
+    }
+
+    /// Forget the device at `address` and everything behind it
+    pub fn detach(&self, address: u8) {
+        let mut r = &self.inner;
+
+        while let Some(child) = r.lock(|inner| {
+            inner
+                .devices
+                .iter()
+                .flatten()
+                .find(|d| d.parent.map(|(hub, _)| hub) == Some(address))
+                .map(|d| d.address)
+        }) {
+            self.detach(child);
+        }
+
+        let removed = r.lock(|inner| {
+            let slot = inner
+                .devices
+                .iter_mut()
+                .find(|d| d.map(|d| d.address) == Some(address))?;
+
+            slot.take()
+        });
+        if removed.is_none() {
+            return;
+        }
+
+        let drivers = r.lock(|inner| inner.drivers);
+        for driver in drivers.iter().flatten() {
+            driver.detach(address);
+        }
+
+        crate::debug!("USB device {} disconnected", address);
+    }
+
+    /// Let every class driver do its periodic work
+    pub fn poll(&self, now_us: u64) {
+        let mut r = &self.inner;
+        let drivers = r.lock(|inner| inner.drivers);
+
+        for driver in drivers.iter().flatten() {
+            driver.poll(self, now_us);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::mock::FakeHost;
+
+    struct Recorder {
+        attached: NullLock<Option<(u8, u8)>>,
+    }
+
+    impl ClassDriver for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn probe(&self, _device: &Device, interface: &InterfaceDescriptor) -> bool {
+            interface.class == 3
+        }
+
+        fn attach(
+            &self,
+            _bus: &Bus,
+            device: &Device,
+            interface: &InterfaceDescriptor,
+            endpoints: &[EndpointDescriptor],
+        ) -> Result<()> {
+            assert_eq!(endpoints.len(), interface.endpoints as usize);
+
+            let mut r = &self.attached;
+            r.lock(|attached| *attached = Some((device.address, endpoints[0].address)));
+            Ok(())
+        }
+
+        fn detach(&self, _address: u8) {
+            let mut r = &self.attached;
+            r.lock(|attached| *attached = None);
+        }
+    }
+
+    #[test]
+    fn devices_are_enumerated_and_bound() {
+        static RECORDER: Recorder = Recorder {
+            attached: NullLock::new(None),
+        };
+
+        let host = FakeHost::keyboard();
+        let bus = Bus::new();
+        bus.set_host(host);
+        bus.register_driver(&RECORDER).unwrap();
+
+        assert_eq!(bus.attach(None, Speed::Full, None), Ok(1));
+
+        let device = bus.device(1).unwrap();
+        assert_eq!(device.descriptor.max_packet_size0, 8);
+        assert_eq!(device.configuration, 1);
+        assert_eq!(host.address(), 1);
+        assert_eq!(host.configuration(), 1);
+
+        let mut r = &RECORDER.attached;
+        assert_eq!(r.lock(|a| *a), Some((1, 0x81)));
+
+        bus.detach(1);
+        assert!(bus.device(1).is_none());
+        assert_eq!(r.lock(|a| *a), None);
+    }
+
+    #[test]
+    fn detaching_a_hub_takes_its_children() {
+        let bus = Bus::new();
+        let device = |address, parent| Device {
+            address,
+            speed: Speed::High,
+            parent,
+            split: None,
+            descriptor: DeviceDescriptor::default(),
+            configuration: 1,
+        };
+
+        let mut r = &bus.inner;
+        r.lock(|inner| {
+            inner.devices[0] = Some(device(1, None));
+            inner.devices[1] = Some(device(2, Some((1, 1))));
+            inner.devices[2] = Some(device(3, Some((2, 4))));
+            inner.devices[3] = Some(device(4, Some((1, 2))));
+        });
+
+        bus.detach_port(1, 1);
+
+        let mut left = [0; MAX_DEVICES];
+        let mut count = 0;
+        bus.devices(&mut |d| {
+            left[count] = d.address;
+            count += 1;
+        });
+        assert_eq!(&left[..count], &[1, 4]);
+        assert_eq!(r.lock(|inner| inner.free_address()), Some(2));
+    }
+}
@@ -0,0 +1,327 @@
+//! Standard descriptors, USB 2.0 chapter 9.6, and the hub descriptor from 11.23.2.1.
+//!
+//! Devices send descriptors as packed little endian structures, each starting with its length
+//! and type. The parsers only look at the fields the kernel uses and accept descriptors longer
+//! than the spec says, newer revisions append fields.
+
+use super::{Error, Result};
+use crate::interface::usb::{Direction, TransferType};
+
+pub const DEVICE: u8 = 1;
+pub const CONFIGURATION: u8 = 2;
+pub const STRING: u8 = 3;
+pub const INTERFACE: u8 = 4;
+pub const ENDPOINT: u8 = 5;
+pub const HUB: u8 = 0x29;
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+/// Make sure `bytes` holds a descriptor of type `kind` at least `len` bytes long
+fn check(bytes: &[u8], kind: u8, len: usize) -> Result<()> {
+    if bytes.len() < len || (bytes[0] as usize) < len || bytes[1] != kind {
+        return Err(Error::BadDescriptor);
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceDescriptor {
+    /// BCD, 0x0200 for USB 2.0
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Of the default control endpoint
+    pub max_packet_size0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub device_version: u16,
+    /// String descriptor indices, 0 if there is none
+    pub manufacturer_string: u8,
+    pub product_string: u8,
+    pub serial_string: u8,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const LEN: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Result<DeviceDescriptor> {
+        check(bytes, DEVICE, Self::LEN)?;
+
+        Ok(DeviceDescriptor {
+            usb_version: le16(bytes, 2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size0: bytes[7],
+            vendor: le16(bytes, 8),
+            product: le16(bytes, 10),
+            device_version: le16(bytes, 12),
+            manufacturer_string: bytes[14],
+            product_string: bytes[15],
+            serial_string: bytes[16],
+            configurations: bytes[17],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConfigurationDescriptor {
+    /// Of the configuration and every interface and endpoint descriptor following it
+    pub total_length: u16,
+    pub interfaces: u8,
+    /// What to pass to SET_CONFIGURATION
+    pub value: u8,
+    pub string: u8,
+    pub attributes: u8,
+    pub max_power_ma: u16,
+}
+
+impl ConfigurationDescriptor {
+    pub const LEN: usize = 9;
+
+    pub fn parse(bytes: &[u8]) -> Result<ConfigurationDescriptor> {
+        check(bytes, CONFIGURATION, Self::LEN)?;
+
+        Ok(ConfigurationDescriptor {
+            total_length: le16(bytes, 2),
+            interfaces: bytes[4],
+            value: bytes[5],
+            string: bytes[6],
+            attributes: bytes[7],
+            max_power_ma: bytes[8] as u16 * 2,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub endpoints: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub string: u8,
+}
+
+impl InterfaceDescriptor {
+    pub const LEN: usize = 9;
+
+    pub fn parse(bytes: &[u8]) -> Result<InterfaceDescriptor> {
+        check(bytes, INTERFACE, Self::LEN)?;
+
+        Ok(InterfaceDescriptor {
+            number: bytes[2],
+            alternate: bytes[3],
+            endpoints: bytes[4],
+            class: bytes[5],
+            subclass: bytes[6],
+            protocol: bytes[7],
+            string: bytes[8],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EndpointDescriptor {
+    /// Endpoint number in bits 0-3, bit 7 set for IN
+    pub address: u8,
+    /// Transfer type in bits 0-1
+    pub attributes: u8,
+    /// Bits 0-10, high speed periodic endpoints keep extra transactions per microframe above
+    pub max_packet_size: u16,
+    /// Polling interval of periodic endpoints, in frames or as an exponent depending on speed
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub const LEN: usize = 7;
+
+    pub fn parse(bytes: &[u8]) -> Result<EndpointDescriptor> {
+        check(bytes, ENDPOINT, Self::LEN)?;
+
+        Ok(EndpointDescriptor {
+            address: bytes[2],
+            attributes: bytes[3],
+            max_packet_size: le16(bytes, 4),
+            interval: bytes[6],
+        })
+    }
+
+    pub fn number(&self) -> u8 {
+        self.address & 0x0F
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.address & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    pub fn kind(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HubDescriptor {
+    pub ports: u8,
+    pub characteristics: u16,
+    /// How long after switching a port on its power is good
+    pub power_on_to_good_ms: u16,
+}
+
+impl HubDescriptor {
+    pub const LEN: usize = 7;
+
+    pub fn parse(bytes: &[u8]) -> Result<HubDescriptor> {
+        check(bytes, HUB, Self::LEN)?;
+
+        Ok(HubDescriptor {
+            ports: bytes[2],
+            characteristics: le16(bytes, 3),
+            power_on_to_good_ms: bytes[5] as u16 * 2,
+        })
+    }
+}
+
+/// One entry of a configuration descriptor set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Descriptor<'a> {
+    Configuration(ConfigurationDescriptor),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    /// Class or vendor specific, as the type and the raw bytes
+    Other(u8, &'a [u8]),
+}
+
+/// Walks the descriptors GET_DESCRIPTOR(CONFIGURATION) returns. Stops at the first one that
+/// doesn't fit.
+pub struct Descriptors<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Descriptors<'a> {
+    pub fn new(bytes: &'a [u8]) -> Descriptors<'a> {
+        Descriptors { bytes }
+    }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Descriptor<'a>;
+
+    fn next(&mut self) -> Option<Descriptor<'a>> {
+        let len = *self.bytes.first()? as usize;
+        if len < 2 || len > self.bytes.len() {
+            return None;
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        let parsed = match bytes[1] {
+            CONFIGURATION => ConfigurationDescriptor::parse(bytes).map(Descriptor::Configuration),
+            INTERFACE => InterfaceDescriptor::parse(bytes).map(Descriptor::Interface),
+            ENDPOINT => EndpointDescriptor::parse(bytes).map(Descriptor::Endpoint),
+            _ => Err(Error::BadDescriptor),
+        };
+
+        Some(parsed.unwrap_or(Descriptor::Other(bytes[1], bytes)))
+    }
+}
+
+/// Turn a UTF-16 string descriptor into ASCII in `out`, anything else becomes '?'. Returns the
+/// number of bytes written.
+pub fn decode_string(bytes: &[u8], out: &mut [u8]) -> Result<usize> {
+    check(bytes, STRING, 2)?;
+
+    let end = (bytes[0] as usize).min(bytes.len());
+    let mut len = 0;
+    for (unit, slot) in bytes[2..end].chunks_exact(2).zip(out.iter_mut()) {
+        let c = u16::from_le_bytes([unit[0], unit[1]]);
+        *slot = if c < 0x80 { c as u8 } else { b'?' };
+        len += 1;
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_descriptor_is_parsed() {
+        let bytes = [
+            18, 1, 0, 2, 9, 0, 2, 64, 0x24, 4, 0x14, 0xEC, 0, 1, 0, 0, 0, 1,
+        ];
+
+        let device = DeviceDescriptor::parse(&bytes).unwrap();
+        assert_eq!(device.usb_version, 0x0200);
+        assert_eq!(device.class, 9);
+        assert_eq!(device.max_packet_size0, 64);
+        assert_eq!((device.vendor, device.product), (0x0424, 0xEC14));
+
+        assert_eq!(
+            DeviceDescriptor::parse(&bytes[..8]),
+            Err(Error::BadDescriptor)
+        );
+    }
+
+    #[test]
+    fn configuration_is_walked() {
+        let bytes = [
+            9, 2, 34, 0, 1, 1, 0, 0xA0, 50, // configuration
+            9, 4, 0, 0, 1, 3, 1, 1, 0, // interface, HID boot keyboard
+            9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0, // HID, class specific
+            7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN, interrupt
+        ];
+
+        let mut descriptors = Descriptors::new(&bytes);
+        match descriptors.next() {
+            Some(Descriptor::Configuration(c)) => {
+                assert_eq!((c.total_length, c.value, c.max_power_ma), (34, 1, 100))
+            }
+            d => panic!("{:?}", d),
+        }
+        match descriptors.next() {
+            Some(Descriptor::Interface(i)) => assert_eq!((i.class, i.protocol), (3, 1)),
+            d => panic!("{:?}", d),
+        }
+        assert_eq!(
+            descriptors.next(),
+            Some(Descriptor::Other(0x21, &bytes[18..27]))
+        );
+        match descriptors.next() {
+            Some(Descriptor::Endpoint(e)) => {
+                assert_eq!(e.number(), 1);
+                assert_eq!(e.direction(), Direction::In);
+                assert_eq!(e.kind(), TransferType::Interrupt);
+                assert_eq!(e.max_packet_size, 8);
+            }
+            d => panic!("{:?}", d),
+        }
+        assert_eq!(descriptors.next(), None);
+    }
+
+    #[test]
+    fn strings_are_decoded_to_ascii() {
+        let bytes = [12, 3, b'P', 0, b'i', 0, 0xE9, 0, b'c', 0, 0x3B, 0x26];
+        let mut out = [0; 16];
+
+        assert_eq!(decode_string(&bytes, &mut out), Ok(5));
+        assert_eq!(&out[..5], b"Pi?c?");
+    }
+}
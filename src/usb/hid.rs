@@ -0,0 +1,309 @@
+//! Keyboards and mice, USB HID 1.11.
+//!
+//! Only the boot protocol (HID 1.11 appendix B) is spoken, so there is no report descriptor to
+//! parse: keyboards send a modifier byte and up to six pressed keys, mice a button byte and
+//! their movement. The drivers read the interrupt IN endpoint when its interval is up and turn
+//! the reports into `Event`s on an `EventQueue`.
+//!
+//! Keys are translated with a US layout. Held keys repeat after `keyboard::REPEAT_DELAY_US`,
+//! the keyboards are put in idle mode and only report changes, so the repeats are made here.
+//!
+//! The queue implements `interface::console::Read`, which makes a keyboard usable as the
+//! console input on boards without a serial cable.
+
+mod keyboard;
+pub mod keymap;
+mod mouse;
+
+pub use keyboard::KeyboardDriver;
+pub use mouse::MouseDriver;
+
+use super::descriptor::{EndpointDescriptor, InterfaceDescriptor};
+use super::request::{HOST_TO_DEVICE, RECIPIENT_INTERFACE, TYPE_CLASS};
+use super::{Bus, Device, Error, Result};
+use crate::{arch, arch::sync::NullLock, interface};
+use interface::usb::{Direction, Endpoint, SetupPacket, Speed, TransferType};
+
+const HID_CLASS: u8 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+// Class requests, HID 1.11 7.2
+pub(super) const SET_REPORT: u8 = 0x09;
+pub(super) const SET_IDLE: u8 = 0x0A;
+pub(super) const SET_PROTOCOL: u8 = 0x0B;
+
+/// wValue of SET_PROTOCOL
+const BOOT_PROTOCOL: u16 = 0;
+
+/// Report type in the high byte of SET_REPORT's wValue
+const REPORT_OUTPUT: u16 = 2;
+
+/// Events that haven't been read yet, newer ones are dropped once it is full
+const QUEUE_LEN: usize = 64;
+
+fn class_request(request: u8, value: u16, interface: u8, length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: HOST_TO_DEVICE | TYPE_CLASS | RECIPIENT_INTERFACE,
+        request,
+        value,
+        index: interface as u16,
+        length,
+    }
+}
+
+/// Whether `interface` speaks the boot protocol `protocol`
+fn is_boot(interface: &InterfaceDescriptor, protocol: u8) -> bool {
+    interface.class == HID_CLASS
+        && interface.subclass == SUBCLASS_BOOT
+        && interface.protocol == protocol
+}
+
+/// Polling interval of an interrupt endpoint, `interval` is in frames at full and low speed and
+/// an exponent of microframes at high speed
+fn interval_us(speed: Speed, interval: u8) -> u64 {
+    match speed {
+        Speed::High => 125 << interval.saturating_sub(1).min(15),
+        _ => interval.max(1) as u64 * 1000,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// A key went down or repeats, `ch` is what it types if anything
+    KeyPress {
+        usage: u8,
+        modifiers: u8,
+        ch: Option<char>,
+    },
+    KeyRelease {
+        usage: u8,
+    },
+    /// Buttons held, bit 0 is the left one, and the movement since the last report
+    Mouse {
+        buttons: u8,
+        dx: i8,
+        dy: i8,
+        wheel: i8,
+    },
+}
+
+/// The interrupt IN endpoint an interface reports on
+#[derive(Clone, Copy)]
+struct Reports {
+    device: Device,
+    interface: u8,
+    endpoint: Endpoint,
+    interval_us: u64,
+    next_poll: u64,
+}
+
+impl Reports {
+    /// Switch `interface` to the boot protocol and find its report endpoint
+    fn open(
+        bus: &Bus,
+        device: &Device,
+        interface: &InterfaceDescriptor,
+        endpoints: &[EndpointDescriptor],
+    ) -> Result<Reports> {
+        let descriptor = endpoints
+            .iter()
+            .find(|e| e.kind() == TransferType::Interrupt && e.direction() == Direction::In)
+            .ok_or(Error::BadDescriptor)?;
+
+        let setup = class_request(SET_PROTOCOL, BOOT_PROTOCOL, interface.number, 0);
+        bus.control(device, &setup, &mut [])?;
+
+        // Only report changes. Optional, some mice stall it.
+        let setup = class_request(SET_IDLE, 0, interface.number, 0);
+        if let Err(e) = bus.control(device, &setup, &mut []) {
+            crate::debug!("USB device {}: SET_IDLE: {:?}", device.address, e);
+        }
+
+        Ok(Reports {
+            device: *device,
+            interface: interface.number,
+            endpoint: device.endpoint(descriptor),
+            interval_us: interval_us(device.speed, descriptor.interval),
+            next_poll: 0,
+        })
+    }
+
+    /// Read a report into `buf` if the interval is up and the device has one, returns its length
+    fn read(&mut self, bus: &Bus, now_us: u64, buf: &mut [u8]) -> Option<usize> {
+        if now_us < self.next_poll {
+            return None;
+        }
+        self.next_poll = now_us + self.interval_us;
+
+        match bus.transfer(&mut self.endpoint, buf) {
+            Ok(len) => Some(len),
+            Err(Error::Transfer(interface::usb::Error::Nak)) => None,
+            Err(e) => {
+                crate::debug!("USB device {}: report: {:?}", self.device.address, e);
+                None
+            }
+        }
+    }
+
+    /// Send an output report, the LEDs of a keyboard
+    fn write(&self, bus: &Bus, report: &mut [u8]) -> Result<()> {
+        let setup = class_request(
+            SET_REPORT,
+            REPORT_OUTPUT << 8,
+            self.interface,
+            report.len() as u16,
+        );
+
+        bus.control(&self.device, &setup, report).map(|_| ())
+    }
+}
+
+struct EventQueueInner {
+    events: [Event; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct EventQueue {
+    inner: NullLock<EventQueueInner>,
+}
+
+impl EventQueue {
+    pub const fn new() -> EventQueue {
+        EventQueue {
+            inner: NullLock::new(EventQueueInner {
+                events: [Event::KeyRelease { usage: 0 }; QUEUE_LEN],
+                head: 0,
+                len: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            if inner.len == QUEUE_LEN {
+                inner.dropped += 1;
+                return;
+            }
+
+            inner.events[(inner.head + inner.len) % QUEUE_LEN] = event;
+            inner.len += 1;
+        });
+    }
+
+    pub fn pop(&self) -> Option<Event> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            if inner.len == 0 {
+                return None;
+            }
+
+            let event = inner.events[inner.head];
+            inner.head = (inner.head + 1) % QUEUE_LEN;
+            inner.len -= 1;
+
+            Some(event)
+        })
+    }
+
+    /// Events lost because nobody read the queue in time
+    pub fn dropped(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.dropped)
+    }
+
+    /// The next typed character, events without one are thrown away
+    pub fn next_char(&self) -> Option<char> {
+        while let Some(event) = self.pop() {
+            if let Event::KeyPress { ch: Some(c), .. } = event {
+                return Some(c);
+            }
+        }
+
+        None
+    }
+}
+
+impl interface::console::Read for EventQueue {
+    /// Polls the bus until a key is typed
+    fn read_char(&self) -> char {
+        loop {
+            if let Some(c) = self.next_char() {
+                return c;
+            }
+
+            crate::usb::poll();
+            arch::nop();
+        }
+    }
+
+    /// Only what has been queued already, the bus is left to the caller to poll
+    fn try_read_char(&self) -> Option<char> {
+        self.next_char()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(c: char) -> Event {
+        Event::KeyPress {
+            usage: 0,
+            modifiers: 0,
+            ch: Some(c),
+        }
+    }
+
+    #[test]
+    fn queue_keeps_order_and_drops_when_full() {
+        let queue = EventQueue::new();
+        queue.push(key('a'));
+        queue.push(Event::KeyRelease { usage: 4 });
+        assert_eq!(queue.pop(), Some(key('a')));
+        assert_eq!(queue.pop(), Some(Event::KeyRelease { usage: 4 }));
+        assert_eq!(queue.pop(), None);
+
+        for _ in 0..QUEUE_LEN + 2 {
+            queue.push(key('b'));
+        }
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn only_key_presses_are_characters() {
+        let queue = EventQueue::new();
+        queue.push(Event::Mouse {
+            buttons: 1,
+            dx: 0,
+            dy: 0,
+            wheel: 0,
+        });
+        queue.push(Event::KeyPress {
+            usage: 0x52,
+            modifiers: 0,
+            ch: None,
+        });
+        queue.push(key('x'));
+
+        assert_eq!(queue.next_char(), Some('x'));
+        assert_eq!(queue.next_char(), None);
+    }
+
+    #[test]
+    fn intervals_depend_on_the_speed() {
+        assert_eq!(interval_us(Speed::Full, 10), 10_000);
+        assert_eq!(interval_us(Speed::Low, 0), 1_000);
+        assert_eq!(interval_us(Speed::High, 4), 1_000);
+    }
+}
@@ -0,0 +1,285 @@
+//! Boot protocol keyboards, HID 1.11 appendix B.1.
+
+use super::keymap::{self, CAPS_LOCK, ERROR_ROLL_OVER};
+use super::{is_boot, Event, EventQueue, Reports, PROTOCOL_KEYBOARD};
+use crate::usb::descriptor::{EndpointDescriptor, InterfaceDescriptor};
+use crate::usb::{Bus, ClassDriver, Device, Error, Result};
+use crate::{arch::sync::NullLock, interface};
+
+const MAX_KEYBOARDS: usize = 2;
+
+/// Modifiers, a reserved byte and up to six keys held down
+const REPORT_LEN: usize = 8;
+
+/// How long a key has to be held before it repeats, and how often it does then
+pub const REPEAT_DELAY_US: u64 = 500_000;
+pub const REPEAT_INTERVAL_US: u64 = 33_000;
+
+/// Bit of the LED output report
+const LED_CAPS_LOCK: u8 = 1 << 1;
+
+#[derive(Clone, Copy)]
+struct Keyboard {
+    reports: Reports,
+    /// The last report, to tell which keys went up and down
+    report: [u8; REPORT_LEN],
+    caps_lock: bool,
+    /// The key that repeats while held and when it does next
+    repeat: Option<(u8, u64)>,
+}
+
+impl Keyboard {
+    fn new(reports: Reports) -> Keyboard {
+        Keyboard {
+            reports,
+            report: [0; REPORT_LEN],
+            caps_lock: false,
+            repeat: None,
+        }
+    }
+
+    fn press(&self, usage: u8, events: &EventQueue) {
+        let modifiers = self.report[0];
+
+        events.push(Event::KeyPress {
+            usage,
+            modifiers,
+            ch: keymap::translate(usage, modifiers, self.caps_lock),
+        });
+    }
+
+    /// Queue the keys that went up and down since the last report. Returns whether caps lock
+    /// was toggled.
+    fn update(&mut self, report: &[u8; REPORT_LEN], now_us: u64, events: &EventQueue) -> bool {
+        // Too many keys held to tell which, wait until some are let go
+        if report[2..].contains(&ERROR_ROLL_OVER) {
+            return false;
+        }
+
+        let previous = self.report;
+        self.report = *report;
+
+        for &usage in previous[2..].iter() {
+            if usage != 0 && !report[2..].contains(&usage) {
+                events.push(Event::KeyRelease { usage });
+                if self.repeat.map(|(held, _)| held) == Some(usage) {
+                    self.repeat = None;
+                }
+            }
+        }
+
+        let mut toggled = false;
+        for &usage in report[2..].iter() {
+            if usage != 0 && !previous[2..].contains(&usage) {
+                if usage == CAPS_LOCK {
+                    self.caps_lock = !self.caps_lock;
+                    toggled = true;
+                }
+
+                self.press(usage, events);
+
+                // The newest key is the one that repeats
+                if keymap::repeats(usage) {
+                    self.repeat = Some((usage, now_us + REPEAT_DELAY_US));
+                }
+            }
+        }
+
+        toggled
+    }
+
+    fn repeat(&mut self, now_us: u64, events: &EventQueue) {
+        if let Some((usage, at)) = self.repeat {
+            if now_us >= at {
+                self.press(usage, events);
+                self.repeat = Some((usage, now_us + REPEAT_INTERVAL_US));
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct KeyboardDriver {
+    events: &'static EventQueue,
+    inner: NullLock<[Option<Keyboard>; MAX_KEYBOARDS]>,
+}
+
+impl KeyboardDriver {
+    pub const fn new(events: &'static EventQueue) -> KeyboardDriver {
+        KeyboardDriver {
+            events,
+            inner: NullLock::new([None; MAX_KEYBOARDS]),
+        }
+    }
+}
+
+impl ClassDriver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn probe(&self, _device: &Device, interface: &InterfaceDescriptor) -> bool {
+        is_boot(interface, PROTOCOL_KEYBOARD)
+    }
+
+    fn attach(
+        &self,
+        bus: &Bus,
+        device: &Device,
+        interface: &InterfaceDescriptor,
+        endpoints: &[EndpointDescriptor],
+    ) -> Result<()> {
+        let reports = Reports::open(bus, device, interface, endpoints)?;
+
+        let mut r = &self.inner;
+        r.lock(|keyboards| {
+            let slot = keyboards
+                .iter_mut()
+                .find(|k| k.is_none())
+                .ok_or(Error::NoSpace)?;
+            *slot = Some(Keyboard::new(reports));
+
+            Ok(())
+        })
+    }
+
+    fn detach(&self, address: u8) {
+        let mut r = &self.inner;
+        r.lock(|keyboards| {
+            for keyboard in keyboards.iter_mut() {
+                if keyboard.map(|k| k.reports.device.address) == Some(address) {
+                    *keyboard = None;
+                }
+            }
+        });
+    }
+
+    fn poll(&self, bus: &Bus, now_us: u64) {
+        let mut r = &self.inner;
+
+        for slot in 0..MAX_KEYBOARDS {
+            // Copied out, reading the report and setting the LEDs go through the bus
+            let mut keyboard = match r.lock(|keyboards| keyboards[slot]) {
+                Some(keyboard) => keyboard,
+                None => continue,
+            };
+
+            let mut report = [0; REPORT_LEN];
+            if let Some(len) = keyboard.reports.read(bus, now_us, &mut report) {
+                if len >= 3 && keyboard.update(&report, now_us, self.events) {
+                    let mut leds = [if keyboard.caps_lock { LED_CAPS_LOCK } else { 0 }];
+                    if let Err(e) = keyboard.reports.write(bus, &mut leds) {
+                        crate::debug!(
+                            "USB device {}: LEDs: {:?}",
+                            keyboard.reports.device.address,
+                            e
+                        );
+                    }
+                }
+            }
+            keyboard.repeat(now_us, self.events);
+
+            r.lock(|keyboards| {
+                if let Some(k) = keyboards[slot].as_mut() {
+                    if k.reports.device.address == keyboard.reports.device.address {
+                        *k = keyboard;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::mock::FakeHost;
+
+    fn keyboard(events: &'static EventQueue) -> (&'static FakeHost, Bus) {
+        let driver: &'static KeyboardDriver = Box::leak(Box::new(KeyboardDriver::new(events)));
+        let host = FakeHost::keyboard();
+        let bus = Bus::new();
+        bus.set_host(host);
+        bus.register_driver(driver).unwrap();
+        bus.attach(None, interface::usb::Speed::Full, None).unwrap();
+
+        (host, bus)
+    }
+
+    fn press(usage: u8, modifiers: u8, ch: Option<char>) -> Option<Event> {
+        Some(Event::KeyPress {
+            usage,
+            modifiers,
+            ch,
+        })
+    }
+
+    #[test]
+    fn reports_become_key_events() {
+        static EVENTS: EventQueue = EventQueue::new();
+        let (host, bus) = keyboard(&EVENTS);
+        assert_eq!(host.protocol(), Some(0));
+
+        // Shift and a, then b on top, then everything let go
+        host.queue_report(&[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+        bus.poll(0);
+        host.queue_report(&[0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+        bus.poll(10_000);
+        host.queue_report(&[0; 8]);
+        bus.poll(20_000);
+
+        assert_eq!(EVENTS.pop(), press(0x04, 0x02, Some('A')));
+        assert_eq!(EVENTS.pop(), press(0x05, 0x02, Some('B')));
+        assert_eq!(EVENTS.pop(), Some(Event::KeyRelease { usage: 0x04 }));
+        assert_eq!(EVENTS.pop(), Some(Event::KeyRelease { usage: 0x05 }));
+        assert_eq!(EVENTS.pop(), None);
+    }
+
+    #[test]
+    fn held_keys_repeat_until_released() {
+        static EVENTS: EventQueue = EventQueue::new();
+        let (host, bus) = keyboard(&EVENTS);
+
+        host.queue_report(&[0, 0, 0x2C, 0, 0, 0, 0, 0]);
+        bus.poll(0);
+        bus.poll(REPEAT_DELAY_US - 1);
+        assert_eq!(EVENTS.next_char(), Some(' '));
+        assert_eq!(EVENTS.next_char(), None);
+
+        bus.poll(REPEAT_DELAY_US);
+        bus.poll(REPEAT_DELAY_US + REPEAT_INTERVAL_US);
+        assert_eq!(EVENTS.next_char(), Some(' '));
+        assert_eq!(EVENTS.next_char(), Some(' '));
+
+        host.queue_report(&[0; 8]);
+        bus.poll(REPEAT_DELAY_US + 2 * REPEAT_INTERVAL_US);
+        bus.poll(10 * REPEAT_DELAY_US);
+        assert_eq!(EVENTS.pop(), Some(Event::KeyRelease { usage: 0x2C }));
+        assert_eq!(EVENTS.pop(), None);
+    }
+
+    #[test]
+    fn caps_lock_toggles_and_lights_up() {
+        static EVENTS: EventQueue = EventQueue::new();
+        let (host, bus) = keyboard(&EVENTS);
+
+        host.queue_report(&[0, 0, CAPS_LOCK, 0, 0, 0, 0, 0]);
+        bus.poll(0);
+        assert_eq!(host.leds(), LED_CAPS_LOCK);
+
+        host.queue_report(&[0; 8]);
+        bus.poll(10_000);
+        host.queue_report(&[0, 0, 0x04, 0, 0, 0, 0, 0]);
+        bus.poll(20_000);
+        assert_eq!(EVENTS.next_char(), Some('A'));
+
+        // Rolled over reports are ignored, nothing was let go
+        host.queue_report(&[0, 0, 1, 1, 1, 1, 1, 1]);
+        bus.poll(30_000);
+        assert_eq!(EVENTS.pop(), None);
+    }
+}
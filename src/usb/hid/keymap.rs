@@ -0,0 +1,97 @@
+//! Keyboard usages to characters, US layout. Usages are from the HID usage tables, chapter 10.
+
+// Modifier byte of a boot report, either side counts
+pub const CTRL: u8 = 0x11;
+pub const SHIFT: u8 = 0x22;
+
+pub const CAPS_LOCK: u8 = 0x39;
+
+/// Usage 0x01, all keys read as this while more are held than the keyboard can tell apart
+pub const ERROR_ROLL_OVER: u8 = 0x01;
+
+const FIRST: u8 = 0x04;
+const PLAIN: &[u8] = b"abcdefghijklmnopqrstuvwxyz1234567890\n\x1b\x08\t -=[]\\#;'`,./";
+const SHIFTED: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ!@#$%^&*()\n\x1b\x08\t _+{}|~:\"~<>?";
+
+/// Num lock is assumed on
+const KEYPAD_FIRST: u8 = 0x54;
+const KEYPAD: &[u8] = b"/*-+\n1234567890.";
+
+const DELETE: u8 = 0x4C;
+
+/// Whether holding `usage` should repeat it
+pub fn repeats(usage: u8) -> bool {
+    match usage {
+        // Caps, scroll and num lock
+        CAPS_LOCK | 0x47 | 0x53 => false,
+        u => u >= FIRST,
+    }
+}
+
+/// What pressing `usage` types, if anything
+pub fn translate(usage: u8, modifiers: u8, caps_lock: bool) -> Option<char> {
+    let index = usage.wrapping_sub(FIRST) as usize;
+    let keypad = usage.wrapping_sub(KEYPAD_FIRST) as usize;
+
+    let c = if index < PLAIN.len() {
+        let letter = index < 26;
+        let shift = modifiers & SHIFT != 0;
+        if shift != (letter && caps_lock) {
+            SHIFTED[index]
+        } else {
+            PLAIN[index]
+        }
+    } else if keypad < KEYPAD.len() {
+        KEYPAD[keypad]
+    } else if usage == DELETE {
+        0x7F
+    } else {
+        return None;
+    };
+
+    // Control characters, ^C is 0x03
+    if modifiers & CTRL != 0 && c.is_ascii_alphabetic() {
+        return Some((c & 0x1F) as char);
+    }
+
+    Some(c as char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_cover_the_same_keys() {
+        assert_eq!(PLAIN.len(), SHIFTED.len());
+        assert_eq!(FIRST as usize + PLAIN.len(), CAPS_LOCK as usize);
+    }
+
+    #[test]
+    fn shift_and_caps_lock() {
+        assert_eq!(translate(0x04, 0, false), Some('a'));
+        assert_eq!(translate(0x04, 0x02, false), Some('A'));
+        assert_eq!(translate(0x04, 0, true), Some('A'));
+        assert_eq!(translate(0x04, 0x20, true), Some('a'));
+
+        // Caps lock leaves the digits alone
+        assert_eq!(translate(0x1E, 0, true), Some('1'));
+        assert_eq!(translate(0x1E, 0x02, true), Some('!'));
+        assert_eq!(translate(0x38, 0x02, false), Some('?'));
+    }
+
+    #[test]
+    fn control_keypad_and_keys_without_characters() {
+        assert_eq!(translate(0x06, 0x01, false), Some('\x03'));
+        assert_eq!(translate(0x28, 0, false), Some('\n'));
+        assert_eq!(translate(0x58, 0, false), Some('\n'));
+        assert_eq!(translate(0x62, 0, false), Some('0'));
+        assert_eq!(translate(DELETE, 0, false), Some('\x7f'));
+
+        // F1 and the arrows
+        assert_eq!(translate(0x3A, 0, false), None);
+        assert_eq!(translate(0x52, 0, false), None);
+        assert!(!repeats(CAPS_LOCK));
+        assert!(repeats(0x52));
+    }
+}
@@ -0,0 +1,169 @@
+//! Boot protocol mice, HID 1.11 appendix B.2.
+
+use super::{is_boot, Event, EventQueue, Reports, PROTOCOL_MOUSE};
+use crate::usb::descriptor::{EndpointDescriptor, InterfaceDescriptor};
+use crate::usb::{Bus, ClassDriver, Device, Error, Result};
+use crate::{arch::sync::NullLock, interface};
+
+const MAX_MICE: usize = 2;
+
+/// Buttons and movement, most mice append the wheel although the boot protocol doesn't have it
+const REPORT_LEN: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Mouse {
+    reports: Reports,
+    buttons: u8,
+}
+
+/// What a report says, `None` if nothing moved and no button changed since `buttons`
+fn parse(report: &[u8], buttons: u8) -> Option<Event> {
+    if report.len() < 3 {
+        return None;
+    }
+
+    let event = Event::Mouse {
+        buttons: report[0] & 0x7,
+        dx: report[1] as i8,
+        dy: report[2] as i8,
+        wheel: report.get(3).map_or(0, |&w| w as i8),
+    };
+
+    match event {
+        Event::Mouse {
+            buttons: b,
+            dx: 0,
+            dy: 0,
+            wheel: 0,
+        } if b == buttons => None,
+        _ => Some(event),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct MouseDriver {
+    events: &'static EventQueue,
+    inner: NullLock<[Option<Mouse>; MAX_MICE]>,
+}
+
+impl MouseDriver {
+    pub const fn new(events: &'static EventQueue) -> MouseDriver {
+        MouseDriver {
+            events,
+            inner: NullLock::new([None; MAX_MICE]),
+        }
+    }
+}
+
+impl ClassDriver for MouseDriver {
+    fn name(&self) -> &'static str {
+        "mouse"
+    }
+
+    fn probe(&self, _device: &Device, interface: &InterfaceDescriptor) -> bool {
+        is_boot(interface, PROTOCOL_MOUSE)
+    }
+
+    fn attach(
+        &self,
+        bus: &Bus,
+        device: &Device,
+        interface: &InterfaceDescriptor,
+        endpoints: &[EndpointDescriptor],
+    ) -> Result<()> {
+        let reports = Reports::open(bus, device, interface, endpoints)?;
+
+        let mut r = &self.inner;
+        r.lock(|mice| {
+            let slot = mice
+                .iter_mut()
+                .find(|m| m.is_none())
+                .ok_or(Error::NoSpace)?;
+            *slot = Some(Mouse {
+                reports,
+                buttons: 0,
+            });
+
+            Ok(())
+        })
+    }
+
+    fn detach(&self, address: u8) {
+        let mut r = &self.inner;
+        r.lock(|mice| {
+            for mouse in mice.iter_mut() {
+                if mouse.map(|m| m.reports.device.address) == Some(address) {
+                    *mouse = None;
+                }
+            }
+        });
+    }
+
+    fn poll(&self, bus: &Bus, now_us: u64) {
+        let mut r = &self.inner;
+
+        for slot in 0..MAX_MICE {
+            let mut mouse = match r.lock(|mice| mice[slot]) {
+                Some(mouse) => mouse,
+                None => continue,
+            };
+
+            let mut report = [0; REPORT_LEN];
+            if let Some(len) = mouse.reports.read(bus, now_us, &mut report) {
+                if let Some(event) = parse(&report[..len], mouse.buttons) {
+                    if let Event::Mouse { buttons, .. } = event {
+                        mouse.buttons = buttons;
+                    }
+                    self.events.push(event);
+                }
+            }
+
+            r.lock(|mice| {
+                if let Some(m) = mice[slot].as_mut() {
+                    if m.reports.device.address == mouse.reports.device.address {
+                        *m = mouse;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_without_news_are_skipped() {
+        assert_eq!(parse(&[0, 0, 0], 0), None);
+        assert_eq!(parse(&[0, 0, 0, 0], 0), None);
+        assert_eq!(parse(&[0, 0], 1), None);
+
+        assert_eq!(
+            parse(&[0, 0, 0], 1),
+            Some(Event::Mouse {
+                buttons: 0,
+                dx: 0,
+                dy: 0,
+                wheel: 0
+            })
+        );
+    }
+
+    #[test]
+    fn movement_is_signed() {
+        assert_eq!(
+            parse(&[0x09, 0xFF, 5, 0xFE], 1),
+            Some(Event::Mouse {
+                buttons: 1,
+                dx: -1,
+                dy: 5,
+                wheel: -2
+            })
+        );
+    }
+}
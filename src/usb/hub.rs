@@ -0,0 +1,386 @@
+//! Hub class driver, USB 2.0 chapter 11.
+//!
+//! Ports are switched on when the hub is attached and then polled with GET_STATUS. A connection
+//! change detaches whatever was on the port and, if something is plugged in now, resets the port
+//! and enumerates the new device. Full and low speed devices behind a high speed hub have their
+//! transactions split by it, deeper hubs pass on the split of the hub above them.
+
+use super::descriptor::{self, EndpointDescriptor, HubDescriptor, InterfaceDescriptor};
+use super::request::{self, DEVICE_TO_HOST, HOST_TO_DEVICE, RECIPIENT_OTHER, TYPE_CLASS};
+use super::{Bus, ClassDriver, Device, Error, Result};
+use crate::{arch, arch::sync::NullLock, interface};
+use interface::usb::{SetupPacket, Speed, Split};
+
+const HUB_CLASS: u8 = 9;
+
+const MAX_HUBS: usize = 4;
+
+/// Ports beyond this are left alone, `Hub::attached` has a bit per port
+const MAX_PORTS: u8 = 15;
+
+const POLL_INTERVAL_US: u64 = 250_000;
+
+/// USB 2.0 7.1.7.5, a reset lasts 10-20ms. Give up after that many status reads 10ms apart.
+const RESET_POLLS: usize = 10;
+const RESET_POLL_US: usize = 10_000;
+/// USB 2.0 7.1.7.3, time the device gets after a reset before it has to answer
+const RESET_RECOVERY_US: usize = 10_000;
+
+// Port features, USB 2.0 table 11-17
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_ENABLE: u16 = 17;
+const C_PORT_OVER_CURRENT: u16 = 19;
+const C_PORT_RESET: u16 = 20;
+
+// wPortStatus and wPortChange bits, USB 2.0 tables 11-21 and 11-22
+const STATUS_CONNECTION: u16 = 1 << 0;
+const STATUS_ENABLE: u16 = 1 << 1;
+const STATUS_RESET: u16 = 1 << 4;
+const STATUS_LOW_SPEED: u16 = 1 << 9;
+const STATUS_HIGH_SPEED: u16 = 1 << 10;
+const CHANGE_CONNECTION: u16 = 1 << 0;
+const CHANGE_ENABLE: u16 = 1 << 1;
+const CHANGE_OVER_CURRENT: u16 = 1 << 3;
+const CHANGE_RESET: u16 = 1 << 4;
+
+fn get_hub_descriptor(length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: DEVICE_TO_HOST | TYPE_CLASS,
+        request: request::GET_DESCRIPTOR,
+        value: (descriptor::HUB as u16) << 8,
+        index: 0,
+        length,
+    }
+}
+
+fn port_feature(request: u8, feature: u16, port: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: HOST_TO_DEVICE | TYPE_CLASS | RECIPIENT_OTHER,
+        request,
+        value: feature,
+        index: port as u16,
+        length: 0,
+    }
+}
+
+fn get_port_status(port: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: DEVICE_TO_HOST | TYPE_CLASS | RECIPIENT_OTHER,
+        request: request::GET_STATUS,
+        value: 0,
+        index: port as u16,
+        length: 4,
+    }
+}
+
+/// Speed of the device on a port, from its status
+fn port_speed(status: u16) -> Speed {
+    if status & STATUS_LOW_SPEED != 0 {
+        Speed::Low
+    } else if status & STATUS_HIGH_SPEED != 0 {
+        Speed::High
+    } else {
+        Speed::Full
+    }
+}
+
+/// Who splits the transactions of a `speed` device on `port` of `hub`
+fn child_split(hub: &Device, port: u8, speed: Speed) -> Option<Split> {
+    match (hub.speed, speed) {
+        (Speed::High, Speed::High) => None,
+        (Speed::High, _) => Some(Split {
+            hub_address: hub.address,
+            port,
+        }),
+        _ => hub.split,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Hub {
+    device: Device,
+    ports: u8,
+    /// Bit n set while a device is enumerated on port n
+    attached: u16,
+}
+
+struct HubDriverInner {
+    hubs: [Option<Hub>; MAX_HUBS],
+    last_poll: u64,
+}
+
+impl HubDriverInner {
+    const fn new() -> HubDriverInner {
+        HubDriverInner {
+            hubs: [None; MAX_HUBS],
+            last_poll: 0,
+        }
+    }
+}
+
+fn set_port_feature(bus: &Bus, hub: &Device, feature: u16, port: u8) -> Result<()> {
+    let setup = port_feature(request::SET_FEATURE, feature, port);
+    bus.control(hub, &setup, &mut []).map(|_| ())
+}
+
+fn clear_port_feature(bus: &Bus, hub: &Device, feature: u16, port: u8) -> Result<()> {
+    let setup = port_feature(request::CLEAR_FEATURE, feature, port);
+    bus.control(hub, &setup, &mut []).map(|_| ())
+}
+
+/// wPortStatus and wPortChange of `port`
+fn port_status(bus: &Bus, hub: &Device, port: u8) -> Result<(u16, u16)> {
+    let mut status = [0; 4];
+    if bus.control(hub, &get_port_status(port), &mut status)? < 4 {
+        return Err(Error::BadDescriptor);
+    }
+
+    Ok((
+        u16::from_le_bytes([status[0], status[1]]),
+        u16::from_le_bytes([status[2], status[3]]),
+    ))
+}
+
+/// Reset `port` and return the speed of the device on it
+fn reset_port(bus: &Bus, hub: &Device, port: u8) -> Result<Speed> {
+    set_port_feature(bus, hub, PORT_RESET, port)?;
+
+    for _ in 0..RESET_POLLS {
+        arch::wait_usec(RESET_POLL_US);
+
+        let (status, change) = port_status(bus, hub, port)?;
+        if status & STATUS_RESET != 0 && change & CHANGE_RESET == 0 {
+            continue;
+        }
+
+        clear_port_feature(bus, hub, C_PORT_RESET, port)?;
+        if status & STATUS_ENABLE == 0 {
+            break;
+        }
+        arch::wait_usec(RESET_RECOVERY_US);
+
+        return Ok(port_speed(status));
+    }
+
+    Err(Error::Transfer(interface::usb::Error::Timeout))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// OS interface implementations
+////////////////////////////////////////////////////////////////////////////////
+use interface::sync::Mutex;
+
+pub struct HubDriver {
+    inner: NullLock<HubDriverInner>,
+}
+
+impl HubDriver {
+    pub const fn new() -> HubDriver {
+        HubDriver {
+            inner: NullLock::new(HubDriverInner::new()),
+        }
+    }
+
+    /// Look at every port of the hub in `slot` and catch up with what was plugged in or out
+    fn scan(&self, bus: &Bus, slot: usize) {
+        let mut r = &self.inner;
+        let hub = match r.lock(|inner| inner.hubs[slot]) {
+            Some(hub) => hub,
+            None => return,
+        };
+
+        for port in 1..=hub.ports {
+            let (status, change) = match port_status(bus, &hub.device, port) {
+                Ok(status) => status,
+                Err(e) => {
+                    crate::debug!("Hub {} port {}: {:?}", hub.device.address, port, e);
+                    continue;
+                }
+            };
+
+            for &(bit, feature) in [
+                (CHANGE_CONNECTION, C_PORT_CONNECTION),
+                (CHANGE_ENABLE, C_PORT_ENABLE),
+                (CHANGE_OVER_CURRENT, C_PORT_OVER_CURRENT),
+            ]
+            .iter()
+            {
+                if change & bit != 0 {
+                    let _ = clear_port_feature(bus, &hub.device, feature, port);
+                }
+            }
+
+            let bit = 1 << port;
+            let mut attached =
+                r.lock(|inner| inner.hubs[slot].map_or(0, |h| h.attached) & bit != 0);
+            let connected = status & STATUS_CONNECTION != 0;
+
+            if attached && (!connected || change & CHANGE_CONNECTION != 0) {
+                bus.detach_port(hub.device.address, port);
+                attached = false;
+            }
+
+            if connected && !attached {
+                // Marked even if enumeration fails, it is tried again once the device is replugged
+                attached = true;
+                if let Err(e) = self.attach_port(bus, &hub.device, port) {
+                    crate::warn!(
+                        "Hub {} port {}: enumeration failed: {:?}",
+                        hub.device.address,
+                        port,
+                        e
+                    );
+                }
+            }
+
+            // The hub may have gone while enumerating, only touch it if it is still there
+            r.lock(|inner| {
+                if let Some(h) = inner.hubs[slot].as_mut() {
+                    if h.device.address == hub.device.address {
+                        h.attached = if attached {
+                            h.attached | bit
+                        } else {
+                            h.attached & !bit
+                        };
+                    }
+                }
+            });
+        }
+    }
+
+    fn attach_port(&self, bus: &Bus, hub: &Device, port: u8) -> Result<u8> {
+        let speed = reset_port(bus, hub, port)?;
+
+        bus.attach(
+            Some((hub.address, port)),
+            speed,
+            child_split(hub, port, speed),
+        )
+    }
+}
+
+impl ClassDriver for HubDriver {
+    fn name(&self) -> &'static str {
+        "hub"
+    }
+
+    fn probe(&self, _device: &Device, interface: &InterfaceDescriptor) -> bool {
+        interface.class == HUB_CLASS
+    }
+
+    fn attach(
+        &self,
+        bus: &Bus,
+        device: &Device,
+        _interface: &InterfaceDescriptor,
+        _endpoints: &[EndpointDescriptor],
+    ) -> Result<()> {
+        let mut bytes = [0; HubDescriptor::LEN];
+        let len = bus.control(device, &get_hub_descriptor(bytes.len() as u16), &mut bytes)?;
+        let descriptor = HubDescriptor::parse(&bytes[..len])?;
+        let ports = descriptor.ports.min(MAX_PORTS);
+
+        let mut r = &self.inner;
+        let slot = r
+            .lock(|inner| {
+                let slot = inner.hubs.iter().position(|h| h.is_none())?;
+                inner.hubs[slot] = Some(Hub {
+                    device: *device,
+                    ports,
+                    attached: 0,
+                });
+
+                Some(slot)
+            })
+            .ok_or(Error::NoSpace)?;
+
+        for port in 1..=ports {
+            set_port_feature(bus, device, PORT_POWER, port)?;
+        }
+        arch::wait_usec(descriptor.power_on_to_good_ms as usize * 1000);
+
+        crate::debug!("Hub {}: {} ports", device.address, ports);
+
+        // Whatever is plugged in already is found like a new connection
+        self.scan(bus, slot);
+
+        Ok(())
+    }
+
+    fn detach(&self, address: u8) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for hub in inner.hubs.iter_mut() {
+                if hub.map_or(false, |h| h.device.address == address) {
+                    *hub = None;
+                }
+            }
+        });
+    }
+
+    fn poll(&self, bus: &Bus, now_us: u64) {
+        let mut r = &self.inner;
+        let due = r.lock(|inner| {
+            if now_us.saturating_sub(inner.last_poll) < POLL_INTERVAL_US {
+                return false;
+            }
+            inner.last_poll = now_us;
+
+            true
+        });
+
+        if due {
+            for slot in 0..MAX_HUBS {
+                self.scan(bus, slot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::descriptor::DeviceDescriptor;
+
+    fn hub(address: u8, speed: Speed, split: Option<Split>) -> Device {
+        Device {
+            address,
+            speed,
+            parent: None,
+            split,
+            descriptor: DeviceDescriptor::default(),
+            configuration: 1,
+        }
+    }
+
+    #[test]
+    fn speed_comes_from_the_port_status() {
+        assert_eq!(port_speed(STATUS_CONNECTION | STATUS_LOW_SPEED), Speed::Low);
+        assert_eq!(
+            port_speed(STATUS_CONNECTION | STATUS_HIGH_SPEED),
+            Speed::High
+        );
+        assert_eq!(port_speed(STATUS_CONNECTION), Speed::Full);
+    }
+
+    #[test]
+    fn slow_devices_are_split_by_the_nearest_high_speed_hub() {
+        let high = hub(1, Speed::High, None);
+        assert_eq!(child_split(&high, 3, Speed::High), None);
+
+        let split = child_split(&high, 3, Speed::Full);
+        assert_eq!(
+            split,
+            Some(Split {
+                hub_address: 1,
+                port: 3
+            })
+        );
+
+        // A full speed hub behind it passes the split on to its own children
+        let full = hub(2, Speed::Full, split);
+        assert_eq!(child_split(&full, 1, Speed::Low), split);
+    }
+}
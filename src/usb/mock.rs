@@ -0,0 +1,156 @@
+//! A host controller with a single scripted device behind it, for testing enumeration and class
+//! drivers on the build machine.
+
+use super::{descriptor, hid, request};
+use crate::{arch::sync::NullLock, interface};
+use interface::sync::Mutex;
+use interface::usb::{Endpoint, Error, HostController, Result, SetupPacket, Speed};
+
+struct FakeDevice {
+    address: u8,
+    configuration: u8,
+    device: &'static [u8],
+    configuration_set: &'static [u8],
+    /// Last SET_PROTOCOL, HID devices start out in the report protocol
+    protocol: Option<u8>,
+    /// Last output report
+    leds: u8,
+    /// Interrupt IN reports waiting to be read, oldest first
+    reports: [[u8; 8]; 4],
+    queued: usize,
+}
+
+pub struct FakeHost {
+    inner: NullLock<FakeDevice>,
+}
+
+/// Full speed boot keyboard, 8 byte control endpoint and reports on endpoint 1
+const KEYBOARD_DEVICE: [u8; 18] = [
+    18, 1, 0x10, 1, 0, 0, 0, 8, 0xD9, 4, 0x02, 0x07, 0, 1, 0, 0, 0, 1,
+];
+const KEYBOARD_CONFIGURATION: [u8; 34] = [
+    9, 2, 34, 0, 1, 1, 0, 0xA0, 50, // configuration
+    9, 4, 0, 0, 1, 3, 1, 1, 0, // interface, HID boot keyboard
+    9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0, // HID
+    7, 5, 0x81, 3, 8, 0, 10, // endpoint 1 IN, interrupt
+];
+
+impl FakeHost {
+    /// A boot keyboard on the root port, not addressed yet
+    pub fn keyboard() -> &'static FakeHost {
+        Box::leak(Box::new(FakeHost {
+            inner: NullLock::new(FakeDevice {
+                address: 0,
+                configuration: 0,
+                device: &KEYBOARD_DEVICE,
+                configuration_set: &KEYBOARD_CONFIGURATION,
+                protocol: None,
+                leds: 0,
+                reports: [[0; 8]; 4],
+                queued: 0,
+            }),
+        }))
+    }
+
+    pub fn address(&self) -> u8 {
+        let mut r = &self.inner;
+        r.lock(|device| device.address)
+    }
+
+    pub fn configuration(&self) -> u8 {
+        let mut r = &self.inner;
+        r.lock(|device| device.configuration)
+    }
+
+    pub fn protocol(&self) -> Option<u8> {
+        let mut r = &self.inner;
+        r.lock(|device| device.protocol)
+    }
+
+    pub fn leds(&self) -> u8 {
+        let mut r = &self.inner;
+        r.lock(|device| device.leds)
+    }
+
+    /// Have the next interrupt IN transfer return `report`
+    pub fn queue_report(&self, report: &[u8]) {
+        let mut r = &self.inner;
+        r.lock(|device| {
+            let slot = &mut device.reports[device.queued];
+            slot[..report.len()].copy_from_slice(report);
+            device.queued += 1;
+        });
+    }
+}
+
+fn copy(from: &[u8], to: &mut [u8], length: u16) -> usize {
+    let len = from.len().min(to.len()).min(length as usize);
+    to[..len].copy_from_slice(&from[..len]);
+
+    len
+}
+
+impl HostController for FakeHost {
+    fn port_speed(&self) -> Option<Speed> {
+        Some(Speed::Full)
+    }
+
+    fn reset_port(&self) -> Result<Speed> {
+        Ok(Speed::Full)
+    }
+
+    fn control(
+        &self,
+        endpoint: &mut Endpoint,
+        setup: &SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|device| {
+            // Nobody answers at other addresses
+            if endpoint.device != device.address {
+                return Err(Error::Timeout);
+            }
+
+            if setup.request_type & request::TYPE_CLASS != 0 {
+                match setup.request {
+                    hid::SET_PROTOCOL => device.protocol = Some(setup.value as u8),
+                    hid::SET_REPORT => device.leds = data[0],
+                    hid::SET_IDLE => {}
+                    _ => return Err(Error::Stall),
+                }
+
+                return Ok(data.len());
+            }
+
+            match (setup.request, (setup.value >> 8) as u8) {
+                (request::SET_ADDRESS, _) => device.address = setup.value as u8,
+                (request::SET_CONFIGURATION, _) => device.configuration = setup.value as u8,
+                (request::GET_DESCRIPTOR, descriptor::DEVICE) => {
+                    return Ok(copy(device.device, data, setup.length))
+                }
+                (request::GET_DESCRIPTOR, descriptor::CONFIGURATION) => {
+                    return Ok(copy(device.configuration_set, data, setup.length))
+                }
+                _ => return Err(Error::Stall),
+            }
+
+            Ok(0)
+        })
+    }
+
+    fn transfer(&self, _endpoint: &mut Endpoint, data: &mut [u8]) -> Result<usize> {
+        let mut r = &self.inner;
+        r.lock(|device| {
+            if device.queued == 0 {
+                return Err(Error::Nak);
+            }
+
+            let len = copy(&device.reports[0], data, 8);
+            device.reports.rotate_left(1);
+            device.queued -= 1;
+
+            Ok(len)
+        })
+    }
+}
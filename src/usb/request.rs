@@ -0,0 +1,52 @@
+//! Standard device requests, USB 2.0 chapter 9.4.
+
+use crate::interface::usb::SetupPacket;
+
+// bmRequestType
+pub const HOST_TO_DEVICE: u8 = 0x00;
+pub const DEVICE_TO_HOST: u8 = 0x80;
+pub const TYPE_CLASS: u8 = 0x20;
+pub const RECIPIENT_DEVICE: u8 = 0x00;
+pub const RECIPIENT_INTERFACE: u8 = 0x01;
+pub const RECIPIENT_OTHER: u8 = 0x03;
+
+// bRequest
+pub const GET_STATUS: u8 = 0;
+pub const CLEAR_FEATURE: u8 = 1;
+pub const SET_FEATURE: u8 = 3;
+pub const SET_ADDRESS: u8 = 5;
+pub const GET_DESCRIPTOR: u8 = 6;
+pub const SET_CONFIGURATION: u8 = 9;
+
+/// Language used for string descriptors when the device doesn't list any
+pub const LANGUAGE_EN_US: u16 = 0x0409;
+
+pub fn get_descriptor(kind: u8, index: u8, language: u16, length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: DEVICE_TO_HOST | RECIPIENT_DEVICE,
+        request: GET_DESCRIPTOR,
+        value: (kind as u16) << 8 | index as u16,
+        index: language,
+        length,
+    }
+}
+
+pub fn set_address(address: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: HOST_TO_DEVICE | RECIPIENT_DEVICE,
+        request: SET_ADDRESS,
+        value: address as u16,
+        index: 0,
+        length: 0,
+    }
+}
+
+pub fn set_configuration(value: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: HOST_TO_DEVICE | RECIPIENT_DEVICE,
+        request: SET_CONFIGURATION,
+        value: value as u16,
+        index: 0,
+        length: 0,
+    }
+}